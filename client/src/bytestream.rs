@@ -1,8 +1,11 @@
 use std::time::Duration;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use comsrv_protocol::{
-    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, ModBusProtocol, Request, Response,
+    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, Endian, LoopbackExchange,
+    LoopbackRequest, ModBusProtocol, Request, Response, SerialPortConfig, SerialRequest,
+    SerialResponse,
 };
 
 use crate::{lock, modbus::ModBusPipe, LockGuard, Lockable, Locked, Rpc, DEFAULT_RPC_TIMEOUT};
@@ -12,6 +15,8 @@ pub struct ByteStreamPipe<T: Rpc> {
     instrument: ByteStreamInstrument,
     lock: Locked,
     pub timeout: Duration,
+    /// If set, queries discard buffered input before writing. Defaults to `true`.
+    pub flush_input: bool,
 }
 
 impl<T: Rpc> Clone for ByteStreamPipe<T> {
@@ -21,6 +26,7 @@ impl<T: Rpc> Clone for ByteStreamPipe<T> {
             instrument: self.instrument.clone(),
             lock: Locked::new(),
             timeout: self.timeout,
+            flush_input: self.flush_input,
         }
     }
 }
@@ -41,6 +47,7 @@ impl<T: Rpc> ByteStreamPipe<T> {
             instrument,
             lock: Locked::new(),
             timeout: DEFAULT_RPC_TIMEOUT,
+            flush_input: true,
         }
     }
 
@@ -50,6 +57,7 @@ impl<T: Rpc> ByteStreamPipe<T> {
             instrument,
             lock: Locked::new(),
             timeout,
+            flush_input: true,
         }
     }
 
@@ -64,6 +72,7 @@ impl<T: Rpc> ByteStreamPipe<T> {
                     instrument: self.instrument.clone(),
                     request,
                     lock: self.lock.check_lock(),
+                    truncate: false,
                 },
                 self.timeout,
             )
@@ -83,6 +92,23 @@ impl<T: Rpc> ByteStreamPipe<T> {
         }
     }
 
+    /// Write all data buffered by the `comsrv` to the instrument
+    pub async fn flush(&mut self) -> crate::Result<()> {
+        match self.request(ByteStreamRequest::Flush).await? {
+            ByteStreamResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Close the connection to the instrument, which remains connected to the `comsrv`. Data buffered by the
+    /// `comsrv` is written first if `flush` is set, otherwise it is discarded.
+    pub async fn close(&mut self, flush: bool) -> crate::Result<()> {
+        match self.request(ByteStreamRequest::Close { flush }).await? {
+            ByteStreamResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub async fn read_all(&mut self) -> crate::Result<Vec<u8>> {
         match self.request(ByteStreamRequest::ReadAll).await? {
             ByteStreamResponse::Data(x) => Ok(x),
@@ -90,6 +116,40 @@ impl<T: Rpc> ByteStreamPipe<T> {
         }
     }
 
+    /// Same as [`ByteStreamPipe::read_all`] but returns at most `max_bytes`.
+    pub async fn read_all_max(&mut self, max_bytes: u32) -> crate::Result<Vec<u8>> {
+        match self
+            .request(ByteStreamRequest::ReadAllMax { max_bytes })
+            .await?
+        {
+            ByteStreamResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Read data as it arrives until no data arrived for `idle` or `timeout` elapsed. The data is not returned
+    /// but broadcast in [`ByteStreamResponse::Chunk`] notifications, see [`crate::ws::WsRpc::subscribe`].
+    pub async fn read_all_stream(
+        &mut self,
+        idle: Duration,
+        timeout: Duration,
+    ) -> crate::Result<()> {
+        let request = Request::Bytes {
+            instrument: self.instrument.clone(),
+            request: ByteStreamRequest::ReadAllStream {
+                idle: idle.into(),
+                timeout: timeout.into(),
+            },
+            lock: self.lock.check_lock(),
+            truncate: false,
+        };
+        match self.rpc.request(request, timeout + self.timeout).await? {
+            Response::Bytes(ByteStreamResponse::Done) => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub async fn read_to_term(&mut self, term: u8, timeout: Duration) -> crate::Result<Vec<u8>> {
         let req = ByteStreamRequest::ReadToTerm {
             term,
@@ -112,6 +172,40 @@ impl<T: Rpc> ByteStreamPipe<T> {
         }
     }
 
+    /// Read up to `count` bytes. Returns as soon as any data is available or an empty buffer
+    /// if no data arrived within `timeout`.
+    pub async fn read_up_to(&mut self, count: u32, timeout: Duration) -> crate::Result<Vec<u8>> {
+        let req = ByteStreamRequest::ReadUpTo {
+            count,
+            timeout: timeout.into(),
+        };
+        match self.request(req).await? {
+            ByteStreamResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Read a message preceded by a length prefix of `prefix_len` bytes (1, 2 or 4) and return
+    /// the payload. Fails if the length exceeds `max_len`.
+    pub async fn read_length_prefixed(
+        &mut self,
+        prefix_len: u8,
+        endian: Endian,
+        max_len: u32,
+        timeout: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        let req = ByteStreamRequest::ReadLengthPrefixed {
+            prefix_len,
+            endian,
+            max_len,
+            timeout: timeout.into(),
+        };
+        match self.request(req).await? {
+            ByteStreamResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub async fn cobs_write(&mut self, data: &[u8]) -> crate::Result<()> {
         let req = ByteStreamRequest::CobsWrite(data.to_vec());
         match self.request(req).await? {
@@ -128,10 +222,28 @@ impl<T: Rpc> ByteStreamPipe<T> {
         }
     }
 
+    /// Same as [`ByteStreamPipe::cobs_read`] but fails if the encoded frame exceeds `max_frame_size` bytes.
+    pub async fn cobs_read_max(
+        &mut self,
+        max_frame_size: u32,
+        timeout: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        let req = ByteStreamRequest::CobsReadMax {
+            timeout: timeout.into(),
+            max_frame_size,
+        };
+        match self.request(req).await? {
+            ByteStreamResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub async fn cobs_query(&mut self, write: &[u8], timeout: Duration) -> crate::Result<Vec<u8>> {
         let req = ByteStreamRequest::CobsQuery {
             data: write.to_vec(),
             timeout: timeout.into(),
+            flush_input: self.flush_input,
+            max_frame_size: None,
         };
         match self.request(req).await? {
             ByteStreamResponse::Data(x) => Ok(x),
@@ -171,6 +283,7 @@ impl<T: Rpc> ByteStreamPipe<T> {
             line: write.to_string(),
             timeout: timeout.into(),
             term,
+            flush_input: self.flush_input,
         };
         match self.request(req).await? {
             ByteStreamResponse::String(x) => Ok(x),
@@ -178,6 +291,51 @@ impl<T: Rpc> ByteStreamPipe<T> {
         }
     }
 
+    /// Return the configuration the driver applied to the serial port. This may differ from the requested one,
+    /// e.g. if the adapter snaps the baud rate to the nearest supported one.
+    pub async fn actual_serial_config(&mut self) -> crate::Result<SerialPortConfig> {
+        let instrument = match &self.instrument {
+            ByteStreamInstrument::Serial(x) => x.clone(),
+            _ => {
+                return Err(crate::Error::Other(anyhow!(
+                    "Only serial ports report their actual configuration"
+                )))
+            }
+        };
+        let req = Request::Serial {
+            instrument,
+            request: SerialRequest::GetActualConfig,
+            lock: self.lock.check_lock(),
+        };
+        match self.rpc.request(req, self.timeout).await? {
+            Response::Serial(SerialResponse::Config(x)) => Ok(x),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Script the simulated device of a loopback instrument to reply with the `response` of each
+    /// exchange once it received its `request`, see [`LoopbackRequest::Script`].
+    pub async fn script_loopback(&mut self, exchanges: Vec<LoopbackExchange>) -> crate::Result<()> {
+        let instrument = match &self.instrument {
+            ByteStreamInstrument::Loopback(x) => x.clone(),
+            _ => {
+                return Err(crate::Error::Other(anyhow!(
+                    "Only loopback instruments may be scripted"
+                )))
+            }
+        };
+        let req = Request::Loopback {
+            instrument,
+            request: LoopbackRequest::Script(exchanges),
+        };
+        match self.rpc.request(req, self.timeout).await? {
+            Response::Done => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub fn modbus(&self, station_address: u8, protocol: ModBusProtocol) -> ModBusPipe<T> {
         ModBusPipe::new(
             self.rpc.clone(),
@@ -187,3 +345,109 @@ impl<T: Rpc> ByteStreamPipe<T> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use comsrv_protocol::{LoopbackAddress, ProtocolError, TcpAddress, TcpInstrument};
+
+    fn pipe(rpc: MockRpc) -> ByteStreamPipe<MockRpc> {
+        let instrument = ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port: 5000,
+            },
+            options: None,
+        });
+        ByteStreamPipe::new(rpc, instrument)
+    }
+
+    #[tokio::test]
+    async fn write_and_query() {
+        let rpc = MockRpc::new();
+        rpc.expect(
+            |req| match req {
+                Request::Bytes { request, .. } => {
+                    matches!(request, ByteStreamRequest::Write(x) if x == b"abc")
+                }
+                _ => false,
+            },
+            Response::Bytes(ByteStreamResponse::Done),
+        )
+        .expect(
+            |req| match req {
+                Request::Bytes {
+                    request: ByteStreamRequest::QueryLine { line, term, .. },
+                    ..
+                } => line == "*IDN?" && *term == b'\n',
+                _ => false,
+            },
+            Response::Bytes(ByteStreamResponse::String("comsrv".to_string())),
+        );
+        let mut pipe = pipe(rpc.clone());
+        pipe.write(b"abc").await.unwrap();
+        let reply = pipe
+            .query_line("*IDN?", b'\n', Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(reply, "comsrv");
+        rpc.assert_done();
+    }
+
+    #[tokio::test]
+    async fn script_loopback() {
+        let rpc = MockRpc::new();
+        rpc.expect(
+            |req| match req {
+                Request::Loopback {
+                    instrument,
+                    request: LoopbackRequest::Script(exchanges),
+                } => {
+                    instrument.name == "dev"
+                        && exchanges.len() == 1
+                        && exchanges[0].request == b"*IDN?\n"
+                        && exchanges[0].response == b"Mock\n"
+                }
+                _ => false,
+            },
+            Response::Done,
+        );
+        let exchanges = vec![LoopbackExchange {
+            request: b"*IDN?\n".to_vec(),
+            response: b"Mock\n".to_vec(),
+        }];
+        let instrument = ByteStreamInstrument::Loopback(LoopbackAddress {
+            name: "dev".to_string(),
+        });
+        let mut loopback = ByteStreamPipe::new(rpc.clone(), instrument);
+        loopback.script_loopback(exchanges.clone()).await.unwrap();
+        rpc.assert_done();
+
+        assert!(pipe(rpc).script_loopback(exchanges).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let rpc = MockRpc::new();
+        rpc.expect_remote_error(
+            |_| true,
+            comsrv_protocol::Error::Protocol(ProtocolError::Timeout),
+        )
+        .expect_timeout(|_| true)
+        .expect(|_| true, Response::Done);
+        let mut pipe = pipe(rpc.clone());
+        assert!(matches!(
+            pipe.read_all().await,
+            Err(crate::Error::Remote(comsrv_protocol::Error::Protocol(
+                ProtocolError::Timeout
+            )))
+        ));
+        assert!(matches!(pipe.read_all().await, Err(crate::Error::Timeout)));
+        assert!(matches!(
+            pipe.read_all().await,
+            Err(crate::Error::UnexpectdResponse)
+        ));
+        rpc.assert_done();
+    }
+}
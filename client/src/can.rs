@@ -68,6 +68,158 @@ impl CanBus {
         rx
     }
 
+    /// Record all frames received on the bus to `path` in the candump log format. `path` is a new file
+    /// in the recording directory of the `comsrv`, which must be started with `--recording-dir`.
+    pub async fn start_recording(&mut self, path: &str) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::StartRecording {
+                path: path.to_string(),
+            },
+            lock: None,
+        };
+        match self
+            .rpc
+            .request(request, Duration::from_millis(100))
+            .await?
+        {
+            Response::Can {
+                response: CanResponse::Started,
+                ..
+            } => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Stop recording and wait until all frames are written.
+    pub async fn stop_recording(&mut self) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::StopRecording,
+            lock: None,
+        };
+        match self.rpc.request(request, Duration::from_secs(1)).await? {
+            Response::Can {
+                response: CanResponse::Stopped,
+                ..
+            } => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Transmit the frames of a candump or ASC log file at `path` with their original timing scaled by `speed`.
+    /// If `repeat` is set, the log is replayed until `stop_replay()` is called.
+    pub async fn replay_file(&mut self, path: &str, repeat: bool, speed: f32) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::ReplayFile {
+                path: path.to_string(),
+                repeat,
+                speed,
+            },
+            lock: None,
+        };
+        match self.rpc.request(request, Duration::from_secs(1)).await? {
+            Response::Can {
+                response: CanResponse::Started,
+                ..
+            } => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Stop an ongoing replay.
+    pub async fn stop_replay(&mut self) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::StopReplay,
+            lock: None,
+        };
+        match self
+            .rpc
+            .request(request, Duration::from_millis(100))
+            .await?
+        {
+            Response::Can {
+                response: CanResponse::Stopped,
+                ..
+            } => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Discard stale frames: The `comsrv` drops frames which it has not yet processed as well as partially
+    /// received GCT messages and all notifications buffered in `subscription` are dropped.
+    pub async fn clear_rx<U>(&mut self, subscription: &mut Receiver<U>) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::ClearRx,
+            lock: None,
+        };
+        match self
+            .rpc
+            .request(request, Duration::from_millis(100))
+            .await?
+        {
+            Response::Can {
+                response: CanResponse::Ok,
+                ..
+            } => {}
+            Response::Error(x) => return Err(x.into()),
+            _ => return Err(crate::Error::UnexpectdResponse),
+        }
+        // notifications broadcast before the reply may still be forwarded by the subscriber task
+        task::yield_now().await;
+        while subscription.try_recv().is_ok() {}
+        Ok(())
+    }
+
+    /// Pause reception while the receive queue of the `comsrv` is full, which is the default.
+    /// Persistent backlogs then cause receive overruns in the driver or the adapter. If disabled,
+    /// frames received while the queue is full are dropped instead.
+    pub async fn set_backpressure(&mut self, enable: bool) -> crate::Result<()> {
+        let request = Request::Can {
+            instrument: self.instrument.clone(),
+            request: CanRequest::SetBackpressure(enable),
+            lock: None,
+        };
+        match self
+            .rpc
+            .request(request, Duration::from_millis(100))
+            .await?
+        {
+            Response::Can {
+                response: CanResponse::Ok,
+                ..
+            } => Ok(()),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Transmit a remote frame and wait for the data frame answering it, i.e. the next data frame with the
+    /// same id. Returns the payload of the data frame.
+    pub async fn remote_request(
+        &mut self,
+        id: u32,
+        ext_id: bool,
+        dlc: u8,
+        timeout: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        let mut subscription = self.subscribe(remote_reply_filter(id, ext_id)).await;
+        self.send(Message::RawRemote(RemoteFrame { id, ext_id, dlc }))
+            .await?;
+        match tokio::time::timeout(timeout, subscription.recv()).await {
+            Ok(Some(data)) => Ok(data),
+            Ok(None) => Err(crate::Error::EndpointHangUp),
+            Err(_) => Err(crate::Error::Timeout),
+        }
+    }
+
     pub async fn send(&mut self, msg: Message) -> crate::Result<()> {
         let can_request = match msg {
             Message::RawData(msg) => CanRequest::TxRaw(CanMessage::Data(msg)),
@@ -90,6 +242,14 @@ impl CanBus {
     }
 }
 
+/// Match the data frame answering a remote frame with the given id. Returns its payload.
+fn remote_reply_filter(id: u32, ext_id: bool) -> impl Fn(Message) -> Option<Vec<u8>> {
+    move |msg| match msg {
+        Message::RawData(x) if x.id == id && x.ext_id == ext_id => Some(x.data),
+        _ => None,
+    }
+}
+
 async fn subscriber_task<U: 'static + Send, T: Fn(Message) -> Option<U> + Send + 'static>(
     tx: &Sender<U>,
     mut notifications: UnboundedReceiver<Response>,
@@ -120,3 +280,34 @@ async fn subscriber_task<U: 'static + Send, T: Fn(Message) -> Option<U> + Send +
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(id: u32, ext_id: bool, data: &[u8]) -> Message {
+        Message::RawData(DataFrame {
+            id,
+            ext_id,
+            data: data.to_vec(),
+        })
+    }
+
+    #[test]
+    fn match_remote_reply() {
+        let filter = remote_reply_filter(0x123, false);
+        // on a loopback bus, the remote frame itself is received as well
+        let remote = Message::RawRemote(RemoteFrame {
+            id: 0x123,
+            ext_id: false,
+            dlc: 2,
+        });
+        assert!(filter(remote).is_none());
+        assert!(filter(data(0x124, false, &[1, 2])).is_none());
+        assert!(filter(data(0x123, true, &[1, 2])).is_none());
+        assert_eq!(filter(data(0x123, false, &[1, 2])), Some(vec![1, 2]));
+
+        let filter = remote_reply_filter(0x1234_5678, true);
+        assert_eq!(filter(data(0x1234_5678, true, &[])), Some(vec![]));
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use comsrv_protocol::cobs_stream::{CobsStreamRequest, CobsStreamResponse, CrcKind};
+use comsrv_protocol::{ByteStreamInstrument, Request, Response};
+
+use crate::{lock, LockGuard, Lockable, Locked, Rpc, DEFAULT_RPC_TIMEOUT};
+
+/// Controls a COBS stream on a bytestream instrument. Received frames are broadcast as notifications,
+/// use [`crate::ws::WsRpc::subscribe_cobs`] to receive them.
+pub struct CobsStreamPipe<T: Rpc> {
+    rpc: T,
+    instrument: ByteStreamInstrument,
+    lock: Locked,
+    pub timeout: Duration,
+}
+
+impl<T: Rpc> Clone for CobsStreamPipe<T> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc: self.rpc.clone(),
+            instrument: self.instrument.clone(),
+            lock: Locked::new(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Rpc> Lockable<T> for CobsStreamPipe<T> {
+    async fn lock(&mut self, timeout: Duration) -> crate::Result<LockGuard<T>> {
+        let ret = lock(&mut self.rpc, &self.instrument.address(), timeout).await?;
+        self.lock = ret.locked();
+        Ok(ret)
+    }
+}
+
+impl<T: Rpc> CobsStreamPipe<T> {
+    pub fn new(rpc: T, instrument: ByteStreamInstrument) -> Self {
+        Self {
+            rpc,
+            instrument,
+            lock: Locked::new(),
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    pub async fn request(
+        &mut self,
+        request: CobsStreamRequest,
+    ) -> crate::Result<CobsStreamResponse> {
+        let ret = self
+            .rpc
+            .request(
+                Request::CobsStream {
+                    instrument: self.instrument.clone(),
+                    request,
+                    lock: self.lock.check_lock(),
+                },
+                self.timeout,
+            )
+            .await?;
+        match ret {
+            Response::CobsStream(x) => Ok(x),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    async fn request_done(&mut self, request: CobsStreamRequest) -> crate::Result<()> {
+        match self.request(request).await? {
+            CobsStreamResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    pub async fn start(&mut self, use_crc: bool) -> crate::Result<()> {
+        self.start_with_crc(use_crc.into()).await
+    }
+
+    /// Start the stream appending a CRC of the given kind to each frame.
+    pub async fn start_with_crc(&mut self, crc: CrcKind) -> crate::Result<()> {
+        self.request_done(CobsStreamRequest::start(crc)).await
+    }
+
+    pub async fn stop(&mut self) -> crate::Result<()> {
+        self.request_done(CobsStreamRequest::Stop).await
+    }
+
+    pub async fn send_frame(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.request_done(CobsStreamRequest::SendFrame {
+            data: data.to_vec(),
+        })
+        .await
+    }
+
+    /// Wait until all frames sent before are written to the underlying stream.
+    pub async fn flush(&mut self) -> crate::Result<()> {
+        self.request_done(CobsStreamRequest::Flush).await
+    }
+
+    /// Returns whether the stream is alive and whether it uses a CRC, without sending a frame.
+    pub async fn status(&mut self) -> crate::Result<(bool, bool)> {
+        match self.request(CobsStreamRequest::Status).await? {
+            CobsStreamResponse::Status { alive, use_crc, .. } => Ok((alive, use_crc)),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Returns whether the stream is alive and the kind of CRC it uses.
+    pub async fn crc_status(&mut self) -> crate::Result<(bool, CrcKind)> {
+        match self.request(CobsStreamRequest::Status).await? {
+            CobsStreamResponse::Status {
+                alive,
+                use_crc,
+                crc,
+            } => Ok((alive, crc.unwrap_or_else(|| use_crc.into()))),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::anyhow;
 use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
 
-use comsrv_protocol::{GctMessage, SysCtrlType};
+use comsrv_protocol::{CanMessage, Endian, GctMessage, Request, Response, SysCtrlType};
 
 use crate::can::{CanBus, Message};
+use crate::{Rpc, DEFAULT_RPC_TIMEOUT};
 
 #[derive(Clone, Debug)]
 pub struct MonitorIndex {
@@ -48,6 +51,60 @@ impl MonitorValue {
     pub fn new(index: MonitorIndex, value: Vec<u8>) -> Self {
         Self { index, value }
     }
+
+    /// Returns the payload as array, failing if it does not consist of exactly `N` bytes.
+    fn bytes<const N: usize>(&self) -> crate::Result<[u8; N]> {
+        let mut ret = [0; N];
+        if self.value.len() != N {
+            return Err(crate::Error::Other(anyhow!(
+                "Expected a monitor value of {} bytes but got {} bytes",
+                N,
+                self.value.len()
+            )));
+        }
+        ret.copy_from_slice(&self.value);
+        Ok(ret)
+    }
+
+    pub fn as_u16(&self, endian: Endian) -> crate::Result<u16> {
+        let x = self.bytes()?;
+        Ok(match endian {
+            Endian::Little => u16::from_le_bytes(x),
+            Endian::Big => u16::from_be_bytes(x),
+        })
+    }
+
+    pub fn as_i16(&self, endian: Endian) -> crate::Result<i16> {
+        let x = self.bytes()?;
+        Ok(match endian {
+            Endian::Little => i16::from_le_bytes(x),
+            Endian::Big => i16::from_be_bytes(x),
+        })
+    }
+
+    pub fn as_u32(&self, endian: Endian) -> crate::Result<u32> {
+        let x = self.bytes()?;
+        Ok(match endian {
+            Endian::Little => u32::from_le_bytes(x),
+            Endian::Big => u32::from_be_bytes(x),
+        })
+    }
+
+    pub fn as_i32(&self, endian: Endian) -> crate::Result<i32> {
+        let x = self.bytes()?;
+        Ok(match endian {
+            Endian::Little => i32::from_le_bytes(x),
+            Endian::Big => i32::from_be_bytes(x),
+        })
+    }
+
+    pub fn as_f32(&self, endian: Endian) -> crate::Result<f32> {
+        let x = self.bytes()?;
+        Ok(match endian {
+            Endian::Little => f32::from_le_bytes(x),
+            Endian::Big => f32::from_be_bytes(x),
+        })
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -72,6 +129,84 @@ impl From<NodeId> for u8 {
     }
 }
 
+/// Build a request for the given readings of a monitoring group.
+fn monitoring_request_message(
+    source: NodeId,
+    destination: NodeId,
+    group: u8,
+    readings: &[u8],
+) -> crate::Result<GctMessage> {
+    let mut request = 0u64;
+    if group > 31 {
+        return Err(crate::Error::Other(anyhow!("Invalid group index")));
+    }
+    for x in readings {
+        if *x > 63 {
+            return Err(crate::Error::Other(anyhow!("Invalid reading index")));
+        }
+        request |= 1_u64 << x;
+    }
+    Ok(GctMessage::MonitoringRequest {
+        src: source.0,
+        dst: destination.0,
+        group_idx: group,
+        readings: request,
+    })
+}
+
+/// Build a SysCtrl message of type `tp`.
+fn sysctrl_message(
+    source: NodeId,
+    cmd: u16,
+    destination: NodeId,
+    tp: SysCtrlType,
+    data: Vec<u8>,
+) -> GctMessage {
+    GctMessage::SysCtrl {
+        src: source.0,
+        dst: destination.0,
+        cmd,
+        tp,
+        data,
+    }
+}
+
+/// Collect the requested `readings` from `subscription` until all of them are received, the subscription
+/// is closed or the `deadline` elapses. The collected values are sorted by reading index.
+async fn collect_readings(
+    subscription: &mut Receiver<MonitorValue>,
+    readings: &[u8],
+    deadline: Option<Instant>,
+) -> Vec<MonitorValue> {
+    let mut ret = vec![None; 64];
+    let mut filled = 0;
+    loop {
+        let value = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, subscription.recv()).await {
+                Ok(x) => x,
+                Err(_) => break,
+            },
+            None => subscription.recv().await,
+        };
+        let value = match value {
+            Some(x) => x,
+            None => break,
+        };
+        let idx = value.index.reading_index;
+        if !readings.contains(&idx) {
+            continue;
+        }
+        if ret[idx as usize].is_none() {
+            filled += 1;
+        }
+        ret[idx as usize] = Some(value);
+        if filled == readings.len() {
+            break;
+        }
+    }
+    ret.into_iter().flatten().collect()
+}
+
 #[derive(Clone)]
 pub struct GctCanDevice {
     bus: CanBus,
@@ -104,17 +239,67 @@ impl GctCanDevice {
         group: u8,
         readings: &[u8],
     ) -> crate::Result<Vec<MonitorValue>> {
-        let mut request = 0u64;
-        if group > 31 {
-            return Err(crate::Error::Other(anyhow!("Invalid group index")));
-        }
-        for x in readings {
-            if *x > 63 {
-                return Err(crate::Error::Other(anyhow!("Invalid reading index")));
-            }
-            request |= 1_u64 << x;
-        }
+        self.monitor_request_no_timeout_from(self.controller_node_id, destination, group, readings)
+            .await
+    }
+
+    /// Like [`GctCanDevice::monitor_request_no_timeout`], but sends the request from `source` instead of
+    /// the controller node id.
+    pub async fn monitor_request_no_timeout_from(
+        &mut self,
+        source: NodeId,
+        destination: NodeId,
+        group: u8,
+        readings: &[u8],
+    ) -> crate::Result<Vec<MonitorValue>> {
+        self.monitor_request_until(source, destination, group, readings, None)
+            .await
+    }
+
+    /// Like [`GctCanDevice::monitor_request`], but returns the values collected so far once `timeout`
+    /// elapses instead of failing with [`crate::Error::Timeout`]. Hence, the returned values may
+    /// only cover a subset of `readings`.
+    pub async fn monitor_request_partial(
+        &mut self,
+        destination: NodeId,
+        group: u8,
+        readings: &[u8],
+        timeout: Duration,
+    ) -> crate::Result<Vec<MonitorValue>> {
+        self.monitor_request_partial_from(
+            self.controller_node_id,
+            destination,
+            group,
+            readings,
+            timeout,
+        )
+        .await
+    }
+
+    /// Like [`GctCanDevice::monitor_request_partial`], but sends the request from `source` instead of
+    /// the controller node id.
+    pub async fn monitor_request_partial_from(
+        &mut self,
+        source: NodeId,
+        destination: NodeId,
+        group: u8,
+        readings: &[u8],
+        timeout: Duration,
+    ) -> crate::Result<Vec<MonitorValue>> {
+        let deadline = Instant::now() + timeout;
+        self.monitor_request_until(source, destination, group, readings, Some(deadline))
+            .await
+    }
 
+    async fn monitor_request_until(
+        &mut self,
+        source: NodeId,
+        destination: NodeId,
+        group: u8,
+        readings: &[u8],
+        deadline: Option<Instant>,
+    ) -> crate::Result<Vec<MonitorValue>> {
+        let msg = monitoring_request_message(source, destination, group, readings)?;
         let mut subscription = self
             .bus
             .subscribe({
@@ -144,33 +329,9 @@ impl GctCanDevice {
             })
             .await;
 
-        self.bus
-            .clone()
-            .send(crate::can::Message::Gct(GctMessage::MonitoringRequest {
-                src: self.controller_node_id.0,
-                dst: destination.0,
-                group_idx: group,
-                readings: request,
-            }))
-            .await?;
-
-        let mut ret = vec![None; 64];
-        let mut filled = 0;
-        while let Some(value) = subscription.recv().await {
-            let idx = value.index.reading_index;
-            if !readings.contains(&idx) {
-                continue;
-            }
-            if ret[idx as usize].is_none() {
-                filled += 1;
-            }
-            ret[idx as usize] = Some(value);
-            if filled == readings.len() {
-                break;
-            }
-        }
-        let ret = ret.iter().filter_map(|x| x.clone()).collect();
-        Ok(ret)
+        self.bus.clone().send(crate::can::Message::Gct(msg)).await?;
+
+        Ok(collect_readings(&mut subscription, readings, deadline).await)
     }
 
     pub async fn monitor_request(
@@ -179,10 +340,30 @@ impl GctCanDevice {
         group: u8,
         readings: &[u8],
         timeout: Duration,
+    ) -> crate::Result<Vec<MonitorValue>> {
+        self.monitor_request_from(
+            self.controller_node_id,
+            destination,
+            group,
+            readings,
+            timeout,
+        )
+        .await
+    }
+
+    /// Like [`GctCanDevice::monitor_request`], but sends the request from `source` instead of the
+    /// controller node id.
+    pub async fn monitor_request_from(
+        &mut self,
+        source: NodeId,
+        destination: NodeId,
+        group: u8,
+        readings: &[u8],
+        timeout: Duration,
     ) -> crate::Result<Vec<MonitorValue>> {
         match tokio::time::timeout(
             timeout,
-            self.monitor_request_no_timeout(destination, group, readings),
+            self.monitor_request_no_timeout_from(source, destination, group, readings),
         )
         .await
         {
@@ -197,13 +378,20 @@ impl GctCanDevice {
         destination: NodeId,
         data: Vec<u8>,
     ) -> crate::Result<()> {
-        let msg = GctMessage::SysCtrl {
-            src: self.controller_node_id.0,
-            dst: destination.0,
-            cmd,
-            tp: comsrv_protocol::SysCtrlType::None,
-            data,
-        };
+        self.sysctrl_write_from(self.controller_node_id, cmd, destination, data)
+            .await
+    }
+
+    /// Like [`GctCanDevice::sysctrl_write`], but sends the command from `source` instead of the
+    /// controller node id.
+    pub async fn sysctrl_write_from(
+        &mut self,
+        source: NodeId,
+        cmd: u16,
+        destination: NodeId,
+        data: Vec<u8>,
+    ) -> crate::Result<()> {
+        let msg = sysctrl_message(source, cmd, destination, SysCtrlType::None, data);
         self.bus.send(Message::Gct(msg)).await
     }
 
@@ -216,19 +404,38 @@ impl GctCanDevice {
             .await
     }
 
+    /// Like [`GctCanDevice::sysctrl_read_no_timeout`], but sends the query from `source` instead of
+    /// the controller node id.
+    pub async fn sysctrl_read_no_timeout_from(
+        &mut self,
+        source: NodeId,
+        cmd: u16,
+        destination: NodeId,
+    ) -> crate::Result<Vec<u8>> {
+        self.sysctrl_write_read_no_timeout_from(source, cmd, destination, vec![])
+            .await
+    }
+
     pub async fn sysctrl_write_read_no_timeout(
         &mut self,
         cmd: u16,
         destination: NodeId,
         data: Vec<u8>,
     ) -> crate::Result<Vec<u8>> {
-        let msg = GctMessage::SysCtrl {
-            src: self.controller_node_id.0,
-            dst: destination.0,
-            cmd,
-            tp: comsrv_protocol::SysCtrlType::Query,
-            data,
-        };
+        self.sysctrl_write_read_no_timeout_from(self.controller_node_id, cmd, destination, data)
+            .await
+    }
+
+    /// Like [`GctCanDevice::sysctrl_write_read_no_timeout`], but sends the query from `source`
+    /// instead of the controller node id.
+    pub async fn sysctrl_write_read_no_timeout_from(
+        &mut self,
+        source: NodeId,
+        cmd: u16,
+        destination: NodeId,
+        data: Vec<u8>,
+    ) -> crate::Result<Vec<u8>> {
+        let msg = sysctrl_message(source, cmd, destination, SysCtrlType::Query, data);
         let mut subscription = self
             .bus
             .subscribe({
@@ -266,7 +473,25 @@ impl GctCanDevice {
         destination: NodeId,
         timeout: Duration,
     ) -> crate::Result<Vec<u8>> {
-        match tokio::time::timeout(timeout, self.sysctrl_read_no_timeout(cmd, destination)).await {
+        self.sysctrl_read_from(self.controller_node_id, cmd, destination, timeout)
+            .await
+    }
+
+    /// Like [`GctCanDevice::sysctrl_read`], but sends the query from `source` instead of the
+    /// controller node id.
+    pub async fn sysctrl_read_from(
+        &mut self,
+        source: NodeId,
+        cmd: u16,
+        destination: NodeId,
+        timeout: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        match tokio::time::timeout(
+            timeout,
+            self.sysctrl_read_no_timeout_from(source, cmd, destination),
+        )
+        .await
+        {
             Ok(x) => x,
             Err(_) => Err(crate::Error::Timeout),
         }
@@ -278,10 +503,24 @@ impl GctCanDevice {
         destination: NodeId,
         data: Vec<u8>,
         timeout: Duration,
+    ) -> crate::Result<Vec<u8>> {
+        self.sysctrl_write_read_from(self.controller_node_id, cmd, destination, data, timeout)
+            .await
+    }
+
+    /// Like [`GctCanDevice::sysctrl_write_read`], but sends the query from `source` instead of the
+    /// controller node id.
+    pub async fn sysctrl_write_read_from(
+        &mut self,
+        source: NodeId,
+        cmd: u16,
+        destination: NodeId,
+        data: Vec<u8>,
+        timeout: Duration,
     ) -> crate::Result<Vec<u8>> {
         match tokio::time::timeout(
             timeout,
-            self.sysctrl_write_read_no_timeout(cmd, destination, data),
+            self.sysctrl_write_read_no_timeout_from(source, cmd, destination, data),
         )
         .await
         {
@@ -290,3 +529,115 @@ impl GctCanDevice {
         }
     }
 }
+
+/// Encode `msg` to the CAN frames it is sent as, using the GCT codec of the `comsrv` without a bus.
+pub async fn encode<T: Rpc>(rpc: &mut T, msg: GctMessage) -> crate::Result<Vec<CanMessage>> {
+    match rpc
+        .request(Request::GctEncode(msg), DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::CanMessages(x)) => Ok(x),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(crate::Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Decode the GCT messages contained in `frames`, using the GCT codec of the `comsrv` without a bus.
+pub async fn decode<T: Rpc>(
+    rpc: &mut T,
+    frames: Vec<CanMessage>,
+) -> crate::Result<Vec<GctMessage>> {
+    match rpc
+        .request(Request::GctDecode(frames), DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::GctMessages(x)) => Ok(x),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(crate::Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8]) -> MonitorValue {
+        MonitorValue::new(MonitorIndex::new(1, 2).unwrap(), data.to_vec())
+    }
+
+    #[test]
+    fn decode_monitor_values() {
+        let x = value(&[0x12, 0x34]);
+        assert_eq!(x.as_u16(Endian::Little).unwrap(), 0x3412);
+        assert_eq!(x.as_u16(Endian::Big).unwrap(), 0x1234);
+        let x = value(&[0xFE, 0xFF]);
+        assert_eq!(x.as_i16(Endian::Little).unwrap(), -2);
+        assert_eq!(x.as_i16(Endian::Big).unwrap(), -257);
+
+        let x = value(&[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(x.as_u32(Endian::Little).unwrap(), 0x0403_0201);
+        assert_eq!(x.as_u32(Endian::Big).unwrap(), 0x0102_0304);
+        let x = value(&[0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(x.as_i32(Endian::Little).unwrap(), -16_777_217);
+        assert_eq!(x.as_i32(Endian::Big).unwrap(), -2);
+
+        let x = value(&1.5_f32.to_le_bytes());
+        assert_eq!(x.as_f32(Endian::Little).unwrap(), 1.5);
+        let x = value(&1.5_f32.to_be_bytes());
+        assert_eq!(x.as_f32(Endian::Big).unwrap(), 1.5);
+    }
+
+    #[test]
+    fn decode_length_mismatch() {
+        let x = value(&[0x01, 0x02, 0x03]);
+        assert!(matches!(
+            x.as_u16(Endian::Little),
+            Err(crate::Error::Other(_))
+        ));
+        assert!(matches!(x.as_i32(Endian::Big), Err(crate::Error::Other(_))));
+        assert!(matches!(
+            x.as_f32(Endian::Little),
+            Err(crate::Error::Other(_))
+        ));
+        assert!(value(&[]).as_i16(Endian::Big).is_err());
+    }
+
+    #[tokio::test]
+    async fn collect_partial_readings() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        for idx in [5, 7, 2] {
+            let index = MonitorIndex::new(1, idx).unwrap();
+            tx.send(MonitorValue::new(index, vec![idx])).await.unwrap();
+        }
+        // reading 3 never arrives and 7 was not requested
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let values = collect_readings(&mut rx, &[2, 3, 5], Some(deadline)).await;
+        let indices: Vec<_> = values.iter().map(|x| x.index.reading_index).collect();
+        assert_eq!(indices, vec![2, 5]);
+        assert_eq!(values[0].value, vec![2]);
+        assert!(!tx.is_closed());
+    }
+
+    fn src(msg: &GctMessage) -> u8 {
+        match msg {
+            GctMessage::SysCtrl { src, .. } | GctMessage::MonitoringRequest { src, .. } => *src,
+            _ => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn encode_source_node_id() {
+        let destination = NodeId::new(0x10).unwrap();
+        for source in [0x01, 0x7E] {
+            let source = NodeId::new(source).unwrap();
+            let msg = sysctrl_message(source, 0x12, destination, SysCtrlType::Query, vec![1]);
+            assert_eq!(src(&msg), u8::from(source));
+            let msg = monitoring_request_message(source, destination, 2, &[0, 63]).unwrap();
+            assert_eq!(src(&msg), u8::from(source));
+        }
+        assert!(NodeId::new(0).is_err());
+        assert!(NodeId::new(NodeId::broadcast_address()).is_err());
+    }
+}
@@ -1,9 +1,11 @@
-use crate::Rpc;
+use crate::{echoed_response, with_id, Rpc};
 use anyhow::anyhow;
 use async_trait::async_trait;
 use comsrv_protocol::{Request, Response};
+use reqwest::header::HeaderMap;
 use reqwest::{Client, Error};
 use std::time::Duration;
+use uuid::Uuid;
 
 impl From<reqwest::Error> for crate::Error {
     fn from(x: Error) -> Self {
@@ -17,10 +19,33 @@ impl From<reqwest::Error> for crate::Error {
     }
 }
 
+/// Settings applied to the underlying [`reqwest::Client`].
+#[derive(Clone, Default)]
+struct ClientSettings {
+    connect_timeout: Option<Duration>,
+    pool_idle_timeout: Option<Duration>,
+    default_headers: HeaderMap,
+}
+
+impl ClientSettings {
+    fn build(&self) -> crate::Result<Client> {
+        let mut builder = Client::builder().default_headers(self.default_headers.clone());
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        Ok(builder.build()?)
+    }
+}
+
 #[derive(Clone)]
 pub struct HttpRpc {
     host: String,
     port: u16,
+    base_path: String,
+    settings: ClientSettings,
     client: Option<reqwest::Client>,
 }
 
@@ -29,6 +54,8 @@ impl HttpRpc {
         HttpRpc {
             host: "127.0.0.1".to_string(),
             port: 5903,
+            base_path: String::new(),
+            settings: Default::default(),
             client: None,
         }
     }
@@ -37,6 +64,8 @@ impl HttpRpc {
         HttpRpc {
             host: host.to_string(),
             port: 5903,
+            base_path: String::new(),
+            settings: Default::default(),
             client: None,
         }
     }
@@ -45,9 +74,96 @@ impl HttpRpc {
         Self {
             host: host.to_string(),
             port,
+            base_path: String::new(),
+            settings: Default::default(),
             client: None,
         }
     }
+
+    /// Returns a [`HttpRpcBuilder`] to configure the underlying HTTP client.
+    pub fn builder() -> HttpRpcBuilder {
+        HttpRpcBuilder::new()
+    }
+
+    fn url(&self) -> String {
+        format!("{}:{}{}", self.host, self.port, self.base_path)
+    }
+}
+
+/// Builder for a [`HttpRpc`] exposing the configuration of the underlying [`reqwest::Client`].
+///
+/// The `timeout` passed to [`Rpc::request`] is still applied to each request.
+#[derive(Clone)]
+pub struct HttpRpcBuilder {
+    host: String,
+    port: u16,
+    base_path: String,
+    settings: ClientSettings,
+}
+
+impl HttpRpcBuilder {
+    pub fn new() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 5903,
+            base_path: String::new(),
+            settings: Default::default(),
+        }
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Path appended to `host:port`, e.g. if the `comsrv` is served behind a reverse proxy.
+    pub fn base_path(mut self, base_path: &str) -> Self {
+        let base_path = base_path.trim_end_matches('/');
+        self.base_path = if base_path.is_empty() || base_path.starts_with('/') {
+            base_path.to_string()
+        } else {
+            format!("/{}", base_path)
+        };
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Headers sent along with every request.
+    pub fn default_headers(mut self, headers: HeaderMap) -> Self {
+        self.settings.default_headers = headers;
+        self
+    }
+
+    pub fn build(self) -> crate::Result<HttpRpc> {
+        let client = self.settings.build()?;
+        Ok(HttpRpc {
+            host: self.host,
+            port: self.port,
+            base_path: self.base_path,
+            settings: self.settings,
+            client: Some(client),
+        })
+    }
+}
+
+impl Default for HttpRpcBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Default for HttpRpc {
@@ -56,11 +172,14 @@ impl Default for HttpRpc {
     }
 }
 
-#[async_trait]
-impl Rpc for HttpRpc {
-    async fn request(&mut self, request: Request, timeout: Duration) -> crate::Result<Response> {
-        let url = format!("{}:{}", self.host, self.port);
-        let client = self.client.take().unwrap_or_else(Client::new);
+impl HttpRpc {
+    /// Send `request` and return the response as received, i.e. possibly wrapped in [`Response::Echo`].
+    async fn send(&mut self, request: Request, timeout: Duration) -> crate::Result<Response> {
+        let url = self.url();
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => self.settings.build()?,
+        };
         let response = client
             .get(&url)
             .timeout(timeout)
@@ -74,3 +193,91 @@ impl Rpc for HttpRpc {
         ret.map_err(|x| x.into())
     }
 }
+
+#[async_trait]
+impl Rpc for HttpRpc {
+    async fn request(&mut self, request: Request, timeout: Duration) -> crate::Result<Response> {
+        self.send(request, timeout)
+            .await
+            .map(Response::without_echo)
+    }
+
+    async fn request_with_id(
+        &mut self,
+        request: Request,
+        timeout: Duration,
+    ) -> crate::Result<(Response, Uuid)> {
+        let id = Uuid::new_v4();
+        let response = self.send(with_id(id, request), timeout).await?;
+        Ok((echoed_response(id, response)?, id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accept a single HTTP request, reply with `Response::Done` and return the request head.
+    async fn serve_once(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut data = Vec::new();
+        let mut buf = [0_u8; 1024];
+        let head_len = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+            if let Some(idx) = data.windows(4).position(|x| x == b"\r\n\r\n") {
+                break idx + 4;
+            }
+        };
+        let head = String::from_utf8(data[..head_len].to_vec()).unwrap();
+        let content_length = head
+            .lines()
+            .filter_map(|x| x.split_once(':'))
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .map(|(_, v)| v.trim().parse::<usize>().unwrap())
+            .unwrap_or(0);
+        while data.len() < head_len + content_length {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+        }
+        let body = serde_json::to_string(&Response::Done).unwrap();
+        let reply = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(reply.as_bytes()).await.unwrap();
+        head
+    }
+
+    #[tokio::test]
+    async fn builder_base_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_once(listener));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-comsrv-test", HeaderValue::from_static("foo"));
+        let mut rpc = HttpRpc::builder()
+            .host("http://127.0.0.1")
+            .port(port)
+            .base_path("comsrv/")
+            .connect_timeout(Duration::from_secs(1))
+            .pool_idle_timeout(Duration::from_secs(10))
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let ret = rpc
+            .request(Request::ListSerialPorts, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(matches!(ret, Response::Done));
+
+        let head = server.await.unwrap();
+        assert!(head.starts_with("GET /comsrv HTTP/1.1\r\n"));
+        assert!(head.contains("x-comsrv-test: foo\r\n"));
+    }
+}
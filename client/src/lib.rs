@@ -13,20 +13,30 @@
 //!  * [`ws::WsRpc`] - Communicate over WebSocket. This is fast and allows listening to notification. However, it comes at comes at the
 //!     cost of maintaining some state in the application (the TCP connection).
 //!
+//! To treat several `comsrv` instances as one, [`multi::MultiRpc`] routes requests by a prefix of the address.
+//!
+//! With the `test-util` feature enabled, [`mock::MockRpc`] allows testing code using an [`Rpc`] without a running `comsrv`.
+//!
 //! This crate comes with some types that provide an easy-to-use interface to interact with device connected to the `comsrv`:
 //!
 //!  * [`bytestream::ByteStreamPipe`] - To communicate with devices attached to bytestream-like communication devices (SerialPorts, TCP streams, FTDIs, ..)
+//!  * [`cobs_stream::CobsStreamPipe`] - To exchange COBS encoded frames over a bytestream
 //!  * [`modbus::ModBusPipe`] - ModBus/TCP and ModBus/RTU client operating on any [`bytestream::ByteStreamPipe`]
+//!  * [`scpi::ScpiPipe`] - To communicate with SCPI instruments over VXI-11 or VISA
+//!  * [`prologix::PrologixAdapter`] - To configure a Prologix GPIB adapter
 //!  * [`can::CanBus`] - To interact with a CAN bus.
+//!  * [`sigrok::SigrokDevice`] - To acquire logic or analog data from devices supported by sigrok
 //!  * [`gctcan::GctCanDevice`] - Abstracts over the communication protocol used with a node on a GCT-CAN network
 //!
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::io;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use broadcast_wsrpc::client::ClientError;
-use comsrv_protocol::{Address, Request, Response};
-use protocol::FtdiDeviceInfo;
+use comsrv_protocol::{Address, InstrumentOptions, ModBusRegisterMap, Request, Response};
+use protocol::{FtdiDeviceInfo, SerialPortInfo, SigrokDeviceInfo, SigrokResponse};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use thiserror::Error;
@@ -35,9 +45,18 @@ use uuid::Uuid;
 
 pub mod bytestream;
 pub mod can;
+pub mod cobs_stream;
 pub mod gctcan;
 pub mod http;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 pub mod modbus;
+pub mod multi;
+pub mod prologix;
+#[cfg(any(test, feature = "record-replay"))]
+pub mod replay;
+pub mod scpi;
+pub mod sigrok;
 pub mod ws;
 
 pub use comsrv_protocol as protocol;
@@ -64,6 +83,110 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// Returns the raw bytes of a reply which could not be decoded as UTF-8 by the `comsrv`.
+    pub fn invalid_utf8_data(&self) -> Option<&[u8]> {
+        match self {
+            Error::Remote(protocol::Error::Protocol(protocol::ProtocolError::InvalidUtf8 {
+                data,
+            })) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Determines whether it makes sense to retry the request which failed with this error.
+    ///
+    /// Errors reported by the `comsrv` are classified with [`protocol::Error::should_retry`].
+    pub fn is_retryable(&self) -> bool {
+        match self.without_context() {
+            Error::Io(err) => protocol::is_retryable_io_error(err),
+            Error::Timeout | Error::EndpointHangUp => true,
+            Error::UnexpectdResponse | Error::Other(_) => false,
+            Error::Remote(err) => err.should_retry(),
+        }
+    }
+
+    /// Returns true if either the request or the operation on the instrument timed out.
+    pub fn is_timeout(&self) -> bool {
+        match self.without_context() {
+            Error::Io(err) => err.kind() == io::ErrorKind::TimedOut,
+            Error::Timeout => true,
+            Error::Remote(protocol::Error::Protocol(protocol::ProtocolError::Timeout)) => true,
+            Error::Remote(protocol::Error::Transport(protocol::TransportError::Io(err)))
+            | Error::Remote(protocol::Error::Protocol(protocol::ProtocolError::Io(err))) => {
+                err.kind() == io::ErrorKind::TimedOut
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error was reported by the `comsrv`, as opposed to an error in communicating with it.
+    pub fn is_remote(&self) -> bool {
+        matches!(self.without_context(), Error::Remote(_))
+    }
+
+    /// Wrap this error with an additional message describing the operation which failed.
+    ///
+    /// The classification of the error, e.g. by [`Error::is_retryable`], is preserved.
+    pub fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Self {
+        match self {
+            Error::Other(err) => Error::Other(err.context(context)),
+            err => Error::Other(anyhow::Error::new(err).context(context)),
+        }
+    }
+
+    /// Describe the error including all context added with [`Error::context`], whereas the `Display` output
+    /// only contains the outermost context.
+    pub fn to_string_with_context(&self) -> String {
+        match self {
+            Error::Other(err) => format!("Other Error: {:#}", err),
+            err => err.to_string(),
+        }
+    }
+
+    /// Returns the error wrapped by [`Error::context`] or `self` if no context was added.
+    fn without_context(&self) -> &Error {
+        match self {
+            Error::Other(err) => err.downcast_ref::<Error>().unwrap_or(self),
+            _ => self,
+        }
+    }
+}
+
+/// Serializes the error as `{ "kind": "...", "message": "..." }`, where `kind` identifies the variant and
+/// `message` contains the human readable description of the error.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let kind = match self {
+            Error::Io(_) => "Io",
+            Error::Timeout => "Timeout",
+            Error::EndpointHangUp => "EndpointHangUp",
+            Error::UnexpectdResponse => "UnexpectedResponse",
+            Error::Other(_) => "Other",
+            Error::Remote(protocol::Error::Transport(_)) => "RemoteTransport",
+            Error::Remote(protocol::Error::Protocol(_)) => "RemoteProtocol",
+            Error::Remote(protocol::Error::Argument(_)) => "RemoteArgument",
+            Error::Remote(protocol::Error::Internal(_)) => "RemoteInternal",
+        };
+        let mut state = serializer.serialize_struct("Error", 2)?;
+        state.serialize_field("kind", kind)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(x: io::Error) -> Self {
+        Error::Io(x)
+    }
+}
+
 impl From<protocol::Error> for Error {
     fn from(x: protocol::Error) -> Self {
         Error::Remote(x)
@@ -85,6 +208,41 @@ impl From<ClientError> for Error {
 #[async_trait]
 pub trait Rpc: Clone + Send + 'static {
     async fn request(&mut self, request: Request, timeout: Duration) -> crate::Result<Response>;
+
+    /// Same as [`Rpc::request`] but also returns an id unique to this request, e.g. to correlate log entries.
+    ///
+    /// The request is sent as [`Request::WithId`], such that the `comsrv` handles and logs it under the
+    /// returned id. [`ws::WsRpc`] and [`http::HttpRpc`] check that the `comsrv` echoes the id back.
+    async fn request_with_id(
+        &mut self,
+        request: Request,
+        timeout: Duration,
+    ) -> crate::Result<(Response, Uuid)> {
+        let id = Uuid::new_v4();
+        let response = self.request(with_id(id, request), timeout).await?;
+        Ok((response, id))
+    }
+}
+
+/// Wrap `request` in a [`Request::WithId`] carrying `id`.
+pub(crate) fn with_id(id: Uuid, request: Request) -> Request {
+    Request::WithId {
+        id,
+        request: Box::new(request),
+    }
+}
+
+/// Unwrap the [`Response::Echo`] the `comsrv` answers a [`Request::WithId`] with. Fails if the echoed id
+/// does not match `id`.
+pub(crate) fn echoed_response(id: Uuid, response: Response) -> crate::Result<Response> {
+    match response {
+        Response::Echo {
+            request_id,
+            response,
+            ..
+        } if request_id == id.to_string() => Ok(*response),
+        _ => Err(Error::UnexpectdResponse),
+    }
 }
 
 /// An `#[async_trait]` which defines a lockable resource of the `comsrv`.
@@ -206,6 +364,266 @@ pub async fn list_serial_ports<T: Rpc>(rpc: &mut T) -> crate::Result<Vec<String>
     }
 }
 
+/// List all serial ports connected to the system including USB metadata, such as vendor and
+/// product IDs of USB serial adapters.
+pub async fn list_serial_ports_detailed<T: Rpc>(rpc: &mut T) -> crate::Result<Vec<SerialPortInfo>> {
+    match rpc
+        .request(Request::ListSerialPortsDetailed, DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::SerialPortsDetailed(ret)) => Ok(ret),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Query the channels and sample rates supported by the sigrok device at `addr`.
+pub async fn sigrok_device_info<T: Rpc>(
+    rpc: &mut T,
+    addr: &str,
+) -> crate::Result<SigrokDeviceInfo> {
+    let request = Request::SigrokDeviceInfo {
+        addr: addr.to_string(),
+    };
+    match rpc.request(request, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Sigrok(SigrokResponse::DeviceInfo(ret))) => Ok(ret),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Update the options of the instrument at `addr` without performing any IO.
+pub async fn set_instrument_options<T: Rpc>(
+    rpc: &mut T,
+    addr: Address,
+    options: InstrumentOptions,
+) -> crate::Result<()> {
+    let request = Request::SetInstrumentOptions {
+        addr,
+        options,
+        lock: None,
+    };
+    match rpc.request(request, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Done) => Ok(()),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Query the options in effect for the instrument connected at `addr`. The fields of the returned
+/// JSON object depend on the kind of instrument.
+pub async fn get_instrument_options<T: Rpc>(
+    rpc: &mut T,
+    addr: Address,
+) -> crate::Result<serde_json::Value> {
+    let request = Request::GetInstrumentOptions { addr };
+    match rpc.request(request, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::InstrumentOptions(ret)) => Ok(ret),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Register `alias` for `addr` on the `comsrv`. Afterwards, `alias::<alias>` may be used in place of the
+/// address string, e.g. as port of a serial instrument.
+pub async fn set_alias<T: Rpc>(rpc: &mut T, alias: &str, addr: Address) -> crate::Result<()> {
+    let request = Request::SetAlias {
+        alias: alias.to_string(),
+        addr,
+    };
+    match rpc.request(request, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Done) => Ok(()),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// List all aliases registered on the `comsrv`
+pub async fn list_aliases<T: Rpc>(rpc: &mut T) -> crate::Result<HashMap<String, Address>> {
+    match rpc.request(Request::ListAliases, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Aliases(ret)) => Ok(ret),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Query the kind of instrument at `addr` and the names of the requests it supports.
+pub async fn describe_instrument<T: Rpc>(
+    rpc: &mut T,
+    addr: Address,
+) -> crate::Result<(String, Vec<String>)> {
+    match rpc
+        .request(Request::DescribeInstrument { addr }, DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::InstrumentCapabilities {
+            kind,
+            supported_requests,
+        }) => Ok((kind, supported_requests)),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Query up to `count` of the most recent broadcasts, oldest first. Allows clients connecting late
+/// to catch up with notifications. Requires the `comsrv` to be started with a replay buffer.
+pub async fn replay_recent<T: Rpc>(rpc: &mut T, count: usize) -> crate::Result<Vec<Response>> {
+    match rpc
+        .request(Request::ReplayRecent { count }, DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::RecentBroadcasts(ret)) => Ok(ret),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Run the self-test of the `comsrv`, which exercises its core code paths without any hardware.
+/// Returns whether all tests passed and one line per test describing its result.
+pub async fn self_test<T: Rpc>(rpc: &mut T) -> crate::Result<(bool, Vec<String>)> {
+    match rpc.request(Request::SelfTest, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::SelfTest { passed, details }) => Ok((passed, details)),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Serve `register_map` to ModBus TCP masters connecting to `bind`. Returns the address the server listens on.
+pub async fn start_modbus_server<T: Rpc>(
+    rpc: &mut T,
+    bind: &str,
+    register_map: ModBusRegisterMap,
+) -> crate::Result<String> {
+    let req = Request::StartModbusServer {
+        bind: bind.to_string(),
+        register_map,
+    };
+    match rpc.request(req, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::ModbusServer { addr }) => Ok(addr),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Stop the ModBus server listening on `addr`.
+pub async fn stop_modbus_server<T: Rpc>(rpc: &mut T, addr: &str) -> crate::Result<()> {
+    let req = Request::StopModbusServer {
+        addr: addr.to_string(),
+    };
+    match rpc.request(req, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Done) => Ok(()),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Check the arguments and address of `request` without performing any IO. Fails with the error the request
+/// would fail with, if it is invalid.
+pub async fn validate<T: Rpc>(rpc: &mut T, request: Request) -> crate::Result<()> {
+    let req = Request::Validate(Box::new(request));
+    match rpc.request(req, DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Done) => Ok(()),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Request types and optional features supported by a `comsrv`, as returned by [`capabilities`]
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub api_version: u32,
+    pub supported_requests: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Returns true if the `comsrv` handles the variant of [`Request`] with the given `name`.
+    pub fn supports(&self, name: &str) -> bool {
+        self.supported_requests.iter().any(|x| x == name)
+    }
+
+    /// Returns true if the optional `feature` is enabled on the `comsrv`.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|x| x == feature)
+    }
+
+    /// Check that the `comsrv` implements the API version of this client and supports all of the
+    /// `requests` given by name.
+    pub fn check_compatible(&self, requests: &[&str]) -> crate::Result<()> {
+        if self.api_version != protocol::API_VERSION {
+            return Err(Error::Other(anyhow::anyhow!(
+                "comsrv implements API version {}, expected {}.",
+                self.api_version,
+                protocol::API_VERSION
+            )));
+        }
+        let missing: Vec<_> = requests.iter().filter(|x| !self.supports(x)).collect();
+        if !missing.is_empty() {
+            return Err(Error::Other(anyhow::anyhow!(
+                "comsrv does not support the requests: {:?}",
+                missing
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Query the API version, request types and optional features supported by the `comsrv`. Versions
+/// of the `comsrv` predating this request reply with an error.
+pub async fn capabilities<T: Rpc>(rpc: &mut T) -> crate::Result<Capabilities> {
+    match rpc
+        .request(Request::Capabilities, DEFAULT_RPC_TIMEOUT)
+        .await
+    {
+        Ok(Response::Capabilities {
+            api_version,
+            supported_requests,
+            features,
+        }) => Ok(Capabilities {
+            api_version,
+            supported_requests,
+            features,
+        }),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
+/// Check whether the instrument at `addr` is reachable within `timeout`. Returns the duration of the
+/// check if it is reachable, `None` otherwise.
+pub async fn ping<T: Rpc>(
+    rpc: &mut T,
+    addr: Address,
+    timeout: Duration,
+) -> crate::Result<Option<Duration>> {
+    let req = Request::Ping {
+        addr,
+        timeout: timeout.into(),
+    };
+    match rpc.request(req, timeout + DEFAULT_RPC_TIMEOUT).await {
+        Ok(Response::Pong {
+            reachable,
+            latency_ms,
+        }) => Ok(latency_ms.filter(|_| reachable).map(Duration::from_millis)),
+        Ok(Response::Error(x)) => Err(x.into()),
+        Ok(_) => Err(Error::UnexpectdResponse),
+        Err(x) => Err(x),
+    }
+}
+
 /// List all FTDIs connected to the systmem
 pub async fn list_ftdis<T: Rpc>(rpc: &mut T) -> crate::Result<Vec<FtdiDeviceInfo>> {
     match rpc
@@ -218,3 +636,153 @@ pub async fn list_ftdis<T: Rpc>(rpc: &mut T) -> crate::Result<Vec<FtdiDeviceInfo
         Err(x) => Err(x),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_classification() {
+        let io_err = |kind| io::Error::new(kind, "error");
+        let remote = |err: protocol::Error| Error::Remote(err);
+
+        let cases = vec![
+            // (error, retryable, timeout, remote)
+            (
+                Error::Io(io_err(io::ErrorKind::ConnectionReset)),
+                true,
+                false,
+                false,
+            ),
+            (
+                Error::Io(io_err(io::ErrorKind::TimedOut)),
+                true,
+                true,
+                false,
+            ),
+            (
+                Error::Io(io_err(io::ErrorKind::PermissionDenied)),
+                false,
+                false,
+                false,
+            ),
+            (Error::Timeout, true, true, false),
+            (Error::EndpointHangUp, true, false, false),
+            (Error::UnexpectdResponse, false, false, false),
+            (Error::Other(anyhow::anyhow!("error")), false, false, false),
+            (
+                remote(protocol::Error::transport(io_err(
+                    io::ErrorKind::BrokenPipe,
+                ))),
+                true,
+                false,
+                true,
+            ),
+            (
+                remote(protocol::Error::transport(io_err(io::ErrorKind::TimedOut))),
+                true,
+                true,
+                true,
+            ),
+            (
+                remote(protocol::Error::transport(anyhow::anyhow!("error"))),
+                false,
+                false,
+                true,
+            ),
+            (
+                remote(protocol::Error::Protocol(protocol::ProtocolError::Timeout)),
+                false,
+                true,
+                true,
+            ),
+            (
+                remote(protocol::Error::Protocol(protocol::ProtocolError::Io(
+                    Arc::new(io_err(io::ErrorKind::TimedOut)),
+                ))),
+                false,
+                true,
+                true,
+            ),
+            (
+                remote(protocol::Error::argument(anyhow::anyhow!("error"))),
+                false,
+                false,
+                true,
+            ),
+            (
+                remote(protocol::Error::internal(anyhow::anyhow!("error"))),
+                false,
+                false,
+                true,
+            ),
+        ];
+        for (err, retryable, timeout, is_remote) in cases {
+            assert_eq!(err.is_retryable(), retryable, "{:?}", err);
+            assert_eq!(err.is_timeout(), timeout, "{:?}", err);
+            assert_eq!(err.is_remote(), is_remote, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn error_context() {
+        fn read() -> Result<()> {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset",
+            ))?;
+            Ok(())
+        }
+        let err = read().unwrap_err();
+        assert!(matches!(&err, Error::Io(x) if x.kind() == io::ErrorKind::ConnectionReset));
+
+        let err = err
+            .context("Failed to read from instrument")
+            .context("Calibration failed");
+        assert_eq!(err.to_string(), "Other Error: Calibration failed");
+        assert_eq!(
+            err.to_string_with_context(),
+            "Other Error: Calibration failed: Failed to read from instrument: IO Error occurred: connection reset"
+        );
+        assert!(err.is_retryable());
+        assert!(!err.is_remote());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_error() {
+        use serde_json::json;
+
+        let to_json = |err: Error| serde_json::to_value(err).unwrap();
+        assert_eq!(
+            to_json(Error::Timeout),
+            json!({ "kind": "Timeout", "message": "Timeout" })
+        );
+        assert_eq!(
+            to_json(Error::Io(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "broken pipe"
+            ))),
+            json!({ "kind": "Io", "message": "IO Error occurred: broken pipe" })
+        );
+        assert_eq!(
+            to_json(Error::Remote(protocol::Error::Protocol(
+                protocol::ProtocolError::Timeout
+            ))),
+            json!({ "kind": "RemoteProtocol", "message": "Remote Error: Protocol Error Timeout" })
+        );
+    }
+
+    #[test]
+    fn check_compatible() {
+        let mut caps = Capabilities {
+            api_version: protocol::API_VERSION,
+            supported_requests: vec!["Bytes".to_string(), "Validate".to_string()],
+            features: vec![],
+        };
+        assert!(caps.check_compatible(&["Bytes", "Validate"]).is_ok());
+        assert!(caps.check_compatible(&["Bytes", "Batch"]).is_err());
+        caps.api_version += 1;
+        assert!(caps.check_compatible(&[]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! A mock [`Rpc`] implementation to test code interacting with the `comsrv` without a running server.
+//!
+//! The mock is configured with a queue of expectations. Each incoming request is checked against the
+//! next expectation and the corresponding canned reply is returned:
+//!
+//! ```
+//! # use comsrv_client::mock::MockRpc;
+//! # use comsrv_client::protocol::{ByteStreamResponse, Request, Response};
+//! let rpc = MockRpc::new();
+//! rpc.expect(
+//!     |req| matches!(req, Request::Bytes { .. }),
+//!     Response::Bytes(ByteStreamResponse::Done),
+//! );
+//! ```
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use comsrv_protocol::{Request, Response};
+
+use crate::{protocol, Rpc};
+
+type Matcher = Box<dyn Fn(&Request) -> bool + Send>;
+
+/// The reply returned by [`MockRpc`] once an expected request was received.
+#[derive(Clone, Debug)]
+pub enum MockReply {
+    Response(Response),
+    /// Reply with [`crate::Error::Remote`]
+    Remote(protocol::Error),
+    /// Reply with [`crate::Error::Timeout`]
+    Timeout,
+}
+
+struct Expectation {
+    matcher: Matcher,
+    reply: MockReply,
+}
+
+/// An [`Rpc`] which asserts that requests arrive in the expected order and returns canned replies.
+///
+/// Clones share the same queue of expectations.
+#[derive(Clone, Default)]
+pub struct MockRpc {
+    expectations: Arc<Mutex<VecDeque<Expectation>>>,
+}
+
+impl fmt::Debug for MockRpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockRpc")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl MockRpc {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Expect a request matching `matcher` and reply with the given `reply`.
+    pub fn expect_reply<F>(&self, matcher: F, reply: MockReply) -> &Self
+    where
+        F: Fn(&Request) -> bool + Send + 'static,
+    {
+        self.expectations.lock().unwrap().push_back(Expectation {
+            matcher: Box::new(matcher),
+            reply,
+        });
+        self
+    }
+
+    /// Expect a request matching `matcher` and reply with `response`.
+    pub fn expect<F>(&self, matcher: F, response: Response) -> &Self
+    where
+        F: Fn(&Request) -> bool + Send + 'static,
+    {
+        self.expect_reply(matcher, MockReply::Response(response))
+    }
+
+    /// Expect a request matching `matcher` and fail it with [`crate::Error::Remote`].
+    pub fn expect_remote_error<F>(&self, matcher: F, error: protocol::Error) -> &Self
+    where
+        F: Fn(&Request) -> bool + Send + 'static,
+    {
+        self.expect_reply(matcher, MockReply::Remote(error))
+    }
+
+    /// Expect a request matching `matcher` and fail it with [`crate::Error::Timeout`].
+    pub fn expect_timeout<F>(&self, matcher: F) -> &Self
+    where
+        F: Fn(&Request) -> bool + Send + 'static,
+    {
+        self.expect_reply(matcher, MockReply::Timeout)
+    }
+
+    /// Number of expectations which have not been consumed yet.
+    pub fn pending(&self) -> usize {
+        self.expectations.lock().unwrap().len()
+    }
+
+    /// Panics if not all expected requests have been received.
+    pub fn assert_done(&self) {
+        let pending = self.pending();
+        assert!(
+            pending == 0,
+            "MockRpc: {} expected request(s) not received",
+            pending
+        );
+    }
+}
+
+#[async_trait]
+impl Rpc for MockRpc {
+    async fn request(&mut self, request: Request, _timeout: Duration) -> crate::Result<Response> {
+        let expectation = self.expectations.lock().unwrap().pop_front();
+        let expectation = match expectation {
+            Some(x) => x,
+            None => panic!("MockRpc: unexpected request: {:?}", request),
+        };
+        assert!(
+            (expectation.matcher)(&request),
+            "MockRpc: request does not match expectation: {:?}",
+            request
+        );
+        match expectation.reply {
+            MockReply::Response(x) => Ok(x),
+            MockReply::Remote(x) => Err(crate::Error::Remote(x)),
+            MockReply::Timeout => Err(crate::Error::Timeout),
+        }
+    }
+}
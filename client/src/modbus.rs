@@ -1,11 +1,17 @@
 use crate::{lock, LockGuard, Lockable, Locked, Rpc, DEFAULT_RPC_TIMEOUT};
+use anyhow::anyhow;
 use async_trait::async_trait;
 use comsrv_protocol::{
-    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, ModBusProtocol, ModBusRequest,
-    ModBusResponse, Request, Response,
+    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, Endian, ModBusProtocol,
+    ModBusRequest, ModBusResponse, Request, Response,
 };
+use futures::stream::{self, Stream};
+use std::collections::HashMap;
 use std::time::Duration;
 
+/// Maximum number of registers which can be read with a single request
+pub const MAX_READ_REGISTERS: u8 = 125;
+
 pub struct ModBusPipe<T: Rpc> {
     rpc: T,
     instrument: ByteStreamInstrument,
@@ -13,6 +19,7 @@ pub struct ModBusPipe<T: Rpc> {
     pub timeout: Duration,
     station_address: u8,
     protocol: ModBusProtocol,
+    inter_frame_delay: Duration,
 }
 
 #[async_trait]
@@ -33,10 +40,19 @@ impl<T: Rpc> Clone for ModBusPipe<T> {
             timeout: self.timeout,
             station_address: self.station_address,
             protocol: self.protocol,
+            inter_frame_delay: self.inter_frame_delay,
         }
     }
 }
 
+/// Unwraps the value of a reply to a request for a single value.
+fn single_value<V>(mut values: Vec<V>) -> crate::Result<V> {
+    if values.len() != 1 {
+        return Err(crate::Error::UnexpectdResponse);
+    }
+    Ok(values.remove(0))
+}
+
 impl<T: Rpc> ModBusPipe<T> {
     pub fn new(
         rpc: T,
@@ -51,6 +67,7 @@ impl<T: Rpc> ModBusPipe<T> {
             timeout: DEFAULT_RPC_TIMEOUT,
             station_address,
             protocol,
+            inter_frame_delay: Duration::ZERO,
         }
     }
 
@@ -68,6 +85,7 @@ impl<T: Rpc> ModBusPipe<T> {
             timeout,
             station_address,
             protocol,
+            inter_frame_delay: Duration::ZERO,
         }
     }
 
@@ -79,6 +97,15 @@ impl<T: Rpc> ModBusPipe<T> {
         self.station_address
     }
 
+    /// Set the guard time between writing a request and reading the reply. Only applies to ModBus RTU.
+    pub fn set_inter_frame_delay(&mut self, inter_frame_delay: Duration) {
+        self.inter_frame_delay = inter_frame_delay;
+    }
+
+    pub fn inter_frame_delay(&self) -> Duration {
+        self.inter_frame_delay
+    }
+
     pub async fn request(&mut self, task: ModBusRequest) -> crate::Result<ModBusResponse> {
         let request = Request::Bytes {
             instrument: self.instrument.clone(),
@@ -86,9 +113,12 @@ impl<T: Rpc> ModBusPipe<T> {
                 timeout: self.timeout.into(),
                 station_address: self.station_address,
                 protocol: self.protocol,
+                inter_frame_delay_ms: self.inter_frame_delay.as_millis().min(u32::MAX as u128)
+                    as u32,
                 request: task,
             },
             lock: self.lock.check_lock(),
+            truncate: false,
         };
 
         let ret = self.rpc.request(request, self.timeout).await?;
@@ -107,11 +137,7 @@ impl<T: Rpc> ModBusPipe<T> {
     }
 
     pub async fn read_single_coil(&mut self, addr: u16) -> crate::Result<bool> {
-        let ret = self.read_coil(addr, 1).await?;
-        if ret.len() != 1 {
-            return Err(crate::Error::UnexpectdResponse);
-        }
-        Ok(ret[0])
+        single_value(self.read_coil(addr, 1).await?)
     }
 
     pub async fn read_discrete(&mut self, addr: u16, cnt: u16) -> crate::Result<Vec<bool>> {
@@ -125,11 +151,7 @@ impl<T: Rpc> ModBusPipe<T> {
     }
 
     pub async fn read_single_discrete(&mut self, addr: u16) -> crate::Result<bool> {
-        let ret = self.read_discrete(addr, 1).await?;
-        if ret.len() != 1 {
-            return Err(crate::Error::UnexpectdResponse);
-        }
-        Ok(ret[0])
+        single_value(self.read_discrete(addr, 1).await?)
     }
 
     pub async fn read_input(&mut self, addr: u16, cnt: u8) -> crate::Result<Vec<u16>> {
@@ -140,11 +162,7 @@ impl<T: Rpc> ModBusPipe<T> {
     }
 
     pub async fn read_single_input(&mut self, addr: u16) -> crate::Result<u16> {
-        let ret = self.read_input(addr, 1).await?;
-        if ret.len() != 1 {
-            return Err(crate::Error::UnexpectdResponse);
-        }
-        Ok(ret[0])
+        single_value(self.read_input(addr, 1).await?)
     }
 
     pub async fn read_holding(&mut self, addr: u16, cnt: u8) -> crate::Result<Vec<u16>> {
@@ -158,11 +176,71 @@ impl<T: Rpc> ModBusPipe<T> {
     }
 
     pub async fn read_single_holding(&mut self, addr: u16) -> crate::Result<u16> {
-        let ret = self.read_holding(addr, 1).await?;
-        if ret.len() != 1 {
-            return Err(crate::Error::UnexpectdResponse);
+        single_value(self.read_holding(addr, 1).await?)
+    }
+
+    /// Reads a single holding register. Equivalent to [`ModBusPipe::read_single_holding`].
+    pub async fn read_single_register(&mut self, addr: u16) -> crate::Result<u16> {
+        self.read_single_holding(addr).await
+    }
+
+    /// Reads the holding registers starting at `addr` and decodes them according to `layout`.
+    ///
+    /// The registers are read with a single request, unless the layout spans more than
+    /// [`MAX_READ_REGISTERS`] registers. Then they are read in several requests, which are only
+    /// atomic if the pipe is locked.
+    pub async fn read_struct(
+        &mut self,
+        addr: u16,
+        layout: &[Field],
+    ) -> crate::Result<HashMap<String, Value>> {
+        let total: usize = layout.iter().map(|x| x.ty.registers()).sum();
+        if addr as usize + total > 0x10000 {
+            return Err(crate::Error::Other(anyhow!(
+                "Layout of {} registers starting at {} exceeds the address space",
+                total,
+                addr
+            )));
         }
-        Ok(ret[0])
+        let mut registers = Vec::with_capacity(total);
+        while registers.len() < total {
+            let cnt = (total - registers.len()).min(MAX_READ_REGISTERS as usize);
+            let start = addr + registers.len() as u16;
+            let values = self.read_holding(start, cnt as u8).await?;
+            if values.len() != cnt {
+                return Err(crate::Error::Other(anyhow!(
+                    "Expected {} registers but device returned {}",
+                    cnt,
+                    values.len()
+                )));
+            }
+            registers.extend(values);
+        }
+        Ok(decode_struct(layout, &registers))
+    }
+
+    /// Reads the holding registers in `start..end` in chunks of up to `chunk` registers.
+    ///
+    /// Yields the start address and values of each chunk. If reading a chunk fails, the error is yielded
+    /// and scanning continues with the next chunk.
+    pub fn scan_holding(
+        &mut self,
+        start: u16,
+        end: u16,
+        chunk: u8,
+    ) -> impl Stream<Item = crate::Result<(u16, Vec<u16>)>> + '_ {
+        let chunk = chunk.clamp(1, MAX_READ_REGISTERS) as u16;
+        stream::unfold((self, start), move |(pipe, addr)| async move {
+            if addr >= end {
+                return None;
+            }
+            let cnt = chunk.min(end - addr);
+            let ret = pipe
+                .read_holding(addr, cnt as u8)
+                .await
+                .map(|values| (addr, values));
+            Some((ret, (pipe, addr + cnt)))
+        })
     }
 
     pub async fn write_coils(&mut self, addr: u16, data: Vec<bool>) -> crate::Result<()> {
@@ -175,6 +253,10 @@ impl<T: Rpc> ModBusPipe<T> {
         }
     }
 
+    pub async fn write_single_coil(&mut self, addr: u16, value: bool) -> crate::Result<()> {
+        self.write_coils(addr, vec![value]).await
+    }
+
     pub async fn write_register(&mut self, addr: u16, data: Vec<u16>) -> crate::Result<()> {
         match self
             .request(ModBusRequest::WriteRegisters { addr, values: data })
@@ -198,6 +280,21 @@ impl<T: Rpc> ModBusPipe<T> {
         }
     }
 
+    /// Send a PDU with an arbitrary function code, e.g. a vendor-specific one. Returns the response PDU as
+    /// is, i.e. the function code followed by the unparsed response data.
+    pub async fn raw_pdu(&mut self, function_code: u8, data: &[u8]) -> crate::Result<Vec<u8>> {
+        match self
+            .request(ModBusRequest::RawPdu {
+                function_code,
+                data: data.to_vec(),
+            })
+            .await?
+        {
+            ModBusResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
     pub async fn ddp(
         &mut self,
         sub_cmd: u8,
@@ -219,3 +316,267 @@ impl<T: Rpc> ModBusPipe<T> {
         }
     }
 }
+
+/// Type of a [`Field`] read with [`ModBusPipe::read_struct`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    /// A string of two characters per register. Trailing NUL characters are removed.
+    String {
+        registers: u16,
+    },
+}
+
+impl FieldType {
+    /// The number of registers occupied by the field
+    pub fn registers(&self) -> usize {
+        match self {
+            FieldType::U16 | FieldType::I16 => 1,
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => 2,
+            FieldType::String { registers } => *registers as usize,
+        }
+    }
+}
+
+/// A named field of a register layout. Fields spanning two registers are transmitted with the
+/// most significant register first, unless `word_order` is set to [`Endian::Little`].
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+    pub word_order: Endian,
+}
+
+impl Field {
+    pub fn new<T: Into<String>>(name: T, ty: FieldType) -> Self {
+        Self {
+            name: name.into(),
+            ty,
+            word_order: Endian::Big,
+        }
+    }
+
+    pub fn with_word_order(mut self, word_order: Endian) -> Self {
+        self.word_order = word_order;
+        self
+    }
+}
+
+/// A value decoded by [`ModBusPipe::read_struct`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    String(String),
+}
+
+/// Decode `registers` according to `layout`, which must span exactly as many registers.
+fn decode_struct(layout: &[Field], registers: &[u16]) -> HashMap<String, Value> {
+    let mut ret = HashMap::new();
+    let mut offset = 0;
+    for field in layout {
+        let data = &registers[offset..offset + field.ty.registers()];
+        offset += data.len();
+        let u32_value = || match field.word_order {
+            Endian::Big => (data[0] as u32) << 16 | data[1] as u32,
+            Endian::Little => (data[1] as u32) << 16 | data[0] as u32,
+        };
+        let value = match field.ty {
+            FieldType::U16 => Value::U16(data[0]),
+            FieldType::I16 => Value::I16(data[0] as i16),
+            FieldType::U32 => Value::U32(u32_value()),
+            FieldType::I32 => Value::I32(u32_value() as i32),
+            FieldType::F32 => Value::F32(f32::from_bits(u32_value())),
+            FieldType::String { .. } => {
+                let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_be_bytes()).collect();
+                let text = String::from_utf8_lossy(&bytes);
+                Value::String(text.trim_end_matches('\0').to_string())
+            }
+        };
+        ret.insert(field.name.clone(), value);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use comsrv_protocol::{TcpAddress, TcpInstrument};
+
+    fn pipe(rpc: MockRpc) -> ModBusPipe<MockRpc> {
+        let instrument = ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port: 502,
+            },
+            options: None,
+        });
+        ModBusPipe::new(rpc, instrument, 1, ModBusProtocol::Tcp)
+    }
+
+    fn modbus_request(req: &Request) -> Option<&ModBusRequest> {
+        match req {
+            Request::Bytes {
+                request: ByteStreamRequest::ModBus { request, .. },
+                ..
+            } => Some(request),
+            _ => None,
+        }
+    }
+
+    fn reply(response: ModBusResponse) -> Response {
+        Response::Bytes(ByteStreamResponse::ModBus(response))
+    }
+
+    #[tokio::test]
+    async fn single_values() {
+        let rpc = MockRpc::new();
+        rpc.expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadCoil { addr: 10, cnt: 1 })
+                )
+            },
+            reply(ModBusResponse::Bool(vec![true])),
+        )
+        .expect(
+            |req| matches!(modbus_request(req), Some(ModBusRequest::WriteCoils { addr: 10, values }) if values == &[false]),
+            reply(ModBusResponse::Done),
+        )
+        .expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding { addr: 20, cnt: 1 })
+                )
+            },
+            reply(ModBusResponse::Number(vec![1234])),
+        )
+        .expect(
+            |req| matches!(modbus_request(req), Some(ModBusRequest::WriteRegisters { addr: 20, values }) if values == &[4321]),
+            reply(ModBusResponse::Done),
+        );
+        let mut pipe = pipe(rpc.clone());
+        assert!(pipe.read_single_coil(10).await.unwrap());
+        pipe.write_single_coil(10, false).await.unwrap();
+        assert_eq!(pipe.read_single_register(20).await.unwrap(), 1234);
+        pipe.write_single_register(20, 4321).await.unwrap();
+        rpc.assert_done();
+    }
+
+    #[tokio::test]
+    async fn unexpected_count() {
+        let rpc = MockRpc::new();
+        rpc.expect(|_| true, reply(ModBusResponse::Number(vec![1, 2])))
+            .expect(|_| true, reply(ModBusResponse::Bool(vec![])));
+        let mut pipe = pipe(rpc.clone());
+        assert!(matches!(
+            pipe.read_single_register(20).await,
+            Err(crate::Error::UnexpectdResponse)
+        ));
+        assert!(matches!(
+            pipe.read_single_coil(10).await,
+            Err(crate::Error::UnexpectdResponse)
+        ));
+        rpc.assert_done();
+    }
+
+    #[tokio::test]
+    async fn scan_holding() {
+        use futures::StreamExt;
+
+        let rpc = MockRpc::new();
+        rpc.expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding { addr: 0, cnt: 125 })
+                )
+            },
+            reply(ModBusResponse::Number(vec![1; 125])),
+        )
+        .expect_remote_error(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding {
+                        addr: 125,
+                        cnt: 125
+                    })
+                )
+            },
+            comsrv_protocol::Error::Protocol(comsrv_protocol::ProtocolError::Timeout),
+        )
+        .expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding { addr: 250, cnt: 50 })
+                )
+            },
+            reply(ModBusResponse::Number(vec![3; 50])),
+        );
+        let mut pipe = pipe(rpc.clone());
+        let chunks: Vec<_> = pipe.scan_holding(0, 300, 200).collect().await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].as_ref().unwrap(), &(0, vec![1; 125]));
+        assert!(chunks[1].is_err());
+        assert_eq!(chunks[2].as_ref().unwrap(), &(250, vec![3; 50]));
+        rpc.assert_done();
+    }
+
+    #[tokio::test]
+    async fn read_struct() {
+        let mut name = vec![0_u16; 126];
+        name[0] = u16::from_be_bytes(*b"PS");
+        name[1] = u16::from_be_bytes(*b"U\0");
+        let mut registers = vec![0x8001, 0xFFFF, 0xFFFE, 0x0000, 0x4048];
+        registers.extend(name);
+        let (first, second) = (registers[..125].to_vec(), registers[125..].to_vec());
+
+        let rpc = MockRpc::new();
+        rpc.expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding {
+                        addr: 100,
+                        cnt: 125
+                    })
+                )
+            },
+            reply(ModBusResponse::Number(first)),
+        )
+        .expect(
+            |req| {
+                matches!(
+                    modbus_request(req),
+                    Some(ModBusRequest::ReadHolding { addr: 225, cnt: 6 })
+                )
+            },
+            reply(ModBusResponse::Number(second)),
+        );
+        let layout = [
+            Field::new("status", FieldType::I16),
+            Field::new("offset", FieldType::I32),
+            Field::new("voltage", FieldType::F32).with_word_order(Endian::Little),
+            Field::new("name", FieldType::String { registers: 126 }),
+        ];
+        let mut pipe = pipe(rpc.clone());
+        let ret = pipe.read_struct(100, &layout).await.unwrap();
+        assert_eq!(ret["status"], Value::I16(-32767));
+        assert_eq!(ret["offset"], Value::I32(-2));
+        assert_eq!(ret["voltage"], Value::F32(3.125));
+        assert_eq!(ret["name"], Value::String("PSU".to_string()));
+        rpc.assert_done();
+    }
+}
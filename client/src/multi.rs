@@ -0,0 +1,331 @@
+//! An [`Rpc`] which fans out to several `comsrv` instances, e.g. one per test rack.
+//!
+//! Each instance is registered as a named endpoint. A request is routed to an endpoint by prefixing the
+//! string identifying the hardware endpoint of the instrument with `<endpoint>@`, for example the port
+//! of a serial instrument:
+//!
+//! ```
+//! # use comsrv_client::http::HttpRpc;
+//! # use comsrv_client::multi::MultiRpc;
+//! # use comsrv_client::protocol::SerialInstrument;
+//! let mut rpc = MultiRpc::new();
+//! rpc.add_endpoint("rack1", HttpRpc::with_host("http://rack1"));
+//! rpc.add_endpoint("rack2", HttpRpc::with_host("http://rack2"));
+//! let instrument = SerialInstrument::new("rack2@/dev/ttyUSB0", 115200, "8N1").unwrap();
+//! ```
+//!
+//! The prefix is removed before the request is forwarded. Requests naming an endpoint which is not
+//! registered fail instead of falling back to another endpoint. Requests without a prefix, such as
+//! [`Request::ListSerialPorts`], are sent to the default endpoint. Hence, addresses routed by a
+//! [`MultiRpc`] must not contain `@`. Note that addresses contained in responses are returned as
+//! reported by the endpoint, i.e. without prefix.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use comsrv_protocol::{
+    Address, ByteStreamInstrument, CanAddress, CanInstrument, Request, Response, ScpiInstrument,
+};
+use uuid::Uuid;
+
+use crate::Rpc;
+
+/// Separates the name of the endpoint from the address. Unlike `::`, it does not occur in instrument
+/// addresses.
+pub const ENDPOINT_SEPARATOR: &str = "@";
+
+#[derive(Clone)]
+pub struct MultiRpc<T: Rpc> {
+    endpoints: HashMap<String, T>,
+    default: Option<String>,
+}
+
+impl<T: Rpc> Default for MultiRpc<T> {
+    fn default() -> Self {
+        Self {
+            endpoints: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+impl<T: Rpc> MultiRpc<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register `rpc` as endpoint `name`. The first endpoint registered becomes the default endpoint.
+    pub fn add_endpoint<S: Into<String>>(&mut self, name: S, rpc: T) -> &mut Self {
+        let name = name.into();
+        if self.default.is_none() {
+            self.default = Some(name.clone());
+        }
+        self.endpoints.insert(name, rpc);
+        self
+    }
+
+    /// Route requests without endpoint prefix to the endpoint `name`.
+    pub fn set_default_endpoint<S: Into<String>>(&mut self, name: S) -> crate::Result<()> {
+        let name = name.into();
+        if !self.endpoints.contains_key(&name) {
+            return Err(unknown_endpoint(&name));
+        }
+        self.default = Some(name);
+        Ok(())
+    }
+
+    /// Select the endpoint `name` explicitly, e.g. to list the serial ports of a specific instance.
+    pub fn endpoint(&self, name: &str) -> Option<&T> {
+        self.endpoints.get(name)
+    }
+
+    pub fn endpoint_mut(&mut self, name: &str) -> Option<&mut T> {
+        self.endpoints.get_mut(name)
+    }
+
+    /// Strip the endpoint prefix of `request` and return the endpoint it is routed to.
+    fn route(&mut self, request: &mut Request) -> crate::Result<&mut T> {
+        let mut prefixed = None;
+        if let Some(addr) = address_string(request) {
+            if let Some((name, rest)) = addr.split_once(ENDPOINT_SEPARATOR) {
+                if !self.endpoints.contains_key(name) {
+                    return Err(unknown_endpoint(name));
+                }
+                prefixed = Some(name.to_string());
+                *addr = rest.to_string();
+            }
+        }
+        let name = match prefixed.or_else(|| self.default.clone()) {
+            Some(name) => name,
+            None => {
+                return Err(crate::Error::Other(anyhow!(
+                    "No endpoint registered to route the request to"
+                )))
+            }
+        };
+        self.endpoints
+            .get_mut(&name)
+            .ok_or_else(|| unknown_endpoint(&name))
+    }
+}
+
+fn unknown_endpoint(name: &str) -> crate::Error {
+    crate::Error::Other(anyhow!("Unknown endpoint: `{}`", name))
+}
+
+#[async_trait]
+impl<T: Rpc> Rpc for MultiRpc<T> {
+    async fn request(
+        &mut self,
+        mut request: Request,
+        timeout: Duration,
+    ) -> crate::Result<Response> {
+        let rpc = self.route(&mut request)?;
+        rpc.request(request, timeout).await
+    }
+
+    async fn request_with_id(
+        &mut self,
+        mut request: Request,
+        timeout: Duration,
+    ) -> crate::Result<(Response, Uuid)> {
+        let rpc = self.route(&mut request)?;
+        rpc.request_with_id(request, timeout).await
+    }
+}
+
+/// The string identifying the hardware endpoint of the instrument addressed by `request`, if any.
+fn address_string(request: &mut Request) -> Option<&mut String> {
+    match request {
+        Request::Bytes { instrument, .. } | Request::CobsStream { instrument, .. } => {
+            match instrument {
+                ByteStreamInstrument::Serial(x) => Some(&mut x.address.port),
+                ByteStreamInstrument::Ftdi(x) => Some(&mut x.address.port),
+                ByteStreamInstrument::Tcp(x) => Some(&mut x.address.host),
+                ByteStreamInstrument::Custom(x) => Some(&mut x.address),
+                ByteStreamInstrument::Loopback(x) => Some(&mut x.name),
+            }
+        }
+        Request::Can { instrument, .. } => match instrument {
+            CanInstrument::PCan { address, .. } => Some(address),
+            CanInstrument::SocketCan { interface } => Some(interface),
+            CanInstrument::UsrCanet { host, .. } => Some(host),
+            CanInstrument::Loopback => None,
+        },
+        Request::Scpi { instrument, .. } => match instrument {
+            ScpiInstrument::Vxi(x) => Some(&mut x.host),
+            ScpiInstrument::Visa(x) => Some(&mut x.address),
+        },
+        Request::Prologix { instrument, .. } | Request::PrologixAdapter { instrument, .. } => {
+            Some(&mut instrument.address.port)
+        }
+        Request::Sigrok { instrument, .. } => Some(&mut instrument.address),
+        Request::Serial { instrument, .. } => Some(&mut instrument.address.port),
+        Request::Loopback { instrument, .. } => Some(&mut instrument.name),
+        Request::SigrokDeviceInfo { addr } => Some(addr),
+        Request::WithId { request, .. } => address_string(request),
+        Request::Lock { addr, .. }
+        | Request::Unlock { addr, .. }
+        | Request::Drop { addr, .. }
+        | Request::SetInstrumentOptions { addr, .. }
+        | Request::GetInstrumentOptions { addr }
+        | Request::SetAlias { addr, .. }
+        | Request::DescribeInstrument { addr }
+        | Request::Ping { addr, .. } => match addr {
+            Address::Tcp(x) => Some(&mut x.host),
+            Address::Ftdi(x) => Some(&mut x.port),
+            Address::Serial(x) => Some(&mut x.port),
+            Address::Vxi(x) | Address::Visa(x) => Some(x),
+            Address::Can(x) => match x {
+                CanAddress::PCan { address } => Some(address),
+                CanAddress::SocketCan { interface } => Some(interface),
+                CanAddress::UsrCanet { host, .. } => Some(host),
+                CanAddress::Loopback => None,
+            },
+            Address::Hid(_) => None,
+            Address::Custom(x) => Some(&mut x.address),
+            Address::Loopback(x) => Some(&mut x.name),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use comsrv_protocol::{
+        ByteStreamRequest, ByteStreamResponse, ScpiRequest, ScpiResponse, SerialAddress,
+        TcpInstrument, VisaInstrument,
+    };
+
+    fn write(host: &str) -> Request {
+        Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument::new(host, 5000)),
+            request: ByteStreamRequest::Write(vec![1]),
+            lock: None,
+            truncate: false,
+        }
+    }
+
+    fn is_write_to(host: &'static str) -> impl Fn(&Request) -> bool {
+        move |req| match req {
+            Request::Bytes {
+                instrument: ByteStreamInstrument::Tcp(x),
+                ..
+            } => x.address.host == host,
+            _ => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn route_by_prefix() {
+        let rack1 = MockRpc::new();
+        let rack2 = MockRpc::new();
+        let mut rpc = MultiRpc::new();
+        rpc.add_endpoint("rack1", rack1.clone())
+            .add_endpoint("rack2", rack2.clone());
+        let timeout = Duration::from_secs(1);
+
+        rack2.expect(
+            is_write_to("10.0.0.2"),
+            Response::Bytes(ByteStreamResponse::Done),
+        );
+        rack1.expect(
+            is_write_to("10.0.0.1"),
+            Response::Bytes(ByteStreamResponse::Done),
+        );
+        rack1.expect(
+            |req| matches!(req, Request::ListSerialPorts),
+            Response::SerialPorts(vec![]),
+        );
+        rpc.request(write("rack2@10.0.0.2"), timeout).await.unwrap();
+        rpc.request(write("rack1@10.0.0.1"), timeout).await.unwrap();
+        // requests without prefix go to the default endpoint
+        rpc.request(Request::ListSerialPorts, timeout)
+            .await
+            .unwrap();
+        rack1.assert_done();
+        rack2.assert_done();
+
+        rpc.set_default_endpoint("rack2").unwrap();
+        assert!(rpc.set_default_endpoint("rack3").is_err());
+        // addresses may contain `::` without selecting an endpoint
+        rack2.expect(
+            |req| match req {
+                Request::Scpi {
+                    instrument: ScpiInstrument::Visa(x),
+                    ..
+                } => x.address == "TCPIP0::10.0.0.3::INSTR",
+                _ => false,
+            },
+            Response::Scpi(ScpiResponse::Done),
+        );
+        let req = Request::Scpi {
+            instrument: ScpiInstrument::Visa(VisaInstrument {
+                address: "TCPIP0::10.0.0.3::INSTR".to_string(),
+            }),
+            request: ScpiRequest::Write("*RST".to_string()),
+            lock: None,
+            timeout: None,
+            truncate: false,
+        };
+        rpc.request(req, timeout).await.unwrap();
+        rack2.assert_done();
+    }
+
+    #[tokio::test]
+    async fn unknown_prefix() {
+        let rack1 = MockRpc::new();
+        let mut rpc = MultiRpc::new();
+        rpc.add_endpoint("rack1", rack1.clone());
+        // a typo in the endpoint name must not send the request to the default endpoint
+        let ret = rpc
+            .request(write("rakc1@10.0.0.1"), Duration::from_secs(1))
+            .await;
+        assert!(matches!(ret, Err(crate::Error::Other(_))));
+        rack1.assert_done();
+    }
+
+    #[tokio::test]
+    async fn route_ping() {
+        let rack1 = MockRpc::new();
+        let rack2 = MockRpc::new();
+        let mut rpc = MultiRpc::new();
+        rpc.add_endpoint("rack1", rack1.clone())
+            .add_endpoint("rack2", rack2.clone());
+
+        rack2.expect(
+            |req| match req {
+                Request::Ping {
+                    addr: Address::Serial(x),
+                    ..
+                } => x.port == "/dev/ttyUSB0",
+                _ => false,
+            },
+            Response::Pong {
+                reachable: true,
+                latency_ms: Some(1),
+            },
+        );
+        let req = Request::Ping {
+            addr: Address::Serial(SerialAddress {
+                port: "rack2@/dev/ttyUSB0".to_string(),
+            }),
+            timeout: Duration::from_secs(1),
+        };
+        rpc.request(req, Duration::from_secs(1)).await.unwrap();
+        rack1.assert_done();
+        rack2.assert_done();
+    }
+
+    #[tokio::test]
+    async fn no_endpoint() {
+        let mut rpc = MultiRpc::<MockRpc>::new();
+        let ret = rpc
+            .request(Request::ListSerialPorts, Duration::from_secs(1))
+            .await;
+        assert!(matches!(ret, Err(crate::Error::Other(_))));
+    }
+}
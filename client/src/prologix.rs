@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use comsrv_protocol::{
+    Address, PrologixAdapterRequest, PrologixAdapterResponse, PrologixInstrument, Request, Response,
+};
+
+use crate::{lock, LockGuard, Lockable, Locked, Rpc, DEFAULT_RPC_TIMEOUT};
+
+const GPIB_ADDRESS_COUNT: u32 = 30;
+
+/// Controls a Prologix GPIB adapter itself, as opposed to the instruments attached to it.
+pub struct PrologixAdapter<T: Rpc> {
+    rpc: T,
+    instrument: PrologixInstrument,
+    lock: Locked,
+    pub timeout: Duration,
+}
+
+impl<T: Rpc> Clone for PrologixAdapter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc: self.rpc.clone(),
+            instrument: self.instrument.clone(),
+            lock: Locked::new(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Rpc> Lockable<T> for PrologixAdapter<T> {
+    async fn lock(&mut self, timeout: Duration) -> crate::Result<LockGuard<T>> {
+        let addr = Address::Serial(self.instrument.address.clone());
+        let ret = lock(&mut self.rpc, &addr, timeout).await?;
+        self.lock = ret.locked();
+        Ok(ret)
+    }
+}
+
+impl<T: Rpc> PrologixAdapter<T> {
+    pub fn new(rpc: T, instrument: PrologixInstrument) -> Self {
+        Self {
+            rpc,
+            instrument,
+            lock: Locked::new(),
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    pub async fn request(
+        &mut self,
+        request: PrologixAdapterRequest,
+    ) -> crate::Result<PrologixAdapterResponse> {
+        let ret = self
+            .rpc
+            .request(
+                Request::PrologixAdapter {
+                    instrument: self.instrument.clone(),
+                    request,
+                    lock: self.lock.check_lock(),
+                },
+                self.timeout,
+            )
+            .await?;
+        match ret {
+            Response::PrologixAdapter(x) => Ok(x),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Set `++auto`, `++eoi`, `++eos` and `++read_tmo_ms` of the adapter. The configuration
+    /// is kept by the `comsrv` and restored if the adapter is re-initialized.
+    pub async fn configure(
+        &mut self,
+        auto: bool,
+        eoi: bool,
+        eos: u8,
+        read_timeout_ms: u32,
+    ) -> crate::Result<()> {
+        let req = PrologixAdapterRequest::Configure {
+            auto,
+            eoi,
+            eos,
+            read_timeout_ms,
+        };
+        match self.request(req).await? {
+            PrologixAdapterResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// List the GPIB addresses of the instruments responding to a serial poll. Each of the
+    /// 30 addresses is granted `timeout` to respond.
+    pub async fn scan_gpib(&mut self, timeout: Duration) -> crate::Result<Vec<u8>> {
+        let req = PrologixAdapterRequest::ScanBus {
+            timeout: timeout.into(),
+        };
+        let rpc_timeout = self.timeout;
+        self.timeout = rpc_timeout + timeout * GPIB_ADDRESS_COUNT;
+        let ret = self.request(req).await;
+        self.timeout = rpc_timeout;
+        match ret? {
+            PrologixAdapterResponse::Addresses(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Records the requests sent to a `comsrv` and replays them from a file, to test code driving instruments
+//! without hardware.
+//!
+//! A session is recorded by wrapping the [`Rpc`] talking to the `comsrv` with a [`RecordingRpc`], which appends
+//! each request and its reply to a file in JSON-lines format. A [`ReplayRpc`] then serves the recorded replies
+//! to identical requests:
+//!
+//! ```no_run
+//! # use comsrv_client::replay::{RecordingRpc, ReplayRpc};
+//! # fn run<T: comsrv_client::Rpc>(rpc: T) -> Result<(), comsrv_client::Error> {
+//! let rpc = RecordingRpc::create(rpc, "session.jsonl")?;
+//! // ... drive the instrument with `rpc`, then later in a test:
+//! let rpc = ReplayRpc::open("session.jsonl")?;
+//! # Ok(())
+//! # }
+//! ```
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use comsrv_protocol::{Request, Response};
+use serde_json::{json, Value};
+
+use crate::{protocol, Error, Rpc};
+
+/// Wraps an [`Rpc`] and appends each request and its reply to a file.
+///
+/// Clones append to the same file.
+#[derive(Clone)]
+pub struct RecordingRpc<T: Rpc> {
+    inner: T,
+    file: Arc<Mutex<File>>,
+}
+
+impl<T: Rpc> RecordingRpc<T> {
+    /// Record the requests sent through `inner` to `path`, replacing an existing file.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map_err(Error::Io)?;
+        Ok(Self {
+            inner,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Returns the wrapped [`Rpc`].
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<T: Rpc> Rpc for RecordingRpc<T> {
+    async fn request(&mut self, request: Request, timeout: Duration) -> crate::Result<Response> {
+        let entry = serde_json::to_value(&request).map_err(|x| Error::Other(anyhow!(x)))?;
+        let ret = self.inner.request(request, timeout).await;
+        let reply = match &ret {
+            Ok(x) => json!({ "Response": x }),
+            Err(Error::Remote(x)) => json!({ "Remote": x }),
+            Err(Error::Timeout) => json!("Timeout"),
+            Err(x) => json!({ "Other": x.to_string() }),
+        };
+        let mut line = json!({ "request": entry, "reply": reply }).to_string();
+        line.push('\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(Error::Io)?;
+        ret
+    }
+}
+
+struct Entry {
+    request: Value,
+    reply: Value,
+}
+
+/// An [`Rpc`] serving the replies recorded by a [`RecordingRpc`].
+///
+/// A request is answered with the reply of the first recorded request which is identical and has not been
+/// answered yet. Requests which were not recorded fail.
+///
+/// Clones share the recorded requests.
+#[derive(Clone)]
+pub struct ReplayRpc {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl ReplayRpc {
+    /// Load a session recorded to `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let data = fs::read_to_string(path).map_err(Error::Io)?;
+        let invalid = |idx: usize| Error::Other(anyhow!("Invalid recording in line {}", idx + 1));
+        let mut entries = Vec::new();
+        for (idx, line) in data
+            .lines()
+            .enumerate()
+            .filter(|(_, x)| !x.trim().is_empty())
+        {
+            let mut value: Value = serde_json::from_str(line).map_err(|_| invalid(idx))?;
+            let request = value.get_mut("request").ok_or_else(|| invalid(idx))?.take();
+            let reply = value.get_mut("reply").ok_or_else(|| invalid(idx))?.take();
+            entries.push(Entry { request, reply });
+        }
+        Ok(Self {
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// Number of recorded requests which have not been replayed yet.
+    pub fn pending(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+fn replay(reply: Value) -> crate::Result<Response> {
+    let invalid = |x: serde_json::Error| Error::Other(anyhow!("Invalid recorded reply: {}", x));
+    if reply == json!("Timeout") {
+        return Err(Error::Timeout);
+    }
+    match reply {
+        Value::Object(mut x) if x.len() == 1 => {
+            if let Some(response) = x.remove("Response") {
+                return serde_json::from_value(response).map_err(invalid);
+            }
+            if let Some(err) = x.remove("Remote") {
+                let err: protocol::Error = serde_json::from_value(err).map_err(invalid)?;
+                return Err(Error::Remote(err));
+            }
+            match x.remove("Other") {
+                Some(Value::String(msg)) => Err(Error::Other(anyhow!(msg))),
+                _ => Err(Error::Other(anyhow!("Invalid recorded reply"))),
+            }
+        }
+        _ => Err(Error::Other(anyhow!("Invalid recorded reply"))),
+    }
+}
+
+#[async_trait]
+impl Rpc for ReplayRpc {
+    async fn request(&mut self, request: Request, _timeout: Duration) -> crate::Result<Response> {
+        let request = serde_json::to_value(&request).map_err(|x| Error::Other(anyhow!(x)))?;
+        let reply = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.iter().position(|x| x.request == request) {
+                Some(idx) => entries.remove(idx).reply,
+                None => {
+                    return Err(Error::Other(anyhow!(
+                        "Request was not recorded: {}",
+                        request
+                    )))
+                }
+            }
+        };
+        replay(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use crate::scpi::ScpiPipe;
+    use comsrv_protocol::{ScpiInstrument, ScpiRequest, ScpiResponse, VxiInstrument};
+
+    fn is_query(cmd: &'static str) -> impl Fn(&Request) -> bool {
+        move |req| matches!(req, Request::Scpi { request: ScpiRequest::QueryString(x), .. } if x == cmd)
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("comsrv-{}.jsonl", uuid::Uuid::new_v4()));
+        let instrument = ScpiInstrument::Vxi(VxiInstrument {
+            host: "127.0.0.1".to_string(),
+        });
+
+        let mock = MockRpc::new();
+        mock.expect(
+            is_query("*IDN?"),
+            Response::Scpi(ScpiResponse::String("ACME,DMM".to_string())),
+        )
+        .expect_remote_error(
+            is_query("MEAS:VOLT?"),
+            protocol::Error::protocol(anyhow!("Overload")),
+        )
+        .expect(
+            is_query("MEAS:VOLT?"),
+            Response::Scpi(ScpiResponse::String("1.5".to_string())),
+        );
+        let rpc = RecordingRpc::create(mock.clone(), &path).unwrap();
+        let mut pipe = ScpiPipe::new(rpc, instrument.clone());
+        assert_eq!(pipe.query("*IDN?").await.unwrap(), "ACME,DMM");
+        assert!(pipe.query_f64("MEAS:VOLT?").await.is_err());
+        assert_eq!(pipe.query_f64("MEAS:VOLT?").await.unwrap(), 1.5);
+        mock.assert_done();
+
+        let rpc = ReplayRpc::open(&path).unwrap();
+        let mut pipe = ScpiPipe::new(rpc.clone(), instrument);
+        assert_eq!(pipe.query("*IDN?").await.unwrap(), "ACME,DMM");
+        assert!(matches!(
+            pipe.query_f64("MEAS:VOLT?").await,
+            Err(Error::Remote(_))
+        ));
+        assert_eq!(pipe.query_f64("MEAS:VOLT?").await.unwrap(), 1.5);
+        assert_eq!(rpc.pending(), 0);
+        assert!(pipe.query("*IDN?").await.is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
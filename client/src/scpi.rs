@@ -0,0 +1,571 @@
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use comsrv_protocol::{
+    visa_attributes, Endian, Request, Response, ScpiInstrument, ScpiRequest, ScpiResponse,
+};
+
+use crate::{lock, LockGuard, Lockable, Locked, Rpc, DEFAULT_RPC_TIMEOUT};
+
+/// Maximum number of entries read by [`ScpiPipe::drain_error_queue`]. Guards against instruments
+/// which never report an empty error queue.
+pub const MAX_ERROR_QUEUE_LENGTH: usize = 64;
+
+/// Encoding of the numbers in a binary block, as selected with `FORM:DATA` and `FORM:BORD`. Defaults
+/// to the SCPI default of `FORM REAL` with normal byte order, i.e. 64-bit floats in big endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealFormat {
+    Real32(Endian),
+    Real64(Endian),
+}
+
+impl Default for RealFormat {
+    fn default() -> Self {
+        RealFormat::Real64(Endian::Big)
+    }
+}
+
+pub struct ScpiPipe<T: Rpc> {
+    rpc: T,
+    instrument: ScpiInstrument,
+    lock: Locked,
+    pub timeout: Duration,
+}
+
+impl<T: Rpc> Clone for ScpiPipe<T> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc: self.rpc.clone(),
+            instrument: self.instrument.clone(),
+            lock: Locked::new(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Rpc> Lockable<T> for ScpiPipe<T> {
+    async fn lock(&mut self, timeout: Duration) -> crate::Result<LockGuard<T>> {
+        let ret = lock(&mut self.rpc, &self.instrument.address(), timeout).await?;
+        self.lock = ret.locked();
+        Ok(ret)
+    }
+}
+
+impl<T: Rpc> ScpiPipe<T> {
+    pub fn new(rpc: T, instrument: ScpiInstrument) -> Self {
+        Self {
+            rpc,
+            instrument,
+            lock: Locked::new(),
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(rpc: T, instrument: ScpiInstrument, timeout: Duration) -> Self {
+        Self {
+            rpc,
+            instrument,
+            lock: Locked::new(),
+            timeout,
+        }
+    }
+
+    pub async fn request(&mut self, request: ScpiRequest) -> crate::Result<ScpiResponse> {
+        let ret = self
+            .rpc
+            .request(
+                Request::Scpi {
+                    instrument: self.instrument.clone(),
+                    request,
+                    lock: self.lock.check_lock(),
+                    timeout: Some(self.timeout.into()),
+                    truncate: false,
+                },
+                self.timeout,
+            )
+            .await?;
+        match ret {
+            Response::Scpi(x) => Ok(x),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    pub async fn write(&mut self, msg: &str) -> crate::Result<()> {
+        match self.request(ScpiRequest::Write(msg.to_string())).await? {
+            ScpiResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    pub async fn query(&mut self, msg: &str) -> crate::Result<String> {
+        match self
+            .request(ScpiRequest::QueryString(msg.to_string()))
+            .await?
+        {
+            ScpiResponse::String(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Send the query `msg` and parse the reply as number, see [`parse_f64`].
+    pub async fn query_f64(&mut self, msg: &str) -> crate::Result<f64> {
+        parse_f64(&self.query(msg).await?)
+    }
+
+    /// Send the query `msg` and parse the reply as integer, see [`parse_i64`].
+    pub async fn query_i64(&mut self, msg: &str) -> crate::Result<i64> {
+        parse_i64(&self.query(msg).await?)
+    }
+
+    /// Send the query `msg` and parse the reply as boolean, see [`parse_bool`].
+    pub async fn query_bool(&mut self, msg: &str) -> crate::Result<bool> {
+        parse_bool(&self.query(msg).await?)
+    }
+
+    /// Send the query `msg` and parse the reply as comma-separated list of numbers, e.g. the reply
+    /// to `FETC:ARR?` with `FORM ASC`.
+    ///
+    /// If the instrument replies with a binary block instead, the query is sent again with
+    /// [`ScpiPipe::query_f64_block`] and the block is decoded with the default [`RealFormat`], since
+    /// the binary data cannot be transferred as string. Use [`ScpiPipe::query_f64_block`] directly
+    /// for other formats.
+    pub async fn query_f64_array(&mut self, msg: &str) -> crate::Result<Vec<f64>> {
+        let reply = self.query(msg).await?;
+        if is_block(&reply) {
+            return self.query_f64_block(msg, RealFormat::default()).await;
+        }
+        parse_f64_array(&reply)
+    }
+
+    /// Send the query `msg` as [`ScpiRequest::QueryBinary`] and decode the binary block of the reply
+    /// as numbers of the given `format`, e.g. the reply to `CURV?` with `FORM REAL`.
+    pub async fn query_f64_block(
+        &mut self,
+        msg: &str,
+        format: RealFormat,
+    ) -> crate::Result<Vec<f64>> {
+        decode_real_block(&self.query_binary(msg).await?, format)
+    }
+
+    pub async fn query_binary(&mut self, msg: &str) -> crate::Result<Vec<u8>> {
+        match self
+            .request(ScpiRequest::QueryBinary(msg.to_string()))
+            .await?
+        {
+            ScpiResponse::Binary { data } => Ok(data),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    pub async fn read_raw(&mut self) -> crate::Result<Vec<u8>> {
+        match self.request(ScpiRequest::ReadRaw).await? {
+            ScpiResponse::Binary { data } => Ok(data),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Lock the instrument in the VISA driver, waiting at most `timeout_ms` milliseconds. Returns the access key
+    /// of shared locks. This lock is independent of the locks of the comsrv.
+    pub async fn visa_lock(
+        &mut self,
+        exclusive: bool,
+        timeout_ms: u32,
+    ) -> crate::Result<Option<String>> {
+        match self
+            .request(ScpiRequest::VisaLock {
+                exclusive,
+                timeout_ms,
+            })
+            .await?
+        {
+            ScpiResponse::Done => Ok(None),
+            ScpiResponse::AccessKey(x) => Ok(Some(x)),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    pub async fn visa_unlock(&mut self) -> crate::Result<()> {
+        match self.request(ScpiRequest::VisaUnlock).await? {
+            ScpiResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Read a VISA attribute, see [`visa_attributes`] for the supported attributes.
+    pub async fn get_visa_attribute(&mut self, code: u32) -> crate::Result<u64> {
+        match self.request(ScpiRequest::GetAttribute { code }).await? {
+            ScpiResponse::Attribute(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Write a VISA attribute, see [`visa_attributes`] for the supported attributes.
+    pub async fn set_visa_attribute(&mut self, code: u32, value: u64) -> crate::Result<()> {
+        match self
+            .request(ScpiRequest::SetAttribute { code, value })
+            .await?
+        {
+            ScpiResponse::Done => Ok(()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// The I/O timeout of the VISA session
+    pub async fn visa_timeout(&mut self) -> crate::Result<Duration> {
+        let millis = self
+            .get_visa_attribute(visa_attributes::VI_ATTR_TMO_VALUE)
+            .await?;
+        Ok(Duration::from_millis(millis))
+    }
+
+    pub async fn set_visa_timeout(&mut self, timeout: Duration) -> crate::Result<()> {
+        let millis = timeout.as_millis() as u64;
+        self.set_visa_attribute(visa_attributes::VI_ATTR_TMO_VALUE, millis)
+            .await
+    }
+
+    /// Terminate reads on `termchar`, or disable the termination character with `None`.
+    pub async fn set_visa_termchar(&mut self, termchar: Option<u8>) -> crate::Result<()> {
+        if let Some(x) = termchar {
+            self.set_visa_attribute(visa_attributes::VI_ATTR_TERMCHAR, x as u64)
+                .await?;
+        }
+        self.set_visa_attribute(
+            visa_attributes::VI_ATTR_TERMCHAR_EN,
+            termchar.is_some() as u64,
+        )
+        .await
+    }
+
+    /// Assert END with the last byte of each write
+    pub async fn set_visa_send_end(&mut self, enable: bool) -> crate::Result<()> {
+        self.set_visa_attribute(visa_attributes::VI_ATTR_SEND_END_EN, enable as u64)
+            .await
+    }
+
+    /// Read the instrument error queue with `SYST:ERR?` until it reports `0,"No error"`.
+    ///
+    /// Returns the `(code, message)` pairs in the order they were read. At most
+    /// [`MAX_ERROR_QUEUE_LENGTH`] entries are read.
+    pub async fn drain_error_queue(&mut self) -> crate::Result<Vec<(i32, String)>> {
+        let mut ret = Vec::new();
+        for _ in 0..MAX_ERROR_QUEUE_LENGTH {
+            let reply = self.query("SYST:ERR?").await?;
+            let (code, msg) = parse_error(&reply)?;
+            if code == 0 {
+                break;
+            }
+            ret.push((code, msg));
+        }
+        Ok(ret)
+    }
+}
+
+/// Parse an entry of the SCPI error queue, e.g. `-113,"Undefined header"`.
+pub fn parse_error(reply: &str) -> crate::Result<(i32, String)> {
+    let invalid = || crate::Error::Other(anyhow!("Invalid error queue entry: `{}`", reply));
+    let (code, msg) = reply.split_once(',').ok_or_else(invalid)?;
+    let code = code.trim().parse::<i32>().map_err(|_| invalid())?;
+    let msg = msg.trim();
+    let msg = msg
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(msg);
+    Ok((code, msg.to_string()))
+}
+
+/// Parse a SCPI number in decimal notation, e.g. `+1.234E+00`, `-5` or `0.5`.
+pub fn parse_f64(reply: &str) -> crate::Result<f64> {
+    reply
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| crate::Error::Other(anyhow!("Invalid number: `{}`", reply)))
+}
+
+/// Parse a comma-separated list of SCPI numbers, e.g. `+1.0E+00, -2.5E-01\n`. An empty reply is
+/// an empty list.
+pub fn parse_f64_array(reply: &str) -> crate::Result<Vec<f64>> {
+    let values = reply.trim();
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+    values
+        .split(',')
+        .map(|x| {
+            x.trim().parse::<f64>().map_err(|_| {
+                crate::Error::Other(anyhow!("Invalid number `{}` in array: `{}`", x, reply))
+            })
+        })
+        .collect()
+}
+
+/// Returns true if `reply` starts with the header of a binary block, e.g. `#18`.
+fn is_block(reply: &str) -> bool {
+    let mut chars = reply.trim_start().chars();
+    chars.next() == Some('#') && matches!(chars.next(), Some(x) if x.is_ascii_digit())
+}
+
+/// Decode the data of a binary block, i.e. without its header, into numbers of the given `format`.
+pub fn decode_real_block(data: &[u8], format: RealFormat) -> crate::Result<Vec<f64>> {
+    let size = match format {
+        RealFormat::Real32(_) => 4,
+        RealFormat::Real64(_) => 8,
+    };
+    let chunks = data.chunks_exact(size);
+    if !chunks.remainder().is_empty() {
+        return Err(crate::Error::Other(anyhow!(
+            "Binary block of {} bytes does not contain {}-byte numbers",
+            data.len(),
+            size
+        )));
+    }
+    let ret = chunks
+        .map(|x| match format {
+            RealFormat::Real32(Endian::Big) => f32::from_be_bytes(x.try_into().unwrap()) as f64,
+            RealFormat::Real32(Endian::Little) => f32::from_le_bytes(x.try_into().unwrap()) as f64,
+            RealFormat::Real64(Endian::Big) => f64::from_be_bytes(x.try_into().unwrap()),
+            RealFormat::Real64(Endian::Little) => f64::from_le_bytes(x.try_into().unwrap()),
+        })
+        .collect();
+    Ok(ret)
+}
+
+/// Parse a SCPI integer. Besides decimal integers such as `+42`, the non-decimal formats `#H1F`,
+/// `#Q17` and `#B101` as well as integral numbers in scientific notation, e.g. `4.2E+01`, are
+/// accepted.
+pub fn parse_i64(reply: &str) -> crate::Result<i64> {
+    let invalid = || crate::Error::Other(anyhow!("Invalid integer: `{}`", reply));
+    let value = reply.trim();
+    let radix = match value.get(..2).map(|x| x.to_ascii_uppercase()).as_deref() {
+        Some("#H") => Some(16),
+        Some("#Q") => Some(8),
+        Some("#B") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        return i64::from_str_radix(&value[2..], radix).map_err(|_| invalid());
+    }
+    if let Ok(x) = value.parse::<i64>() {
+        return Ok(x);
+    }
+    let x = value.parse::<f64>().map_err(|_| invalid())?;
+    if x.fract() != 0.0 || x < i64::MIN as f64 || x >= i64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(x as i64)
+}
+
+/// Parse a SCPI boolean, i.e. `ON`, `OFF`, `1` or `0`, ignoring case and a leading `+`.
+pub fn parse_bool(reply: &str) -> crate::Result<bool> {
+    let value = reply.trim();
+    match value.strip_prefix('+').unwrap_or(value) {
+        x if x.eq_ignore_ascii_case("ON") || x == "1" => Ok(true),
+        x if x.eq_ignore_ascii_case("OFF") || x == "0" => Ok(false),
+        _ => Err(crate::Error::Other(anyhow!("Invalid boolean: `{}`", reply))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::VxiInstrument;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn parse_errors() {
+        let (code, msg) = parse_error("+0,\"No error\"").unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(msg, "No error");
+
+        let (code, msg) = parse_error("-113,\"Undefined header\"\n").unwrap();
+        assert_eq!(code, -113);
+        assert_eq!(msg, "Undefined header");
+
+        let (code, msg) = parse_error("-222,\"Data out of range;VOLT 100,5\"").unwrap();
+        assert_eq!(code, -222);
+        assert_eq!(msg, "Data out of range;VOLT 100,5");
+
+        assert!(parse_error("No error").is_err());
+        assert!(parse_error("abc,\"No error\"").is_err());
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_f64("+1.234E+00\n").unwrap(), 1.234);
+        assert_eq!(parse_f64("-5.5e-3").unwrap(), -0.0055);
+        assert_eq!(parse_f64("42").unwrap(), 42.0);
+        assert!(parse_f64("1.2V").is_err());
+
+        assert_eq!(parse_i64("+42\n").unwrap(), 42);
+        assert_eq!(parse_i64("-7").unwrap(), -7);
+        assert_eq!(parse_i64("4.2E+01").unwrap(), 42);
+        assert_eq!(parse_i64("#H1F").unwrap(), 31);
+        assert_eq!(parse_i64("#q17").unwrap(), 15);
+        assert_eq!(parse_i64("#B101").unwrap(), 5);
+        assert!(parse_i64("4.25").is_err());
+        assert!(parse_i64("9.9E37").is_err());
+    }
+
+    #[test]
+    fn parse_booleans() {
+        for x in ["ON", "on", "1", "+1\n"] {
+            assert!(parse_bool(x).unwrap(), "{}", x);
+        }
+        for x in ["OFF", "Off", "0", "+0"] {
+            assert!(!parse_bool(x).unwrap(), "{}", x);
+        }
+        let err = parse_bool("2").unwrap_err();
+        assert!(err.to_string().contains("`2`"));
+    }
+
+    /// Instrument replying to `QueryString` with `reply` and to `QueryBinary` with `block`
+    #[derive(Clone)]
+    struct ArrayInstrument {
+        reply: String,
+        block: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl Rpc for ArrayInstrument {
+        async fn request(
+            &mut self,
+            request: Request,
+            _timeout: Duration,
+        ) -> crate::Result<Response> {
+            match request {
+                Request::Scpi {
+                    request: ScpiRequest::QueryString(_),
+                    ..
+                } => Ok(Response::Scpi(ScpiResponse::String(self.reply.clone()))),
+                Request::Scpi {
+                    request: ScpiRequest::QueryBinary(_),
+                    ..
+                } => Ok(Response::Scpi(ScpiResponse::Binary {
+                    data: self.block.clone(),
+                })),
+                _ => Err(crate::Error::UnexpectdResponse),
+            }
+        }
+    }
+
+    fn array_pipe(reply: &str, block: Vec<u8>) -> ScpiPipe<ArrayInstrument> {
+        let instrument = ScpiInstrument::Vxi(VxiInstrument {
+            host: "127.0.0.1".to_string(),
+        });
+        let reply = reply.to_string();
+        ScpiPipe::new(ArrayInstrument { reply, block }, instrument)
+    }
+
+    #[tokio::test]
+    async fn query_ascii_array() {
+        let mut pipe = array_pipe("+1.0E+00, -2.5E-01,3\r\n", vec![]);
+        let values = pipe.query_f64_array("FETC:ARR?").await.unwrap();
+        assert_eq!(values, vec![1.0, -0.25, 3.0]);
+
+        let mut pipe = array_pipe("\n", vec![]);
+        assert!(pipe.query_f64_array("FETC:ARR?").await.unwrap().is_empty());
+
+        let err = parse_f64_array("1.0,,2.0").unwrap_err();
+        assert!(err.to_string().contains("1.0,,2.0"));
+    }
+
+    #[tokio::test]
+    async fn query_binary_array() {
+        let block: Vec<u8> = [1.5_f64, -2.0]
+            .iter()
+            .flat_map(|x| x.to_be_bytes())
+            .collect();
+        let mut pipe = array_pipe("", block);
+        let values = pipe
+            .query_f64_block("CURV?", RealFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(values, vec![1.5, -2.0]);
+
+        let block: Vec<u8> = [0.5_f32, 4.0]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let mut pipe = array_pipe("", block);
+        let values = pipe
+            .query_f64_block("CURV?", RealFormat::Real32(Endian::Little))
+            .await
+            .unwrap();
+        assert_eq!(values, vec![0.5, 4.0]);
+
+        assert!(decode_real_block(&[0; 6], RealFormat::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn query_array_with_block_reply() {
+        let block: Vec<u8> = [1.5_f64, -2.0]
+            .iter()
+            .flat_map(|x| x.to_be_bytes())
+            .collect();
+        // the binary data is not transferred as string, the block is queried again
+        let mut pipe = array_pipe("#216?", block);
+        let values = pipe.query_f64_array("CURV?").await.unwrap();
+        assert_eq!(values, vec![1.5, -2.0]);
+
+        // `#H` is a non-decimal number rather than a block
+        assert!(!is_block("#H1F,#H20"));
+        assert!(is_block("  #0?"));
+    }
+
+    #[derive(Clone)]
+    struct ErrorQueue(Arc<Mutex<VecDeque<String>>>);
+
+    #[async_trait]
+    impl Rpc for ErrorQueue {
+        async fn request(
+            &mut self,
+            _request: Request,
+            _timeout: Duration,
+        ) -> crate::Result<Response> {
+            let reply = self
+                .0
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| "-350,\"Queue overflow\"".to_string());
+            Ok(Response::Scpi(ScpiResponse::String(reply)))
+        }
+    }
+
+    fn pipe(replies: &[&str]) -> ScpiPipe<ErrorQueue> {
+        let replies = replies.iter().map(|x| x.to_string()).collect();
+        let instrument = ScpiInstrument::Vxi(VxiInstrument {
+            host: "127.0.0.1".to_string(),
+        });
+        ScpiPipe::new(ErrorQueue(Arc::new(Mutex::new(replies))), instrument)
+    }
+
+    #[tokio::test]
+    async fn drain_until_no_error() {
+        let mut pipe = pipe(&[
+            "-113,\"Undefined header\"",
+            "-222,\"Data out of range\"",
+            "+0,\"No error\"",
+        ]);
+        let errors = pipe.drain_error_queue().await.unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                (-113, "Undefined header".to_string()),
+                (-222, "Data out of range".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drain_is_bounded() {
+        let mut pipe = pipe(&[]);
+        let errors = pipe.drain_error_queue().await.unwrap();
+        assert_eq!(errors.len(), MAX_ERROR_QUEUE_LENGTH);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use comsrv_protocol::{
+    Request, Response, SigrokAcquire, SigrokData, SigrokInstrument, SigrokRequest, SigrokResponse,
+};
+
+use crate::{Rpc, DEFAULT_RPC_TIMEOUT};
+
+/// Samples of the analog channels of a sigrok device
+pub struct AnalogData {
+    pub tsample: f64,
+    pub length: usize,
+    pub channels: HashMap<String, Vec<f32>>,
+}
+
+/// Acquires data from a logic analyzer or oscilloscope supported by sigrok.
+///
+/// Note that `timeout` must cover the whole acquisition.
+#[derive(Clone)]
+pub struct SigrokDevice<T: Rpc> {
+    rpc: T,
+    instrument: SigrokInstrument,
+    pub timeout: Duration,
+}
+
+impl<T: Rpc> SigrokDevice<T> {
+    pub fn new(rpc: T, instrument: SigrokInstrument) -> Self {
+        Self {
+            rpc,
+            instrument,
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    pub async fn request(&mut self, request: SigrokRequest) -> crate::Result<SigrokResponse> {
+        let ret = self
+            .rpc
+            .request(
+                Request::Sigrok {
+                    instrument: self.instrument.clone(),
+                    request,
+                    truncate: false,
+                },
+                self.timeout,
+            )
+            .await?;
+        match ret {
+            Response::Sigrok(x) => Ok(x),
+            Response::Error(x) => Err(x.into()),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Acquire logic levels. The samples of each channel are packed into bytes, LSB first.
+    pub async fn read(
+        &mut self,
+        channels: Vec<String>,
+        sample_rate: u64,
+        acquire: SigrokAcquire,
+    ) -> crate::Result<SigrokData> {
+        let request = SigrokRequest {
+            channels,
+            acquire,
+            sample_rate,
+            analog: false,
+        };
+        match self.request(request).await? {
+            SigrokResponse::Data(x) => Ok(x),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+
+    /// Acquire analog samples.
+    pub async fn read_analog(
+        &mut self,
+        channels: Vec<String>,
+        sample_rate: u64,
+        acquire: SigrokAcquire,
+    ) -> crate::Result<AnalogData> {
+        let request = SigrokRequest {
+            channels,
+            acquire,
+            sample_rate,
+            analog: true,
+        };
+        match self.request(request).await? {
+            SigrokResponse::AnalogData {
+                tsample,
+                length,
+                channels,
+            } => Ok(AnalogData {
+                tsample,
+                length,
+                channels,
+            }),
+            _ => Err(crate::Error::UnexpectdResponse),
+        }
+    }
+}
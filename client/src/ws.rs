@@ -1,9 +1,15 @@
-use crate::Rpc;
+use crate::{echoed_response, with_id, Rpc, DEFAULT_RPC_TIMEOUT};
 use async_trait::async_trait;
-use comsrv_protocol::{Request, Response};
+use comsrv_protocol::cobs_stream::CobsStreamResponse;
+use comsrv_protocol::{
+    ByteStreamInstrument, CanAddress, CanResponse, NotificationFilter, Request, Response,
+};
 use std::io;
 use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::{select, task};
 use url::Url;
+use uuid::Uuid;
 
 type Client = broadcast_wsrpc::client::Client<Request, Response>;
 
@@ -20,11 +26,253 @@ impl WsRpc {
         let client = Client::connect(url, duration).await?;
         Ok(WsRpc { client })
     }
+
+    /// Subscribe to the notifications broadcast by the `comsrv`. Notifications for which `filter`
+    /// returns `None` are dropped.
+    pub fn subscribe<U, F>(&self, filter: F) -> UnboundedReceiver<U>
+    where
+        U: Send + 'static,
+        F: FnMut(Response) -> Option<U> + Send + 'static,
+    {
+        filter_notifications(self.client.notifications(), filter)
+    }
+
+    /// Subscribe to CAN notifications. If `source` is given, only notifications originating from
+    /// that CAN bus are forwarded.
+    pub fn subscribe_can(&self, source: Option<CanAddress>) -> UnboundedReceiver<CanResponse> {
+        self.subscribe(can_filter(source))
+    }
+
+    /// Subscribe to the notifications selected by `filter`.
+    ///
+    /// The filter is applied by the client. Use [`WsRpc::set_notification_filter`] to also reduce the
+    /// notifications the `comsrv` sends on the connection.
+    pub fn subscribe_notifications(
+        &self,
+        filter: NotificationFilter,
+    ) -> UnboundedReceiver<Response> {
+        self.subscribe(notification_filter(filter))
+    }
+
+    /// Let the `comsrv` only send the notifications selected by `filter` on this connection.
+    ///
+    /// The filter applies to all subscribers of the connection, including those of clones of this
+    /// `WsRpc`. The default filter selects all notifications.
+    pub async fn set_notification_filter(
+        &mut self,
+        filter: NotificationFilter,
+    ) -> crate::Result<()> {
+        match self
+            .request(Request::Subscribe { filter }, DEFAULT_RPC_TIMEOUT)
+            .await
+        {
+            Ok(Response::Subscribed { .. }) => Ok(()),
+            Ok(Response::Error(x)) => Err(x.into()),
+            Ok(_) => Err(crate::Error::UnexpectdResponse),
+            Err(x) => Err(x),
+        }
+    }
+
+    /// Subscribe to the frames received by the COBS stream running on `instrument`.
+    pub fn subscribe_cobs(&self, instrument: &ByteStreamInstrument) -> UnboundedReceiver<Vec<u8>> {
+        self.subscribe(cobs_filter(instrument))
+    }
 }
 
 #[async_trait]
 impl Rpc for WsRpc {
     async fn request(&mut self, request: Request, timeout: Duration) -> crate::Result<Response> {
-        Ok(self.client.request(request, timeout).await?)
+        Ok(self.client.request(request, timeout).await?.without_echo())
+    }
+
+    async fn request_with_id(
+        &mut self,
+        request: Request,
+        timeout: Duration,
+    ) -> crate::Result<(Response, Uuid)> {
+        let id = Uuid::new_v4();
+        let response = self.client.request(with_id(id, request), timeout).await?;
+        Ok((echoed_response(id, response)?, id))
+    }
+}
+
+fn filter_notifications<U, F>(
+    mut notifications: UnboundedReceiver<Response>,
+    mut filter: F,
+) -> UnboundedReceiver<U>
+where
+    U: Send + 'static,
+    F: FnMut(Response) -> Option<U> + Send + 'static,
+{
+    let (tx, rx) = unbounded_channel();
+    task::spawn(async move {
+        loop {
+            select! {
+                x = notifications.recv() => {
+                    let x = match x {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    if let Some(x) = filter(x) {
+                        if tx.send(x).is_err() {
+                            break;
+                        }
+                    }
+                }
+                _ = tx.closed() => break,
+            }
+        }
+    });
+    rx
+}
+
+fn can_filter(source: Option<CanAddress>) -> impl FnMut(Response) -> Option<CanResponse> {
+    move |x| match x {
+        Response::Can {
+            source: x_source,
+            response,
+        } if source.is_none() || source.as_ref() == Some(&x_source) => Some(response),
+        _ => None,
+    }
+}
+
+fn notification_filter(filter: NotificationFilter) -> impl FnMut(Response) -> Option<Response> {
+    move |x| if filter.matches(&x) { Some(x) } else { None }
+}
+
+fn cobs_filter(instrument: &ByteStreamInstrument) -> impl FnMut(Response) -> Option<Vec<u8>> {
+    let address = instrument.address();
+    move |x| match x {
+        Response::CobsStream(CobsStreamResponse::MessageReceived { sender, data })
+            if sender.address() == address =>
+        {
+            Some(data)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{CanMessage, DataFrame, TcpAddress, TcpInstrument};
+    use tokio::sync::mpsc::UnboundedSender;
+
+    fn tcp(port: u16) -> ByteStreamInstrument {
+        ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port,
+            },
+            options: None,
+        })
+    }
+
+    fn can(source: CanAddress, id: u32) -> Response {
+        Response::Can {
+            source,
+            response: CanResponse::Raw(CanMessage::Data(DataFrame {
+                id,
+                ext_id: false,
+                data: vec![],
+            })),
+        }
+    }
+
+    fn cobs(sender: ByteStreamInstrument, data: &[u8]) -> Response {
+        Response::CobsStream(CobsStreamResponse::MessageReceived {
+            sender,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Broadcast a mix of notification types, similar to a `comsrv` serving several instruments.
+    fn broadcast(tx: &UnboundedSender<Response>) {
+        let pcan = CanAddress::PCan {
+            address: "usb1".to_string(),
+        };
+        tx.send(can(CanAddress::Loopback, 1)).unwrap();
+        tx.send(cobs(tcp(1000), b"abc")).unwrap();
+        tx.send(Response::Done).unwrap();
+        tx.send(can(pcan, 2)).unwrap();
+        tx.send(cobs(tcp(2000), b"def")).unwrap();
+        tx.send(can(CanAddress::Loopback, 3)).unwrap();
+    }
+
+    fn can_id(x: CanResponse) -> u32 {
+        match x {
+            CanResponse::Raw(CanMessage::Data(x)) => x.id,
+            _ => panic!("Unexpected CAN response: {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_can() {
+        let (tx, rx) = unbounded_channel();
+        let mut all = filter_notifications(rx, can_filter(None));
+        broadcast(&tx);
+        drop(tx);
+        let mut ids = Vec::new();
+        while let Some(x) = all.recv().await {
+            ids.push(can_id(x));
+        }
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let (tx, rx) = unbounded_channel();
+        let mut loopback = filter_notifications(rx, can_filter(Some(CanAddress::Loopback)));
+        broadcast(&tx);
+        drop(tx);
+        let mut ids = Vec::new();
+        while let Some(x) = loopback.recv().await {
+            ids.push(can_id(x));
+        }
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_notifications() {
+        use comsrv_protocol::NotificationKind;
+
+        let filter = NotificationFilter {
+            kinds: vec![NotificationKind::Can],
+            ..Default::default()
+        };
+        let (tx, rx) = unbounded_channel();
+        let mut can = filter_notifications(rx, notification_filter(filter));
+        broadcast(&tx);
+        drop(tx);
+        let mut ids = Vec::new();
+        while let Some(x) = can.recv().await {
+            match x {
+                Response::Can { response, .. } => ids.push(can_id(response)),
+                _ => panic!("Unexpected notification: {:?}", x),
+            }
+        }
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let filter = NotificationFilter {
+            kinds: vec![NotificationKind::Can],
+            can_source: Some(CanAddress::Loopback),
+            can_ids: vec![3],
+        };
+        let (tx, rx) = unbounded_channel();
+        let mut can = filter_notifications(rx, notification_filter(filter));
+        broadcast(&tx);
+        drop(tx);
+        match can.recv().await.unwrap() {
+            Response::Can { response, .. } => assert_eq!(can_id(response), 3),
+            x => panic!("Unexpected notification: {:?}", x),
+        }
+        assert!(can.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribe_cobs() {
+        let (tx, rx) = unbounded_channel();
+        let mut frames = filter_notifications(rx, cobs_filter(&tcp(2000)));
+        broadcast(&tx);
+        drop(tx);
+        assert_eq!(frames.recv().await.unwrap(), b"def".to_vec());
+        assert!(frames.recv().await.is_none());
     }
 }
@@ -0,0 +1,326 @@
+//! Implements a registry of friendly names for instrument addresses.
+//!
+//! An alias is referred to by writing `alias::<name>` in place of the string identifying the hardware endpoint,
+//! for example the port of a serial instrument or the host of a TCP instrument. Aliases are resolved before
+//! a request is dispatched, such that handlers only ever observe resolved addresses.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use comsrv_protocol::{
+    Address, ByteStreamInstrument, CanAddress, CanInstrument, CustomAddress, LoopbackAddress, Request, ScpiInstrument,
+};
+
+/// Prefix marking an alias in place of an address string
+pub const ALIAS_PREFIX: &str = "alias::";
+
+/// Registry of aliases. Aliases are kept in memory for the lifetime of the server.
+#[derive(Default)]
+pub struct Aliases {
+    aliases: Mutex<HashMap<String, Address>>,
+}
+
+impl Aliases {
+    /// Register `alias` for `addr`, replacing a previous registration of the same name.
+    ///
+    /// `addr` may itself refer to an alias, which is resolved before registration.
+    pub fn set(&self, alias: String, mut addr: Address) -> crate::Result<()> {
+        let alias = alias_name(alias)?;
+        self.resolve_address(&mut addr)?;
+        self.aliases.lock().unwrap().insert(alias, addr);
+        Ok(())
+    }
+
+    /// Replace all registered aliases with `aliases`. Aliases may refer to each other, but not in a cycle.
+    pub fn replace(&self, aliases: HashMap<String, Address>) -> crate::Result<()> {
+        let mut unresolved = HashMap::new();
+        for (alias, addr) in aliases {
+            unresolved.insert(alias_name(alias)?, addr);
+        }
+        let count = unresolved.len();
+        let registry = Aliases {
+            aliases: Mutex::new(unresolved),
+        };
+        // each pass resolves one level of indirection and a chain of aliases is shorter than the number of aliases
+        for _ in 0..count {
+            let mut resolved = registry.list();
+            for addr in resolved.values_mut() {
+                registry.resolve_address(addr)?;
+            }
+            *registry.aliases.lock().unwrap() = resolved;
+        }
+        let resolved = registry.list();
+        for (alias, addr) in &resolved {
+            // any alias still referred to is part of a cycle
+            if Aliases::default().resolve_address(&mut addr.clone()).is_err() {
+                return Err(crate::Error::argument(anyhow!(
+                    "Alias `{}` refers to itself through a cycle of aliases",
+                    alias
+                )));
+            }
+        }
+        *self.aliases.lock().unwrap() = resolved;
+        Ok(())
+    }
+
+    pub fn list(&self) -> HashMap<String, Address> {
+        self.aliases.lock().unwrap().clone()
+    }
+
+    /// Replace all aliases in `req` with the addresses they refer to.
+    pub fn resolve(&self, mut req: Request) -> crate::Result<Request> {
+        match &mut req {
+            Request::Bytes { instrument, .. } | Request::CobsStream { instrument, .. } => {
+                self.resolve_bytestream(instrument)?
+            }
+            Request::Can { instrument, .. } => self.resolve_can(instrument)?,
+            Request::Scpi { instrument, .. } => match instrument {
+                ScpiInstrument::Vxi(x) => self.substitute(&mut x.host, |addr| match addr {
+                    Address::Vxi(x) => Some(x),
+                    _ => None,
+                })?,
+                ScpiInstrument::Visa(x) => self.substitute(&mut x.address, |addr| match addr {
+                    Address::Visa(x) => Some(x),
+                    _ => None,
+                })?,
+            },
+            Request::Prologix { instrument, .. } | Request::PrologixAdapter { instrument, .. } => {
+                self.resolve_serial_port(&mut instrument.address.port)?
+            }
+            Request::Serial { instrument, .. } => self.resolve_serial_port(&mut instrument.address.port)?,
+            Request::Loopback { instrument, .. } => self.resolve_loopback(instrument)?,
+            Request::Lock { addr, .. }
+            | Request::Unlock { addr, .. }
+            | Request::Drop { addr, .. }
+            | Request::SetInstrumentOptions { addr, .. }
+            | Request::GetInstrumentOptions { addr }
+            | Request::SetAlias { addr, .. }
+            | Request::DescribeInstrument { addr }
+            | Request::Ping { addr, .. } => self.resolve_address(addr)?,
+            _ => {}
+        }
+        Ok(req)
+    }
+
+    fn resolve_address(&self, addr: &mut Address) -> crate::Result<()> {
+        match addr {
+            Address::Tcp(x) => {
+                let host = x.host.clone();
+                self.substitute_with(&host, x, |addr| match addr {
+                    Address::Tcp(x) => Some(x),
+                    _ => None,
+                })
+            }
+            Address::Ftdi(x) => self.resolve_ftdi_port(&mut x.port),
+            Address::Custom(x) => self.resolve_custom(x),
+            Address::Loopback(x) => self.resolve_loopback(x),
+            Address::Serial(x) => self.resolve_serial_port(&mut x.port),
+            Address::Vxi(x) => self.substitute(x, |addr| match addr {
+                Address::Vxi(x) => Some(x),
+                _ => None,
+            }),
+            Address::Visa(x) => self.substitute(x, |addr| match addr {
+                Address::Visa(x) => Some(x),
+                _ => None,
+            }),
+            Address::Can(x) => {
+                let name = match x {
+                    CanAddress::PCan { address } => address.clone(),
+                    CanAddress::SocketCan { interface } => interface.clone(),
+                    CanAddress::UsrCanet { host, .. } => host.clone(),
+                    CanAddress::Loopback => return Ok(()),
+                };
+                self.substitute_with(&name, x, |addr| match addr {
+                    Address::Can(x) => Some(x),
+                    _ => None,
+                })
+            }
+            Address::Hid(_) => Ok(()),
+        }
+    }
+
+    fn resolve_bytestream(&self, instrument: &mut ByteStreamInstrument) -> crate::Result<()> {
+        match instrument {
+            ByteStreamInstrument::Serial(x) => self.resolve_serial_port(&mut x.address.port),
+            ByteStreamInstrument::Ftdi(x) => self.resolve_ftdi_port(&mut x.address.port),
+            ByteStreamInstrument::Tcp(x) => {
+                let host = x.address.host.clone();
+                self.substitute_with(&host, &mut x.address, |addr| match addr {
+                    Address::Tcp(x) => Some(x),
+                    _ => None,
+                })
+            }
+            ByteStreamInstrument::Custom(x) => self.resolve_custom(x),
+            ByteStreamInstrument::Loopback(x) => self.resolve_loopback(x),
+        }
+    }
+
+    fn resolve_custom(&self, addr: &mut CustomAddress) -> crate::Result<()> {
+        let name = addr.address.clone();
+        self.substitute_with(&name, addr, |addr| match addr {
+            Address::Custom(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    fn resolve_loopback(&self, addr: &mut LoopbackAddress) -> crate::Result<()> {
+        self.substitute(&mut addr.name, |addr| match addr {
+            Address::Loopback(x) => Some(x.name),
+            _ => None,
+        })
+    }
+
+    fn resolve_can(&self, instrument: &mut CanInstrument) -> crate::Result<()> {
+        match instrument {
+            CanInstrument::PCan { address, .. } => self.substitute(address, |addr| match addr {
+                Address::Can(CanAddress::PCan { address }) => Some(address),
+                _ => None,
+            }),
+            CanInstrument::SocketCan { interface } => self.substitute(interface, |addr| match addr {
+                Address::Can(CanAddress::SocketCan { interface }) => Some(interface),
+                _ => None,
+            }),
+            CanInstrument::UsrCanet { host, port } => {
+                let name = host.clone();
+                let mut target = (host.clone(), *port);
+                self.substitute_with(&name, &mut target, |addr| match addr {
+                    Address::Can(CanAddress::UsrCanet { host, port }) => Some((host, port)),
+                    _ => None,
+                })?;
+                *host = target.0;
+                *port = target.1;
+                Ok(())
+            }
+            CanInstrument::Loopback => Ok(()),
+        }
+    }
+
+    fn resolve_serial_port(&self, port: &mut String) -> crate::Result<()> {
+        self.substitute(port, |addr| match addr {
+            Address::Serial(x) => Some(x.port),
+            _ => None,
+        })
+    }
+
+    fn resolve_ftdi_port(&self, port: &mut String) -> crate::Result<()> {
+        self.substitute(port, |addr| match addr {
+            Address::Ftdi(x) => Some(x.port),
+            _ => None,
+        })
+    }
+
+    fn substitute<F: FnOnce(Address) -> Option<String>>(&self, value: &mut String, extract: F) -> crate::Result<()> {
+        let name = value.clone();
+        self.substitute_with(&name, value, extract)
+    }
+
+    /// If `name` refers to an alias, replace `value` with the part of the aliased address
+    /// returned by `extract`. Fails if the alias is unknown or refers to a different kind of address.
+    fn substitute_with<T, F: FnOnce(Address) -> Option<T>>(
+        &self,
+        name: &str,
+        value: &mut T,
+        extract: F,
+    ) -> crate::Result<()> {
+        let alias = match name.strip_prefix(ALIAS_PREFIX) {
+            Some(alias) => alias,
+            None => return Ok(()),
+        };
+        let addr = self
+            .aliases
+            .lock()
+            .unwrap()
+            .get(alias)
+            .cloned()
+            .ok_or_else(|| crate::Error::argument(anyhow!("Unknown alias: `{}`", alias)))?;
+        let err = crate::Error::argument(anyhow!(
+            "Alias `{}` refers to an address of a different kind: {:?}",
+            alias,
+            addr
+        ));
+        *value = extract(addr).ok_or(err)?;
+        Ok(())
+    }
+}
+
+fn alias_name(alias: String) -> crate::Result<String> {
+    let alias = alias.strip_prefix(ALIAS_PREFIX).unwrap_or(&alias).to_string();
+    if alias.is_empty() || alias.contains(char::is_whitespace) {
+        return Err(crate::Error::argument(anyhow!("Invalid alias: `{}`", alias)));
+    }
+    Ok(alias)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{SerialAddress, TcpAddress};
+
+    fn serial(port: &str) -> Address {
+        Address::Serial(SerialAddress { port: port.to_string() })
+    }
+
+    #[test]
+    fn resolve_address() {
+        let aliases = Aliases::default();
+        aliases.set("myplc".to_string(), serial("/dev/ttyUSB0")).unwrap();
+        aliases.set("alias::other".to_string(), serial("alias::myplc")).unwrap();
+
+        let mut addr = serial("alias::myplc");
+        aliases.resolve_address(&mut addr).unwrap();
+        assert_eq!(addr, serial("/dev/ttyUSB0"));
+
+        let mut addr = serial("alias::other");
+        aliases.resolve_address(&mut addr).unwrap();
+        assert_eq!(addr, serial("/dev/ttyUSB0"));
+
+        let mut addr = serial("/dev/ttyUSB1");
+        aliases.resolve_address(&mut addr).unwrap();
+        assert_eq!(addr, serial("/dev/ttyUSB1"));
+
+        assert_eq!(aliases.list().len(), 2);
+    }
+
+    #[test]
+    fn invalid_alias() {
+        let aliases = Aliases::default();
+        aliases.set("myplc".to_string(), serial("/dev/ttyUSB0")).unwrap();
+        assert!(aliases.set("".to_string(), serial("/dev/ttyUSB0")).is_err());
+        assert!(aliases.set("foo".to_string(), serial("alias::unknown")).is_err());
+
+        let mut addr = serial("alias::unknown");
+        assert!(matches!(aliases.resolve_address(&mut addr), Err(crate::Error::Argument(_))));
+
+        let mut addr = Address::Tcp(TcpAddress {
+            host: "alias::myplc".to_string(),
+            port: 502,
+        });
+        assert!(matches!(aliases.resolve_address(&mut addr), Err(crate::Error::Argument(_))));
+    }
+
+    #[test]
+    fn replace_nested() {
+        let aliases = Aliases::default();
+        let mut config = HashMap::new();
+        config.insert("a".to_string(), serial("alias::b"));
+        config.insert("b".to_string(), serial("alias::c"));
+        config.insert("c".to_string(), serial("alias::d"));
+        config.insert("d".to_string(), serial("/dev/ttyUSB0"));
+        aliases.replace(config).unwrap();
+        for addr in aliases.list().values() {
+            assert_eq!(addr, &serial("/dev/ttyUSB0"));
+        }
+
+        let mut config = HashMap::new();
+        config.insert("a".to_string(), serial("alias::b"));
+        config.insert("b".to_string(), serial("alias::a"));
+        config.insert("c".to_string(), serial("/dev/ttyUSB1"));
+        assert!(matches!(aliases.replace(config), Err(crate::Error::Argument(_))));
+        assert_eq!(aliases.list().len(), 4);
+
+        let mut config = HashMap::new();
+        config.insert("a".to_string(), serial("alias::a"));
+        assert!(matches!(aliases.replace(config), Err(crate::Error::Argument(_))));
+    }
+}
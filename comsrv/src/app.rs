@@ -6,25 +6,46 @@
 use broadcast_wsrpc::server::{Requested, Server as WsrpcServer};
 use comsrv_protocol::cobs_stream::CobsStreamRequest;
 use comsrv_protocol::{
-    Address, ByteStreamInstrument, ByteStreamRequest, CanAddress, CanInstrument, CanRequest, FtdiInstrument,
-    HidResponse, PrologixInstrument, PrologixRequest, Request, Response, ScpiInstrument, ScpiRequest, SerialInstrument,
+    Address, ByteStreamInstrument, ByteStreamRequest, CanAddress, CanInstrument, CanRequest, CustomAddress,
+    FtdiInstrument, HidResponse, InstrumentOptions, LoopbackAddress, LoopbackRequest, PrologixAdapterRequest,
+    PrologixInstrument, PrologixRequest, Request, Response, ScpiInstrument, ScpiRequest, SerialInstrument,
     SerialRequest, TcpInstrument, VisaInstrument, VxiInstrument,
 };
 use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::task;
+use tokio::sync::watch;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{timeout_at, Instant};
 use uuid::Uuid;
 
-use crate::transport::{can, ftdi, hid, serial, sigrok, tcp, visa, vxi};
+use crate::transport::custom::{Transport, Transports};
+use crate::transport::{can, custom, ftdi, hid, loopback, serial, sigrok, tcp, visa, vxi};
 use crate::transport::{ftdi::FtdiRequest, tcp::TcpRequest};
 
-use crate::inventory::Inventory;
+use crate::alias::Aliases;
+use crate::coalesce::{self, Coalescer};
+use crate::config::{Config, Defaults};
+use crate::history::History;
+use crate::inventory::{InstrumentDefaults, Inventory, NO_AUTO_DROP};
+use crate::limit;
+use crate::message_limit;
+use crate::modbus_server::ModBusServers;
+use crate::protocol::can::gct;
+use crate::request_log::RequestLog;
+use crate::selftest;
+use crate::validate;
 use anyhow::anyhow;
 use std::convert::TryInto;
-use std::sync::Arc;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 pub type Server = WsrpcServer<Request, Response>;
 
+/// Default time granted to in-flight requests to complete once a shutdown was requested.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 macro_rules! crate_version {
     () => {
         env!("CARGO_PKG_VERSION")
@@ -37,6 +58,28 @@ macro_rules! crate_version {
 pub struct App {
     pub server: Server,
     pub inventories: Arc<Inventories>,
+    /// Time granted to in-flight requests to complete after a shutdown was requested.
+    pub drain_timeout: Duration,
+    /// Maximum size of an incoming message in bytes. Connections sending larger messages are closed. Applies to the
+    /// listeners started afterwards with [`App::listen_ws`] and [`App::listen_http`].
+    pub max_message_size: Option<usize>,
+    /// Maximum size of the data returned by a read in bytes. Larger responses fail or are truncated.
+    pub max_response_size: Option<usize>,
+    /// Bitrate of PCAN instruments which do not specify one.
+    pub default_can_bitrate: Option<u32>,
+    /// Wrap responses in [`Response::Echo`] to correlate them with their requests.
+    pub echo_requests: bool,
+    /// Let identical concurrent reads share a single transaction, see [`crate::coalesce`].
+    pub coalesce_reads: bool,
+    aliases: Arc<Aliases>,
+    defaults: Arc<RwLock<Defaults>>,
+    config_path: Option<PathBuf>,
+    shutdown: Arc<watch::Sender<bool>>,
+    history: Option<Arc<History>>,
+    request_log: Option<Arc<RequestLog>>,
+    transports: Arc<Transports>,
+    coalescer: Arc<Coalescer>,
+    modbus_servers: Arc<ModBusServers>,
 }
 
 /// Contains all the inventories, which contains all IO actors.
@@ -49,57 +92,370 @@ pub struct Inventories {
     visa: Inventory<visa::Instrument>,
     vxi: Inventory<vxi::Instrument>,
     hid: Inventory<hid::Instrument>,
+    custom: Inventory<custom::Instrument>,
+    loopback: Inventory<loopback::Instrument>,
 }
 
 impl Inventories {
     fn new() -> Self {
         Default::default()
     }
+
+    fn update_defaults(&self, f: impl Fn(&mut InstrumentDefaults)) {
+        self.serial.update_defaults(&f);
+        self.can.update_defaults(&f);
+        self.ftdi.update_defaults(&f);
+        self.tcp.update_defaults(&f);
+        self.visa.update_defaults(&f);
+        self.vxi.update_defaults(&f);
+        self.hid.update_defaults(&f);
+        self.custom.update_defaults(&f);
+        self.loopback.update_defaults(&f);
+    }
+
+    /// Drops all instruments which have not been used for longer than `ttl`. Returns the number of instruments dropped.
+    async fn disconnect_idle(&self, ttl: Duration) -> usize {
+        self.tcp.disconnect_idle(ttl).await
+            + self.vxi.disconnect_idle(ttl).await
+            + self.hid.disconnect_idle(ttl).await
+            + self.serial.disconnect_idle(ttl).await
+            + self.visa.disconnect_idle(ttl).await
+            + self.can.disconnect_idle(ttl).await
+            + self.ftdi.disconnect_idle(ttl).await
+            + self.custom.disconnect_idle(ttl).await
+            + self.loopback.disconnect_idle(ttl).await
+    }
 }
 
 impl App {
     pub fn new() -> (Self, UnboundedReceiver<Requested<Request, Response>>) {
         let (server, rx) = Server::new();
+        let (shutdown, _) = watch::channel(false);
         let app = App {
             server,
             inventories: Arc::new(Inventories::new()),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_message_size: None,
+            max_response_size: None,
+            echo_requests: false,
+            coalesce_reads: false,
+            default_can_bitrate: None,
+            aliases: Arc::new(Aliases::default()),
+            defaults: Arc::new(RwLock::new(Defaults::default())),
+            config_path: None,
+            shutdown: Arc::new(shutdown),
+            history: None,
+            request_log: None,
+            transports: Arc::new(Transports::default()),
+            coalescer: Arc::new(Coalescer::default()),
+            modbus_servers: Arc::new(ModBusServers::default()),
         };
         (app, rx)
     }
 
+    /// Register a [`Transport`] serving all [`ByteStreamInstrument::Custom`] instruments of the given `kind`.
+    /// Fails if a transport is already registered for `kind`.
+    pub fn register_transport(&self, kind: &str, transport: Arc<dyn Transport>) -> crate::Result<()> {
+        self.transports.register(kind, transport)
+    }
+
+    /// Load the configuration file at `path`. The file is read again on [`Request::ReloadConfig`].
+    pub fn load_config(&mut self, path: PathBuf) -> crate::Result<()> {
+        self.config_path = Some(path);
+        self.reload_config()
+    }
+
+    /// Reload the configuration file. Aliases registered at runtime are replaced by the aliases in the file.
+    pub fn reload_config(&self) -> crate::Result<()> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| crate::Error::argument(anyhow!("No config file given.")))?;
+        let config = Config::load(path)?;
+        self.aliases.replace(config.aliases)?;
+        *self.defaults.write().unwrap() = config.defaults;
+        Ok(())
+    }
+
+    /// Save the current aliases and default options to the configuration file, e.g. to persist aliases
+    /// registered at runtime. Requested with [`Request::SaveConfig`].
+    pub fn save_config(&self) -> crate::Result<()> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| crate::Error::argument(anyhow!("No config file given.")))?;
+        let config = Config {
+            aliases: self.aliases.list(),
+            defaults: self.defaults.read().unwrap().clone(),
+        };
+        config.save(path)
+    }
+
+    /// Keep the last `capacity` broadcasts, such that they can be queried with [`Request::ReplayRecent`].
+    pub async fn enable_replay_buffer(&mut self, capacity: usize) -> crate::Result<()> {
+        let history = Arc::new(History::new(capacity)?);
+        history.clone().record(&self.server).await;
+        self.history = Some(history);
+        Ok(())
+    }
+
+    /// Append all requests and responses to the file at `path`, see [`crate::request_log`]. The file is rotated
+    /// once it exceeds `max_size` bytes.
+    pub fn enable_request_log(&mut self, path: PathBuf, max_size: u64) -> crate::Result<()> {
+        self.request_log = Some(Arc::new(RequestLog::open(path, max_size)?));
+        Ok(())
+    }
+
+    /// Keep idle connections open instead of dropping them after the drop delay of their transport. A drop delay
+    /// given in the options of a request still applies.
+    pub fn disable_auto_drop(&self) {
+        self.inventories.update_defaults(|x| x.drop_delay = Some(NO_AUTO_DROP));
+    }
+
+    /// Allow CAN recordings to be written to and replayed from `dir`. Recording and replay are rejected unless
+    /// enabled, since the file names are chosen by the clients.
+    pub fn enable_can_recording(&self, dir: PathBuf) -> crate::Result<()> {
+        if !dir.is_dir() {
+            return Err(crate::Error::argument(anyhow!(
+                "Recording directory `{}` does not exist.",
+                dir.display()
+            )));
+        }
+        self.inventories.can.update_defaults(|x| x.recording_dir = Some(dir));
+        Ok(())
+    }
+
+    /// Periodically drop instruments which did not receive a request for longer than `ttl`, regardless of
+    /// the drop delay of their transport. This recovers resources held on behalf of crashed clients.
+    /// Locked instruments are never dropped. The sweep stops once a shutdown is requested.
+    pub fn enable_idle_sweep(&self, ttl: Duration) -> crate::Result<()> {
+        if ttl.is_zero() {
+            return Err(crate::Error::argument(anyhow!("Idle TTL must be larger than zero.")));
+        }
+        let inventories = self.inventories.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(ttl / 2);
+            while !*shutdown.borrow() {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = shutdown.changed() => continue,
+                }
+                let dropped = inventories.disconnect_idle(ttl).await;
+                if dropped > 0 {
+                    log::info!("Dropped {} instruments idle for longer than {:?}.", dropped, ttl);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Listen for WebSocket connections on `addr`. The connections are forwarded to the server by
+    /// [`crate::message_limit`], which enforces [`App::max_message_size`] and filters the notifications as
+    /// requested with [`Request::Subscribe`].
+    pub async fn listen_ws(&self, addr: &SocketAddr) -> crate::Result<()> {
+        let addr = self.forward_connections(addr, message_limit::Protocol::WebSocket).await?;
+        self.server
+            .listen_ws(&addr)
+            .await
+            .map(|_| ())
+            .map_err(|x| crate::Error::transport(anyhow!("Cannot listen on {}: {:?}", addr, x)))
+    }
+
+    /// Listen for HTTP connections on `addr`, enforcing [`App::max_message_size`] like [`App::listen_ws`].
+    pub async fn listen_http(&self, addr: &SocketAddr) -> crate::Result<()> {
+        let addr = self.forward_connections(addr, message_limit::Protocol::Http).await?;
+        self.server.listen_http(&addr).await;
+        Ok(())
+    }
+
+    /// Returns the address on which the server listens for the connections accepted on `addr`. WebSocket
+    /// connections are always forwarded, HTTP connections if a message size limit is set or if `addr` is `::`,
+    /// which is bound dual-stack.
+    async fn forward_connections(
+        &self,
+        addr: &SocketAddr,
+        protocol: message_limit::Protocol,
+    ) -> crate::Result<SocketAddr> {
+        let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified();
+        if self.max_message_size.is_none() && !dual_stack && protocol == message_limit::Protocol::Http {
+            return Ok(*addr);
+        }
+        let listener = message_limit::bind(addr).map_err(crate::Error::transport)?;
+        let upstream = message_limit::loopback_addr().map_err(crate::Error::transport)?;
+        message_limit::forward(listener, upstream, protocol, self.max_message_size);
+        Ok(upstream)
+    }
+
+    /// Request a graceful shutdown. [`App::run`] stops accepting new requests, waits for
+    /// in-flight requests to complete and then shuts down the server.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
     /// Main actor - Listens to incoming messages on `rx` and spawns a new task for each
     /// incoming request.
+    ///
+    /// Once a shutdown is requested, in-flight requests are granted [`App::drain_timeout`] to
+    /// complete before they are aborted.
     pub async fn run(&self, mut rx: UnboundedReceiver<Requested<Request, Response>>) {
-        while let Some(msg) = rx.recv().await {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut in_flight = InFlight::default();
+        while !*shutdown.borrow() {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = shutdown.changed() => continue,
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                None => break,
+            };
             let (req, rep) = msg.split();
             let app = self.clone();
-            log::debug!("Incoming[{}]: {}", rep.request_id(), serde_json::to_string(&req).unwrap());
-            task::spawn(async move {
-                let response = app.handle(req).await.into();
+            // a correlation id chosen by the client replaces the id of the RPC layer and is always echoed back
+            let (request_id, req, echo) = match req {
+                Request::WithId { id, request } => (id.to_string(), *request, true),
+                req => (rep.request_id().to_string(), req, self.echo_requests),
+            };
+            log::debug!("Incoming[{}]: {}", request_id, serde_json::to_string(&req).unwrap());
+            if let Some(request_log) = &self.request_log {
+                request_log.request(&request_id, &req);
+            }
+            in_flight.spawn(async move {
+                let response = app.respond(request_id.clone(), req, echo).await;
                 log::debug!("Answering: {}", serde_json::to_string(&response).unwrap());
+                if let Some(request_log) = &app.request_log {
+                    request_log.response(&request_id, &response);
+                }
                 rep.answer(response);
             });
         }
+        drop(rx);
+        let (drained, aborted) = in_flight.drain(self.drain_timeout).await;
+        log::info!("Shutting down: {} in-flight requests drained, {} aborted.", drained, aborted);
+        let _ = self.drop_all().await;
+        self.modbus_servers.stop_all();
+        self.server.shutdown();
+    }
+
+    /// Handle `req` and wrap the response in [`Response::Echo`] if `echo` is set.
+    async fn respond(&self, request_id: String, req: Request, echo: bool) -> Response {
+        if !echo {
+            return self.handle(req).await.into();
+        }
+        let summary = request_summary(&req);
+        let response = self.handle(req).await.into();
+        Response::Echo {
+            request_id,
+            summary,
+            response: Box::new(response),
+        }
     }
 
     /// Handle an incoming request
     async fn handle(&self, req: Request) -> crate::Result<Response> {
+        let mut req = self.aliases.resolve(req)?;
+        serial::usb::resolve_request(&mut req).await?;
+        self.defaults.read().unwrap().apply(&mut req);
+        let truncate = match &req {
+            Request::Bytes { truncate, .. } | Request::Scpi { truncate, .. } | Request::Sigrok { truncate, .. } => {
+                *truncate
+            }
+            _ => false,
+        };
+        if let Some(max_size) = self.max_response_size {
+            limit::limit_request(&mut req, max_size);
+        }
+        let mut response = match coalesce::key(&req).filter(|_| self.coalesce_reads) {
+            Some(key) => {
+                let app = self.clone();
+                self.coalescer.run(key, async move { app.dispatch(req).await }).await?
+            }
+            None => self.dispatch(req).await?,
+        };
+        let truncated = match self.max_response_size {
+            Some(max_size) => limit::limit_response(&mut response, max_size, truncate)?,
+            None => false,
+        };
+        if !truncate {
+            return Ok(response);
+        }
+        Ok(Response::Limited {
+            truncated,
+            response: Box::new(response),
+        })
+    }
+
+    /// Check `req` as [`App::handle`] would, but without performing any IO
+    fn validate(&self, req: Request) -> crate::Result<()> {
+        let mut req = self.aliases.resolve(req)?;
+        self.defaults.read().unwrap().apply(&mut req);
+        if let Request::Can { instrument, .. } = &mut req {
+            can::apply_default_bitrate(instrument, self.default_can_bitrate)?;
+        }
+        validate::validate(&req)
+    }
+
+    /// List the supported requests and the optional features enabled in this build and configuration
+    fn capabilities(&self) -> Response {
+        let mut features = Vec::new();
+        if cfg!(target_os = "linux") {
+            features.extend(["SocketCan", "SerialLowLatency"]);
+        }
+        if cfg!(target_os = "windows") {
+            features.push("Pcan");
+        }
+        if self.history.is_some() {
+            features.push("ReplayBuffer");
+        }
+        if self.max_response_size.is_some() {
+            features.push("ResponseSizeLimit");
+        }
+        if self.echo_requests {
+            features.push("EchoRequests");
+        }
+        if self.coalesce_reads {
+            features.push("CoalesceReads");
+        }
+        Response::Capabilities {
+            api_version: comsrv_protocol::API_VERSION,
+            supported_requests: SUPPORTED_REQUESTS.iter().map(|x| x.to_string()).collect(),
+            features: features.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    async fn dispatch(&self, req: Request) -> crate::Result<Response> {
         match req {
             Request::Bytes {
                 instrument: ByteStreamInstrument::Ftdi(instrument),
                 request,
                 lock,
+                ..
             } => self.handle_bytestream_ftdi(instrument, request, lock).await,
             Request::Bytes {
                 instrument: ByteStreamInstrument::Serial(instr),
                 request,
                 lock,
+                ..
             } => self.handle_bytestream_serial(instr, request, lock).await,
             Request::Bytes {
                 instrument: ByteStreamInstrument::Tcp(instr),
                 request,
                 lock,
+                ..
             } => self.handle_bytestream_tcp(instr, request, lock).await,
+            Request::Bytes {
+                instrument: ByteStreamInstrument::Custom(addr),
+                request,
+                lock,
+                ..
+            } => self.handle_bytestream_custom(addr, request, lock).await,
+            Request::Bytes {
+                instrument: ByteStreamInstrument::Loopback(addr),
+                request,
+                lock,
+                ..
+            } => self.handle_bytestream_loopback(addr, request, lock).await,
             Request::CobsStream {
                 instrument: ByteStreamInstrument::Serial(instr),
                 request,
@@ -115,6 +471,12 @@ impl App {
                 request,
                 lock,
             } => self.handle_cobs_ftdi(instr, request, lock).await,
+            Request::CobsStream {
+                instrument: ByteStreamInstrument::Custom(_) | ByteStreamInstrument::Loopback(_),
+                ..
+            } => Err(crate::Error::argument(anyhow!(
+                "COBS streams are not supported on custom or loopback instruments."
+            ))),
             Request::Can {
                 instrument,
                 request,
@@ -125,12 +487,14 @@ impl App {
                 request,
                 lock,
                 timeout,
+                ..
             } => self.handle_visa(instr, request, lock, timeout).await,
             Request::Scpi {
                 instrument: ScpiInstrument::Vxi(instr),
                 request,
                 lock,
                 timeout,
+                ..
             } => self.handle_vxi(instr, request, lock, timeout).await,
             Request::Prologix {
                 instrument,
@@ -138,22 +502,63 @@ impl App {
                 lock,
                 timeout,
             } => self.handle_prologix(instrument, request, lock, timeout).await,
-            Request::Sigrok { instrument, request } => {
-                sigrok::read(&instrument.address, request).await.map(Response::Sigrok)
-            }
+            Request::PrologixAdapter {
+                instrument,
+                request,
+                lock,
+            } => self.handle_prologix_adapter(instrument, request, lock).await,
+            Request::Sigrok {
+                instrument, request, ..
+            } => sigrok::read(&instrument.address, request).await.map(Response::Sigrok),
             Request::Hid {
                 instrument,
                 request,
                 lock,
             } => self.handle_hid(instrument, request, lock).await,
             Request::ListSigrokDevices => sigrok::list().await.map(Response::Sigrok),
+            Request::SigrokDeviceInfo { addr } => sigrok::device_info(&addr).await.map(Response::Sigrok),
             Request::ListConnectedInstruments => self.list_connected_instruments(),
             Request::Lock { addr, timeout } => self.lock(addr, timeout).await,
             Request::Unlock { addr, id } => self.unlock(addr, id).await,
             Request::DropAll => self.drop_all().await,
+            Request::SetInstrumentOptions { addr, options, lock } => {
+                self.set_instrument_options(addr, options, lock).await
+            }
+            Request::GetInstrumentOptions { addr } => self.get_instrument_options(addr).await,
+            Request::SetAlias { alias, addr } => self.aliases.set(alias, addr).map(|_| Response::Done),
+            Request::ListAliases => Ok(Response::Aliases(self.aliases.list())),
+            Request::DescribeInstrument { addr } => Ok(describe_instrument(&addr)),
+            Request::ReplayRecent { count } => match &self.history {
+                Some(history) => Ok(Response::RecentBroadcasts(history.recent(count))),
+                None => Err(crate::Error::argument(anyhow!("Replay buffer is not enabled."))),
+            },
+            Request::ReloadConfig => self.reload_config().map(|_| Response::Done),
+            Request::SaveConfig => self.save_config().map(|_| Response::Done),
+            // the filter is installed by the connection forwarding the reply, see `crate::subscription`
+            Request::Subscribe { filter } => Ok(Response::Subscribed { filter }),
+            Request::Validate(inner) => self.validate(*inner).map(|_| Response::Done),
+            Request::WithId { .. } => Err(crate::Error::argument(anyhow!(
+                "A request with a correlation id must not be wrapped in another request."
+            ))),
+            Request::Capabilities => Ok(self.capabilities()),
+            Request::GctEncode(msg) => gct::encode(msg).map(Response::CanMessages),
+            Request::GctDecode(msgs) => {
+                let mut decoder = gct::Decoder::new();
+                let msgs = msgs.into_iter().filter_map(|x| decoder.decode(x)).collect();
+                Ok(Response::GctMessages(msgs))
+            }
+            Request::StartModbusServer { bind, register_map } => {
+                let addr = self.modbus_servers.start(&bind, register_map).await?;
+                Ok(Response::ModbusServer { addr })
+            }
+            Request::StopModbusServer { addr } => self.modbus_servers.stop(&addr).map(|_| Response::Done),
+            Request::Ping { addr, timeout } => self.ping(addr, timeout.into()).await,
+            Request::SelfTest => {
+                let (passed, details) = selftest::run().await;
+                Ok(Response::SelfTest { passed, details })
+            }
             Request::Shutdown => {
-                let _ = self.drop_all();
-                self.server.shutdown();
+                self.shutdown();
                 Ok(Response::Done)
             }
             Request::ListHidDevices => hid::list_devices().await.map(|x| Response::Hid(HidResponse::List(x))),
@@ -167,6 +572,9 @@ impl App {
                 })
             }
             Request::ListSerialPorts => serial::list_devices().await.map(Response::SerialPorts),
+            Request::ListSerialPortsDetailed => {
+                serial::list_devices_detailed().await.map(Response::SerialPortsDetailed)
+            }
             Request::ListFtdiDevices => ftdi::list_ftdi().await.map(Response::FtdiDevices),
             Request::ListCanDevices => can::list_can_devices().await.map(Response::CanDevices),
             Request::Drop { addr, id } => self.drop(addr, id.as_ref()).await,
@@ -175,6 +583,14 @@ impl App {
                 request,
                 lock,
             } => self.handle_serial_request(instrument, request, lock).await,
+            Request::Loopback {
+                instrument,
+                request: LoopbackRequest::Script(exchanges),
+            } => {
+                let exchanges = exchanges.into_iter().map(|x| (x.request, x.response)).collect();
+                loopback::script_loopback(&instrument.name, exchanges);
+                Ok(Response::Done)
+            }
         }
     }
 
@@ -192,6 +608,7 @@ impl App {
             .request(crate::transport::serial::Request::Serial {
                 params: instrument.port_config.try_into()?,
                 req: request,
+                options: instrument.options,
             })
             .await?;
         match response {
@@ -218,6 +635,40 @@ impl App {
             .map(Response::Bytes)
     }
 
+    async fn handle_bytestream_custom(
+        &self,
+        addr: CustomAddress,
+        req: ByteStreamRequest,
+        lock: Option<Uuid>,
+    ) -> crate::Result<Response> {
+        let transport = self.transports.get(&addr.kind)?;
+        self.inventories
+            .custom
+            .wait_connect(&self.server, &addr, lock.as_ref())
+            .await?
+            .request(custom::Request {
+                transport,
+                request: req,
+            })
+            .await
+            .map(Response::Bytes)
+    }
+
+    async fn handle_bytestream_loopback(
+        &self,
+        addr: LoopbackAddress,
+        req: ByteStreamRequest,
+        lock: Option<Uuid>,
+    ) -> crate::Result<Response> {
+        self.inventories
+            .loopback
+            .wait_connect(&self.server, &addr, lock.as_ref())
+            .await?
+            .request(req)
+            .await
+            .map(Response::Bytes)
+    }
+
     async fn handle_bytestream_tcp(
         &self,
         instr: TcpInstrument,
@@ -254,6 +705,7 @@ impl App {
             .request(serial::Request::Bytes {
                 params: instr.port_config.try_into()?,
                 req,
+                options: instr.options,
             })
             .await?;
         match ret {
@@ -262,7 +714,13 @@ impl App {
         }
     }
 
-    async fn handle_can(&self, instr: CanInstrument, req: CanRequest, lock: Option<Uuid>) -> crate::Result<Response> {
+    async fn handle_can(
+        &self,
+        mut instr: CanInstrument,
+        req: CanRequest,
+        lock: Option<Uuid>,
+    ) -> crate::Result<Response> {
+        can::apply_default_bitrate(&mut instr, self.default_can_bitrate)?;
         let addr: CanAddress = instr.clone().into();
         self.inventories
             .can
@@ -332,6 +790,25 @@ impl App {
         }
     }
 
+    async fn handle_prologix_adapter(
+        &self,
+        instr: PrologixInstrument,
+        req: PrologixAdapterRequest,
+        lock: Option<Uuid>,
+    ) -> crate::Result<Response> {
+        let ret = self
+            .inventories
+            .serial
+            .wait_connect(&self.server, &instr.address, lock.as_ref())
+            .await?
+            .request(serial::Request::PrologixAdapter(req))
+            .await?;
+        match ret {
+            serial::Response::PrologixAdapter(x) => Ok(Response::PrologixAdapter(x)),
+            _ => Err(invalid_response_for_request()),
+        }
+    }
+
     async fn handle_hid(
         &self,
         instrument: comsrv_protocol::HidInstrument,
@@ -363,6 +840,12 @@ impl App {
             comsrv_protocol::Address::Vxi(x) => self.inventories.vxi.wait_and_lock(&self.server, &x, timeout).await,
             comsrv_protocol::Address::Visa(x) => self.inventories.visa.wait_and_lock(&self.server, &x, timeout).await,
             comsrv_protocol::Address::Can(x) => self.inventories.can.wait_and_lock(&self.server, &x, timeout).await,
+            comsrv_protocol::Address::Custom(x) => {
+                self.inventories.custom.wait_and_lock(&self.server, &x, timeout).await
+            }
+            comsrv_protocol::Address::Loopback(x) => {
+                self.inventories.loopback.wait_and_lock(&self.server, &x, timeout).await
+            }
         }?;
         Ok(Response::Locked { lock_id })
     }
@@ -376,10 +859,138 @@ impl App {
             comsrv_protocol::Address::Vxi(_) => self.inventories.vxi.unlock(id).await,
             comsrv_protocol::Address::Visa(_) => self.inventories.visa.unlock(id).await,
             comsrv_protocol::Address::Can(_) => self.inventories.can.unlock(id).await,
+            comsrv_protocol::Address::Custom(_) => self.inventories.custom.unlock(id).await,
+            comsrv_protocol::Address::Loopback(_) => self.inventories.loopback.unlock(id).await,
+        }
+        Ok(Response::Done)
+    }
+
+    async fn set_instrument_options(
+        &self,
+        addr: Address,
+        options: InstrumentOptions,
+        lock: Option<Uuid>,
+    ) -> crate::Result<Response> {
+        match (addr, options) {
+            (Address::Tcp(addr), InstrumentOptions::Tcp(options)) => {
+                self.inventories
+                    .tcp
+                    .wait_connect(&self.server, &addr, lock.as_ref())
+                    .await?
+                    .request(TcpRequest::SetOptions(options))
+                    .await?;
+            }
+            (Address::Visa(addr), InstrumentOptions::Visa(options)) => {
+                self.inventories
+                    .visa
+                    .wait_connect(&self.server, &addr, lock.as_ref())
+                    .await?
+                    .set_options(options);
+            }
+            (Address::Vxi(addr), InstrumentOptions::Vxi(options)) => {
+                self.inventories
+                    .vxi
+                    .wait_connect(&self.server, &addr, lock.as_ref())
+                    .await?
+                    .set_options(options)
+                    .await?;
+            }
+            (addr, options) => {
+                return Err(crate::Error::argument(anyhow!(
+                    "Options {:?} are not supported for instrument {:?}",
+                    options,
+                    addr
+                )))
+            }
         }
         Ok(Response::Done)
     }
 
+    async fn get_instrument_options(&self, addr: Address) -> crate::Result<Response> {
+        let not_connected =
+            |addr: &dyn std::fmt::Debug| crate::Error::argument(anyhow!("No instrument connected at {:?}", addr));
+        let options = match &addr {
+            Address::Tcp(x) => {
+                let mut instr = self.inventories.tcp.get(x).ok_or_else(|| not_connected(x))?;
+                match instr.request(TcpRequest::GetOptions).await? {
+                    tcp::TcpResponse::Options(x) => serde_json::to_value(x).unwrap(),
+                    _ => return Err(invalid_response_for_request()),
+                }
+            }
+            Address::Serial(x) => {
+                let mut instr = self.inventories.serial.get(x).ok_or_else(|| not_connected(x))?;
+                match instr.request(serial::Request::GetOptions).await? {
+                    serial::Response::Options(x) => x,
+                    _ => return Err(invalid_response_for_request()),
+                }
+            }
+            Address::Vxi(x) => {
+                let mut instr = self.inventories.vxi.get(x).ok_or_else(|| not_connected(x))?;
+                instr.options().await?
+            }
+            Address::Visa(x) => {
+                let instr = self.inventories.visa.get(x).ok_or_else(|| not_connected(x))?;
+                serde_json::to_value(instr.options()).unwrap()
+            }
+            addr => {
+                return Err(crate::Error::argument(anyhow!(
+                    "Options cannot be queried for instrument {:?}",
+                    addr
+                )))
+            }
+        };
+        Ok(Response::InstrumentOptions(options))
+    }
+
+    /// Check whether the instrument at `addr` is reachable. Only fails if the address is invalid.
+    async fn ping(&self, addr: Address, timeout: Duration) -> crate::Result<Response> {
+        fn present(found: bool, addr: &Address) -> crate::Result<()> {
+            if found {
+                Ok(())
+            } else {
+                Err(crate::Error::transport(anyhow!("Instrument not found: {:?}", addr)))
+            }
+        }
+
+        let start = Instant::now();
+        let probe = async {
+            match &addr {
+                Address::Tcp(x) => tcp::probe(x).await,
+                Address::Vxi(x) => vxi::probe(x).await,
+                // reuse the session of a connected instrument, since VISA may not allow a second one
+                Address::Visa(x) => match self.inventories.visa.get(x) {
+                    Some(instr) => instr.probe().await,
+                    None => visa::probe(x.clone()).await,
+                },
+                Address::Serial(x) => serial::probe(&x.port).await,
+                Address::Ftdi(x) => {
+                    let devices = ftdi::list_ftdi().await?;
+                    present(devices.iter().any(|y| y.serial_number == x.port), &addr)
+                }
+                Address::Hid(x) => {
+                    let devices = hid::list_devices().await?;
+                    present(devices.iter().any(|y| x.matches(&y.idn)), &addr)
+                }
+                Address::Can(x) => can::probe(x).await,
+                Address::Custom(x) => self.transports.get(&x.kind)?.connect(&x.address).await.map(|_| ()),
+                Address::Loopback(_) => Ok(()),
+            }
+        };
+        let reachable = match timeout_at(start + timeout, probe).await {
+            Ok(Ok(())) => true,
+            Ok(Err(err @ crate::Error::Argument(_))) => return Err(err),
+            Ok(Err(err)) => {
+                log::debug!("Ping {:?} failed: {}", addr, err);
+                false
+            }
+            Err(_) => false,
+        };
+        Ok(Response::Pong {
+            reachable,
+            latency_ms: reachable.then(|| start.elapsed().as_millis() as u64),
+        })
+    }
+
     async fn drop_all(&self) -> crate::Result<Response> {
         self.inventories.tcp.disconnect_all().await;
         self.inventories.vxi.disconnect_all().await;
@@ -388,6 +999,8 @@ impl App {
         self.inventories.visa.disconnect_all().await;
         self.inventories.can.disconnect_all().await;
         self.inventories.ftdi.disconnect_all().await;
+        self.inventories.custom.disconnect_all().await;
+        self.inventories.loopback.disconnect_all().await;
         Ok(Response::Done)
     }
 
@@ -399,6 +1012,8 @@ impl App {
         ret.extend(self.inventories.hid.list().drain(..).map(Address::Hid));
         ret.extend(self.inventories.serial.list().drain(..).map(Address::Serial));
         ret.extend(self.inventories.visa.list().drain(..).map(Address::Visa));
+        ret.extend(self.inventories.custom.list().drain(..).map(Address::Custom));
+        ret.extend(self.inventories.loopback.list().drain(..).map(Address::Loopback));
         Ok(Response::Instruments(ret))
     }
 
@@ -411,6 +1026,8 @@ impl App {
             Address::Vxi(x) => self.inventories.vxi.wait_disconnect(&x, id).await,
             Address::Visa(x) => self.inventories.visa.wait_disconnect(&x, id).await,
             Address::Can(x) => self.inventories.can.wait_disconnect(&x, id).await,
+            Address::Custom(x) => self.inventories.custom.wait_disconnect(&x, id).await,
+            Address::Loopback(x) => self.inventories.loopback.wait_disconnect(&x, id).await,
         }
         Ok(Response::Done)
     }
@@ -429,6 +1046,7 @@ impl App {
             .request(serial::Request::Cobs {
                 params: instr.port_config.try_into()?,
                 req,
+                options: instr.options,
             })
             .await?;
         match ret {
@@ -469,6 +1087,1224 @@ impl App {
     }
 }
 
+/// Names of the variants of [`Request`] handled by [`App::dispatch`]
+const SUPPORTED_REQUESTS: &[&str] = &[
+    "Bytes",
+    "CobsStream",
+    "Can",
+    "Scpi",
+    "Prologix",
+    "PrologixAdapter",
+    "Sigrok",
+    "Hid",
+    "Serial",
+    "Loopback",
+    "ListSigrokDevices",
+    "SigrokDeviceInfo",
+    "ListSerialPorts",
+    "ListSerialPortsDetailed",
+    "ListHidDevices",
+    "ListFtdiDevices",
+    "ListCanDevices",
+    "ListConnectedInstruments",
+    "Lock",
+    "Unlock",
+    "Drop",
+    "DropAll",
+    "SetInstrumentOptions",
+    "GetInstrumentOptions",
+    "SetAlias",
+    "ListAliases",
+    "DescribeInstrument",
+    "ReplayRecent",
+    "Subscribe",
+    "ReloadConfig",
+    "SaveConfig",
+    "SelfTest",
+    "Validate",
+    "WithId",
+    "Ping",
+    "StartModbusServer",
+    "StopModbusServer",
+    "GctEncode",
+    "GctDecode",
+    "Capabilities",
+    "Version",
+    "Shutdown",
+];
+
+/// Describe the instrument at `addr` by the requests which may address it. Requests which apply to
+/// all instruments, such as `Lock` or `Drop`, are omitted.
+fn describe_instrument(addr: &Address) -> Response {
+    let (kind, supported_requests): (_, &[_]) = match addr {
+        Address::Tcp(_) => ("Tcp", &["Bytes", "CobsStream", "SetInstrumentOptions", "GetInstrumentOptions"]),
+        Address::Ftdi(_) => ("Ftdi", &["Bytes"]),
+        Address::Hid(_) => ("Hid", &["Hid"]),
+        Address::Serial(_) => (
+            "Serial",
+            &[
+                "Bytes",
+                "CobsStream",
+                "Serial",
+                "Prologix",
+                "PrologixAdapter",
+                "GetInstrumentOptions",
+            ],
+        ),
+        Address::Vxi(_) => ("Vxi", &["Scpi", "SetInstrumentOptions", "GetInstrumentOptions"]),
+        Address::Visa(_) => ("Visa", &["Scpi", "SetInstrumentOptions", "GetInstrumentOptions"]),
+        Address::Can(_) => ("Can", &["Can"]),
+        Address::Custom(_) => ("Custom", &["Bytes"]),
+        Address::Loopback(_) => ("Loopback", &["Bytes", "Loopback"]),
+    };
+    Response::InstrumentCapabilities {
+        kind: kind.to_string(),
+        supported_requests: supported_requests.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+/// Summarize a request by the names of its variant and its inner request, e.g. `Bytes::ReadLine`.
+fn request_summary(req: &Request) -> String {
+    fn variant_name(value: &serde_json::Value) -> Option<&str> {
+        match value {
+            serde_json::Value::String(x) => Some(x),
+            serde_json::Value::Object(x) if x.len() == 1 => x.keys().next().map(|x| x.as_str()),
+            _ => None,
+        }
+    }
+    let value = serde_json::to_value(req).unwrap_or_default();
+    let name = variant_name(&value).unwrap_or_default().to_string();
+    let inner = value.get(&name).and_then(|x| x.get("request")).and_then(variant_name);
+    match inner {
+        Some(inner) => format!("{}::{}", name, inner),
+        None => name,
+    }
+}
+
 fn invalid_response_for_request() -> crate::Error {
     crate::Error::internal(anyhow!("Invalid response for request."))
 }
+
+/// Tracks the request handlers spawned by [`App::run`].
+#[derive(Default)]
+struct InFlight {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl InFlight {
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, fut: F) {
+        self.tasks.retain(|x| !x.is_finished());
+        self.tasks.push(task::spawn(fut));
+    }
+
+    /// Wait up to `timeout` for all outstanding tasks to complete and abort the remaining ones.
+    ///
+    /// Returns the number of drained and aborted tasks.
+    async fn drain(self, timeout: Duration) -> (usize, usize) {
+        let deadline = Instant::now() + timeout;
+        let mut drained = 0;
+        let mut aborted = 0;
+        for task in self.tasks {
+            if task.is_finished() {
+                continue;
+            }
+            let abort = task.abort_handle();
+            match timeout_at(deadline, task).await {
+                Ok(_) => drained += 1,
+                Err(_) => {
+                    abort.abort();
+                    aborted += 1;
+                }
+            }
+        }
+        (drained, aborted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn reject_oversized_message() {
+        let (mut app, mut rx) = App::new();
+        app.max_message_size = Some(1024);
+        let addr = message_limit::loopback_addr().unwrap();
+        app.listen_ws(&addr).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 101"));
+
+        // masked text frame with 2048 bytes of payload
+        let mut frame = vec![0x81, 0x80 | 126, 0x08, 0x00, 1, 2, 3, 4];
+        frame.extend(vec![b' '; 2048]);
+        let _ = stream.write_all(&frame).await;
+        let n = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn listen_dual_stack() {
+        let (app, _rx) = App::new();
+        let port = std::net::TcpListener::bind("[::]:0").unwrap().local_addr().unwrap().port();
+        let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        app.listen_ws(&addr).await.unwrap();
+
+        for ip in [IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)] {
+            let mut stream = TcpStream::connect((ip, port)).await.unwrap();
+            stream
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"HTTP/1.1 101"));
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_recent_broadcasts() {
+        let (mut app, _rx) = App::new();
+        let req = Request::ReplayRecent { count: 10 };
+        assert!(matches!(app.handle(req).await, Err(crate::Error::Argument(_))));
+
+        app.enable_replay_buffer(10).await.unwrap();
+        app.server.broadcast(Response::Can {
+            source: CanAddress::Loopback,
+            response: comsrv_protocol::CanResponse::Started,
+        });
+        // the broadcast is recorded asynchronously
+        let mut recent = Vec::new();
+        for _ in 0..100 {
+            match app.handle(Request::ReplayRecent { count: 10 }).await.unwrap() {
+                Response::RecentBroadcasts(x) if !x.is_empty() => {
+                    recent = x;
+                    break;
+                }
+                Response::RecentBroadcasts(_) => sleep(Duration::from_millis(10)).await,
+                _ => panic!(),
+            }
+        }
+        assert_eq!(recent.len(), 1);
+        assert!(matches!(
+            &recent[0],
+            Response::Can {
+                source: CanAddress::Loopback,
+                response: comsrv_protocol::CanResponse::Started,
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn echo_requests() {
+        let (mut app, _rx) = App::new();
+        match app.respond("1".to_string(), Request::ListAliases, app.echo_requests).await {
+            Response::Aliases(_) => {}
+            x => panic!("Unexpected response: {:?}", x),
+        }
+
+        app.echo_requests = true;
+        match app.respond("1".to_string(), Request::ListAliases, app.echo_requests).await {
+            Response::Echo {
+                request_id,
+                summary,
+                response,
+            } => {
+                assert_eq!(request_id, "1");
+                assert_eq!(summary, "ListAliases");
+                assert!(matches!(*response, Response::Aliases(_)));
+            }
+            x => panic!("Unexpected response: {:?}", x),
+        }
+
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: comsrv_protocol::TcpAddress {
+                    host: "alias::unknown".to_string(),
+                    port: 5000,
+                },
+                options: None,
+            }),
+            request: ByteStreamRequest::ReadAll,
+            lock: None,
+            truncate: false,
+        };
+        match app.respond("2".to_string(), req, app.echo_requests).await {
+            Response::Echo { summary, response, .. } => {
+                assert_eq!(summary, "Bytes::ReadAll");
+                assert!(matches!(*response, Response::Error(_)));
+            }
+            x => panic!("Unexpected response: {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn request_with_id() {
+        use comsrv_client::ws::WsRpc;
+        use comsrv_client::Rpc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("requests.log");
+        let (mut app, rx) = App::new();
+        app.enable_request_log(log.clone(), 1 << 20).unwrap();
+        let addr = message_limit::loopback_addr().unwrap();
+        app.listen_ws(&addr).await.unwrap();
+        task::spawn({
+            let app = app.clone();
+            async move { app.run(rx).await }
+        });
+
+        let url = url::Url::parse(&format!("ws://{}", addr)).unwrap();
+        let mut rpc = WsRpc::connect(url, Duration::from_secs(1)).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        // fails unless the response echoes the id
+        let (response, first) = rpc.request_with_id(Request::ListAliases, timeout).await.unwrap();
+        assert!(matches!(response, Response::Aliases(_)));
+        let (_, second) = rpc.request_with_id(Request::ListAliases, timeout).await.unwrap();
+        assert_ne!(first, second);
+        let nested = Request::WithId {
+            id: Uuid::new_v4(),
+            request: Box::new(Request::ListAliases),
+        };
+        let (response, _) = rpc.request_with_id(nested, timeout).await.unwrap();
+        assert!(matches!(response, Response::Error(_)));
+
+        // the request and its response are logged under the id chosen by the client
+        let count = |id: Uuid| {
+            let entries = std::fs::read_to_string(&log).unwrap_or_default();
+            entries
+                .lines()
+                .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+                .filter(|x| x["request_id"] == id.to_string())
+                .count()
+        };
+        for _ in 0..100 {
+            if count(first) == 2 && count(second) == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(count(first), 2);
+        assert_eq!(count(second), 2);
+    }
+
+    #[tokio::test]
+    async fn subscribe_notifications() {
+        use comsrv_client::ws::WsRpc;
+        use comsrv_protocol::cobs_stream::CobsStreamResponse;
+        use comsrv_protocol::{NotificationFilter, NotificationKind};
+
+        let (app, rx) = App::new();
+        let addr = message_limit::loopback_addr().unwrap();
+        app.listen_ws(&addr).await.unwrap();
+        task::spawn({
+            let app = app.clone();
+            async move { app.run(rx).await }
+        });
+
+        let url = url::Url::parse(&format!("ws://{}", addr)).unwrap();
+        let mut filtered = WsRpc::connect(url.clone(), Duration::from_secs(1)).await.unwrap();
+        let unfiltered = WsRpc::connect(url, Duration::from_secs(1)).await.unwrap();
+        let filter = NotificationFilter {
+            kinds: vec![NotificationKind::Can],
+            ..Default::default()
+        };
+        filtered.set_notification_filter(filter).await.unwrap();
+        let mut filtered = filtered.client.notifications();
+        let mut unfiltered = unfiltered.client.notifications();
+
+        app.server.broadcast(Response::CobsStream(CobsStreamResponse::Done));
+        app.server.broadcast(Response::Can {
+            source: CanAddress::Loopback,
+            response: comsrv_protocol::CanResponse::Started,
+        });
+        async fn next(rx: &mut UnboundedReceiver<Response>) -> Response {
+            tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap()
+        }
+
+        assert!(matches!(
+            next(&mut unfiltered).await,
+            Response::CobsStream(CobsStreamResponse::Done)
+        ));
+        assert!(matches!(next(&mut unfiltered).await, Response::Can { .. }));
+        // the COBS notification is not sent on the filtered connection
+        assert!(matches!(next(&mut filtered).await, Response::Can { .. }));
+    }
+
+    #[tokio::test]
+    async fn gct_encode_decode() {
+        use comsrv_protocol::GctMessage;
+
+        let (app, _rx) = App::new();
+        let data: Vec<u8> = (0..20).collect();
+        let ddp = GctMessage::Ddp {
+            src: 1,
+            dst: 2,
+            data: data.clone(),
+            version: 2,
+        };
+        let frames = match app.handle(Request::GctEncode(ddp)).await.unwrap() {
+            Response::CanMessages(x) => x,
+            x => panic!("Unexpected response: {:?}", x),
+        };
+        assert_eq!(frames.len(), 3);
+        match app.handle(Request::GctDecode(frames)).await.unwrap() {
+            Response::GctMessages(msgs) => match &msgs[..] {
+                [GctMessage::Ddp {
+                    src: 1,
+                    dst: 2,
+                    data: rx_data,
+                    ..
+                }] => assert_eq!(rx_data, &data),
+                x => panic!("Unexpected messages: {:?}", x),
+            },
+            x => panic!("Unexpected response: {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn gct_source_node_id() {
+        use comsrv_client::can::{CanBus, Message};
+        use comsrv_client::gctcan::{GctCanDevice, NodeId};
+        use comsrv_client::ws::WsRpc;
+        use comsrv_protocol::GctMessage;
+
+        let (app, rx) = App::new();
+        let addr = message_limit::loopback_addr().unwrap();
+        app.listen_ws(&addr).await.unwrap();
+        task::spawn({
+            let app = app.clone();
+            async move { app.run(rx).await }
+        });
+
+        let url = url::Url::parse(&format!("ws://{}", addr)).unwrap();
+        let rpc = WsRpc::connect(url, Duration::from_secs(1)).await.unwrap();
+        let mut bus = CanBus::new(CanInstrument::Loopback, rpc);
+        bus.connect().await.unwrap();
+        let destination = NodeId::new(0x6A).unwrap();
+        let mut sent = bus
+            .subscribe(move |x| match x {
+                Message::Gct(GctMessage::SysCtrl { src, dst, .. })
+                | Message::Gct(GctMessage::MonitoringRequest { src, dst, .. })
+                    if dst == u8::from(destination) =>
+                {
+                    Some(src)
+                }
+                _ => None,
+            })
+            .await;
+        async fn next_source(rx: &mut tokio::sync::mpsc::Receiver<u8>) -> Option<u8> {
+            tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap()
+        }
+
+        let mut device = GctCanDevice::new(bus, NodeId::new(0x01).unwrap());
+        device.sysctrl_write(0x12, destination, vec![1]).await.unwrap();
+        assert_eq!(next_source(&mut sent).await, Some(0x01));
+
+        let source = NodeId::new(0x05).unwrap();
+        device.sysctrl_write_from(source, 0x12, destination, vec![1]).await.unwrap();
+        assert_eq!(next_source(&mut sent).await, Some(0x05));
+
+        let source = NodeId::new(0x07).unwrap();
+        let values = device
+            .monitor_request_partial_from(source, destination, 2, &[0], Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(values.is_empty());
+        assert_eq!(next_source(&mut sent).await, Some(0x07));
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn capabilities() {
+        let (mut app, _rx) = App::new();
+        app.coalesce_reads = true;
+        match app.handle(Request::Capabilities).await.unwrap() {
+            Response::Capabilities {
+                api_version,
+                supported_requests,
+                features,
+            } => {
+                assert_eq!(api_version, comsrv_protocol::API_VERSION);
+                for name in ["Bytes", "Scpi", "Validate", "Capabilities", "Version"] {
+                    assert!(supported_requests.iter().any(|x| x == name), "{} is missing", name);
+                }
+                assert!(features.iter().any(|x| x == "CoalesceReads"));
+                assert!(!features.iter().any(|x| x == "ReplayBuffer"));
+            }
+            x => panic!("Unexpected response: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn supported_requests_are_variants() {
+        // unit variants deserialize from their name, all others fail with `invalid type` instead of `unknown variant`
+        for name in SUPPORTED_REQUESTS {
+            if let Err(err) = serde_json::from_str::<Request>(&format!("\"{}\"", name)) {
+                assert!(!err.to_string().contains("unknown variant"), "{}: {}", name, err);
+            }
+        }
+    }
+
+    #[test]
+    fn all_variants_are_supported() {
+        // serde lists all variants when rejecting an unknown one, e.g. "unknown variant `X`, expected one of `Bytes`, ..."
+        let err = serde_json::from_str::<Request>("\"NoSuchRequest\"").unwrap_err().to_string();
+        let (_, expected) = err.split_once("expected one of").unwrap();
+        let variants: Vec<_> = expected.split('`').skip(1).step_by(2).collect();
+        assert!(variants.contains(&"Bytes") && variants.contains(&"Shutdown"), "{}", err);
+        for variant in variants {
+            assert!(SUPPORTED_REQUESTS.contains(&variant), "{} is missing", variant);
+        }
+    }
+
+    #[tokio::test]
+    async fn instrument_capabilities() {
+        use comsrv_protocol::SerialAddress;
+
+        let (app, _rx) = App::new();
+        let req = Request::DescribeInstrument {
+            addr: Address::Can(CanAddress::Loopback),
+        };
+        match app.handle(req).await.unwrap() {
+            Response::InstrumentCapabilities {
+                kind,
+                supported_requests,
+            } => {
+                assert_eq!(kind, "Can");
+                assert_eq!(supported_requests, vec!["Can"]);
+            }
+            _ => panic!(),
+        }
+
+        let req = Request::DescribeInstrument {
+            addr: Address::Serial(SerialAddress {
+                port: "/dev/ttyUSB0".to_string(),
+            }),
+        };
+        match app.handle(req).await.unwrap() {
+            Response::InstrumentCapabilities {
+                kind,
+                supported_requests,
+            } => {
+                assert_eq!(kind, "Serial");
+                assert!(supported_requests.iter().any(|x| x == "Bytes"));
+                assert!(supported_requests.iter().any(|x| x == "Serial"));
+                assert!(!supported_requests.iter().any(|x| x == "Can"));
+            }
+            _ => panic!(),
+        }
+
+        let req = Request::DescribeInstrument {
+            addr: Address::Visa("TCPIP::192.168.1.10::INSTR".to_string()),
+        };
+        match app.handle(req).await.unwrap() {
+            Response::InstrumentCapabilities {
+                kind,
+                supported_requests,
+            } => {
+                assert_eq!(kind, "Visa");
+                assert_eq!(supported_requests, vec!["Scpi", "SetInstrumentOptions", "GetInstrumentOptions"]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn request_with_alias() {
+        use comsrv_protocol::TcpAddress;
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddress {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let (app, _rx) = App::new();
+        let req = Request::SetAlias {
+            alias: "myplc".to_string(),
+            addr: Address::Tcp(addr.clone()),
+        };
+        assert!(matches!(app.handle(req).await, Ok(Response::Done)));
+        match app.handle(Request::ListAliases).await.unwrap() {
+            Response::Aliases(x) => assert_eq!(x["myplc"], Address::Tcp(addr.clone())),
+            _ => panic!(),
+        }
+
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: TcpAddress {
+                    host: "alias::myplc".to_string(),
+                    port: 0,
+                },
+                options: None,
+            }),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        assert!(matches!(app.handle(req).await, Ok(Response::Bytes(_))));
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        match app.handle(Request::ListConnectedInstruments).await.unwrap() {
+            Response::Instruments(x) => assert_eq!(x, vec![Address::Tcp(addr)]),
+            _ => panic!(),
+        }
+
+        let req = Request::Drop {
+            addr: Address::Tcp(TcpAddress {
+                host: "alias::unknown".to_string(),
+                port: 0,
+            }),
+            id: None,
+        };
+        assert!(matches!(app.handle(req).await, Err(crate::Error::Argument(_))));
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        use crate::transport::custom::Stream;
+        use comsrv_protocol::ByteStreamResponse;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        /// Connects to an in-memory stream which echoes all data
+        struct Echo;
+
+        #[async_trait::async_trait]
+        impl Transport for Echo {
+            async fn connect(&self, _address: &str) -> crate::Result<Box<dyn Stream>> {
+                let (local, mut remote) = tokio::io::duplex(256);
+                tokio::spawn(async move {
+                    let mut buf = [0_u8; 256];
+                    while let Ok(n) = remote.read(&mut buf).await {
+                        if n == 0 || remote.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Ok(Box::new(local))
+            }
+        }
+
+        let (app, _rx) = App::new();
+        let addr = CustomAddress {
+            kind: "echo".to_string(),
+            address: "dev0".to_string(),
+        };
+        let query = |addr: &CustomAddress, lock| Request::Bytes {
+            instrument: ByteStreamInstrument::Custom(addr.clone()),
+            request: ByteStreamRequest::QueryLine {
+                line: "hello".to_string(),
+                timeout: Duration::from_secs(1).into(),
+                term: b'\n',
+                flush_input: true,
+            },
+            lock,
+            truncate: false,
+        };
+        assert!(matches!(app.handle(query(&addr, None)).await, Err(crate::Error::Argument(_))));
+
+        app.register_transport("echo", Arc::new(Echo)).unwrap();
+        assert!(app.register_transport("echo", Arc::new(Echo)).is_err());
+        match app.handle(query(&addr, None)).await.unwrap() {
+            Response::Bytes(ByteStreamResponse::String(x)) => assert_eq!(x, "hello"),
+            x => panic!("{:?}", x),
+        }
+        match app.handle(Request::ListConnectedInstruments).await.unwrap() {
+            Response::Instruments(x) => assert_eq!(x, vec![Address::Custom(addr.clone())]),
+            _ => panic!(),
+        }
+
+        let req = Request::Lock {
+            addr: Address::Custom(addr.clone()),
+            timeout: Duration::from_secs(1).into(),
+        };
+        let lock_id = match app.handle(req).await.unwrap() {
+            Response::Locked { lock_id } => lock_id,
+            _ => panic!(),
+        };
+        assert!(matches!(app.handle(query(&addr, Some(lock_id))).await, Ok(Response::Bytes(_))));
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limit_read_exact() {
+        use comsrv_protocol::ByteStreamResponse;
+
+        let (mut app, _rx) = App::new();
+        app.max_response_size = Some(4);
+        let addr = LoopbackAddress {
+            name: "app::limit_read_exact".to_string(),
+        };
+        let request = |request, truncate| Request::Bytes {
+            instrument: ByteStreamInstrument::Loopback(addr.clone()),
+            request,
+            lock: None,
+            truncate,
+        };
+        let read = ByteStreamRequest::ReadExact {
+            count: 8,
+            timeout: Duration::from_secs(1).into(),
+        };
+
+        app.handle(request(ByteStreamRequest::Write(vec![1; 8]), false)).await.unwrap();
+        let ret = app.handle(request(read.clone(), false)).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+
+        app.handle(request(ByteStreamRequest::Write(vec![1; 8]), false)).await.unwrap();
+        match app.handle(request(read, true)).await.unwrap() {
+            Response::Limited { truncated, response } => {
+                assert!(truncated);
+                assert!(matches!(&*response, Response::Bytes(ByteStreamResponse::Data(x)) if x.len() == 4));
+            }
+            x => panic!("{:?}", x),
+        }
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn loopback_query_line() {
+        use comsrv_protocol::{ByteStreamResponse, LoopbackExchange};
+
+        let (app, _rx) = App::new();
+        let addr = LoopbackAddress {
+            name: "app::loopback_query_line".to_string(),
+        };
+        let script = Request::Loopback {
+            instrument: addr.clone(),
+            request: LoopbackRequest::Script(vec![LoopbackExchange {
+                request: b"*IDN?\n".to_vec(),
+                response: b"Mock,1234\n".to_vec(),
+            }]),
+        };
+        assert!(matches!(app.handle(script).await, Ok(Response::Done)));
+        let query = |line: &str| Request::Bytes {
+            instrument: ByteStreamInstrument::Loopback(addr.clone()),
+            request: ByteStreamRequest::QueryLine {
+                line: line.to_string(),
+                timeout: Duration::from_secs(1).into(),
+                term: b'\n',
+                flush_input: true,
+            },
+            lock: None,
+            truncate: false,
+        };
+        match app.handle(query("*IDN?")).await.unwrap() {
+            Response::Bytes(ByteStreamResponse::String(x)) => assert_eq!(x, "Mock,1234"),
+            x => panic!("{:?}", x),
+        }
+        // the script is consumed, thus the device echoes
+        match app.handle(query("hello")).await.unwrap() {
+            Response::Bytes(ByteStreamResponse::String(x)) => assert_eq!(x, "hello"),
+            x => panic!("{:?}", x),
+        }
+        match app.handle(Request::ListConnectedInstruments).await.unwrap() {
+            Response::Instruments(x) => assert_eq!(x, vec![Address::Loopback(addr.clone())]),
+            _ => panic!(),
+        }
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ping_tcp() {
+        use comsrv_protocol::TcpAddress;
+
+        let (app, _rx) = App::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddress {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let ping = |addr: &TcpAddress| Request::Ping {
+            addr: Address::Tcp(addr.clone()),
+            timeout: Duration::from_secs(1).into(),
+        };
+        match app.handle(ping(&addr)).await.unwrap() {
+            Response::Pong { reachable, latency_ms } => assert!(reachable && latency_ms.is_some()),
+            x => panic!("{:?}", x),
+        }
+        match app.handle(Request::ListConnectedInstruments).await.unwrap() {
+            Response::Instruments(x) => assert!(x.is_empty()),
+            _ => panic!(),
+        }
+
+        drop(listener);
+        match app.handle(ping(&addr)).await.unwrap() {
+            Response::Pong { reachable, latency_ms } => assert!(!reachable && latency_ms.is_none()),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_flushes_buffered_writes() {
+        use comsrv_protocol::{TcpAddress, TcpOptions, WriteBuffering};
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (app, _rx) = App::new();
+        let request = |request| Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: TcpAddress {
+                    host: "127.0.0.1".to_string(),
+                    port: listener.local_addr().unwrap().port(),
+                },
+                options: Some(TcpOptions {
+                    auto_drop: None,
+                    connection_timeout: None,
+                    nodelay: None,
+                    keepalive: None,
+                    mode: None,
+                    default_term: None,
+                    write_buffering: Some(WriteBuffering {
+                        max_size: 1024,
+                        max_delay: Duration::from_secs(10).into(),
+                    }),
+                    min_interval: None,
+                    detect_term: None,
+                }),
+            }),
+            request,
+            lock: None,
+            truncate: false,
+        };
+
+        for flush in [true, false] {
+            let req = request(ByteStreamRequest::Write(b"hello".to_vec()));
+            assert!(matches!(app.handle(req).await, Ok(Response::Bytes(_))));
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let req = request(ByteStreamRequest::Close { flush });
+            assert!(matches!(app.handle(req).await, Ok(Response::Bytes(_))));
+            let mut buf = Vec::new();
+            socket.read_to_end(&mut buf).await.unwrap();
+            let expected: &[u8] = if flush { b"hello" } else { b"" };
+            assert_eq!(buf, expected);
+        }
+        // the instrument remains in the inventory
+        match app.handle(Request::ListConnectedInstruments).await.unwrap() {
+            Response::Instruments(x) => assert_eq!(x.len(), 1),
+            _ => panic!(),
+        }
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweep_idle_instruments() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (app, _rx) = App::new();
+        assert!(app.enable_idle_sweep(Duration::ZERO).is_err());
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument::new("127.0.0.1", port)),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        assert!(app.handle(req).await.is_ok());
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        app.enable_idle_sweep(Duration::from_millis(100)).unwrap();
+        // the default drop delay of TCP instruments is much longer, only the sweep closes the connection
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), socket.read_to_end(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 5);
+        assert!(matches!(app.list_connected_instruments(), Ok(Response::Instruments(x)) if x.is_empty()));
+        app.shutdown();
+    }
+
+    #[tokio::test]
+    async fn set_tcp_drop_delay() {
+        use comsrv_protocol::{TcpAddress, TcpOptions};
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddress {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let (app, _rx) = App::new();
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: addr.clone(),
+                options: None,
+            }),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        assert!(app.handle(req).await.is_ok());
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let req = Request::SetInstrumentOptions {
+            addr: Address::Tcp(addr),
+            options: InstrumentOptions::Tcp(TcpOptions {
+                auto_drop: Some(Duration::from_millis(100).into()),
+                connection_timeout: None,
+                nodelay: None,
+                keepalive: None,
+                mode: None,
+                default_term: None,
+                write_buffering: None,
+                min_interval: None,
+                detect_term: None,
+            }),
+            lock: None,
+        };
+        assert!(matches!(app.handle(req).await, Ok(Response::Done)));
+
+        // the default drop delay is much longer, the connection is only closed with the new option applied
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), socket.read_to_end(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 5);
+        assert_eq!(buf, b"hello");
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_tcp_options() {
+        use comsrv_protocol::{TcpAddress, TcpOptions};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddress {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let (app, _rx) = App::new();
+        let req = Request::GetInstrumentOptions {
+            addr: Address::Tcp(addr.clone()),
+        };
+        assert!(matches!(app.handle(req.clone()).await, Err(crate::Error::Argument(_))));
+
+        let options = TcpOptions {
+            auto_drop: Some(Duration::from_millis(1500).into()),
+            connection_timeout: None,
+            nodelay: None,
+            keepalive: None,
+            mode: None,
+            default_term: None,
+            write_buffering: None,
+            min_interval: None,
+            detect_term: None,
+        };
+        let set = Request::SetInstrumentOptions {
+            addr: Address::Tcp(addr),
+            options: InstrumentOptions::Tcp(options),
+            lock: None,
+        };
+        assert!(matches!(app.handle(set).await, Ok(Response::Done)));
+        let options = match app.handle(req).await {
+            Ok(Response::InstrumentOptions(x)) => serde_json::from_value::<TcpOptions>(x).unwrap(),
+            _ => panic!(),
+        };
+        assert_eq!(Duration::from(options.auto_drop.unwrap()), Duration::from_millis(1500));
+        assert_eq!(options.nodelay, Some(true));
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn disable_auto_drop() {
+        use comsrv_protocol::{TcpAddress, TcpOptions};
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TcpAddress {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let (app, _rx) = App::new();
+        app.disable_auto_drop();
+        let write = |options| Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: addr.clone(),
+                options,
+            }),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        assert!(app.handle(write(None)).await.is_ok());
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let req = Request::GetInstrumentOptions {
+            addr: Address::Tcp(addr.clone()),
+        };
+        let options = match app.handle(req).await {
+            Ok(Response::InstrumentOptions(x)) => serde_json::from_value::<TcpOptions>(x).unwrap(),
+            _ => panic!(),
+        };
+        assert_eq!(Duration::from(options.auto_drop.unwrap()), NO_AUTO_DROP);
+
+        // a drop delay given with the request still applies
+        let options = TcpOptions {
+            auto_drop: Some(Duration::from_millis(100).into()),
+            connection_timeout: None,
+            nodelay: None,
+            keepalive: None,
+            mode: None,
+            default_term: None,
+            write_buffering: None,
+            min_interval: None,
+            detect_term: None,
+        };
+        assert!(app.handle(write(Some(options))).await.is_ok());
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), socket.read_to_end(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 10);
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_transactional_mode() {
+        use comsrv_protocol::{InstrumentMode, TcpAddress, TcpOptions};
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (app, _rx) = App::new();
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Tcp(TcpInstrument {
+                address: TcpAddress {
+                    host: "127.0.0.1".to_string(),
+                    port: listener.local_addr().unwrap().port(),
+                },
+                options: Some(TcpOptions {
+                    auto_drop: None,
+                    connection_timeout: None,
+                    nodelay: None,
+                    keepalive: None,
+                    mode: Some(InstrumentMode::Transactional),
+                    default_term: None,
+                    write_buffering: None,
+                    min_interval: None,
+                    detect_term: None,
+                }),
+            }),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        for _ in 0..2 {
+            assert!(app.handle(req.clone()).await.is_ok());
+            // each request uses its own connection, which is closed once the request completes
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(1), socket.read_to_end(&mut buf)).await;
+            assert_eq!(read.unwrap().unwrap(), 5);
+            assert_eq!(buf, b"hello");
+        }
+        app.drop_all().await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn serial_transactional_mode() {
+        use comsrv_protocol::{InstrumentMode, SerialAddress, SerialInstrument, SerialOptions, SerialPortConfig};
+        use tokio::io::AsyncReadExt;
+        use tokio_serial::{SerialPort, SerialStream};
+
+        // the slave side of a pseudo terminal serves as serial port
+        let (mut master, slave) = SerialStream::pair().unwrap();
+        let port = slave.name().unwrap();
+        drop(slave);
+
+        let (app, _rx) = App::new();
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Serial(SerialInstrument {
+                address: SerialAddress { port },
+                port_config: SerialPortConfig {
+                    config: "8N1".to_string(),
+                    baudrate: 9600,
+                    hardware_flow_control: Default::default(),
+                },
+                options: Some(SerialOptions {
+                    auto_drop: None,
+                    mode: Some(InstrumentMode::Transactional),
+                    default_term: None,
+                    min_interval: None,
+                }),
+            }),
+            request: ByteStreamRequest::Write(b"hello".to_vec()),
+            lock: None,
+            truncate: false,
+        };
+        assert!(app.handle(req).await.is_ok());
+
+        // once the port is closed, reading from the master returns the written data followed by an error
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), master.read_to_end(&mut buf)).await;
+        assert!(read.unwrap().is_err());
+        assert_eq!(buf, b"hello");
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cobs_stream_status() {
+        use comsrv_protocol::cobs_stream::CobsStreamResponse;
+        use comsrv_protocol::TcpAddress;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instrument = ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port: listener.local_addr().unwrap().port(),
+            },
+            options: None,
+        });
+        let (app, _rx) = App::new();
+        let request = |request| Request::CobsStream {
+            instrument: instrument.clone(),
+            request,
+            lock: None,
+        };
+        let status = || async {
+            match app.handle(request(CobsStreamRequest::Status)).await {
+                Ok(Response::CobsStream(CobsStreamResponse::Status { alive, use_crc, .. })) => (alive, use_crc),
+                _ => panic!(),
+            }
+        };
+
+        assert_eq!(status().await, (false, false));
+        app.handle(request(CobsStreamRequest::Start {
+            use_crc: false,
+            crc: None,
+        }))
+        .await
+        .unwrap();
+        let _socket = listener.accept().await.unwrap();
+        assert_eq!(status().await, (true, false));
+        app.handle(request(CobsStreamRequest::Stop)).await.unwrap();
+        assert_eq!(status().await, (false, false));
+        app.drop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_config() {
+        use comsrv_protocol::{SerialAddress, SerialInstrument, SerialPortConfig};
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let config = r#"{
+            "aliases": {
+                "myplc": { "Serial": { "port": "/dev/ttyUSB0" } },
+                "other": { "Serial": { "port": "alias::myplc" } }
+            },
+            "defaults": {
+                "serial": { "auto_drop": { "micros": 0, "seconds": 10 } },
+                "scpi_timeout": { "micros": 0, "seconds": 5 }
+            }
+        }"#;
+        file.write_all(config.as_bytes()).unwrap();
+
+        let (mut app, _rx) = App::new();
+        app.load_config(file.path().to_path_buf()).unwrap();
+        let aliases = app.aliases.list();
+        let addr = Address::Serial(SerialAddress {
+            port: "/dev/ttyUSB0".to_string(),
+        });
+        assert_eq!(aliases["myplc"], addr);
+        assert_eq!(aliases["other"], addr);
+
+        let req = Request::Bytes {
+            instrument: ByteStreamInstrument::Serial(SerialInstrument {
+                address: SerialAddress {
+                    port: "alias::myplc".to_string(),
+                },
+                port_config: SerialPortConfig {
+                    config: "8N1".to_string(),
+                    baudrate: 115200,
+                    hardware_flow_control: Default::default(),
+                },
+                options: None,
+            }),
+            request: ByteStreamRequest::Disconnect,
+            lock: None,
+            truncate: false,
+        };
+        let mut req = app.aliases.resolve(req).unwrap();
+        app.defaults.read().unwrap().apply(&mut req);
+        match req {
+            Request::Bytes {
+                instrument: ByteStreamInstrument::Serial(instrument),
+                ..
+            } => {
+                assert_eq!(instrument.address.port, "/dev/ttyUSB0");
+                let auto_drop: Duration = instrument.options.unwrap().auto_drop.unwrap().into();
+                assert_eq!(auto_drop, Duration::from_secs(10));
+            }
+            _ => panic!(),
+        }
+
+        let req = Request::SetAlias {
+            alias: "added".to_string(),
+            addr: Address::Serial(SerialAddress {
+                port: "/dev/ttyUSB1".to_string(),
+            }),
+        };
+        app.handle(req).await.unwrap();
+        assert!(matches!(app.handle(Request::SaveConfig).await, Ok(Response::Done)));
+        let saved = Config::load(file.path()).unwrap();
+        assert_eq!(saved.aliases["other"], addr);
+        assert!(saved.aliases.contains_key("added"));
+        assert!(saved.defaults.scpi_timeout.is_some());
+
+        let (app, _rx) = App::new();
+        assert!(matches!(app.handle(Request::SaveConfig).await, Err(crate::Error::Argument(_))));
+    }
+
+    #[tokio::test]
+    async fn drain_completes_long_request() {
+        let mut in_flight = InFlight::default();
+        let done = Arc::new(AtomicBool::new(false));
+        in_flight.spawn({
+            let done = done.clone();
+            async move {
+                sleep(Duration::from_millis(100)).await;
+                done.store(true, Ordering::SeqCst);
+            }
+        });
+        in_flight.spawn(async {});
+        let (drained, aborted) = in_flight.drain(Duration::from_secs(5)).await;
+        assert!(done.load(Ordering::SeqCst));
+        assert!(drained >= 1);
+        assert_eq!(aborted, 0);
+    }
+
+    #[tokio::test]
+    async fn drain_aborts_after_deadline() {
+        let mut in_flight = InFlight::default();
+        let done = Arc::new(AtomicBool::new(false));
+        in_flight.spawn({
+            let done = done.clone();
+            async move {
+                sleep(Duration::from_secs(10)).await;
+                done.store(true, Ordering::SeqCst);
+            }
+        });
+        let (drained, aborted) = in_flight.drain(Duration::from_millis(50)).await;
+        assert_eq!((drained, aborted), (0, 1));
+        assert!(!done.load(Ordering::SeqCst));
+    }
+}
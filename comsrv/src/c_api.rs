@@ -48,7 +48,7 @@ pub extern "C" fn comsrv_spawn() -> AppState {
 #[no_mangle]
 pub extern "C" fn comsrv_stop(state: AppState) {
     let app: &App = unsafe { &*state.app };
-    app.server.shutdown();
+    app.shutdown();
     log::debug!("Application stopping.");
 }
 
@@ -0,0 +1,122 @@
+//! Implements the subcommands which run the `comsrv` binary as client of a running `comsrv`, e.g. for quick
+//! diagnostics.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use comsrv_client::ws::WsRpc;
+use comsrv_client::Rpc;
+use comsrv_protocol::{ScpiInstrument, ScpiRequest, VisaInstrument, VxiInstrument};
+use url::Url;
+
+use crate::{Request, Response};
+
+/// URL of a `comsrv` running on the local machine with default settings
+pub const DEFAULT_URL: &str = "ws://127.0.0.1:5902";
+
+/// Parse the request of the `query` subcommand. The request is either given in its JSON encoding or as one of
+/// the following shorthands: `version`, `list-instruments`, `list-serial-ports`, `list-hid-devices`,
+/// `list-ftdi-devices`, `list-can-devices`, `list-aliases`, `drop-all` or `self-test`.
+pub fn parse_query(query: &str) -> crate::Result<Request> {
+    let request = match query.trim() {
+        "version" => Request::Version,
+        "list-instruments" => Request::ListConnectedInstruments,
+        "list-serial-ports" => Request::ListSerialPorts,
+        "list-hid-devices" => Request::ListHidDevices,
+        "list-ftdi-devices" => Request::ListFtdiDevices,
+        "list-can-devices" => Request::ListCanDevices,
+        "list-aliases" => Request::ListAliases,
+        "drop-all" => Request::DropAll,
+        "self-test" => Request::SelfTest,
+        x => serde_json::from_str(x)
+            .map_err(|err| crate::Error::argument(anyhow!("Invalid request `{}`: {}", x, err)))?,
+    };
+    Ok(request)
+}
+
+/// Build the request of the `scpi` subcommand.
+///
+/// The instrument may be given explicitly as `vxi::<host>` or `visa::<resource>`. Otherwise, addresses
+/// containing `::` are treated as VISA resources and all others as hosts of VXI-11 instruments.
+/// Commands ending with `?` are queries, all other commands are written without reading a reply.
+pub fn scpi_request(addr: &str, command: &str) -> Request {
+    let instrument = if let Some(host) = addr.strip_prefix("vxi::") {
+        ScpiInstrument::Vxi(VxiInstrument { host: host.to_string() })
+    } else if let Some(address) = addr.strip_prefix("visa::") {
+        ScpiInstrument::Visa(VisaInstrument {
+            address: address.to_string(),
+        })
+    } else if addr.contains("::") {
+        ScpiInstrument::Visa(VisaInstrument {
+            address: addr.to_string(),
+        })
+    } else {
+        ScpiInstrument::Vxi(VxiInstrument { host: addr.to_string() })
+    };
+    let request = if command.trim_end().ends_with('?') {
+        ScpiRequest::QueryString(command.to_string())
+    } else {
+        ScpiRequest::Write(command.to_string())
+    };
+    Request::Scpi {
+        instrument,
+        request,
+        lock: None,
+        timeout: None,
+        truncate: false,
+    }
+}
+
+/// Connect to the `comsrv` at `url` and send `request` to it.
+pub async fn query(url: &str, request: Request, timeout: Duration) -> Result<Response, comsrv_client::Error> {
+    let url = Url::parse(url).map_err(|err| comsrv_client::Error::Other(anyhow!("Invalid URL `{}`: {}", url, err)))?;
+    let mut rpc = WsRpc::connect(url, timeout).await.map_err(comsrv_client::Error::Io)?;
+    rpc.request(request, timeout).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_queries() {
+        assert!(matches!(parse_query("version"), Ok(Request::Version)));
+        assert!(matches!(parse_query("list-instruments"), Ok(Request::ListConnectedInstruments)));
+        assert!(matches!(parse_query("\"ListAliases\""), Ok(Request::ListAliases)));
+        assert!(matches!(
+            parse_query(r#"{"DescribeInstrument": {"addr": {"Vxi": "192.168.0.1"}}}"#),
+            Ok(Request::DescribeInstrument { .. })
+        ));
+        assert!(matches!(parse_query("list-everything"), Err(crate::Error::Argument(_))));
+    }
+
+    #[test]
+    fn build_scpi_requests() {
+        let req = scpi_request("192.168.0.1", "*IDN?");
+        assert!(matches!(
+            req,
+            Request::Scpi {
+                instrument: ScpiInstrument::Vxi(VxiInstrument { ref host }),
+                request: ScpiRequest::QueryString(_),
+                ..
+            } if host == "192.168.0.1"
+        ));
+        let req = scpi_request("TCPIP::192.168.0.1::INSTR", "*RST");
+        assert!(matches!(
+            req,
+            Request::Scpi {
+                instrument: ScpiInstrument::Visa(VisaInstrument { ref address }),
+                request: ScpiRequest::Write(_),
+                ..
+            } if address == "TCPIP::192.168.0.1::INSTR"
+        ));
+        let req = scpi_request("vxi::alias::dmm", "*IDN?");
+        assert!(matches!(
+            req,
+            Request::Scpi {
+                instrument: ScpiInstrument::Vxi(VxiInstrument { ref host }),
+                ..
+            } if host == "alias::dmm"
+        ));
+    }
+}
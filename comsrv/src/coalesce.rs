@@ -0,0 +1,136 @@
+//! Coalesces identical concurrent reads, which is enabled with `--coalesce-reads`.
+//!
+//! While a read is in flight, identical reads to the same instrument do not start another transaction but wait for
+//! the pending one and receive a copy of its result. Only requests without side effects are coalesced, currently
+//! the register and coil reads of ModBus.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use comsrv_protocol::{ByteStreamRequest, ModBusRequest, Request, Response};
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+type Pending = Shared<BoxFuture<'static, crate::Result<Response>>>;
+
+/// Tracks the in-flight reads, indexed by their key.
+#[derive(Default)]
+pub struct Coalescer {
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl Coalescer {
+    /// Await the pending read with the given `key` or start a new one by running `read`.
+    pub async fn run<F>(&self, key: String, read: F) -> crate::Result<Response>
+    where
+        F: Future<Output = crate::Result<Response>> + Send + 'static,
+    {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(x) => x.clone(),
+                None => {
+                    let pending_reads = self.pending.clone();
+                    let entry_key = key.clone();
+                    let fut = async move {
+                        let ret = read.await;
+                        pending_reads.lock().unwrap().remove(&entry_key);
+                        ret
+                    }
+                    .boxed()
+                    .shared();
+                    pending.insert(key, fut.clone());
+                    fut
+                }
+            }
+        };
+        pending.await
+    }
+}
+
+/// Return the key identifying `req` if it may be coalesced with identical requests.
+pub fn key(req: &Request) -> Option<String> {
+    match req {
+        Request::Bytes {
+            request:
+                ByteStreamRequest::ModBus {
+                    request:
+                        ModBusRequest::ReadCoil { .. }
+                        | ModBusRequest::ReadDiscrete { .. }
+                        | ModBusRequest::ReadInput { .. }
+                        | ModBusRequest::ReadHolding { .. },
+                    ..
+                },
+            ..
+        } => serde_json::to_string(req).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{ByteStreamInstrument, ByteStreamResponse, LoopbackAddress, ModBusProtocol};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn read_holding(addr: u16) -> Request {
+        Request::Bytes {
+            instrument: ByteStreamInstrument::Loopback(LoopbackAddress {
+                name: "plc".to_string(),
+            }),
+            request: ByteStreamRequest::ModBus {
+                timeout: Duration::from_millis(100).into(),
+                station_address: 1,
+                protocol: ModBusProtocol::Tcp,
+                inter_frame_delay_ms: 0,
+                request: ModBusRequest::ReadHolding { addr, cnt: 10 },
+            },
+            lock: None,
+            truncate: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_reads_share_transaction() {
+        let coalescer = Arc::new(Coalescer::default());
+        let transactions = Arc::new(AtomicUsize::new(0));
+        let key = key(&read_holding(100)).unwrap();
+        let reads = (0..10).map(|_| {
+            let coalescer = coalescer.clone();
+            let transactions = transactions.clone();
+            let key = key.clone();
+            tokio::spawn(async move {
+                let read = async move {
+                    transactions.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok(Response::Bytes(ByteStreamResponse::Done))
+                };
+                coalescer.run(key, read).await
+            })
+        });
+        for ret in futures::future::join_all(reads).await {
+            assert!(matches!(ret.unwrap(), Ok(Response::Bytes(ByteStreamResponse::Done))));
+        }
+        assert_eq!(transactions.load(Ordering::SeqCst), 1);
+        assert!(coalescer.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_reads_are_coalesced() {
+        assert_ne!(key(&read_holding(100)), key(&read_holding(101)));
+        let mut write = read_holding(100);
+        if let Request::Bytes {
+            request: ByteStreamRequest::ModBus { request, .. },
+            ..
+        } = &mut write
+        {
+            *request = ModBusRequest::WriteRegisters {
+                addr: 100,
+                values: vec![1],
+            };
+        }
+        assert!(key(&write).is_none());
+        assert!(key(&Request::ListAliases).is_none());
+    }
+}
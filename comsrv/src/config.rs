@@ -0,0 +1,83 @@
+//! Implements loading and saving the configuration of a `comsrv` deployment.
+//!
+//! The configuration file is JSON-encoded and contains aliases as well as default options, which are applied
+//! to requests not specifying them. For example:
+//!
+//! ```json
+//! {
+//!     "aliases": {
+//!         "myplc": { "Serial": { "port": "/dev/ttyUSB0" } }
+//!     },
+//!     "defaults": {
+//!         "tcp": { "connection_timeout": { "micros": 0, "seconds": 2 } },
+//!         "scpi_timeout": { "micros": 0, "seconds": 5 }
+//!     }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use comsrv_protocol::{Address, ByteStreamInstrument, Duration, Request, SerialOptions, TcpOptions};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub aliases: HashMap<String, Address>,
+    #[serde(default)]
+    pub defaults: Defaults,
+}
+
+/// Options applied to requests which do not specify them
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Defaults {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub serial: Option<SerialOptions>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ftdi: Option<SerialOptions>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tcp: Option<TcpOptions>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub scpi_timeout: Option<Duration>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> crate::Result<Config> {
+        let data = fs::read_to_string(path)
+            .map_err(|x| crate::Error::argument(anyhow!("Cannot read config file `{}`: {}", path.display(), x)))?;
+        serde_json::from_str(&data)
+            .map_err(|x| crate::Error::argument(anyhow!("Invalid config file `{}`: {}", path.display(), x)))
+    }
+
+    pub fn save(&self, path: &Path) -> crate::Result<()> {
+        let data = serde_json::to_string_pretty(self).map_err(|x| crate::Error::internal(anyhow!(x)))?;
+        fs::write(path, data)
+            .map_err(|x| crate::Error::argument(anyhow!("Cannot write config file `{}`: {}", path.display(), x)))
+    }
+}
+
+impl Defaults {
+    /// Fill in the default options for all options not given in `req`.
+    pub fn apply(&self, req: &mut Request) {
+        match req {
+            Request::Bytes { instrument, .. } | Request::CobsStream { instrument, .. } => match instrument {
+                ByteStreamInstrument::Serial(x) => fill(&mut x.options, &self.serial),
+                ByteStreamInstrument::Ftdi(x) => fill(&mut x.options, &self.ftdi),
+                ByteStreamInstrument::Tcp(x) => fill(&mut x.options, &self.tcp),
+                ByteStreamInstrument::Custom(_) | ByteStreamInstrument::Loopback(_) => {}
+            },
+            Request::Serial { instrument, .. } => fill(&mut instrument.options, &self.serial),
+            Request::Scpi { timeout, .. } | Request::Prologix { timeout, .. } => fill(timeout, &self.scpi_timeout),
+            _ => {}
+        }
+    }
+}
+
+fn fill<T: Clone>(value: &mut Option<T>, default: &Option<T>) {
+    if value.is_none() {
+        *value = default.clone();
+    }
+}
@@ -0,0 +1,96 @@
+//! Implements a bounded history of broadcast messages.
+//!
+//! Broadcasts are not stored by the RPC server, thus a client connecting shortly after a burst of notifications
+//! misses them. If enabled, the most recent broadcasts are kept in a ring buffer and may be queried with
+//! [`Request::ReplayRecent`](comsrv_protocol::Request::ReplayRecent).
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use comsrv_protocol::Response;
+use tokio::task;
+
+use crate::app::Server;
+
+/// Upper limit of the number of broadcasts kept in the history
+pub const MAX_HISTORY_CAPACITY: usize = 1000;
+
+pub struct History {
+    capacity: usize,
+    messages: Mutex<VecDeque<Response>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> crate::Result<Self> {
+        if capacity == 0 || capacity > MAX_HISTORY_CAPACITY {
+            return Err(crate::Error::argument(anyhow!(
+                "Replay buffer capacity must be between 1 and {}, got {}",
+                MAX_HISTORY_CAPACITY,
+                capacity
+            )));
+        }
+        Ok(Self {
+            capacity,
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+        })
+    }
+
+    /// Start recording the broadcasts of `server`. Broadcasts sent after this function returns are recorded.
+    pub async fn record(self: Arc<Self>, server: &Server) {
+        let mut client = server.loopback().await;
+        task::spawn(async move {
+            while let Some(msg) = client.next().await {
+                if let broadcast_wsrpc::Response::Notify(msg) = msg {
+                    self.push(msg);
+                }
+            }
+        });
+    }
+
+    fn push(&self, msg: Response) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(msg);
+    }
+
+    /// Returns up to `count` of the most recent broadcasts, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<Response> {
+        let messages = self.messages.lock().unwrap();
+        let skip = messages.len().saturating_sub(count);
+        messages.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        assert!(History::new(0).is_err());
+        assert!(History::new(MAX_HISTORY_CAPACITY + 1).is_err());
+
+        let history = History::new(2).unwrap();
+        for x in 0..3 {
+            history.push(Response::Version {
+                major: x,
+                minor: 0,
+                build: 0,
+            });
+        }
+        let majors: Vec<_> = history
+            .recent(10)
+            .into_iter()
+            .map(|x| match x {
+                Response::Version { major, .. } => major,
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(majors, vec![1, 2]);
+        assert_eq!(history.recent(1).len(), 1);
+        assert!(history.recent(0).is_empty());
+    }
+}
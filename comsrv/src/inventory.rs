@@ -2,26 +2,26 @@
 /// Also, access to instruments may be locked for a given amount of time. During this time, only
 /// the task accesssing the instrument has access to the instrument.
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use std::fmt::Debug;
 use std::hash::Hash;
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
-use tokio::sync::Mutex as AsyncMutex;
-use tokio::time::sleep;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Instant};
 use uuid::Uuid;
 
 use crate::app::Server;
 
-/// Used to lock/unlock an instrument. Allows waiting
-/// for the lock because internally an AsyncMutex is used.
+/// Used to unlock an instrument. While the lock is held, the task owning it holds the permit of the
+/// access semaphore of the instrument.
 #[derive(Clone)]
 struct Lock {
-    mutex: Arc<AsyncMutex<()>>,
     unlock: mpsc::Sender<()>,
     id: Uuid,
 }
@@ -29,14 +29,7 @@ struct Lock {
 impl Lock {
     fn new(id: Uuid) -> (Self, mpsc::Receiver<()>) {
         let (tx, rx) = mpsc::channel(1);
-        (
-            Self {
-                mutex: Arc::new(AsyncMutex::new(())),
-                unlock: tx,
-                id,
-            },
-            rx,
-        )
+        (Self { unlock: tx, id }, rx)
     }
 
     async fn release(self) {
@@ -51,13 +44,34 @@ pub trait InstrumentAddress: 'static + Clone + Send + Hash + PartialEq + Eq + De
 
 impl<T: 'static + Clone + Send + Hash + PartialEq + Eq + Debug> InstrumentAddress for T {}
 
+/// Drop delay which keeps idle connections open for the lifetime of the process
+pub const NO_AUTO_DROP: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Defaults for the instruments connected by an [`Inventory`]. Options given with requests take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct InstrumentDefaults {
+    /// Delay after which idle connections are dropped. `None` selects the delay of the transport.
+    pub drop_delay: Option<Duration>,
+    /// Directory in which CAN recordings are stored and replayed from. `None` disables recording and replay.
+    pub recording_dir: Option<PathBuf>,
+}
+
 #[async_trait]
 pub trait Instrument: 'static + Clone + Send {
     type Address: InstrumentAddress;
 
-    fn connect(server: &Server, addr: &Self::Address) -> crate::Result<Self>;
+    fn connect(server: &Server, addr: &Self::Address, defaults: &InstrumentDefaults) -> crate::Result<Self>;
 
     async fn wait_for_closed(&self);
+
+    /// Stop the actor of the instrument, which closes the underlying connection.
+    fn disconnect(&mut self);
+
+    /// Whether the instrument is processing a request or otherwise in use, e.g. listening to a bus. Busy
+    /// instruments are not dropped by [`Inventory::disconnect_idle`].
+    fn is_busy(&self) -> bool {
+        false
+    }
 }
 
 /// Contains an instrument which can be locked
@@ -65,11 +79,42 @@ pub trait Instrument: 'static + Clone + Send {
 struct LockableInstrument<T: Instrument> {
     instr: T,
     lock: Option<Lock>,
+    /// Time the instrument was last retrieved from the inventory
+    last_used: Instant,
 }
 
 struct InventoryShared<T: Instrument> {
     instruments: HashMap<T::Address, LockableInstrument<T>>,
-    locks: HashMap<Uuid, T::Address>,
+    locks: HashMap<Uuid, (T::Address, Lock)>,
+    /// Semaphores with a single permit, which is held by the owner of the lock on an address.
+    /// The semaphores queue waiters in FIFO order and thus grant the lock in the order it was requested.
+    access: HashMap<T::Address, Arc<Semaphore>>,
+    defaults: InstrumentDefaults,
+}
+
+impl<T: Instrument> InventoryShared<T> {
+    fn access(&mut self, addr: &T::Address) -> Arc<Semaphore> {
+        self.access
+            .entry(addr.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(1)))
+            .clone()
+    }
+
+    /// Drop the semaphores which are neither held nor waited for.
+    fn prune_access(&mut self) {
+        self.access.retain(|_, x| Arc::strong_count(x) > 1);
+    }
+
+    /// Remove the lock with the given id, also if the instrument was dropped in the meantime.
+    fn remove_lock(&mut self, id: Uuid) -> Option<Lock> {
+        let (addr, lock) = self.locks.remove(&id)?;
+        if let Some(instr) = self.instruments.get_mut(&addr) {
+            if instr.lock.as_ref().map(|x| x.id) == Some(id) {
+                instr.lock.take();
+            }
+        }
+        Some(lock)
+    }
 }
 
 /// A collect of instruments, public API of this module
@@ -87,26 +132,35 @@ impl<T: Instrument> Inventory<T> {
         let inner = InventoryShared {
             instruments: Default::default(),
             locks: Default::default(),
+            access: Default::default(),
+            defaults: Default::default(),
         };
         let inner = Arc::new(Mutex::new(inner));
         Self(inner)
     }
 
+    /// Modify the defaults applied to the instruments connected from now on.
+    pub fn update_defaults(&self, f: impl FnOnce(&mut InstrumentDefaults)) {
+        f(&mut self.0.lock().unwrap().defaults);
+    }
+
     /// Connect a new instrument. This function creates a new instrument and registers it
     /// in the `Inventory`. However, it does not perform any io, however it may fail
     /// due to invalid arguments.
     pub fn connect(&self, server: &Server, addr: &T::Address) -> crate::Result<T> {
         let mut inner = self.0.lock().unwrap();
 
-        if let Some(ret) = inner.instruments.get(addr) {
+        if let Some(ret) = inner.instruments.get_mut(addr) {
+            ret.last_used = Instant::now();
             return Ok(ret.instr.clone());
         }
         log::debug!("Opening instrument: {:?}", addr);
-        let ret = T::connect(server, addr)?;
+        let ret = T::connect(server, addr, &inner.defaults)?;
 
         let instr = LockableInstrument {
             instr: ret.clone(),
             lock: None,
+            last_used: Instant::now(),
         };
         inner.instruments.insert(addr.clone(), instr);
         Ok(ret)
@@ -123,6 +177,7 @@ impl<T: Instrument> Inventory<T> {
         log::debug!("Dropping instrument: {:?}", addr);
         let instr = {
             let mut inner = self.0.lock().unwrap();
+            inner.prune_access();
             inner.instruments.remove(addr)
         };
         if let Some(instr) = instr {
@@ -141,6 +196,7 @@ impl<T: Instrument> Inventory<T> {
         let mut instruments = Vec::new();
         {
             let mut inner = self.0.lock().unwrap();
+            inner.prune_access();
             inner.instruments.clear();
             for (_, instr) in inner.instruments.drain() {
                 instruments.push(instr);
@@ -151,6 +207,38 @@ impl<T: Instrument> Inventory<T> {
         }
     }
 
+    /// Drops all instruments which are not locked, not busy and have not been used for longer than `ttl`.
+    /// Returns the number of instruments dropped.
+    pub async fn disconnect_idle(&self, ttl: Duration) -> usize {
+        let mut instruments = Vec::new();
+        {
+            let mut inner = self.0.lock().unwrap();
+            let idle: Vec<_> = inner
+                .instruments
+                .iter()
+                .filter(|(_, instr)| instr.lock.is_none() && instr.last_used.elapsed() > ttl && !instr.instr.is_busy())
+                .map(|(addr, _)| addr.clone())
+                .collect();
+            for addr in idle {
+                log::debug!("Dropping idle instrument: {:?}", addr);
+                instruments.extend(inner.instruments.remove(&addr));
+            }
+            inner.prune_access();
+        }
+        let ret = instruments.len();
+        for mut instr in instruments {
+            instr.instr.disconnect();
+            instr.instr.wait_for_closed().await;
+        }
+        ret
+    }
+
+    /// Return the instrument connected at `addr` without connecting a new one.
+    pub fn get(&self, addr: &T::Address) -> Option<T> {
+        let inner = self.0.lock().unwrap();
+        inner.instruments.get(addr).map(|x| x.instr.clone())
+    }
+
     /// Return a list of keys of instruments.
     pub fn list(&self) -> Vec<T::Address> {
         let inner = self.0.lock().unwrap();
@@ -159,104 +247,89 @@ impl<T: Instrument> Inventory<T> {
 
     /// Wait for the lock on a given instrument. If a `lock_id` is provided and matches the
     /// lock which is currently held, access to the `Instrument` is granted.
+    ///
+    /// Waiters are queued in FIFO order together with the tasks waiting in [`Inventory::wait_and_lock()`].
     pub async fn wait_for_lock(&self, addr: &T::Address, lock_id: Option<&Uuid>) {
-        let mutex = {
-            let inner = self.0.lock().unwrap();
+        let access = {
+            let mut inner = self.0.lock().unwrap();
             match inner.instruments.get(addr) {
-                Some(LockableInstrument {
-                    instr: _,
-                    lock: Some(lock),
-                }) => {
-                    if let Some(id) = lock_id {
-                        if lock.id == *id {
-                            return;
-                        }
+                Some(LockableInstrument { lock: Some(lock), .. }) => {
+                    if lock_id == Some(&lock.id) {
+                        return;
                     }
-                    lock.mutex.clone()
                 }
                 _ => return,
             }
+            inner.access(addr)
         };
         log::debug!("Waiting for lock...");
-        // TODO: this produces multiple process to proceed
-        // hence multiple process waiting for the lock on an instrument
-        // would possibly be able to acquire the lock at the same time
-        // This is a bug.
-        let _ = mutex.lock().await;
+        // the semaphore is never closed
+        let _ = access.acquire().await;
         log::debug!("Lock acquired, proceeding.");
     }
 
-    // Wait for the lock on the instrument to be released and create a new lock on it
+    /// Wait for the lock on the instrument to be released and create a new lock on it. Locks are granted in
+    /// the order they were requested.
     pub async fn wait_and_lock(&self, server: &Server, addr: &T::Address, timeout: Duration) -> crate::Result<Uuid> {
-        self.wait_for_lock(addr, None).await;
-        self.lock(server, addr, timeout).await
+        let access = self.0.lock().unwrap().access(addr);
+        let permit = access
+            .acquire_owned()
+            .await
+            .map_err(|_| crate::Error::internal(anyhow!("Lock semaphore closed.")))?;
+        self.lock(server, addr, timeout, permit)
     }
 
     /// Lock the given instrument for a given duration and returns an ID representing the
-    /// newly created lock. If a lock is still present on the address, the lock is removed and
-    /// unlocked.
-    /// If this behavior is undesirable, call wait_for_lock() before calling this function.
-    pub async fn lock(&self, server: &Server, addr: &T::Address, timeout: Duration) -> crate::Result<Uuid> {
+    /// newly created lock. The lock is held until it expires or is unlocked, after which `permit` is released.
+    fn lock(
+        &self,
+        server: &Server,
+        addr: &T::Address,
+        timeout: Duration,
+        permit: OwnedSemaphorePermit,
+    ) -> crate::Result<Uuid> {
         let ret = Uuid::new_v4();
 
-        let (lock, mut unlock) = {
+        let mut unlock = {
             let mut inner = self.0.lock().unwrap();
             let (lock, unlock) = Lock::new(ret);
             match inner.instruments.get_mut(addr) {
                 Some(instr) => {
-                    if let Some(old_lock) = instr.lock.take() {
-                        tokio::task::spawn(async move {
-                            log::debug!("Unlocking: {}", old_lock.id);
-                            old_lock.release().await;
-                        });
-                    }
+                    instr.last_used = Instant::now();
                     instr.lock = Some(lock.clone());
                 }
                 None => {
-                    let instr = Instrument::connect(server, addr)?;
+                    let instr = T::connect(server, addr, &inner.defaults)?;
                     let instr = LockableInstrument {
                         instr,
                         lock: Some(lock.clone()),
+                        last_used: Instant::now(),
                     };
                     inner.instruments.insert(addr.clone(), instr);
                 }
             };
-            inner.locks.insert(ret, addr.clone());
-            (lock, unlock)
+            inner.locks.insert(ret, (addr.clone(), lock));
+            unlock
         };
 
-        let (tx, rx) = oneshot::channel();
-
         let lock_id = ret;
         let inv = self.clone();
+        log::debug!("Locking: {}", lock_id);
         tokio::task::spawn(async move {
-            log::debug!("Locking: {}", lock_id);
-            let locked = lock.mutex.lock().await;
-            let _ = tx.send(());
-
             tokio::select! {
                 _ = sleep(timeout) => {},
                 _ = unlock.recv() => {},
             }
-
-            drop(locked);
-            drop(lock);
-            inv.unlock(lock_id).await;
+            inv.0.lock().unwrap().remove_lock(lock_id);
+            // hand over to the next waiter only once the lock is removed
+            drop(permit);
         });
-        let _ = rx.await;
         Ok(ret)
     }
 
     /// Unlock an instrument.
     pub async fn unlock(&self, id: Uuid) {
-        let lock = {
-            let mut inner = self.0.lock().unwrap();
-            inner
-                .locks
-                .remove(&id)
-                .and_then(|lock| inner.instruments.get_mut(&lock))
-                .and_then(|x| x.lock.take())
-        };
+        let lock = self.0.lock().unwrap().remove_lock(id);
         if let Some(lock) = lock {
             log::debug!("Unlocking: {}", id);
             lock.release().await;
@@ -269,3 +342,89 @@ impl<T: Instrument> Default for Inventory<T> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Clone)]
+    struct Dummy;
+
+    #[async_trait]
+    impl Instrument for Dummy {
+        type Address = u32;
+
+        fn connect(_server: &Server, _addr: &Self::Address, _defaults: &InstrumentDefaults) -> crate::Result<Self> {
+            Ok(Dummy)
+        }
+
+        async fn wait_for_closed(&self) {}
+
+        fn disconnect(&mut self) {}
+    }
+
+    #[derive(Clone)]
+    struct Busy(Arc<AtomicBool>);
+
+    #[async_trait]
+    impl Instrument for Busy {
+        type Address = u32;
+
+        fn connect(_server: &Server, _addr: &Self::Address, _defaults: &InstrumentDefaults) -> crate::Result<Self> {
+            Ok(Busy(Default::default()))
+        }
+
+        async fn wait_for_closed(&self) {}
+
+        fn disconnect(&mut self) {}
+
+        fn is_busy(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_busy_instruments() {
+        let (server, _rx) = Server::new();
+        let inv = Inventory::<Busy>::new();
+        let instr = inv.connect(&server, &1).unwrap();
+        instr.0.store(true, Ordering::SeqCst);
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(inv.disconnect_idle(Duration::from_millis(10)).await, 0);
+        instr.0.store(false, Ordering::SeqCst);
+        assert_eq!(inv.disconnect_idle(Duration::from_millis(10)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn fifo_locks() {
+        let (server, _rx) = Server::new();
+        let inv = Inventory::<Dummy>::new();
+        let first = inv.wait_and_lock(&server, &1, Duration::from_secs(10)).await.unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut waiters = Vec::new();
+        for k in 0..5 {
+            let (inv, server, order, active) = (inv.clone(), server.clone(), order.clone(), active.clone());
+            waiters.push(tokio::spawn(async move {
+                let id = inv.wait_and_lock(&server, &1, Duration::from_secs(10)).await.unwrap();
+                assert_eq!(active.fetch_add(1, Ordering::SeqCst), 0);
+                order.lock().unwrap().push(k);
+                sleep(Duration::from_millis(5)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                inv.unlock(id).await;
+            }));
+            // make sure the waiters are queued in order
+            sleep(Duration::from_millis(10)).await;
+        }
+        // an address which is not locked is not affected
+        inv.wait_for_lock(&2, None).await;
+
+        inv.unlock(first).await;
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
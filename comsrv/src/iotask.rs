@@ -6,8 +6,11 @@
 use crate::Error;
 use anyhow::anyhow;
 use async_trait::async_trait;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task;
+use tokio::time::{sleep_until, Duration, Instant};
 
 /// Trait constraining the `Request` and `Response` associated types of `IoHandler`.
 #[allow(unused)]
@@ -61,11 +64,15 @@ enum RequestMsg<T: IoHandler> {
 /// Additionally it allows the task to be disconnected by calling the `disconnect()` function.
 pub struct IoTask<T: IoHandler> {
     tx: mpsc::UnboundedSender<RequestMsg<T>>,
+    requests: RequestCounter,
 }
 
 impl<T: IoHandler> Clone for IoTask<T> {
     fn clone(&self) -> Self {
-        Self { tx: self.tx.clone() }
+        Self {
+            tx: self.tx.clone(),
+            requests: self.requests.clone(),
+        }
     }
 }
 
@@ -91,7 +98,10 @@ impl<T: 'static + IoHandler> IoTask<T> {
                 }
             }
         });
-        IoTask { tx }
+        IoTask {
+            tx,
+            requests: RequestCounter::default(),
+        }
     }
 
     pub async fn wait_for_closed(&self) {
@@ -99,13 +109,18 @@ impl<T: 'static + IoHandler> IoTask<T> {
     }
 
     /// Drop the internal actor.
-    #[allow(dead_code)]
     pub fn disconnect(&mut self) {
         let _ = self.tx.send(RequestMsg::Drop);
     }
 
+    /// Whether a request is queued or being processed.
+    pub fn is_busy(&self) -> bool {
+        self.requests.is_busy()
+    }
+
     /// Send a request and receive a response.
     pub async fn request(&mut self, req: T::Request) -> crate::Result<T::Response> {
+        let _request = self.requests.start();
         let (tx, rx) = oneshot::channel();
         let msg = RequestMsg::Task { req, answer: Some(tx) };
         self.tx
@@ -114,3 +129,54 @@ impl<T: 'static + IoHandler> IoTask<T> {
         rx.await.map_err(|_| Error::internal(anyhow!("Channel disconnected")))?
     }
 }
+
+/// Counts the requests sent to an actor which did not complete yet.
+#[derive(Clone, Default)]
+pub struct RequestCounter(Arc<AtomicUsize>);
+
+/// Marks a request as in progress until it is dropped.
+pub struct RequestGuard(Arc<AtomicUsize>);
+
+impl RequestCounter {
+    pub fn start(&self) -> RequestGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        RequestGuard(self.0.clone())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.0.load(Ordering::SeqCst) > 0
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Enforces a minimum interval between the start of consecutive requests of an `IoHandler`, such that devices
+/// which misbehave when polled too fast are protected from bursts of requests.
+#[derive(Default)]
+pub struct RateLimit {
+    min_interval: Duration,
+    last_start: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Set the minimum interval. A zero interval disables the rate limit.
+    pub fn set_min_interval(&mut self, min_interval: Duration) {
+        self.min_interval = min_interval;
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// Wait until the minimum interval elapsed since the previous request started and start the next one.
+    pub async fn wait(&mut self) {
+        if let Some(last_start) = self.last_start {
+            sleep_until(last_start + self.min_interval).await;
+        }
+        self.last_start = Some(Instant::now());
+    }
+}
@@ -7,14 +7,28 @@ extern crate lazy_static;
 
 pub use comsrv_protocol::{Request, Response};
 
+mod alias;
 pub mod app;
 pub mod c_api;
+pub mod cli;
+mod coalesce;
+pub mod config;
+pub mod history;
 mod inventory;
 mod iotask;
+mod limit;
+mod message_limit;
+mod modbus_server;
 mod protocol;
+mod request_log;
+mod selftest;
+mod subscription;
 mod transport;
+mod validate;
 
 pub use comsrv_protocol as rpc;
+pub use transport::custom::{Stream, Transport};
+pub use transport::loopback::script_loopback;
 
 pub type Error = comsrv_protocol::Error;
 pub type Result<T> = std::result::Result<T, comsrv_protocol::Error>;
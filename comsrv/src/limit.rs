@@ -0,0 +1,135 @@
+//! Enforces the maximum size of response payloads, which is configured with `--max-response-size`.
+//!
+//! The limit applies to the binary data returned by byte stream reads, e.g. [`ByteStreamRequest::ReadAll`] or
+//! [`ByteStreamRequest::ReadExact`], binary SCPI queries and sigrok acquisitions. Oversized responses fail with a
+//! protocol error, unless the request sets `truncate`, in which case the response is cut to the limit.
+
+use anyhow::anyhow;
+use comsrv_protocol::{
+    ByteStreamRequest, ByteStreamResponse, Request, Response, ScpiResponse, SigrokData, SigrokResponse,
+};
+
+/// Size of a sample of [`SigrokResponse::AnalogData`] in bytes
+const ANALOG_SAMPLE_SIZE: usize = std::mem::size_of::<f32>();
+
+/// Limit the data read by `req` to one byte more than `max_size`. This bounds the memory used by the read while
+/// still detecting oversized responses.
+pub fn limit_request(req: &mut Request, max_size: usize) {
+    let max_bytes = max_size.saturating_add(1).min(u32::MAX as usize) as u32;
+    if let Request::Bytes { request, .. } = req {
+        match request {
+            ByteStreamRequest::ReadAll => *request = ByteStreamRequest::ReadAllMax { max_bytes },
+            ByteStreamRequest::ReadAllMax { max_bytes: x } if *x > max_bytes => *x = max_bytes,
+            _ => {}
+        }
+    }
+}
+
+/// Check the payload of `response` against `max_size`. Oversized payloads fail unless `truncate` is set, in which
+/// case they are cut to `max_size`. Returns whether the payload was truncated.
+pub fn limit_response(response: &mut Response, max_size: usize, truncate: bool) -> crate::Result<bool> {
+    let size = payload_size(response);
+    if size <= max_size {
+        return Ok(false);
+    }
+    if !truncate {
+        return Err(crate::Error::protocol(anyhow!(
+            "Response size of {} bytes exceeds limit of {} bytes.",
+            size,
+            max_size
+        )));
+    }
+    match response {
+        Response::Bytes(ByteStreamResponse::Data(data)) | Response::Scpi(ScpiResponse::Binary { data }) => {
+            data.truncate(max_size);
+        }
+        Response::Sigrok(SigrokResponse::Data(SigrokData { length, channels, .. })) => {
+            let samples = max_size / channels.len();
+            channels.values_mut().for_each(|x| x.truncate(samples));
+            *length = (*length).min(samples);
+        }
+        Response::Sigrok(SigrokResponse::AnalogData { length, channels, .. }) => {
+            let samples = max_size / (channels.len() * ANALOG_SAMPLE_SIZE);
+            channels.values_mut().for_each(|x| x.truncate(samples));
+            *length = (*length).min(samples);
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+/// Size of the binary payload of `response`. Responses without binary payload, e.g. strings, are not limited.
+fn payload_size(response: &Response) -> usize {
+    match response {
+        Response::Bytes(ByteStreamResponse::Data(data)) | Response::Scpi(ScpiResponse::Binary { data }) => data.len(),
+        Response::Sigrok(SigrokResponse::Data(data)) => data.channels.values().map(|x| x.len()).sum(),
+        Response::Sigrok(SigrokResponse::AnalogData { channels, .. }) => {
+            channels.values().map(|x| x.len() * ANALOG_SAMPLE_SIZE).sum()
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Response {
+        Response::Bytes(ByteStreamResponse::Data(vec![0; len]))
+    }
+
+    #[test]
+    fn error_above_limit() {
+        let mut response = data(16);
+        assert!(!limit_response(&mut response, 16, false).unwrap());
+        let mut response = data(17);
+        let err = limit_response(&mut response, 16, false).unwrap_err();
+        assert!(matches!(err, crate::Error::Protocol(_)));
+    }
+
+    #[test]
+    fn truncate_above_limit() {
+        let mut response = data(16);
+        assert!(!limit_response(&mut response, 16, true).unwrap());
+        let mut response = data(17);
+        assert!(limit_response(&mut response, 16, true).unwrap());
+        assert_eq!(payload_size(&response), 16);
+
+        let mut channels = std::collections::HashMap::new();
+        channels.insert("D0".to_string(), vec![0; 10]);
+        channels.insert("D1".to_string(), vec![0; 10]);
+        let mut response = Response::Sigrok(SigrokResponse::Data(SigrokData {
+            tsample: 1e-6,
+            length: 10,
+            channels,
+        }));
+        assert!(limit_response(&mut response, 16, true).unwrap());
+        match response {
+            Response::Sigrok(SigrokResponse::Data(data)) => {
+                assert_eq!(data.length, 8);
+                assert!(data.channels.values().all(|x| x.len() == 8));
+            }
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    #[test]
+    fn limit_read_all() {
+        let mut req = Request::Bytes {
+            instrument: comsrv_protocol::ByteStreamInstrument::Loopback(comsrv_protocol::LoopbackAddress {
+                name: "limit".to_string(),
+            }),
+            request: ByteStreamRequest::ReadAll,
+            lock: None,
+            truncate: false,
+        };
+        limit_request(&mut req, 16);
+        assert!(matches!(
+            req,
+            Request::Bytes {
+                request: ByteStreamRequest::ReadAllMax { max_bytes: 17 },
+                ..
+            }
+        ));
+    }
+}
@@ -1,10 +1,13 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::process::exit;
+use std::time::Duration;
 
 use clap::{crate_authors, crate_version, App as ClapApp, Arg};
 use tokio::runtime::Runtime;
 
 use comsrv::app::App;
+use comsrv::cli::{self, DEFAULT_URL};
+use comsrv::{Request, Response};
 use env_logger::Env;
 
 fn main() {
@@ -26,13 +29,126 @@ fn main() {
                 .default_value("5903")
                 .help("Define the port to listen on for HTTP."),
         )
+        .arg(Arg::with_name("bind").long("bind").default_value("0.0.0.0").help(
+            "Address to listen on. Use `127.0.0.1` to only accept local connections. \
+                     IPv6 addresses such as `::1` are accepted, `::` also accepts IPv4 connections.",
+        ))
         .arg(
             Arg::with_name("broadcast_reqrep")
                 .long("broadcast-requests")
                 .short('b')
                 .help("Broadcast requests back to RPC bus"),
         )
+        .arg(
+            Arg::with_name("drain-timeout")
+                .long("drain-timeout")
+                .default_value("5")
+                .help("Seconds granted to in-flight requests to complete on shutdown."),
+        )
+        .arg(
+            Arg::with_name("max-message-size")
+                .long("max-message-size")
+                .takes_value(true)
+                .help("Reject requests larger than the given number of bytes."),
+        )
+        .arg(
+            Arg::with_name("max-response-size")
+                .long("max-response-size")
+                .takes_value(true)
+                .help("Fail or truncate reads returning more than the given number of bytes."),
+        )
+        .arg(
+            Arg::with_name("can-bitrate")
+                .long("can-bitrate")
+                .takes_value(true)
+                .help("Bitrate of PCAN instruments which do not specify one."),
+        )
+        .arg(
+            Arg::with_name("echo-requests")
+                .long("echo-requests")
+                .help("Wrap each response with the id and a summary of its request."),
+        )
+        .arg(
+            Arg::with_name("coalesce-reads")
+                .long("coalesce-reads")
+                .help("Let identical concurrent ModBus reads share a single transaction."),
+        )
+        .arg(
+            Arg::with_name("no-auto-drop")
+                .long("no-auto-drop")
+                .help("Keep idle connections open instead of dropping them after a delay, e.g. for debugging."),
+        )
+        .arg(
+            Arg::with_name("replay-buffer")
+                .long("replay-buffer")
+                .takes_value(true)
+                .help("Keep the given number of recent broadcasts for clients connecting late."),
+        )
+        .arg(
+            Arg::with_name("request-log")
+                .long("request-log")
+                .takes_value(true)
+                .help("Append all requests and responses to the given file in JSON-lines format."),
+        )
+        .arg(
+            Arg::with_name("request-log-size")
+                .long("request-log-size")
+                .default_value("10000000")
+                .help("Rotate the request log once it exceeds the given number of bytes."),
+        )
+        .arg(
+            Arg::with_name("recording-dir")
+                .long("recording-dir")
+                .takes_value(true)
+                .help("Enable CAN recording and replay. Files are stored in and read from the given directory."),
+        )
+        .arg(
+            Arg::with_name("idle-ttl")
+                .long("idle-ttl")
+                .takes_value(true)
+                .help("Drop instruments which did not receive a request for the given number of seconds."),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("Load aliases and default options from the given JSON file."),
+        )
         .arg(Arg::with_name("verbose").long("verbose").short('v').help("Log verbose output"))
+        .subcommand(
+            ClapApp::new("query")
+                .about("Send a request to a running comsrv and print the response as JSON.")
+                .arg(
+                    Arg::with_name("url")
+                        .required(true)
+                        .help("URL of the comsrv, e.g. `ws://127.0.0.1:5902`."),
+                )
+                .arg(Arg::with_name("request").required(true).help(
+                    "Request encoded as JSON or one of `version`, `list-instruments`, `list-serial-ports`, \
+                     `list-hid-devices`, `list-ftdi-devices`, `list-can-devices`, `list-aliases`, `drop-all`, `self-test`.",
+                ))
+                .arg(timeout_arg()),
+        )
+        .subcommand(
+            ClapApp::new("scpi")
+                .about("Send a SCPI command to an instrument using a running comsrv and print the response as JSON.")
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .default_value(DEFAULT_URL)
+                        .help("URL of the comsrv."),
+                )
+                .arg(Arg::with_name("addr").required(true).help(
+                    "VXI-11 host or VISA resource of the instrument. \
+                     Use a `vxi::` or `visa::` prefix to select the instrument type explicitly.",
+                ))
+                .arg(
+                    Arg::with_name("command")
+                        .required(true)
+                        .help("SCPI command. Commands ending with `?` are queries."),
+                )
+                .arg(timeout_arg()),
+        )
         .get_matches();
 
     let broadcast_reqrep = matches.is_present("broadcast_reqrep");
@@ -43,6 +159,27 @@ fn main() {
         env_logger::init();
     }
 
+    match matches.subcommand() {
+        Some(("query", args)) => {
+            let request = cli::parse_query(args.value_of("request").unwrap());
+            exit(run_client(args.value_of("url").unwrap(), request, parse_timeout(args)));
+        }
+        Some(("scpi", args)) => {
+            let request = cli::scpi_request(args.value_of("addr").unwrap(), args.value_of("command").unwrap());
+            exit(run_client(args.value_of("url").unwrap(), Ok(request), parse_timeout(args)));
+        }
+        _ => {}
+    }
+
+    let bind = matches.value_of("bind").unwrap();
+    let bind = match parse_bind_address(bind) {
+        Ok(bind) => bind,
+        Err(_) => {
+            println!("Cannot parse `{}` as an IP address.", bind);
+            exit(1);
+        }
+    };
+
     let ws_port = matches.value_of("port").map(|x| match x.parse::<u16>() {
         Ok(port) => port,
         Err(_) => {
@@ -59,28 +196,125 @@ fn main() {
         }
     });
 
+    let drain_timeout = matches.value_of("drain-timeout").map(|x| match x.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        _ => {
+            println!("Cannot parse `{}` as a timeout in seconds.", x);
+            exit(1);
+        }
+    });
+
+    let max_message_size = matches.value_of("max-message-size").map(|x| match x.parse::<usize>() {
+        Ok(size) => size,
+        Err(_) => {
+            println!("Cannot parse `{}` as a message size.", x);
+            exit(1);
+        }
+    });
+
+    let max_response_size = matches.value_of("max-response-size").map(|x| match x.parse::<usize>() {
+        Ok(size) => size,
+        Err(_) => {
+            println!("Cannot parse `{}` as a response size.", x);
+            exit(1);
+        }
+    });
+
+    let can_bitrate = matches.value_of("can-bitrate").map(|x| match x.parse::<u32>() {
+        Ok(bitrate) if bitrate > 0 => bitrate,
+        _ => {
+            println!("Cannot parse `{}` as a bitrate.", x);
+            exit(1);
+        }
+    });
+
+    let replay_buffer = matches.value_of("replay-buffer").map(|x| match x.parse::<usize>() {
+        Ok(capacity) => capacity,
+        Err(_) => {
+            println!("Cannot parse `{}` as a number of messages.", x);
+            exit(1);
+        }
+    });
+
+    let request_log_size = matches.value_of("request-log-size").map(|x| match x.parse::<u64>() {
+        Ok(size) => size,
+        Err(_) => {
+            println!("Cannot parse `{}` as a file size.", x);
+            exit(1);
+        }
+    });
+
+    let idle_ttl = matches.value_of("idle-ttl").map(|x| match x.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        _ => {
+            println!("Cannot parse `{}` as a timeout in seconds.", x);
+            exit(1);
+        }
+    });
+
     let rt = Runtime::new().unwrap();
     rt.block_on(async move {
-        let (app, rx) = App::new();
+        let (mut app, rx) = App::new();
+        if let Some(drain_timeout) = drain_timeout {
+            app.drain_timeout = drain_timeout;
+        }
+        app.max_message_size = max_message_size;
+        app.max_response_size = max_response_size;
+        app.default_can_bitrate = can_bitrate;
+        app.echo_requests = matches.is_present("echo-requests");
+        app.coalesce_reads = matches.is_present("coalesce-reads");
+        if matches.is_present("no-auto-drop") {
+            app.disable_auto_drop();
+        }
+        if let Some(capacity) = replay_buffer {
+            if let Err(err) = app.enable_replay_buffer(capacity).await {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+        if let (Some(path), Some(max_size)) = (matches.value_of("request-log"), request_log_size) {
+            if let Err(err) = app.enable_request_log(path.into(), max_size) {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+        if let Some(config) = matches.value_of("config") {
+            if let Err(err) = app.load_config(config.into()) {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+        if let Some(dir) = matches.value_of("recording-dir") {
+            if let Err(err) = app.enable_can_recording(dir.into()) {
+                println!("{}", err);
+                exit(1);
+            }
+        }
         app.server.enable_broadcast_reqrep(broadcast_reqrep);
+        if let Some(ttl) = idle_ttl {
+            if let Err(err) = app.enable_idle_sweep(ttl) {
+                println!("{}", err);
+                exit(1);
+            }
+        }
 
         if let Some(ws_port) = ws_port {
-            let ws_addr: SocketAddr = format!("0.0.0.0:{}", ws_port).parse().unwrap();
+            let ws_addr = SocketAddr::new(bind, ws_port);
             println!("Listening on ws://{}", ws_addr);
-            app.server.listen_ws(&ws_addr).await.expect("Failed to listen on WebSocket");
+            app.listen_ws(&ws_addr).await.expect("Failed to listen on WebSocket");
         }
 
         if let Some(http_port) = http_port {
-            let http_addr: SocketAddr = format!("0.0.0.0:{}", http_port).parse().unwrap();
+            let http_addr = SocketAddr::new(bind, http_port);
             println!("Listening on http://{}", http_addr);
-            app.server.listen_http(&http_addr).await;
+            app.listen_http(&http_addr).await.expect("Failed to listen on HTTP");
         }
 
         let _ = ctrlc::set_handler({
             let app = app.clone();
             move || {
                 log::debug!("Ctrl-C received, shutting down.");
-                app.server.shutdown();
+                app.shutdown();
             }
         });
 
@@ -88,3 +322,78 @@ fn main() {
         log::debug!("Application quitting.");
     });
 }
+
+fn timeout_arg() -> Arg<'static> {
+    Arg::with_name("timeout")
+        .long("timeout")
+        .default_value("3")
+        .help("Seconds to wait for the connection and the response.")
+}
+
+fn parse_timeout(args: &clap::ArgMatches) -> Duration {
+    let timeout = args.value_of("timeout").unwrap();
+    match timeout.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        _ => {
+            eprintln!("Cannot parse `{}` as a timeout in seconds.", timeout);
+            exit(1);
+        }
+    }
+}
+
+/// Send `request` to the comsrv at `url` and print the response as JSON. Returns the exit code of the process.
+fn run_client(url: &str, request: comsrv::Result<Request>, timeout: Duration) -> i32 {
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    let rt = Runtime::new().unwrap();
+    match rt.block_on(cli::query(url, request, timeout)) {
+        Ok(response) => {
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+            if matches!(response, Response::Error(_)) {
+                1
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/// Parse the `--bind` argument. IPv6 addresses may be enclosed in brackets, e.g. `[::1]`.
+fn parse_bind_address(bind: &str) -> Result<IpAddr, std::net::AddrParseError> {
+    let bind = bind.trim();
+    let bind = bind.strip_prefix('[').and_then(|x| x.strip_suffix(']')).unwrap_or(bind);
+    bind.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn bind_address() {
+        let addr = parse_bind_address("127.0.0.1").unwrap();
+        assert_eq!(SocketAddr::new(addr, 5902), "127.0.0.1:5902".parse().unwrap());
+        assert!(parse_bind_address("0.0.0.0").unwrap().is_unspecified());
+        assert!(parse_bind_address("localhost").is_err());
+        assert!(parse_bind_address("127.0.0.1:5902").is_err());
+        assert!(parse_bind_address("256.0.0.1").is_err());
+    }
+
+    #[test]
+    fn bind_address_ipv6() {
+        assert_eq!(parse_bind_address("::1").unwrap(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(parse_bind_address("[::1]").unwrap(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert!(parse_bind_address("[::]").unwrap().is_unspecified());
+        assert!(parse_bind_address("[::1").is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! Enforces the maximum size of incoming messages, which is configured with `--max-message-size`.
+//!
+//! `broadcast_wsrpc` receives and deserializes a whole message before it hands the request to the [`crate::app::App`],
+//! hence checking the size of a request does not prevent oversized messages from being buffered. Instead, the
+//! RPC server listens on a loopback address and the public connections are forwarded to it. While forwarding, the
+//! WebSocket frame headers and HTTP request headers sent by the client are inspected and the connection is closed
+//! as soon as a message would exceed the limit, before its payload is forwarded.
+//!
+//! WebSocket connections are always forwarded, such that the notifications sent to them can be filtered as
+//! described in [`crate::subscription`]. HTTP connections to the unspecified IPv6 address are forwarded the same
+//! way, even without a limit, such that the public listener can be bound dual-stack, which cannot be configured on
+//! the listeners of the RPC server.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+use crate::subscription::Subscription;
+
+/// Maximum size of the headers of an HTTP request or of the WebSocket handshake
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Size of the buffer used to forward data sent by the client
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Pause after a failed `accept()`, such that persistent errors, e.g. running out of file descriptors, do not
+/// make the listener spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    WebSocket,
+    Http,
+}
+
+/// Select a free port on the loopback interface, on which the RPC server listens for forwarded connections.
+pub fn loopback_addr() -> io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+/// Bind a listener to `addr`. IPv6 listeners clear `IPV6_V6ONLY` such that `::` also accepts IPv4 connections,
+/// regardless of the default of the system.
+pub fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accept connections on `listener` and forward them to `upstream`. Connections sending a message larger than
+/// `max_size` bytes are closed. The notifications sent to WebSocket connections are filtered by their
+/// [`Subscription`].
+pub fn forward(listener: TcpListener, upstream: SocketAddr, protocol: Protocol, max_size: Option<usize>) {
+    task::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(x) => x,
+                Err(err) => {
+                    log::warn!("Cannot accept connection: {}", err);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            task::spawn(async move {
+                if let Err(err) = forward_connection(stream, upstream, protocol, max_size).await {
+                    log::warn!("Closing connection from {}: {}", peer, err);
+                }
+            });
+        }
+    });
+}
+
+async fn forward_connection(
+    client: TcpStream,
+    upstream: SocketAddr,
+    protocol: Protocol,
+    max_size: Option<usize>,
+) -> io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    let _ = client.set_nodelay(true);
+    let _ = server.set_nodelay(true);
+    if max_size.is_none() && protocol == Protocol::Http {
+        let mut client = client;
+        return tokio::io::copy_bidirectional(&mut client, &mut server).await.map(|_| ());
+    }
+    let (mut client_rx, mut client_tx) = client.into_split();
+    let (mut server_rx, mut server_tx) = server.into_split();
+
+    let downstream = async {
+        let mut subscription = Subscription::default();
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = server_rx.read(&mut buf).await?;
+            if n == 0 {
+                return client_tx.shutdown().await;
+            }
+            if protocol == Protocol::WebSocket {
+                client_tx.write_all(&subscription.process(&buf[..n])).await?;
+            } else {
+                client_tx.write_all(&buf[..n]).await?;
+            }
+        }
+    };
+    let upstream = async {
+        let mut limit = max_size.map(|x| MessageLimit::new(protocol, x));
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = client_rx.read(&mut buf).await?;
+            if n == 0 {
+                return server_tx.shutdown().await;
+            }
+            if let Some(limit) = &mut limit {
+                limit
+                    .check(&buf[..n])
+                    .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+            }
+            server_tx.write_all(&buf[..n]).await?;
+        }
+    };
+    tokio::pin!(downstream, upstream);
+    tokio::select! {
+        ret = &mut downstream => ret,
+        ret = &mut upstream => {
+            ret?;
+            downstream.await
+        }
+    }
+}
+
+enum State {
+    /// Reading the headers of an HTTP request or of the WebSocket handshake
+    Headers,
+    /// Reading the header of a WebSocket frame
+    FrameHeader,
+    /// Skipping the payload of a frame or the body of a request
+    Payload { remaining: u64 },
+}
+
+/// Follows the data sent by a client to detect messages exceeding the limit by their headers.
+struct MessageLimit {
+    protocol: Protocol,
+    max_size: u64,
+    state: State,
+    header: Vec<u8>,
+    /// Size of the fragments of the current WebSocket message
+    message_size: u64,
+}
+
+impl MessageLimit {
+    fn new(protocol: Protocol, max_size: usize) -> Self {
+        Self {
+            protocol,
+            max_size: max_size as u64,
+            state: State::Headers,
+            header: Vec::new(),
+            message_size: 0,
+        }
+    }
+
+    /// Process the next chunk of `data` sent by the client. Fails if a message exceeds the limit.
+    fn check(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            match self.state {
+                State::Payload { remaining } => {
+                    let n = remaining.min(data.len() as u64);
+                    data = &data[n as usize..];
+                    self.state = if n == remaining {
+                        self.next_header()
+                    } else {
+                        State::Payload {
+                            remaining: remaining - n,
+                        }
+                    };
+                }
+                State::Headers => {
+                    self.header.push(data[0]);
+                    data = &data[1..];
+                    if self.header.ends_with(b"\r\n\r\n") {
+                        self.state = self.headers_complete()?;
+                        self.header.clear();
+                    } else if self.header.len() > MAX_HEADER_SIZE {
+                        return Err(format!("Headers exceed {} bytes.", MAX_HEADER_SIZE));
+                    }
+                }
+                State::FrameHeader => {
+                    self.header.push(data[0]);
+                    data = &data[1..];
+                    if let Some(state) = self.frame_header_complete()? {
+                        self.state = state;
+                        self.header.clear();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_header(&self) -> State {
+        match self.protocol {
+            Protocol::WebSocket => State::FrameHeader,
+            Protocol::Http => State::Headers,
+        }
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), String> {
+        if size > self.max_size {
+            Err(format!(
+                "Message size of {} bytes exceeds limit of {} bytes.",
+                size, self.max_size
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn headers_complete(&self) -> Result<State, String> {
+        if self.protocol == Protocol::WebSocket {
+            // the handshake request does not carry a body
+            return Ok(State::FrameHeader);
+        }
+        let headers = String::from_utf8_lossy(&self.header);
+        let mut length = 0;
+        for line in headers.split("\r\n").skip(1) {
+            let (name, value) = match line.split_once(':') {
+                Some(x) => x,
+                None => continue,
+            };
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid Content-Length `{}`.", value.trim()))?;
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err("Requests without Content-Length are not accepted with a message size limit.".to_string());
+            }
+        }
+        self.check_size(length)?;
+        if length == 0 {
+            Ok(State::Headers)
+        } else {
+            Ok(State::Payload { remaining: length })
+        }
+    }
+
+    /// Returns the next state once the frame header is complete. Fails if the frame exceeds the limit.
+    fn frame_header_complete(&mut self) -> Result<Option<State>, String> {
+        let header = &self.header;
+        if header.len() < 2 {
+            return Ok(None);
+        }
+        let masked = header[1] & 0x80 != 0;
+        let (ext_len, length) = match header[1] & 0x7F {
+            126 => (2, None),
+            127 => (8, None),
+            x => (0, Some(x as u64)),
+        };
+        let header_len = 2 + ext_len + if masked { 4 } else { 0 };
+        if header.len() < header_len {
+            return Ok(None);
+        }
+        let length = length.unwrap_or_else(|| header[2..2 + ext_len].iter().fold(0, |acc, x| (acc << 8) | *x as u64));
+        let opcode = header[0] & 0x0F;
+        // control frames may interleave fragmented messages and are limited to 125 bytes
+        if opcode < 0x8 {
+            if opcode != 0 {
+                self.message_size = 0;
+            }
+            self.message_size = self.message_size.saturating_add(length);
+            self.check_size(self.message_size)?;
+        }
+        if length == 0 {
+            Ok(Some(State::FrameHeader))
+        } else {
+            Ok(Some(State::Payload { remaining: length }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n";
+
+    /// Build a masked WebSocket frame header with the given opcode and payload length.
+    fn frame(fin: bool, opcode: u8, length: u64) -> Vec<u8> {
+        let mut ret = vec![if fin { 0x80 } else { 0 } | opcode];
+        if length < 126 {
+            ret.push(0x80 | length as u8);
+        } else if length <= u16::MAX as u64 {
+            ret.push(0x80 | 126);
+            ret.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            ret.push(0x80 | 127);
+            ret.extend_from_slice(&length.to_be_bytes());
+        }
+        ret.extend_from_slice(&[1, 2, 3, 4]);
+        ret
+    }
+
+    #[test]
+    fn websocket_frames() {
+        let mut limit = MessageLimit::new(Protocol::WebSocket, 1024);
+        limit.check(HANDSHAKE).unwrap();
+        let mut data = frame(true, 1, 1024);
+        data.extend(vec![0; 1024]);
+        data.extend(frame(true, 9, 4));
+        data.extend([0; 4]);
+        // feed the data in small chunks, splitting headers
+        for chunk in data.chunks(3) {
+            limit.check(chunk).unwrap();
+        }
+        let mut data = frame(true, 2, 1025);
+        assert!(limit.check(&data[..1]).is_ok());
+        assert!(limit.check(&data.split_off(1)).is_err());
+
+        let mut limit = MessageLimit::new(Protocol::WebSocket, 1024);
+        limit.check(HANDSHAKE).unwrap();
+        assert!(limit.check(&frame(true, 2, 1 << 40)).is_err());
+    }
+
+    #[test]
+    fn websocket_fragments() {
+        let mut limit = MessageLimit::new(Protocol::WebSocket, 1024);
+        limit.check(HANDSHAKE).unwrap();
+        let mut data = frame(false, 1, 1000);
+        data.extend(vec![0; 1000]);
+        data.extend(frame(true, 10, 0));
+        limit.check(&data).unwrap();
+        assert!(limit.check(&frame(true, 0, 100)).is_err());
+
+        let mut limit = MessageLimit::new(Protocol::WebSocket, 1024);
+        limit.check(HANDSHAKE).unwrap();
+        let mut data = frame(true, 1, 1000);
+        data.extend(vec![0; 1000]);
+        data.extend(frame(true, 1, 1000));
+        limit.check(&data).unwrap();
+    }
+
+    #[test]
+    fn http_requests() {
+        let mut limit = MessageLimit::new(Protocol::Http, 10);
+        let mut data = b"POST / HTTP/1.1\r\ncontent-length: 10\r\n\r\n0123456789".to_vec();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        limit.check(&data).unwrap();
+        assert!(limit.check(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n").is_err());
+
+        let mut limit = MessageLimit::new(Protocol::Http, 10);
+        assert!(limit.check(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+
+        let mut limit = MessageLimit::new(Protocol::Http, 10);
+        assert!(limit.check(&vec![b'a'; MAX_HEADER_SIZE + 1]).is_err());
+    }
+}
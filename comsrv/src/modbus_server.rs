@@ -0,0 +1,163 @@
+//! Manages the ModBus TCP servers started with
+//! [`Request::StartModbusServer`](comsrv_protocol::Request::StartModbusServer).
+//!
+//! Each server serves its own register map to all masters connected to it. Writes of the masters update the map,
+//! such that they are visible to subsequent reads.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use comsrv_protocol::ModBusRegisterMap;
+use tokio::net::TcpListener;
+use tokio::task::{self, JoinHandle};
+
+use crate::protocol::modbus::server;
+
+/// Pause after a failed `accept()`, such that persistent errors do not make the server spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+struct Listener {
+    task: JoinHandle<()>,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Listener {
+    fn stop(self) {
+        self.task.abort();
+        for connection in self.connections.lock().unwrap().drain(..) {
+            connection.abort();
+        }
+    }
+}
+
+/// The running servers, indexed by the address they listen on.
+#[derive(Default)]
+pub struct ModBusServers(Mutex<HashMap<String, Listener>>);
+
+impl ModBusServers {
+    /// Listen on `bind` and serve `register_map`. Returns the address the server listens on.
+    pub async fn start(&self, bind: &str, register_map: ModBusRegisterMap) -> crate::Result<String> {
+        let listener = TcpListener::bind(bind).await.map_err(crate::Error::transport)?;
+        let addr = listener.local_addr().map_err(crate::Error::transport)?.to_string();
+        log::info!("Serving ModBus register map on {}", addr);
+        let map = Arc::new(Mutex::new(register_map));
+        let connections: Arc<Mutex<Vec<JoinHandle<()>>>> = Default::default();
+        let connections_copy = connections.clone();
+        let listen_addr = addr.clone();
+        let task = task::spawn(async move {
+            loop {
+                let (mut stream, peer) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        log::warn!("Cannot accept ModBus connection on {}: {}", listen_addr, err);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let map = map.clone();
+                let connection = task::spawn(async move {
+                    if let Err(err) = server::serve(&mut stream, &map).await {
+                        log::debug!("Closing ModBus connection of {}: {}", peer, err);
+                    }
+                });
+                let mut connections = connections_copy.lock().unwrap();
+                connections.retain(|x| !x.is_finished());
+                connections.push(connection);
+            }
+        });
+        let server = Listener { task, connections };
+        if let Some(previous) = self.0.lock().unwrap().insert(addr.clone(), server) {
+            previous.stop();
+        }
+        Ok(addr)
+    }
+
+    /// Stop the server listening on `addr` and close its connections.
+    pub fn stop(&self, addr: &str) -> crate::Result<()> {
+        match self.0.lock().unwrap().remove(addr) {
+            Some(server) => {
+                server.stop();
+                Ok(())
+            }
+            None => Err(crate::Error::argument(anyhow!("No ModBus server listening on `{}`.", addr))),
+        }
+    }
+
+    /// Stop all servers and close their connections.
+    pub fn stop_all(&self) {
+        for (addr, server) in self.0.lock().unwrap().drain() {
+            log::info!("Stopping ModBus server on {}", addr);
+            server.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{ModBusProtocol, ModBusRequest, ModBusResponse};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn read_register() {
+        let servers = ModBusServers::default();
+        let map = ModBusRegisterMap {
+            holdings: vec![10, 20, 30],
+            ..Default::default()
+        };
+        let addr = servers.start("127.0.0.1:0", map).await.unwrap();
+
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        let request = ModBusRequest::ReadHolding { addr: 1, cnt: 2 };
+        let ret =
+            crate::protocol::modbus::handle(timeout, 1, ModBusProtocol::Tcp, Duration::ZERO, request, &mut stream)
+                .await;
+        assert!(matches!(ret, Ok(ModBusResponse::Number(x)) if x == [20, 30]));
+
+        let request = ModBusRequest::WriteRegisters {
+            addr: 0,
+            values: vec![11],
+        };
+        let ret =
+            crate::protocol::modbus::handle(timeout, 1, ModBusProtocol::Tcp, Duration::ZERO, request, &mut stream)
+                .await;
+        assert!(matches!(ret, Ok(ModBusResponse::Done)));
+        let request = ModBusRequest::ReadHolding { addr: 0, cnt: 1 };
+        let ret =
+            crate::protocol::modbus::handle(timeout, 1, ModBusProtocol::Tcp, Duration::ZERO, request, &mut stream)
+                .await;
+        assert!(matches!(ret, Ok(ModBusResponse::Number(x)) if x == [11]));
+
+        let request = ModBusRequest::ReadHolding { addr: 2, cnt: 2 };
+        let ret =
+            crate::protocol::modbus::handle(timeout, 1, ModBusProtocol::Tcp, Duration::ZERO, request, &mut stream)
+                .await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+
+        servers.stop(&addr).unwrap();
+        assert!(servers.stop(&addr).is_err());
+    }
+
+    #[tokio::test]
+    async fn stop_all() {
+        let servers = ModBusServers::default();
+        let addr = servers.start("127.0.0.1:0", ModBusRegisterMap::default()).await.unwrap();
+        assert!(TcpStream::connect(&addr).await.is_ok());
+        servers.stop_all();
+        assert!(servers.stop(&addr).is_err());
+        // the listener is closed once the aborted task is dropped
+        let mut refused = false;
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(refused);
+    }
+}
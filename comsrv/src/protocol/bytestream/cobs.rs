@@ -6,6 +6,9 @@ use std::{io, pin::Pin};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Default limit for the length of an encoded COBS frame, excluding the delimiter.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
 pub struct StreamingCobsDecoder<T: AsyncRead> {
     inner: Pin<Box<T>>,
     buf: Vec<u8>,
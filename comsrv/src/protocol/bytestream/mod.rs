@@ -1,9 +1,11 @@
 /// This module implements a request handler for handling operation on a bytesstream-like
 /// instrument, for example TCP streams or serial ports
+use crate::app::Server;
 use crate::Error;
 use anyhow::anyhow;
-use cobs::{cobs_decode, cobs_encode};
-use comsrv_protocol::{ByteStreamRequest, ByteStreamResponse};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use cobs::{cobs_decode, cobs_encode, DEFAULT_MAX_FRAME_SIZE};
+use comsrv_protocol::{ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, Endian, Response, WriteBuffering};
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
@@ -13,19 +15,36 @@ use tokio::time;
 
 pub mod cobs;
 
+/// Size of the buffer used by [`read_all`] for each read from the underlying stream.
+pub const READ_ALL_BUFFER_SIZE: usize = 8192;
+
 struct ReadAll<'a, T: AsyncRead + Unpin> {
     inner: &'a mut T,
+    max_bytes: Option<usize>,
+    buf: Vec<u8>,
 }
 
 impl<'a, T: AsyncRead + Unpin> Future for ReadAll<'a, T> {
     type Output = io::Result<Vec<u8>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
         let mut ret = Vec::new();
         loop {
-            let mut buf_data = [0_u8; 1000];
-            let mut buf = ReadBuf::new(&mut buf_data);
-            match Pin::new(&mut self.inner).poll_read(cx, &mut buf) {
+            let len = match this.max_bytes {
+                Some(max_bytes) if ret.len() >= max_bytes => return Poll::Ready(Ok(ret)),
+                Some(max_bytes) => (max_bytes - ret.len()).min(this.buf.len()),
+                None => this.buf.len(),
+            };
+            let mut buf = ReadBuf::new(&mut this.buf[..len]);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    // End of stream. Return what was read so far, the next read reports the closed connection.
+                    if ret.is_empty() {
+                        return Poll::Ready(Err(connection_closed()));
+                    }
+                    return Poll::Ready(Ok(ret));
+                }
                 Poll::Ready(Ok(())) => {
                     ret.extend_from_slice(buf.filled());
                     continue;
@@ -41,21 +60,190 @@ impl<'a, T: AsyncRead + Unpin> Future for ReadAll<'a, T> {
     }
 }
 
+/// Error returned if the remote end closed the stream.
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")
+}
+
+/// Read all data currently buffered in the stream without waiting for more data to arrive.
+///
+/// Fails with [`io::ErrorKind::UnexpectedEof`] if the stream was closed and no more data is buffered.
 pub async fn read_all<T: AsyncRead + Unpin>(stream: &mut T) -> io::Result<Vec<u8>> {
-    let fut = ReadAll { inner: stream };
+    read_all_max(stream, None).await
+}
+
+/// Same as [`read_all`] but stops after `max_bytes`. The remaining data stays in the stream.
+pub async fn read_all_max<T: AsyncRead + Unpin>(stream: &mut T, max_bytes: Option<usize>) -> io::Result<Vec<u8>> {
+    let fut = ReadAll {
+        inner: stream,
+        max_bytes,
+        buf: vec![0; READ_ALL_BUFFER_SIZE],
+    };
     fut.await
 }
 
+/// Read data as it arrives and pass each chunk to `emit` until no data arrived for `idle` or `timeout` elapsed.
+/// Each chunk holds at most [`READ_ALL_BUFFER_SIZE`] bytes.
+pub async fn read_all_stream<T, F>(
+    stream: &mut T,
+    idle: std::time::Duration,
+    timeout: std::time::Duration,
+    mut emit: F,
+) -> crate::Result<()>
+where
+    T: AsyncRead + Unpin,
+    F: FnMut(Vec<u8>),
+{
+    let deadline = time::Instant::now() + timeout;
+    let mut buf = vec![0; READ_ALL_BUFFER_SIZE];
+    loop {
+        let idle_deadline = (time::Instant::now() + idle).min(deadline);
+        match time::timeout_at(idle_deadline, AsyncReadExt::read(stream, &mut buf)).await {
+            Ok(Ok(0)) => return Err(Error::transport(connection_closed())),
+            Ok(Ok(n)) => emit(buf[..n].to_vec()),
+            Ok(Err(err)) => return Err(Error::transport(err)),
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// Same as [`handle`] but additionally supports [`ByteStreamRequest::ReadAllStream`], which broadcasts
+/// the data read through `server` as originating from `sender`.
+pub async fn handle_broadcast<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut T,
+    req: ByteStreamRequest,
+    server: &Server,
+    sender: ByteStreamInstrument,
+) -> crate::Result<ByteStreamResponse> {
+    match req {
+        ByteStreamRequest::ReadAllStream { idle, timeout } => {
+            log::debug!("stream all bytes until idle for {:?}", idle);
+            read_all_stream(stream, idle.into(), timeout.into(), |data| {
+                server.broadcast(Response::Bytes(ByteStreamResponse::Chunk {
+                    sender: sender.clone(),
+                    data,
+                }))
+            })
+            .await?;
+            Ok(ByteStreamResponse::Done)
+        }
+        req => handle(stream, req).await,
+    }
+}
+
+/// Coalesces the data of [`ByteStreamRequest::Write`] requests according to [`WriteBuffering`].
+///
+/// The buffer is flushed before any other request is handled. The owner must call [`WriteBuffer::flush()`]
+/// once [`WriteBuffer::deadline()`] has passed.
+#[derive(Default)]
+pub struct WriteBuffer {
+    max_size: usize,
+    max_delay: std::time::Duration,
+    data: Vec<u8>,
+    deadline: Option<time::Instant>,
+}
+
+impl WriteBuffer {
+    pub fn configure(&mut self, config: &WriteBuffering) {
+        self.max_size = config.max_size as usize;
+        self.max_delay = config.max_delay.clone().into();
+    }
+
+    pub fn config(&self) -> WriteBuffering {
+        WriteBuffering {
+            max_size: self.max_size as u32,
+            max_delay: self.max_delay.into(),
+        }
+    }
+
+    /// Time at which the buffered data must be flushed, `None` if the buffer is empty
+    pub fn deadline(&self) -> Option<time::Instant> {
+        self.deadline
+    }
+
+    /// Discard the buffered data
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.deadline = None;
+    }
+
+    /// Write the buffered data to `stream`. If writing fails, the data remains buffered.
+    pub async fn flush<T: AsyncWrite + Unpin>(&mut self, stream: &mut T) -> crate::Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        log::debug!("flush {} buffered bytes", self.data.len());
+        if let Err(err) = AsyncWriteExt::write_all(stream, &self.data).await {
+            return Err(Error::transport(err));
+        }
+        self.clear();
+        AsyncWriteExt::flush(stream).await.map_err(Error::transport)
+    }
+
+    /// Same as [`handle_broadcast`] but buffers the data of [`ByteStreamRequest::Write`] requests.
+    pub async fn handle_broadcast<T: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut T,
+        req: ByteStreamRequest,
+        server: &Server,
+        sender: ByteStreamInstrument,
+    ) -> crate::Result<ByteStreamResponse> {
+        match req {
+            ByteStreamRequest::Write(data) if self.max_size > 0 => {
+                log::debug!("buffer write: {:?}", data);
+                self.data.extend_from_slice(&data);
+                if self.data.len() >= self.max_size {
+                    self.flush(stream).await?;
+                } else if self.deadline.is_none() {
+                    self.deadline = Some(time::Instant::now() + self.max_delay);
+                }
+                Ok(ByteStreamResponse::Done)
+            }
+            req => {
+                self.flush(stream).await?;
+                handle_broadcast(stream, req, server, sender).await
+            }
+        }
+    }
+}
+
+/// Check the arguments of `req` without performing any IO.
+pub fn validate(req: &ByteStreamRequest) -> crate::Result<()> {
+    match req {
+        ByteStreamRequest::WriteLine { term, .. }
+        | ByteStreamRequest::ReadLine { term, .. }
+        | ByteStreamRequest::QueryLine { term, .. } => check_term(*term),
+        ByteStreamRequest::ReadLengthPrefixed { prefix_len, .. } => check_prefix_len(*prefix_len),
+        ByteStreamRequest::CobsReadMax { max_frame_size, .. }
+        | ByteStreamRequest::CobsQuery {
+            max_frame_size: Some(max_frame_size),
+            ..
+        } => check_max_frame_size(*max_frame_size),
+        ByteStreamRequest::ModBus {
+            station_address,
+            protocol,
+            request,
+            ..
+        } => crate::protocol::modbus::validate(*station_address, *protocol, request),
+        _ => Ok(()),
+    }
+}
+
 pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut T,
     req: ByteStreamRequest,
 ) -> crate::Result<ByteStreamResponse> {
+    validate(&req)?;
     match req {
         ByteStreamRequest::Write(data) => {
             log::debug!("write: {:?}", data);
             AsyncWriteExt::write_all(stream, &data).await.map_err(Error::transport)?;
             Ok(ByteStreamResponse::Done)
         }
+        ByteStreamRequest::Flush => {
+            AsyncWriteExt::flush(stream).await.map_err(Error::transport)?;
+            Ok(ByteStreamResponse::Done)
+        }
         ByteStreamRequest::ReadExact { count, timeout } => {
             log::debug!("read exactly {} bytes", count);
             let mut data = vec![0; count as usize];
@@ -66,50 +254,93 @@ pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
             }?;
             Ok(ByteStreamResponse::Data(data))
         }
+        ByteStreamRequest::ReadUpTo { count, timeout } => {
+            log::debug!("read up to {} bytes", count);
+            let ret = read_up_to(stream, count as usize, timeout.into()).await?;
+            Ok(ByteStreamResponse::Data(ret))
+        }
         ByteStreamRequest::ReadAll => {
             log::debug!("read all bytes");
             let ret = read_all(stream).await?;
             Ok(ByteStreamResponse::Data(ret))
         }
+        ByteStreamRequest::ReadAllMax { max_bytes } => {
+            log::debug!("read all bytes, at most {}", max_bytes);
+            let ret = read_all_max(stream, Some(max_bytes as usize)).await?;
+            Ok(ByteStreamResponse::Data(ret))
+        }
+        ByteStreamRequest::ReadAllStream { .. } => {
+            Err(Error::argument(anyhow!("ReadAllStream is not supported by this instrument")))
+        }
+        ByteStreamRequest::ReadLengthPrefixed {
+            prefix_len,
+            endian,
+            max_len,
+            timeout,
+        } => {
+            log::debug!("read length prefixed message with {} byte prefix", prefix_len);
+            let fut = read_length_prefixed(stream, prefix_len, endian, max_len);
+            match time::timeout(timeout.into(), fut).await {
+                Ok(x) => x.map(ByteStreamResponse::Data),
+                Err(_) => Err(Error::protocol_timeout()),
+            }
+        }
         ByteStreamRequest::CobsWrite(data) => {
             let data = cobs_encode(&data);
             AsyncWriteExt::write_all(stream, &data).await?;
             Ok(ByteStreamResponse::Done)
         }
-        ByteStreamRequest::CobsRead(timeout) => match time::timeout(timeout.into(), cobs_read(stream)).await {
+        ByteStreamRequest::CobsRead(timeout) => {
+            match time::timeout(timeout.into(), cobs_read(stream, DEFAULT_MAX_FRAME_SIZE)).await {
+                Ok(x) => x,
+                Err(_) => Err(crate::Error::protocol_timeout()),
+            }
+        }
+        ByteStreamRequest::CobsReadMax {
+            timeout,
+            max_frame_size,
+        } => match time::timeout(timeout.into(), cobs_read(stream, max_frame_size as usize)).await {
             Ok(x) => x,
             Err(_) => Err(crate::Error::protocol_timeout()),
         },
-        ByteStreamRequest::CobsQuery { data, timeout } => {
-            read_all(stream).await?;
-            match time::timeout(timeout.into(), cobs_query(stream, data)).await {
+        ByteStreamRequest::CobsQuery {
+            data,
+            timeout,
+            flush_input,
+            max_frame_size,
+        } => {
+            if flush_input {
+                read_all(stream).await?;
+            }
+            let max_frame_size = max_frame_size.map_or(DEFAULT_MAX_FRAME_SIZE, |x| x as usize);
+            match time::timeout(timeout.into(), cobs_query(stream, data, flush_input, max_frame_size)).await {
                 Ok(x) => x,
                 Err(_) => Err(crate::Error::protocol_timeout()),
             }
         }
         ByteStreamRequest::WriteLine { mut line, term } => {
-            check_term(term)?;
             line.push(term as char);
             AsyncWriteExt::write_all(stream, line.as_bytes()).await?;
             Ok(ByteStreamResponse::Done)
         }
         ByteStreamRequest::ReadLine { timeout, term } => {
-            check_term(term)?;
             let ret = read_to_term_timeout(stream, term, timeout.into()).await?;
-            let ret = String::from_utf8(ret).map_err(|_| crate::Error::protocol(anyhow!("Cannot decode as UTF-8")))?;
+            let ret = String::from_utf8(ret).map_err(|x| crate::Error::invalid_utf8(x.into_bytes()))?;
             Ok(ByteStreamResponse::String(ret))
         }
         ByteStreamRequest::QueryLine {
             mut line,
             timeout,
             term,
+            flush_input,
         } => {
-            read_all(stream).await?;
-            check_term(term)?;
+            if flush_input {
+                read_all(stream).await?;
+            }
             line.push(term as char);
             AsyncWriteExt::write_all(stream, line.as_bytes()).await?;
             let ret = read_to_term_timeout(stream, term, timeout.into()).await?;
-            let ret = String::from_utf8(ret).map_err(|_| crate::Error::protocol(anyhow!("Cannot decode as UTF-8")))?;
+            let ret = String::from_utf8(ret).map_err(|x| crate::Error::invalid_utf8(x.into_bytes()))?;
             Ok(ByteStreamResponse::String(ret))
         }
         ByteStreamRequest::ReadToTerm { term, timeout } => {
@@ -120,20 +351,104 @@ pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
             timeout,
             station_address,
             protocol,
+            inter_frame_delay_ms,
             request,
         } => {
-            let ret =
-                crate::protocol::modbus::handle(timeout.into(), station_address, protocol, request, stream).await?;
+            let inter_frame_delay = std::time::Duration::from_millis(inter_frame_delay_ms as u64);
+            let ret = crate::protocol::modbus::handle(
+                timeout.into(),
+                station_address,
+                protocol,
+                inter_frame_delay,
+                request,
+                stream,
+            )
+            .await?;
             Ok(ByteStreamResponse::ModBus(ret))
         }
         ByteStreamRequest::Connect => Ok(ByteStreamResponse::Done),
         ByteStreamRequest::Disconnect => Ok(ByteStreamResponse::Done),
+        ByteStreamRequest::Close { flush } => {
+            if flush {
+                AsyncWriteExt::flush(stream).await.map_err(Error::transport)?;
+            }
+            Ok(ByteStreamResponse::Done)
+        }
+    }
+}
+
+/// Replace a `term` of 0 in line requests with the `default_term` of the instrument.
+pub fn apply_default_term(req: &mut ByteStreamRequest, default_term: Option<u8>) {
+    let default_term = match default_term {
+        Some(x) => x,
+        None => return,
+    };
+    match req {
+        ByteStreamRequest::WriteLine { term, .. }
+        | ByteStreamRequest::ReadLine { term, .. }
+        | ByteStreamRequest::QueryLine { term, .. }
+            if *term == 0 =>
+        {
+            *term = default_term
+        }
+        _ => {}
+    }
+}
+
+/// Read up to `count` bytes, returning as soon as any data is available. If no data arrives
+/// within `timeout`, an empty buffer is returned.
+async fn read_up_to<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    count: usize,
+    timeout: std::time::Duration,
+) -> crate::Result<Vec<u8>> {
+    let mut ret = vec![0; count];
+    if count == 0 {
+        return Ok(ret);
+    }
+    // `time::timeout()` polls the read once before checking the deadline, thus a zero timeout
+    // still returns buffered data.
+    match time::timeout(timeout, AsyncReadExt::read(stream, &mut ret)).await {
+        Ok(Ok(0)) => return Err(Error::transport(connection_closed())),
+        Ok(Ok(n)) => ret.truncate(n),
+        Ok(Err(err)) => return Err(Error::transport(err)),
+        Err(_) => ret.clear(),
     }
+    Ok(ret)
+}
+
+/// Read a payload preceded by its length, encoded with `prefix_len` bytes in the given byte order.
+async fn read_length_prefixed<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    prefix_len: u8,
+    endian: Endian,
+    max_len: u32,
+) -> crate::Result<Vec<u8>> {
+    check_prefix_len(prefix_len)?;
+    let mut prefix = vec![0; prefix_len as usize];
+    AsyncReadExt::read_exact(stream, &mut prefix).await?;
+    let len = match endian {
+        Endian::Little => LittleEndian::read_uint(&prefix, prefix.len()),
+        Endian::Big => BigEndian::read_uint(&prefix, prefix.len()),
+    };
+    if len > max_len as u64 {
+        return Err(Error::protocol(anyhow!(
+            "Message length {} exceeds the maximum of {} bytes",
+            len,
+            max_len
+        )));
+    }
+    let mut data = vec![0; len as usize];
+    AsyncReadExt::read_exact(stream, &mut data).await?;
+    Ok(data)
 }
 
 /// pop a u8 from a byte stream
 async fn pop<T: AsyncRead + Unpin>(stream: &mut T) -> crate::Result<u8> {
-    Ok(AsyncReadExt::read_u8(stream).await?)
+    AsyncReadExt::read_u8(stream).await.map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => Error::transport(connection_closed()),
+        _ => Error::transport(err),
+    })
 }
 
 async fn read_to_term_timeout<T: AsyncReadExt + Unpin>(
@@ -160,6 +475,24 @@ async fn read_to_term<T: AsyncReadExt + Unpin>(stream: &mut T, term: u8) -> crat
     Ok(ret)
 }
 
+fn check_prefix_len(prefix_len: u8) -> crate::Result<()> {
+    if !matches!(prefix_len, 1 | 2 | 4) {
+        return Err(Error::argument(anyhow!(
+            "Length prefix must be 1, 2 or 4 bytes but got {}",
+            prefix_len
+        )));
+    }
+    Ok(())
+}
+
+fn check_max_frame_size(max_frame_size: u32) -> crate::Result<()> {
+    if max_frame_size == 0 {
+        Err(crate::Error::argument(anyhow!("The maximum COBS frame size must not be zero.")))
+    } else {
+        Ok(())
+    }
+}
+
 fn check_term(term: u8) -> crate::Result<()> {
     if term == 0 || term > 128 {
         Err(crate::Error::argument(anyhow!("Invalid termination.")))
@@ -168,7 +501,9 @@ fn check_term(term: u8) -> crate::Result<()> {
     }
 }
 
-async fn cobs_read<T: AsyncRead + Unpin>(stream: &mut T) -> crate::Result<ByteStreamResponse> {
+/// Read a COBS frame. Fails with a protocol error if the encoded frame exceeds `max_frame_size`
+/// bytes, such that a line never sending a delimiter does not buffer forever.
+async fn cobs_read<T: AsyncRead + Unpin>(stream: &mut T, max_frame_size: usize) -> crate::Result<ByteStreamResponse> {
     let mut ret = Vec::new();
     // keep readings zeroes
     loop {
@@ -185,6 +520,12 @@ async fn cobs_read<T: AsyncRead + Unpin>(stream: &mut T) -> crate::Result<ByteSt
         if x == 0 {
             break;
         }
+        if ret.len() > max_frame_size {
+            return Err(crate::Error::protocol(anyhow!(
+                "COBS frame exceeds the maximum size of {} bytes",
+                max_frame_size
+            )));
+        }
     }
     // unwrap is save because we cancel above loop only in case we pushed x == 0
     let ret = cobs_decode(&ret);
@@ -194,9 +535,376 @@ async fn cobs_read<T: AsyncRead + Unpin>(stream: &mut T) -> crate::Result<ByteSt
 async fn cobs_query<T: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut T,
     data: Vec<u8>,
+    flush_input: bool,
+    max_frame_size: usize,
 ) -> crate::Result<ByteStreamResponse> {
-    let _ = read_all(stream).await.map_err(crate::Error::transport);
+    if flush_input {
+        let _ = read_all(stream).await.map_err(crate::Error::transport);
+    }
     let data = cobs_encode(&data);
     AsyncWriteExt::write_all(stream, &data).await.map_err(Error::transport)?;
-    cobs_read(stream).await
+    cobs_read(stream, max_frame_size).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::duplex;
+    use tokio::time::sleep;
+
+    /// Counts the writes to the wrapped stream
+    struct CountWrites<T> {
+        inner: T,
+        writes: usize,
+    }
+
+    impl<T: AsyncRead + Unpin> AsyncRead for CountWrites<T> {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> AsyncWrite for CountWrites<T> {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn buffered_writes() {
+        let (server, _rx) = Server::new();
+        let sender = ByteStreamInstrument::Tcp(comsrv_protocol::TcpInstrument::new("127.0.0.1", 0));
+        let (a, mut b) = duplex(1024);
+        let mut stream = CountWrites { inner: a, writes: 0 };
+        let mut buffer = WriteBuffer::default();
+        buffer.configure(&WriteBuffering {
+            max_size: 64,
+            max_delay: Duration::from_secs(10).into(),
+        });
+
+        for data in [b"ab", b"cd", b"ef"] {
+            let req = ByteStreamRequest::Write(data.to_vec());
+            let ret = buffer.handle_broadcast(&mut stream, req, &server, sender.clone()).await;
+            assert!(matches!(ret, Ok(ByteStreamResponse::Done)));
+        }
+        assert_eq!(stream.writes, 0);
+        assert!(buffer.deadline().is_some());
+
+        b.write_all(b"ok").await.unwrap();
+        let req = ByteStreamRequest::ReadExact {
+            count: 2,
+            timeout: Duration::from_millis(100).into(),
+        };
+        match buffer.handle_broadcast(&mut stream, req, &server, sender.clone()).await {
+            Ok(ByteStreamResponse::Data(x)) => assert_eq!(x, b"ok"),
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(stream.writes, 1);
+        assert!(buffer.deadline().is_none());
+        let mut buf = [0; 6];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"abcdef");
+
+        // exceeding `max_size` flushes immediately
+        let req = ByteStreamRequest::Write(vec![0; 64]);
+        buffer.handle_broadcast(&mut stream, req, &server, sender).await.unwrap();
+        assert_eq!(stream.writes, 2);
+    }
+
+    #[tokio::test]
+    async fn read_all_large() {
+        let data: Vec<u8> = (0..100_000_u32).map(|x| x as u8).collect();
+        let (mut a, mut b) = duplex(data.len());
+        b.write_all(&data).await.unwrap();
+        let ret = read_all(&mut a).await.unwrap();
+        assert_eq!(ret, data);
+        assert!(read_all(&mut a).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_all_stream_bursts() {
+        let (server, _rx) = Server::new();
+        let mut client = server.loopback().await;
+        let (mut a, mut b) = duplex(64);
+        let writer = tokio::spawn(async move {
+            for burst in 1..=3_u8 {
+                b.write_all(&[burst; 10]).await.unwrap();
+                sleep(Duration::from_millis(20)).await;
+            }
+            // keep the stream open until the reader idles
+            b
+        });
+        let sender = ByteStreamInstrument::Tcp(comsrv_protocol::TcpInstrument::new("127.0.0.1", 1234));
+        let req = ByteStreamRequest::ReadAllStream {
+            idle: Duration::from_millis(200).into(),
+            timeout: Duration::from_secs(5).into(),
+        };
+        let ret = handle_broadcast(&mut a, req, &server, sender).await.unwrap();
+        assert!(matches!(ret, ByteStreamResponse::Done));
+        let _b = writer.await.unwrap();
+
+        let mut chunks = Vec::new();
+        while chunks.iter().map(Vec::len).sum::<usize>() < 30 {
+            if let Some(broadcast_wsrpc::Response::Notify(Response::Bytes(ByteStreamResponse::Chunk {
+                data, ..
+            }))) = client.next().await
+            {
+                chunks.push(data);
+            }
+        }
+        assert!(chunks.len() > 1);
+        let expected: Vec<u8> = (1..=3).flat_map(|x| [x; 10]).collect();
+        assert_eq!(chunks.concat(), expected);
+    }
+
+    #[tokio::test]
+    async fn read_all_max_bytes() {
+        let data: Vec<u8> = (0..20_000_u32).map(|x| x as u8).collect();
+        let (mut a, mut b) = duplex(data.len());
+        b.write_all(&data).await.unwrap();
+        let ret = read_all_max(&mut a, Some(10_000)).await.unwrap();
+        assert_eq!(ret, &data[..10_000]);
+        let ret = read_all_max(&mut a, Some(3)).await.unwrap();
+        assert_eq!(ret, &data[10_000..10_003]);
+        let ret = read_all(&mut a).await.unwrap();
+        assert_eq!(ret, &data[10_003..]);
+    }
+
+    #[tokio::test]
+    async fn length_prefixed() {
+        let cases: Vec<(u8, Endian, Vec<u8>)> = vec![
+            (1, Endian::Little, vec![3]),
+            (1, Endian::Big, vec![3]),
+            (2, Endian::Little, vec![3, 0]),
+            (2, Endian::Big, vec![0, 3]),
+            (4, Endian::Little, vec![3, 0, 0, 0]),
+            (4, Endian::Big, vec![0, 0, 0, 3]),
+        ];
+        for (prefix_len, endian, prefix) in cases {
+            let (mut a, mut b) = duplex(64);
+            b.write_all(&prefix).await.unwrap();
+            b.write_all(&[1, 2, 3, 4]).await.unwrap();
+            let ret = read_length_prefixed(&mut a, prefix_len, endian, 16).await.unwrap();
+            assert_eq!(ret, &[1, 2, 3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_exceeds_max() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(&[0, 17]).await.unwrap();
+        let ret = read_length_prefixed(&mut a, 2, Endian::Big, 16).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+
+        let ret = read_length_prefixed(&mut a, 3, Endian::Big, 16).await;
+        assert!(matches!(ret, Err(crate::Error::Argument(_))));
+    }
+
+    #[tokio::test]
+    async fn default_term() {
+        let mut req = ByteStreamRequest::QueryLine {
+            line: "*IDN?".to_string(),
+            timeout: Duration::from_millis(100).into(),
+            term: 0,
+            flush_input: false,
+        };
+        apply_default_term(&mut req, None);
+        assert!(matches!(req, ByteStreamRequest::QueryLine { term: 0, .. }));
+        apply_default_term(&mut req, Some(b'\r'));
+        assert!(matches!(req, ByteStreamRequest::QueryLine { term: b'\r', .. }));
+        apply_default_term(&mut req, Some(b'\n'));
+        assert!(matches!(req, ByteStreamRequest::QueryLine { term: b'\r', .. }));
+
+        let (mut a, mut b) = duplex(64);
+        b.write_all(b"comsrv\r").await.unwrap();
+        let ret = handle(&mut a, req).await.unwrap();
+        assert!(matches!(ret, ByteStreamResponse::String(x) if x == "comsrv"));
+        let mut buf = [0; 6];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"*IDN?\r");
+    }
+
+    fn is_connection_closed(err: &crate::Error) -> bool {
+        match err {
+            crate::Error::Transport(comsrv_protocol::TransportError::Io(x)) => x.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn read_all_closed() {
+        let (mut a, mut b) = duplex(64);
+        assert!(read_all(&mut a).await.unwrap().is_empty());
+        b.write_all(b"abc").await.unwrap();
+        drop(b);
+        assert_eq!(read_all(&mut a).await.unwrap(), b"abc");
+        let err = read_all(&mut a).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = handle(&mut a, ByteStreamRequest::ReadAll).await.unwrap_err();
+        assert!(is_connection_closed(&err));
+        assert!(err.should_retry());
+    }
+
+    #[tokio::test]
+    async fn read_to_term_closed() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(b"abc").await.unwrap();
+        drop(b);
+        let err = read_to_term_timeout(&mut a, b'\n', Duration::from_secs(1)).await.unwrap_err();
+        assert!(is_connection_closed(&err));
+    }
+
+    #[tokio::test]
+    async fn read_up_to_closed() {
+        let (mut a, b) = duplex(64);
+        drop(b);
+        let err = read_up_to(&mut a, 10, Duration::from_millis(100)).await.unwrap_err();
+        assert!(is_connection_closed(&err));
+    }
+
+    #[tokio::test]
+    async fn query_line_keep_input() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(b"status\n").await.unwrap();
+        let req = ByteStreamRequest::QueryLine {
+            line: "*IDN?".to_string(),
+            timeout: Duration::from_millis(100).into(),
+            term: b'\n',
+            flush_input: false,
+        };
+        let ret = handle(&mut a, req).await.unwrap();
+        assert!(matches!(ret, ByteStreamResponse::String(x) if x == "status"));
+        let written = read_all(&mut b).await.unwrap();
+        assert_eq!(written, b"*IDN?\n");
+
+        b.write_all(b"status\n").await.unwrap();
+        let req = ByteStreamRequest::QueryLine {
+            line: "*IDN?".to_string(),
+            timeout: Duration::from_millis(100).into(),
+            term: b'\n',
+            flush_input: true,
+        };
+        let err = handle(&mut a, req).await.unwrap_err();
+        assert!(matches!(err, crate::Error::Protocol(comsrv_protocol::ProtocolError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn read_line_invalid_utf8() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(b"abc\xff\xfe\n").await.unwrap();
+        let req = ByteStreamRequest::ReadLine {
+            timeout: Duration::from_millis(100).into(),
+            term: b'\n',
+        };
+        let err = handle(&mut a, req).await.unwrap_err();
+
+        // the raw bytes survive the round trip over the wire
+        let json = serde_json::to_string(&err).unwrap();
+        let err: crate::Error = serde_json::from_str(&json).unwrap();
+        match err {
+            crate::Error::Protocol(comsrv_protocol::ProtocolError::InvalidUtf8 { data }) => {
+                assert_eq!(data, b"abc\xff\xfe")
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn cobs_read_max_frame_size() {
+        let (mut a, mut b) = duplex(64);
+        // encoded length of [1, 2, 3, 4] is 5 bytes, excluding the delimiter
+        b.write_all(&cobs_encode(&[1, 2, 3, 4])).await.unwrap();
+        match cobs_read(&mut a, 5).await.unwrap() {
+            ByteStreamResponse::Data(x) => assert_eq!(x, [1, 2, 3, 4]),
+            _ => panic!(),
+        }
+
+        b.write_all(&cobs_encode(&[1, 2, 3, 4, 5])).await.unwrap();
+        assert!(matches!(cobs_read(&mut a, 5).await, Err(crate::Error::Protocol(_))));
+
+        // a line never sending a delimiter fails instead of buffering forever
+        b.write_all(&[1; 32]).await.unwrap();
+        assert!(cobs_read(&mut a, 16).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cobs_read_configured_max_frame_size() {
+        let read = |max_frame_size| ByteStreamRequest::CobsReadMax {
+            timeout: Duration::from_millis(100).into(),
+            max_frame_size,
+        };
+        let (mut a, mut b) = duplex(64);
+        b.write_all(&cobs_encode(&[1, 2, 3, 4])).await.unwrap();
+        let ret = handle(&mut a, read(5)).await.unwrap();
+        assert!(matches!(ret, ByteStreamResponse::Data(x) if x == [1, 2, 3, 4]));
+        b.write_all(&cobs_encode(&[1, 2, 3, 4, 5])).await.unwrap();
+        assert!(matches!(handle(&mut a, read(5)).await, Err(crate::Error::Protocol(_))));
+        assert!(matches!(handle(&mut a, read(0)).await, Err(crate::Error::Argument(_))));
+
+        let (mut a, mut b) = duplex(64);
+        b.write_all(&cobs_encode(&[1, 2, 3, 4, 5])).await.unwrap();
+        let req = ByteStreamRequest::CobsQuery {
+            data: b"query".to_vec(),
+            timeout: Duration::from_millis(100).into(),
+            flush_input: false,
+            max_frame_size: Some(5),
+        };
+        assert!(matches!(handle(&mut a, req).await, Err(crate::Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn cobs_query_keep_input() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(&cobs_encode(b"status")).await.unwrap();
+        let req = ByteStreamRequest::CobsQuery {
+            data: b"query".to_vec(),
+            timeout: Duration::from_millis(100).into(),
+            flush_input: false,
+            max_frame_size: None,
+        };
+        let ret = handle(&mut a, req).await.unwrap();
+        assert!(matches!(ret, ByteStreamResponse::Data(x) if x == b"status"));
+    }
+
+    #[tokio::test]
+    async fn read_up_to_immediate() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(b"abcdef").await.unwrap();
+        let ret = read_up_to(&mut a, 4, Duration::from_millis(100)).await.unwrap();
+        assert_eq!(ret, b"abcd");
+        let ret = read_up_to(&mut a, 4, Duration::ZERO).await.unwrap();
+        assert_eq!(ret, b"ef");
+    }
+
+    #[tokio::test]
+    async fn read_up_to_delayed() {
+        let (mut a, mut b) = duplex(64);
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(20)).await;
+            b.write_all(b"abc").await.unwrap();
+            sleep(Duration::from_millis(200)).await;
+        });
+        let ret = read_up_to(&mut a, 10, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(ret, b"abc");
+    }
+
+    #[tokio::test]
+    async fn read_up_to_no_data() {
+        let (mut a, _b) = duplex(64);
+        let ret = read_up_to(&mut a, 10, Duration::from_millis(10)).await.unwrap();
+        assert!(ret.is_empty());
+        let ret = read_up_to(&mut a, 10, Duration::ZERO).await.unwrap();
+        assert!(ret.is_empty());
+    }
 }
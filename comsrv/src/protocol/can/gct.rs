@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 
 use super::crc::crc16;
-use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use comsrv_protocol::{
     CanMessage, DataFrame, GctMessage, MessageId, SysCtrlType, BROADCAST_ADDR, MSGTYPE_DDP, MSGTYPE_HEARTBEAT,
@@ -173,7 +172,8 @@ impl Decoder {
     }
 
     pub fn reset(&mut self) {
-        self.ddp_v1.clear()
+        self.ddp_v1.clear();
+        self.ddp_v2.clear();
     }
 
     pub fn decode(&mut self, msg: CanMessage) -> Option<GctMessage> {
@@ -257,9 +257,7 @@ fn encode_ddp_v2(src: u8, dst: u8, mut data: Vec<u8>) -> Vec<CanMessage> {
 }
 
 pub fn encode(msg: GctMessage) -> crate::Result<Vec<CanMessage>> {
-    if msg.validate().is_err() {
-        return Err(crate::Error::argument(anyhow!("Invalid CAN Message Parameters")));
-    }
+    msg.validate().map_err(crate::Error::argument)?;
     let ret = match msg {
         GctMessage::SysCtrl {
             src,
@@ -345,6 +343,7 @@ pub fn encode(msg: GctMessage) -> crate::Result<Vec<CanMessage>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use comsrv_protocol::{ValidationError, MAX_DDP_DATA_LEN_V1};
 
     #[test]
     fn ddp() {
@@ -492,4 +491,100 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn validate_sysctrl() {
+        let msg = |src, dst, cmd, data| GctMessage::SysCtrl {
+            src,
+            dst,
+            cmd,
+            tp: SysCtrlType::None,
+            data,
+        };
+        assert!(msg(12, 34, 452, vec![1, 2, 3]).validate().is_ok());
+        assert!(matches!(
+            msg(BROADCAST_ADDR, 34, 452, vec![]).validate(),
+            Err(ValidationError::InvalidSourceAddress(BROADCAST_ADDR))
+        ));
+        assert!(matches!(
+            msg(12, 0x80, 452, vec![]).validate(),
+            Err(ValidationError::InvalidDestinationAddress(0x80))
+        ));
+        assert!(matches!(
+            msg(12, 34, 1024, vec![]).validate(),
+            Err(ValidationError::CommandOutOfRange(1024))
+        ));
+        assert!(matches!(
+            msg(12, 34, 452, vec![0; 9]).validate(),
+            Err(ValidationError::DataTooLong { len: 9, max: 8 })
+        ));
+    }
+
+    #[test]
+    fn validate_monitoring() {
+        let data = |group_idx, reading_idx| GctMessage::MonitoringData {
+            src: 12,
+            group_idx,
+            reading_idx,
+            data: vec![],
+        };
+        assert!(matches!(data(32, 0).validate(), Err(ValidationError::GroupIndexOutOfRange(32))));
+        assert!(matches!(
+            data(0, 64).validate(),
+            Err(ValidationError::ReadingIndexOutOfRange(64))
+        ));
+        let request = GctMessage::MonitoringRequest {
+            src: 12,
+            dst: 34,
+            group_idx: 40,
+            readings: 0,
+        };
+        assert!(matches!(request.validate(), Err(ValidationError::GroupIndexOutOfRange(40))));
+    }
+
+    #[test]
+    fn validate_ddp_and_heartbeat() {
+        let ddp = |data: Vec<u8>, version| GctMessage::Ddp {
+            src: 12,
+            dst: 34,
+            data,
+            version,
+        };
+        assert!(ddp(vec![0; MAX_DDP_DATA_LEN_V1], 1).validate().is_ok());
+        assert!(matches!(
+            ddp(vec![0; MAX_DDP_DATA_LEN_V1 + 1], 1).validate(),
+            Err(ValidationError::DataTooLong { .. })
+        ));
+        assert!(ddp(vec![0; MAX_DDP_DATA_LEN_V1 + 1], 2).validate().is_ok());
+        assert!(matches!(
+            ddp(vec![], 3).validate(),
+            Err(ValidationError::UnsupportedDdpVersion(3))
+        ));
+        let hb = GctMessage::Heartbeat {
+            src: 12,
+            product_id: 0xFFFF,
+        };
+        assert!(matches!(hb.validate(), Err(ValidationError::InvalidProductId(0xFFFF))));
+    }
+
+    #[test]
+    fn encode_invalid() {
+        let msg = GctMessage::SysCtrl {
+            src: 12,
+            dst: 34,
+            cmd: 2000,
+            tp: SysCtrlType::None,
+            data: vec![],
+        };
+        let err = encode(msg).unwrap_err();
+        match err {
+            crate::Error::Argument(err) => {
+                assert!(matches!(
+                    err.downcast_ref::<ValidationError>(),
+                    Some(ValidationError::CommandOutOfRange(2000))
+                ));
+            }
+            _ => panic!(),
+        }
+    }
 }
@@ -1,4 +1,5 @@
-use comsrv_protocol::cobs_stream::CobsStreamResponse;
+use anyhow::anyhow;
+use comsrv_protocol::cobs_stream::{CobsStreamResponse, CrcKind};
 use std::io;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -8,15 +9,21 @@ use tokio::{select, task};
 
 use crate::app::Server;
 use crate::protocol::bytestream::cobs::cobs_decode;
+use crate::protocol::can::crc::crc16;
 use comsrv_protocol::{ByteStreamInstrument, Response};
 
-use super::bytestream::cobs::cobs_encode;
+use super::bytestream::cobs::{cobs_encode, DEFAULT_MAX_FRAME_SIZE};
+
+enum Transmit {
+    Frame(Vec<u8>),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
 
 #[derive(Clone)]
 pub struct CobsStream {
     cancel: Arc<Mutex<Option<oneshot::Sender<()>>>>,
-    tx: mpsc::UnboundedSender<Vec<u8>>,
-    use_crc: bool,
+    tx: mpsc::UnboundedSender<Transmit>,
+    crc: CrcKind,
 }
 
 impl CobsStream {
@@ -25,7 +32,7 @@ impl CobsStream {
         write: Write,
         server: Server,
         instr: ByteStreamInstrument,
-        use_crc: bool,
+        crc: CrcKind,
     ) -> CobsStream {
         let (cancel_tx, cancel_rx) = oneshot::channel();
         let (transmit_tx, transmit_rx) = mpsc::unbounded_channel();
@@ -34,8 +41,8 @@ impl CobsStream {
         let write = Box::pin(write);
 
         let fut = async move {
-            let mut decoder = CobsDecoder::new(server.clone(), instr);
-            let encoder = CobsEncoder::new();
+            let mut decoder = CobsDecoder::new(server.clone(), instr, crc);
+            let encoder = CobsEncoder::new(crc);
             let err = select! {
                     err = decoder.decode_stream(read) => Some(err),
                     err = encoder.transmit_frames(write, transmit_rx) => err,
@@ -48,13 +55,23 @@ impl CobsStream {
         task::spawn(fut);
         CobsStream {
             cancel: Arc::new(Mutex::new(Some(cancel_tx))),
-            use_crc,
+            crc,
             tx: transmit_tx,
         }
     }
 
     pub fn send<D: Into<Vec<u8>>>(&self, data: D) -> crate::Result<()> {
-        self.tx.send(data.into()).map_err(crate::Error::internal)
+        self.tx
+            .send(Transmit::Frame(data.into()))
+            .map_err(|_| crate::Error::internal(anyhow!("CobsStream transmitter closed")))
+    }
+
+    /// Wait until all previously sent frames have been written and the underlying writer was flushed.
+    pub async fn flush(&self) -> crate::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let stream_closed = || crate::Error::transport(io::Error::new(io::ErrorKind::BrokenPipe, "Stream closed"));
+        self.tx.send(Transmit::Flush(tx)).map_err(|_| stream_closed())?;
+        rx.await.map_err(|_| stream_closed())?.map_err(crate::Error::transport)
     }
 
     pub fn cancel(self) {
@@ -65,8 +82,8 @@ impl CobsStream {
         }
     }
 
-    pub fn use_crc(&self) -> bool {
-        self.use_crc
+    pub fn crc(&self) -> CrcKind {
+        self.crc
     }
 
     pub fn is_alive(&self) -> bool {
@@ -88,33 +105,96 @@ impl Drop for CobsStream {
     }
 }
 
+/// CRC-32 with the reflected polynomial 0xEDB88320, as used by Ethernet and zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for x in data {
+        crc ^= *x as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Append the CRC of `frame` to it.
+fn append_crc(crc: CrcKind, frame: &mut Vec<u8>) {
+    match crc {
+        CrcKind::None => {}
+        CrcKind::Crc16 => frame.extend(crc16(frame).to_be_bytes()),
+        CrcKind::Crc32 => frame.extend(crc32(frame).to_le_bytes()),
+    }
+}
+
+/// Check and remove the CRC at the end of `frame`. Returns `None` if the CRC does not match.
+fn strip_crc(crc: CrcKind, mut frame: Vec<u8>) -> Option<Vec<u8>> {
+    let crc_len = match crc {
+        CrcKind::None => return Some(frame),
+        CrcKind::Crc16 => 2,
+        CrcKind::Crc32 => 4,
+    };
+    let len = frame.len().checked_sub(crc_len)?;
+    let valid = match crc {
+        CrcKind::Crc16 => frame[len..] == crc16(&frame[..len]).to_be_bytes(),
+        CrcKind::Crc32 => frame[len..] == crc32(&frame[..len]).to_le_bytes(),
+        CrcKind::None => true,
+    };
+    frame.truncate(len);
+    valid.then_some(frame)
+}
+
 struct CobsDecoder {
     buf: Vec<u8>,
     server: Server,
     instr: ByteStreamInstrument,
+    crc: CrcKind,
+    max_frame_size: usize,
+    overflow: bool,
 }
 
 impl CobsDecoder {
-    fn new(server: Server, instr: ByteStreamInstrument) -> Self {
+    fn new(server: Server, instr: ByteStreamInstrument, crc: CrcKind) -> Self {
         Self {
             buf: Vec::new(),
             server,
             instr,
+            crc,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            overflow: false,
         }
     }
 
-    fn push(&mut self, value: u8) {
-        self.buf.push(value);
-        if value == 0 {
-            let decoded = cobs_decode(&self.buf);
-            // TODO: crc
+    /// Push a received byte to the decoder. Returns the data of a frame once it is completed and its CRC is valid.
+    fn push(&mut self, value: u8) -> Option<Vec<u8>> {
+        if self.overflow {
+            // discard the remainder of an oversized frame
+            self.overflow = value != 0;
+            return None;
+        }
+        if value != 0 && self.buf.len() >= self.max_frame_size {
+            log::warn!(
+                "Dropping COBS frame exceeding the maximum size of {} bytes",
+                self.max_frame_size
+            );
             self.buf.clear();
-            log::info!("COBS frame received (length = {})", decoded.len());
-            self.server.broadcast(Response::CobsStream(CobsStreamResponse::MessageReceived {
-                sender: self.instr.clone(),
-                data: decoded,
-            }));
+            self.overflow = true;
+            return None;
+        }
+        self.buf.push(value);
+        if value != 0 {
+            return None;
         }
+        let decoded = cobs_decode(&self.buf);
+        self.buf.clear();
+        let ret = strip_crc(self.crc, decoded);
+        if ret.is_none() {
+            log::warn!("Dropping COBS frame with invalid {:?}", self.crc);
+        }
+        ret
     }
 
     async fn decode_stream<T: AsyncRead + Send + 'static>(&mut self, mut stream: Pin<Box<T>>) -> io::Error {
@@ -122,7 +202,13 @@ impl CobsDecoder {
             let byte = stream.read_u8().await;
             match byte {
                 Ok(x) => {
-                    self.push(x);
+                    if let Some(data) = self.push(x) {
+                        log::info!("COBS frame received (length = {})", data.len());
+                        self.server.broadcast(Response::CobsStream(CobsStreamResponse::MessageReceived {
+                            sender: self.instr.clone(),
+                            data,
+                        }));
+                    }
                 }
                 Err(err) => return err,
             }
@@ -130,25 +216,137 @@ impl CobsDecoder {
     }
 }
 
-struct CobsEncoder {}
+struct CobsEncoder {
+    crc: CrcKind,
+}
 
 impl CobsEncoder {
-    fn new() -> Self {
-        Self {}
+    fn new(crc: CrcKind) -> Self {
+        Self { crc }
     }
 
     async fn transmit_frames<T: AsyncWrite + Send + 'static>(
         &self,
         mut stream: Pin<Box<T>>,
-        mut frames_to_transmit: mpsc::UnboundedReceiver<Vec<u8>>,
+        mut frames_to_transmit: mpsc::UnboundedReceiver<Transmit>,
     ) -> Option<io::Error> {
         while let Some(tx) = frames_to_transmit.recv().await {
-            let encoded = cobs_encode(&tx);
-            // TODO: crc
-            if let Err(err) = stream.write(&encoded).await {
-                return Some(err);
+            match tx {
+                Transmit::Frame(mut frame) => {
+                    append_crc(self.crc, &mut frame);
+                    let encoded = cobs_encode(&frame);
+                    if let Err(err) = stream.write_all(&encoded).await {
+                        return Some(err);
+                    }
+                }
+                Transmit::Flush(done) => {
+                    let ret = stream.flush().await;
+                    let err = ret.as_ref().err().map(|x| io::Error::new(x.kind(), x.to_string()));
+                    let _ = done.send(ret);
+                    if err.is_some() {
+                        return err;
+                    }
+                }
             }
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{TcpAddress, TcpInstrument};
+    use futures::FutureExt;
+
+    #[test]
+    fn decoder_drops_oversized_frame() {
+        let (server, _rx) = Server::new();
+        let instr = ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port: 1234,
+            },
+            options: None,
+        });
+        let mut decoder = CobsDecoder::new(server, instr, CrcKind::None);
+        decoder.max_frame_size = 4;
+        for x in [5, 1, 2, 3, 4, 6] {
+            decoder.push(x);
+        }
+        assert!(decoder.overflow);
+        assert!(decoder.buf.is_empty());
+        // the frame is discarded up to the next delimiter
+        decoder.push(0);
+        assert!(!decoder.overflow);
+        for x in [4, 1, 2, 3] {
+            decoder.push(x);
+        }
+        assert_eq!(decoder.buf, [4, 1, 2, 3]);
+        decoder.push(0);
+        assert!(decoder.buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flush_after_send() {
+        let (server, _rx) = Server::new();
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(local);
+        let instr = ByteStreamInstrument::Tcp(TcpInstrument {
+            address: TcpAddress {
+                host: "127.0.0.1".to_string(),
+                port: 1234,
+            },
+            options: None,
+        });
+        let stream = CobsStream::start(read, write, server, instr, CrcKind::None);
+        stream.send(vec![1, 2, 3]).unwrap();
+        stream.send(vec![4, 0, 5]).unwrap();
+        stream.flush().await.unwrap();
+
+        let mut expected = cobs_encode(&[1, 2, 3]);
+        expected.extend(cobs_encode(&[4, 0, 5]));
+        let mut buf = vec![0; 1024];
+        let n = remote.read(&mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(&buf[..n], &expected[..]);
+    }
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[tokio::test]
+    async fn round_trip_crc() {
+        let instr = ByteStreamInstrument::Tcp(TcpInstrument::new("127.0.0.1", 1234));
+        for crc in [CrcKind::None, CrcKind::Crc16, CrcKind::Crc32] {
+            let (server, _rx) = Server::new();
+            let (local, mut remote) = tokio::io::duplex(1024);
+            let (read, write) = tokio::io::split(local);
+            let stream = CobsStream::start(read, write, server.clone(), instr.clone(), crc);
+            stream.send(vec![1, 0, 2, 3]).unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = remote.read(&mut buf).now_or_never().unwrap().unwrap();
+            let crc_len = match crc {
+                CrcKind::None => 0,
+                CrcKind::Crc16 => 2,
+                CrcKind::Crc32 => 4,
+            };
+            assert_eq!(cobs_decode(&buf[..n]).len(), 4 + crc_len);
+
+            let mut decoder = CobsDecoder::new(server, instr.clone(), crc);
+            let frames: Vec<_> = buf[..n].iter().filter_map(|x| decoder.push(*x)).collect();
+            assert_eq!(frames, vec![vec![1, 0, 2, 3]]);
+
+            if crc != CrcKind::None {
+                // a corrupted frame is dropped
+                let mut corrupted = cobs_decode(&buf[..n]);
+                corrupted[0] ^= 0x10;
+                let frames: Vec<_> = cobs_encode(&corrupted).iter().filter_map(|x| decoder.push(*x)).collect();
+                assert!(frames.is_empty());
+            }
+        }
+    }
+}
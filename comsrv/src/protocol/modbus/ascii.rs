@@ -0,0 +1,157 @@
+use super::{FunctionCode, ModBusException, TransactionInfo};
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum length of a ModBus ASCII frame including start character and CRLF
+const MAX_FRAME_LENGTH: usize = 513;
+
+pub struct AsciiHandler<T: FunctionCode> {
+    function_code: T,
+}
+
+impl<T: FunctionCode> AsciiHandler<T> {
+    pub fn new(function_code: T) -> Self {
+        Self { function_code }
+    }
+
+    /// Send the request without waiting for a reply, as required for broadcasts.
+    pub async fn send<S: AsyncWrite + Unpin>(
+        &self,
+        transaction: &TransactionInfo,
+        stream: &mut S,
+    ) -> crate::Result<()> {
+        let mut request = Vec::new();
+        request.extend(&[transaction.station_address, self.function_code.function_code()]);
+        self.function_code.format_request(&mut request);
+        if request.len() > u8::MAX as usize {
+            return Err(crate::Error::argument(anyhow!("ModBus frame over length.")));
+        }
+        request.push(lrc(&request));
+        stream.write_all(&encode_frame(&request)).await.map_err(crate::Error::transport)
+    }
+
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        transaction: &TransactionInfo,
+        stream: &mut S,
+    ) -> crate::Result<T::Output> {
+        self.send(transaction, stream).await?;
+
+        let data = read_frame(stream).await?;
+        if data.len() < 3 {
+            return Err(crate::Error::protocol(anyhow!("Invalid frame")));
+        }
+        if lrc(&data) != 0 {
+            return Err(crate::Error::protocol(anyhow!("Invalid LRC in answer")));
+        }
+        let station_address = data[0];
+        let parsed_function_code = data[1];
+        if station_address != transaction.station_address {
+            return Err(crate::Error::protocol(anyhow!("Invalid answer received.")));
+        }
+        if parsed_function_code == (0x80 | self.function_code.function_code()) {
+            return Err(crate::Error::protocol(anyhow!(ModBusException::from_code(data[2]))));
+        } else if parsed_function_code != self.function_code.function_code() {
+            return Err(crate::Error::protocol(anyhow!("Invalid frame")));
+        }
+        if self.function_code.is_unframed() {
+            return self.function_code.parse_frame(&data[2..data.len() - 1]);
+        }
+        let fun_header_len = self.function_code.get_header_length();
+        if data.len() < 2 + fun_header_len + 1 {
+            return Err(crate::Error::protocol(anyhow!("ModBus frame shorter than header")));
+        }
+        let data_len = self.function_code.get_data_length_from_header(&data[2..2 + fun_header_len])?;
+        if data.len() != 2 + fun_header_len + data_len + 1 {
+            return Err(crate::Error::protocol(anyhow!("Invalid receive frame length")));
+        }
+        self.function_code.parse_frame(&data[2 + fun_header_len..data.len() - 1])
+    }
+}
+
+/// Longitudinal redundancy check: two's complement of the sum of all bytes
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0_u8, |acc, x| acc.wrapping_add(*x)).wrapping_neg()
+}
+
+fn encode_frame(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(2 * data.len() + 3);
+    ret.push(b':');
+    for x in data {
+        ret.extend(format!("{:02X}", x).as_bytes());
+    }
+    ret.extend(b"\r\n");
+    ret
+}
+
+/// Read a frame starting with `:` and terminated with CRLF and decode the hex-encoded payload.
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> crate::Result<Vec<u8>> {
+    // skip anything preceding the start of the frame
+    while stream.read_u8().await.map_err(crate::Error::transport)? != b':' {}
+    let mut frame = Vec::new();
+    loop {
+        let x = stream.read_u8().await.map_err(crate::Error::transport)?;
+        if x == b'\n' {
+            break;
+        }
+        frame.push(x);
+        if frame.len() > MAX_FRAME_LENGTH {
+            return Err(crate::Error::protocol(anyhow!("ModBus frame over length.")));
+        }
+    }
+    let frame = frame
+        .strip_suffix(b"\r")
+        .ok_or_else(|| crate::Error::protocol(anyhow!("Invalid frame")))?;
+    if frame.len() % 2 != 0 {
+        return Err(crate::Error::protocol(anyhow!("Invalid frame")));
+    }
+    frame
+        .chunks(2)
+        .map(|x| {
+            std::str::from_utf8(x)
+                .ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(|| crate::Error::protocol(anyhow!("Invalid character in frame")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::modbus::function_codes::READ_HOLDINGS;
+    use crate::protocol::modbus::registers::ReadU16Registers;
+    use tokio::io::duplex;
+
+    #[test]
+    fn lrc_computation() {
+        assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xF2);
+        assert_eq!(lrc(&[0x11, 0x03, 0x00, 0x6B, 0x00, 0x03]), 0x7E);
+        assert_eq!(lrc(&[]), 0);
+        let mut data = vec![0xF7, 0x10, 0x00, 0x01];
+        data.push(lrc(&data));
+        assert_eq!(lrc(&data), 0);
+    }
+
+    #[tokio::test]
+    async fn read_holding_frame() {
+        let (mut a, mut b) = duplex(256);
+        let responder = tokio::spawn(async move {
+            let mut request = [0_u8; 17];
+            b.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b":010300000002FA\r\n");
+            b.write_all(b":010304000A0102EB\r\n").await.unwrap();
+            b
+        });
+        let transaction = TransactionInfo::new(1);
+        let handler = AsciiHandler::new(ReadU16Registers::new(READ_HOLDINGS, 0, 2).unwrap());
+        let ret = handler.handle(&transaction, &mut a).await.unwrap();
+        assert_eq!(ret, vec![10, 258]);
+        let mut b = responder.await.unwrap();
+
+        // corrupted LRC
+        b.write_all(b":010304000A0102EC\r\n").await.unwrap();
+        let err = handler.handle(&transaction, &mut a).await.unwrap_err();
+        assert!(format!("{}", err).contains("LRC"));
+    }
+}
@@ -1,17 +1,24 @@
-/// ModBus protocol implementation for ModBus TCP and RTU
+/// ModBus protocol implementation for ModBus TCP, RTU and ASCII
 ///
 /// Note that the ModBus protocol implementation all operate on bytestreams (i.e. `AsyncRead + AsyncWrite`). On a typical OS it is not
 /// possible to implement ModBus RTU with timer-based framing.
+mod ascii;
 mod ddp;
 mod registers;
 mod rtu;
+pub mod server;
 mod tcp;
 
+use anyhow::anyhow;
 use comsrv_protocol::{ModBusProtocol, ModBusRequest, ModBusResponse};
 
+use ascii::AsciiHandler;
 use ddp::Ddp;
 use function_codes::{READ_COILS, READ_DISCRETES, READ_HOLDINGS, READ_INPUTS};
-use registers::{ReadBoolRegisters, ReadU16Registers, WriteCoils, WriteRegisters};
+use registers::{
+    RawPdu, ReadBoolRegisters, ReadU16Registers, WriteCoils, WriteRegisters, MAX_READ_BOOLS, MAX_READ_REGISTERS,
+    MAX_WRITE_COILS, MAX_WRITE_REGISTERS,
+};
 use rtu::RtuHandler;
 use std::time::Duration;
 use tcp::TcpHandler;
@@ -74,9 +81,26 @@ impl ModBusException {
             x => ModBusException::Unknown(x),
         }
     }
+
+    /// The exception code transmitted in exception replies
+    pub fn code(&self) -> u8 {
+        match self {
+            ModBusException::InvalidFunction => 1,
+            ModBusException::InvalidDataAddress => 2,
+            ModBusException::InvalidDataValue => 3,
+            ModBusException::ServerDeviceFailure => 4,
+            ModBusException::Acknowledge => 5,
+            ModBusException::ServerDeviceBusy => 6,
+            ModBusException::NegativeAcknowledgement => 7,
+            ModBusException::MemoryParityError => 8,
+            ModBusException::GatewayPathUnavailable => 10,
+            ModBusException::GatewayTargetFailedToRespond => 11,
+            ModBusException::Unknown(x) => *x,
+        }
+    }
 }
 
-/// Function code handler. Both the RTU and the TCP handler implementations
+/// Function code handler. The RTU, ASCII and TCP handler implementations
 /// get the necessary information from these handlers to perform the framing
 pub trait FunctionCode {
     type Output;
@@ -87,11 +111,18 @@ pub trait FunctionCode {
     fn parse_frame(&self, data: &[u8]) -> crate::Result<Self::Output>;
 
     fn function_code(&self) -> u8;
+
+    /// Whether the reply carries no length information, such that its end is given by the framing of the protocol.
+    /// The data following the function code is then passed to [`FunctionCode::parse_frame()`] as is.
+    fn is_unframed(&self) -> bool {
+        false
+    }
 }
 
 enum Handler<T: FunctionCode> {
     Tcp(TcpHandler<T>),
     Rtu(RtuHandler<T>),
+    Ascii(AsciiHandler<T>),
 }
 
 impl<T: FunctionCode> Handler<T> {
@@ -99,6 +130,7 @@ impl<T: FunctionCode> Handler<T> {
         match protocol {
             ModBusProtocol::Tcp => Self::Tcp(TcpHandler::new(function_code)),
             ModBusProtocol::Rtu => Self::Rtu(RtuHandler::new(function_code)),
+            ModBusProtocol::Ascii => Self::Ascii(AsciiHandler::new(function_code)),
         }
     }
     async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
@@ -113,6 +145,26 @@ impl<T: FunctionCode> Handler<T> {
         }
     }
 
+    /// Send the request without waiting for a reply
+    async fn send<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        timeout: Duration,
+        transaction: &TransactionInfo,
+    ) -> crate::Result<()> {
+        let send = async {
+            match self {
+                Handler::Tcp(_) => Err(crate::Error::argument(anyhow!("ModBus TCP does not support broadcasts."))),
+                Handler::Rtu(x) => x.send(transaction, stream).await,
+                Handler::Ascii(x) => x.send(transaction, stream).await,
+            }
+        };
+        match tokio::time::timeout(timeout, send).await {
+            Ok(x) => x,
+            Err(_) => Err(crate::Error::protocol_timeout()),
+        }
+    }
+
     async fn handle_no_timeout<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut S,
@@ -121,13 +173,20 @@ impl<T: FunctionCode> Handler<T> {
         match self {
             Handler::Tcp(x) => x.handle(transaction, stream).await,
             Handler::Rtu(x) => x.handle(transaction, stream).await,
+            Handler::Ascii(x) => x.handle(transaction, stream).await,
         }
     }
 }
 
+/// Station address of broadcasts on serial busses. Broadcasts are received by all stations and not replied to.
+/// ModBus TCP has no broadcasts, hence the address is passed on like any other unit id.
+pub const BROADCAST_ADDRESS: u8 = 0;
+
 pub struct TransactionInfo {
     transaction_id: u16,
     station_address: u8,
+    /// Guard time between writing the request and reading the reply, only used by ModBus RTU
+    inter_frame_delay: Duration,
 }
 
 impl TransactionInfo {
@@ -135,21 +194,85 @@ impl TransactionInfo {
         Self {
             transaction_id: rand::random(),
             station_address,
+            inter_frame_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_inter_frame_delay(mut self, inter_frame_delay: Duration) -> Self {
+        self.inter_frame_delay = inter_frame_delay;
+        self
+    }
+}
+
+/// Check that `cnt` items starting at `addr` are within the 16-bit address space and the limits of the
+/// function code.
+fn check_range(name: &str, addr: u16, cnt: usize, max: usize) -> crate::Result<()> {
+    if cnt == 0 {
+        return Err(crate::Error::argument(anyhow!("{}: count must be at least 1", name)));
+    }
+    if cnt > max {
+        return Err(crate::Error::argument(anyhow!(
+            "{}: count of {} exceeds the maximum of {}",
+            name,
+            cnt,
+            max
+        )));
+    }
+    if addr as usize + cnt > 0x10000 {
+        return Err(crate::Error::argument(anyhow!(
+            "{}: {} items starting at address {} exceed the address space",
+            name,
+            cnt,
+            addr
+        )));
+    }
+    Ok(())
+}
+
+/// Reject requests which cannot be represented in a valid ModBus PDU or which cannot be sent to `station_address`.
+pub fn validate(station_address: u8, protocol: ModBusProtocol, request: &ModBusRequest) -> crate::Result<()> {
+    if station_address == BROADCAST_ADDRESS
+        && protocol != ModBusProtocol::Tcp
+        && !matches!(request, ModBusRequest::WriteCoils { .. } | ModBusRequest::WriteRegisters { .. })
+    {
+        return Err(crate::Error::argument(anyhow!(
+            "Only writes may be sent to the broadcast address {}.",
+            BROADCAST_ADDRESS
+        )));
+    }
+    match request {
+        ModBusRequest::ReadCoil { addr, cnt } => check_range("ReadCoil", *addr, *cnt as usize, MAX_READ_BOOLS),
+        ModBusRequest::ReadDiscrete { addr, cnt } => check_range("ReadDiscrete", *addr, *cnt as usize, MAX_READ_BOOLS),
+        ModBusRequest::ReadInput { addr, cnt } => check_range("ReadInput", *addr, *cnt as usize, MAX_READ_REGISTERS),
+        ModBusRequest::ReadHolding { addr, cnt } => {
+            check_range("ReadHolding", *addr, *cnt as usize, MAX_READ_REGISTERS)
+        }
+        ModBusRequest::WriteCoils { addr, values } => check_range("WriteCoils", *addr, values.len(), MAX_WRITE_COILS),
+        ModBusRequest::WriteRegisters { addr, values } => {
+            check_range("WriteRegisters", *addr, values.len(), MAX_WRITE_REGISTERS)
         }
+        ModBusRequest::Ddp { .. } | ModBusRequest::RawPdu { .. } => Ok(()),
     }
 }
 
+/// Run a ModBus transaction on `stream`. The `inter_frame_delay` is awaited between writing the request and reading
+/// the reply. It only applies to ModBus RTU, where slow devices require a guard time.
 pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
     timeout: Duration,
     station_address: u8,
     protocol: ModBusProtocol,
+    inter_frame_delay: Duration,
     request: ModBusRequest,
     stream: &mut T,
 ) -> crate::Result<ModBusResponse> {
+    validate(station_address, protocol, &request)?;
+    if station_address == BROADCAST_ADDRESS && protocol != ModBusProtocol::Tcp {
+        return broadcast(timeout, protocol, request, stream).await;
+    }
     crate::protocol::bytestream::read_all(stream)
         .await
         .map_err(crate::Error::transport)?;
-    let transaction = TransactionInfo::new(station_address);
+    let transaction = TransactionInfo::new(station_address).with_inter_frame_delay(inter_frame_delay);
     let ret = match request {
         ModBusRequest::Ddp {
             sub_cmd,
@@ -161,6 +284,11 @@ pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
             let ret = Handler::new(protocol, fun_code).handle(stream, timeout, &transaction).await?;
             ModBusResponse::Data(ret)
         }
+        ModBusRequest::RawPdu { function_code, data } => {
+            let fun_code = RawPdu::new(function_code, data)?;
+            let ret = Handler::new(protocol, fun_code).handle(stream, timeout, &transaction).await?;
+            ModBusResponse::Data(ret)
+        }
         ModBusRequest::ReadCoil { addr, cnt } => {
             let fun_code = ReadBoolRegisters::new(READ_COILS, addr, cnt)?;
             let ret = Handler::new(protocol, fun_code).handle(stream, timeout, &transaction).await?;
@@ -194,3 +322,249 @@ pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
     };
     Ok(ret)
 }
+
+/// Send a write request to all stations. No reply is awaited, as stations do not reply to broadcasts.
+async fn broadcast<T: AsyncWrite + Unpin>(
+    timeout: Duration,
+    protocol: ModBusProtocol,
+    request: ModBusRequest,
+    stream: &mut T,
+) -> crate::Result<ModBusResponse> {
+    let transaction = TransactionInfo::new(BROADCAST_ADDRESS);
+    match request {
+        ModBusRequest::WriteCoils { addr, values } => {
+            let fun_code = WriteCoils::new(addr, &values)?;
+            Handler::new(protocol, fun_code).send(stream, timeout, &transaction).await?;
+        }
+        ModBusRequest::WriteRegisters { addr, values } => {
+            let fun_code = WriteRegisters::new(addr, &values)?;
+            Handler::new(protocol, fun_code).send(stream, timeout, &transaction).await?;
+        }
+        _ => unreachable!("rejected by validate"),
+    }
+    Ok(ModBusResponse::Done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+
+    fn is_argument_error(request: ModBusRequest) -> bool {
+        matches!(validate(1, ModBusProtocol::Rtu, &request), Err(crate::Error::Argument(_)))
+    }
+
+    #[test]
+    fn validate_reads() {
+        assert!(validate(1, ModBusProtocol::Rtu, &ModBusRequest::ReadHolding { addr: 0xFFFF, cnt: 1 }).is_ok());
+        assert!(validate(1, ModBusProtocol::Rtu, &ModBusRequest::ReadHolding { addr: 0xFF83, cnt: 125 }).is_ok());
+        assert!(is_argument_error(ModBusRequest::ReadHolding { addr: 0xFF84, cnt: 125 }));
+        assert!(is_argument_error(ModBusRequest::ReadHolding { addr: 0, cnt: 0 }));
+        assert!(is_argument_error(ModBusRequest::ReadHolding { addr: 0, cnt: 126 }));
+        assert!(is_argument_error(ModBusRequest::ReadInput { addr: 0, cnt: 126 }));
+        assert!(is_argument_error(ModBusRequest::ReadInput { addr: 0xFFFF, cnt: 2 }));
+
+        assert!(validate(1, ModBusProtocol::Rtu, &ModBusRequest::ReadCoil { addr: 0, cnt: 2000 }).is_ok());
+        assert!(validate(
+            1,
+            ModBusProtocol::Rtu,
+            &ModBusRequest::ReadCoil {
+                addr: 0xF830,
+                cnt: 2000
+            }
+        )
+        .is_ok());
+        assert!(is_argument_error(ModBusRequest::ReadCoil {
+            addr: 0xF831,
+            cnt: 2000
+        }));
+        assert!(is_argument_error(ModBusRequest::ReadCoil { addr: 0, cnt: 2001 }));
+        assert!(is_argument_error(ModBusRequest::ReadDiscrete { addr: 0, cnt: 0 }));
+        assert!(is_argument_error(ModBusRequest::ReadDiscrete { addr: 65000, cnt: 1000 }));
+    }
+
+    #[test]
+    fn validate_broadcast() {
+        let read = ModBusRequest::ReadHolding { addr: 0, cnt: 1 };
+        let write = ModBusRequest::WriteRegisters {
+            addr: 0,
+            values: vec![1],
+        };
+        assert!(validate(BROADCAST_ADDRESS, ModBusProtocol::Rtu, &write).is_ok());
+        assert!(validate(BROADCAST_ADDRESS, ModBusProtocol::Ascii, &write).is_ok());
+        assert!(matches!(
+            validate(BROADCAST_ADDRESS, ModBusProtocol::Rtu, &read),
+            Err(crate::Error::Argument(_))
+        ));
+        assert!(matches!(
+            validate(BROADCAST_ADDRESS, ModBusProtocol::Ascii, &read),
+            Err(crate::Error::Argument(_))
+        ));
+        assert!(validate(BROADCAST_ADDRESS, ModBusProtocol::Tcp, &read).is_ok());
+    }
+
+    #[test]
+    fn validate_writes() {
+        let coils = |cnt| vec![true; cnt];
+        assert!(validate(
+            1,
+            ModBusProtocol::Rtu,
+            &ModBusRequest::WriteCoils {
+                addr: 0,
+                values: coils(1968)
+            }
+        )
+        .is_ok());
+        assert!(is_argument_error(ModBusRequest::WriteCoils {
+            addr: 0,
+            values: coils(1969)
+        }));
+        assert!(is_argument_error(ModBusRequest::WriteCoils {
+            addr: 0,
+            values: coils(0)
+        }));
+        assert!(is_argument_error(ModBusRequest::WriteCoils {
+            addr: 0xFFFF,
+            values: coils(2)
+        }));
+
+        let registers = |cnt| vec![0_u16; cnt];
+        assert!(validate(
+            1,
+            ModBusProtocol::Rtu,
+            &ModBusRequest::WriteRegisters {
+                addr: 0xFF85,
+                values: registers(123)
+            }
+        )
+        .is_ok());
+        assert!(is_argument_error(ModBusRequest::WriteRegisters {
+            addr: 0xFF86,
+            values: registers(123)
+        }));
+        assert!(is_argument_error(ModBusRequest::WriteRegisters {
+            addr: 0,
+            values: registers(124)
+        }));
+        assert!(is_argument_error(ModBusRequest::WriteRegisters {
+            addr: 0,
+            values: registers(0)
+        }));
+    }
+
+    #[tokio::test]
+    async fn reject_before_io() {
+        let (mut a, mut b) = duplex(64);
+        b.write_all(&[1, 2, 3]).await.unwrap();
+        let request = ModBusRequest::ReadHolding { addr: 65500, cnt: 100 };
+        let ret = handle(
+            Duration::from_millis(100),
+            1,
+            ModBusProtocol::Tcp,
+            Duration::ZERO,
+            request,
+            &mut a,
+        )
+        .await;
+        assert!(matches!(ret, Err(crate::Error::Argument(_))));
+        // buffered input is not discarded and nothing is written
+        let buffered = crate::protocol::bytestream::read_all(&mut a).await.unwrap();
+        assert_eq!(buffered, [1, 2, 3]);
+        assert!(crate::protocol::bytestream::read_all(&mut b).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broadcast_write() {
+        let (mut a, mut b) = duplex(64);
+        // the reply of a station would be consumed as an answer if a reply was awaited
+        b.write_all(&[0xAA]).await.unwrap();
+        let request = ModBusRequest::WriteRegisters {
+            addr: 0x10,
+            values: vec![0x1234],
+        };
+        let ret = handle(
+            Duration::from_millis(100),
+            0,
+            ModBusProtocol::Rtu,
+            Duration::ZERO,
+            request,
+            &mut a,
+        )
+        .await;
+        assert!(matches!(ret, Ok(ModBusResponse::Done)));
+        let sent = crate::protocol::bytestream::read_all(&mut b).await.unwrap();
+        assert_eq!(sent[..9], [0, 16, 0, 0x10, 0, 1, 2, 0x12, 0x34]);
+        assert_eq!(rtu::crc(&sent), 0);
+        assert_eq!(crate::protocol::bytestream::read_all(&mut a).await.unwrap(), [0xAA]);
+
+        let request = ModBusRequest::ReadHolding { addr: 0x10, cnt: 1 };
+        let ret = handle(
+            Duration::from_millis(100),
+            0,
+            ModBusProtocol::Rtu,
+            Duration::ZERO,
+            request,
+            &mut a,
+        )
+        .await;
+        assert!(matches!(ret, Err(crate::Error::Argument(_))));
+    }
+
+    /// Records when the reply is first read after writing the request
+    struct TimedStream {
+        inner: DuplexStream,
+        written: Option<Instant>,
+        read: Option<Instant>,
+    }
+
+    impl AsyncRead for TimedStream {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if self.written.is_some() && self.read.is_none() {
+                self.read = Some(Instant::now());
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for TimedStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.written.get_or_insert_with(Instant::now);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn inter_frame_delay() {
+        let (a, mut b) = duplex(64);
+        let mut a = TimedStream {
+            inner: a,
+            written: None,
+            read: None,
+        };
+        let responder = tokio::spawn(async move {
+            let mut request = [0_u8; 8];
+            b.read_exact(&mut request).await.unwrap();
+            let mut reply = vec![1, 3, 2, 0x12, 0x34];
+            reply.extend(&rtu::crc(&reply).to_le_bytes());
+            b.write_all(&reply).await.unwrap();
+        });
+        let delay = Duration::from_millis(50);
+        let request = ModBusRequest::ReadHolding { addr: 0, cnt: 1 };
+        let ret = handle(Duration::from_secs(1), 1, ModBusProtocol::Rtu, delay, request, &mut a).await;
+        assert!(matches!(ret, Ok(ModBusResponse::Number(x)) if x == [0x1234]));
+        assert!(a.read.unwrap() - a.written.unwrap() >= delay);
+        responder.await.unwrap();
+    }
+}
@@ -1,6 +1,15 @@
 use super::FunctionCode;
 use anyhow::anyhow;
 
+/// Maximum number of coils or discrete inputs read by one request
+pub const MAX_READ_BOOLS: usize = 2000;
+/// Maximum number of input or holding registers read by one request
+pub const MAX_READ_REGISTERS: usize = 125;
+/// Maximum number of coils written by one request
+pub const MAX_WRITE_COILS: usize = 1968;
+/// Maximum number of holding registers written by one request
+pub const MAX_WRITE_REGISTERS: usize = 123;
+
 pub struct ReadU16Registers {
     function_code: u8,
     address: u16,
@@ -12,10 +21,11 @@ impl ReadU16Registers {
         if cnt == 0 {
             return Err(crate::Error::argument(anyhow!("Need to read at least 1 register.")));
         }
-        if cnt > 125 {
+        if cnt as usize > MAX_READ_REGISTERS {
             return Err(crate::Error::argument(anyhow!(
-                "Trying to read too many registers: {}. Maximum 125.",
-                cnt
+                "Trying to read too many registers: {}. Maximum {}.",
+                cnt,
+                MAX_READ_REGISTERS
             )));
         }
         Ok(Self {
@@ -72,10 +82,11 @@ impl ReadBoolRegisters {
         if cnt == 0 {
             return Err(crate::Error::argument(anyhow!("Need to read at least 1 register")));
         }
-        if cnt > 1968 {
+        if cnt as usize > MAX_READ_BOOLS {
             return Err(crate::Error::argument(anyhow!(
-                "Trying to read too many registers: {}. Maximum 1968",
-                cnt
+                "Trying to read too many registers: {}. Maximum {}",
+                cnt,
+                MAX_READ_BOOLS
             )));
         }
         Ok(Self {
@@ -139,8 +150,11 @@ impl<'a> WriteCoils<'a> {
         if data.is_empty() {
             return Err(crate::Error::argument(anyhow!("Number of write coils must be > 0")));
         }
-        if data.len() > 0x7B0 {
-            return Err(crate::Error::argument(anyhow!("Number of write coils must be <= 1968")));
+        if data.len() > MAX_WRITE_COILS {
+            return Err(crate::Error::argument(anyhow!(
+                "Number of write coils must be <= {}",
+                MAX_WRITE_COILS
+            )));
         }
         Ok(Self { address, data })
     }
@@ -189,10 +203,13 @@ pub struct WriteRegisters<'a> {
 impl<'a> WriteRegisters<'a> {
     pub fn new(address: u16, data: &'a [u16]) -> crate::Result<Self> {
         if data.is_empty() {
-            return Err(crate::Error::argument(anyhow!("Number of write coils must be > 0")));
+            return Err(crate::Error::argument(anyhow!("Number of write registers must be > 0")));
         }
-        if data.len() > 125 {
-            return Err(crate::Error::argument(anyhow!("Number of write coils must be <= 125")));
+        if data.len() > MAX_WRITE_REGISTERS {
+            return Err(crate::Error::argument(anyhow!(
+                "Number of write registers must be <= {}",
+                MAX_WRITE_REGISTERS
+            )));
         }
         Ok(Self { address, data })
     }
@@ -228,6 +245,61 @@ impl<'a> FunctionCode for WriteRegisters<'a> {
     }
 }
 
+/// Passes an arbitrary PDU, e.g. with a vendor-specific function code. The response PDU is returned as is, i.e. the
+/// function code followed by the unparsed response data.
+///
+/// Since RTU frames carry no length, the end of an RTU reply is detected by its CRC.
+pub struct RawPdu {
+    function_code: u8,
+    data: Vec<u8>,
+}
+
+impl RawPdu {
+    pub fn new(function_code: u8, data: Vec<u8>) -> crate::Result<Self> {
+        if function_code == 0 || function_code >= 0x80 {
+            return Err(crate::Error::argument(anyhow!("Invalid function code: {}", function_code)));
+        }
+        if data.len() > 252 {
+            return Err(crate::Error::argument(anyhow!(
+                "Maximum PDU data length is 252 but got {}",
+                data.len()
+            )));
+        }
+        Ok(Self { function_code, data })
+    }
+}
+
+impl FunctionCode for RawPdu {
+    type Output = Vec<u8>;
+
+    fn format_request(&self, data: &mut Vec<u8>) {
+        data.extend(&self.data);
+    }
+
+    fn get_header_length(&self) -> usize {
+        0
+    }
+
+    fn get_data_length_from_header(&self, _data: &[u8]) -> crate::Result<usize> {
+        Ok(0)
+    }
+
+    fn parse_frame(&self, data: &[u8]) -> crate::Result<Self::Output> {
+        let mut ret = Vec::with_capacity(data.len() + 1);
+        ret.push(self.function_code);
+        ret.extend_from_slice(data);
+        Ok(ret)
+    }
+
+    fn function_code(&self) -> u8 {
+        self.function_code
+    }
+
+    fn is_unframed(&self) -> bool {
+        true
+    }
+}
+
 fn check_write_header(reply: &[u8], addr: u16, num_regs: usize) -> crate::Result<()> {
     let starting_address = u16::from_be_bytes([reply[0], reply[1]]);
     let num_outputs = u16::from_be_bytes([reply[2], reply[3]]);
@@ -239,3 +311,58 @@ fn check_write_header(reply: &[u8], addr: u16, num_regs: usize) -> crate::Result
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::modbus::rtu::{crc, RtuHandler};
+    use crate::protocol::modbus::tcp::TcpHandler;
+    use crate::protocol::modbus::TransactionInfo;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn raw_pdu_rtu() {
+        let (mut a, mut b) = duplex(256);
+        let responder = tokio::spawn(async move {
+            let mut request = [0_u8; 7];
+            b.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[0x11, 0x65, 0xAA, 0xBB, 0xCC]);
+            assert_eq!(crc(&request), 0);
+            // the reply carries no byte count
+            let mut reply = vec![0x11, 0x65, 0x12, 0x34, 0x56];
+            reply.extend(&crc(&reply).to_le_bytes());
+            b.write_all(&reply).await.unwrap();
+        });
+        let handler = RtuHandler::new(RawPdu::new(0x65, vec![0xAA, 0xBB, 0xCC]).unwrap());
+        let ret = handler.handle(&TransactionInfo::new(0x11), &mut a).await.unwrap();
+        assert_eq!(ret, vec![0x65, 0x12, 0x34, 0x56]);
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn raw_pdu_tcp() {
+        let (mut a, mut b) = duplex(256);
+        let responder = tokio::spawn(async move {
+            let mut request = [0_u8; 10];
+            b.read_exact(&mut request).await.unwrap();
+            // MBAP header: transaction id, protocol id, length of unit id and PDU
+            assert_eq!(&request[2..6], &[0, 0, 0, 4]);
+            assert_eq!(&request[6..], &[0x11, 0x65, 0xAA, 0xBB]);
+            let mut reply = request[0..2].to_vec();
+            reply.extend(&[0, 0, 0, 5, 0x11, 0x65, 0x12, 0x34, 0x56]);
+            b.write_all(&reply).await.unwrap();
+        });
+        let handler = TcpHandler::new(RawPdu::new(0x65, vec![0xAA, 0xBB]).unwrap());
+        let ret = handler.handle(&TransactionInfo::new(0x11), &mut a).await.unwrap();
+        assert_eq!(ret, vec![0x65, 0x12, 0x34, 0x56]);
+        responder.await.unwrap();
+    }
+
+    #[test]
+    fn raw_pdu_arguments() {
+        assert!(RawPdu::new(0, vec![]).is_err());
+        assert!(RawPdu::new(0x85, vec![]).is_err());
+        assert!(RawPdu::new(0x65, vec![0; 253]).is_err());
+        assert!(RawPdu::new(0x65, vec![0; 252]).is_ok());
+    }
+}
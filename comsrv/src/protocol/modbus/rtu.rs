@@ -11,11 +11,12 @@ impl<T: FunctionCode> RtuHandler<T> {
         Self { function_code }
     }
 
-    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    /// Send the request without waiting for a reply, as required for broadcasts.
+    pub async fn send<S: AsyncWrite + Unpin>(
         &self,
         transaction: &TransactionInfo,
         stream: &mut S,
-    ) -> crate::Result<T::Output> {
+    ) -> crate::Result<()> {
         let mut request = Vec::new();
         request.extend(&[transaction.station_address, self.function_code.function_code()]);
         self.function_code.format_request(&mut request);
@@ -24,7 +25,18 @@ impl<T: FunctionCode> RtuHandler<T> {
             return Err(crate::Error::argument(anyhow!("ModBus frame over length.")));
         }
         request.extend(&crc(&request).to_le_bytes());
-        stream.write_all(&request).await.map_err(crate::Error::transport)?;
+        stream.write_all(&request).await.map_err(crate::Error::transport)
+    }
+
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        transaction: &TransactionInfo,
+        stream: &mut S,
+    ) -> crate::Result<T::Output> {
+        self.send(transaction, stream).await?;
+        if !transaction.inter_frame_delay.is_zero() {
+            tokio::time::sleep(transaction.inter_frame_delay).await;
+        }
         let mut header = [0_u8; 2];
         stream.read_exact(&mut header).await.map_err(crate::Error::transport)?;
         let station_address = header[0];
@@ -38,6 +50,10 @@ impl<T: FunctionCode> RtuHandler<T> {
         } else if parsed_function_code != self.function_code.function_code() {
             return Err(crate::Error::protocol(anyhow!("Invalid frame")));
         }
+        if self.function_code.is_unframed() {
+            let data = read_to_crc(header.to_vec(), stream).await?;
+            return self.function_code.parse_frame(&data[2..data.len() - 2]);
+        }
         let fun_header_len = self.function_code.get_header_length();
         let mut fun_header = vec![0_u8; fun_header_len];
         stream.read_exact(&mut fun_header).await.map_err(crate::Error::transport)?;
@@ -56,6 +72,24 @@ impl<T: FunctionCode> RtuHandler<T> {
     }
 }
 
+/// Maximum length of an RTU frame: station address, PDU and CRC
+const MAX_FRAME_LENGTH: usize = 256;
+
+/// Read the remainder of a frame starting with `frame` until the CRC of the received data matches. Returns the
+/// complete frame.
+async fn read_to_crc<S: AsyncRead + Unpin>(mut frame: Vec<u8>, stream: &mut S) -> crate::Result<Vec<u8>> {
+    while frame.len() < MAX_FRAME_LENGTH {
+        frame.push(stream.read_u8().await.map_err(crate::Error::transport)?);
+        if frame.len() >= 4 && crc(&frame) == 0 {
+            return Ok(frame);
+        }
+    }
+    Err(crate::Error::protocol(anyhow!(
+        "No valid CRC within {} bytes of the answer",
+        MAX_FRAME_LENGTH
+    )))
+}
+
 pub fn crc(data: &[u8]) -> u16 {
     let mut crc = 0xFFFF_u16;
     for x in data {
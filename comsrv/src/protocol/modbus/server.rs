@@ -0,0 +1,190 @@
+//! Serves a [`ModBusRegisterMap`] to ModBus TCP masters, i.e. the server role of the ModBus TCP implementation.
+use std::ops::Range;
+use std::sync::Mutex;
+
+use comsrv_protocol::ModBusRegisterMap;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::function_codes::{
+    READ_COILS, READ_DISCRETES, READ_HOLDINGS, READ_INPUTS, WRITE_COIL, WRITE_HOLDING, WRITE_MULTIPLE_COILS,
+    WRITE_MULTIPLE_HOLDINGS,
+};
+use super::registers::{MAX_READ_BOOLS, MAX_READ_REGISTERS, MAX_WRITE_COILS, MAX_WRITE_REGISTERS};
+use super::tcp::{format_reply, read_request};
+use super::ModBusException;
+
+/// Answer the requests received on `stream` from `map` until the master closes the connection. Invalid frames
+/// fail, such that the connection is closed.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    map: &Mutex<ModBusRegisterMap>,
+) -> crate::Result<()> {
+    while let Some((header, pdu)) = read_request(stream).await? {
+        let reply = handle_pdu(&mut map.lock().unwrap(), &pdu);
+        let frame = format_reply(&header, &reply);
+        stream.write_all(&frame).await.map_err(crate::Error::transport)?;
+    }
+    Ok(())
+}
+
+/// Handle a request PDU and return the reply PDU, which is an exception reply if the request fails.
+fn handle_pdu(map: &mut ModBusRegisterMap, pdu: &[u8]) -> Vec<u8> {
+    let function_code = pdu[0];
+    match respond(map, function_code, &pdu[1..]) {
+        Ok(data) => {
+            let mut ret = vec![function_code];
+            ret.extend(data);
+            ret
+        }
+        Err(exception) => {
+            log::debug!("ModBus server replies to function code {} with: {}", function_code, exception);
+            vec![0x80 | function_code, exception.code()]
+        }
+    }
+}
+
+fn respond(map: &mut ModBusRegisterMap, function_code: u8, data: &[u8]) -> Result<Vec<u8>, ModBusException> {
+    match function_code {
+        READ_COILS | READ_DISCRETES => {
+            let (addr, cnt) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            check_count(cnt, MAX_READ_BOOLS)?;
+            let table = if function_code == READ_COILS {
+                &map.coils
+            } else {
+                &map.discretes
+            };
+            let values = &table[range(addr, cnt, table.len())?];
+            let mut ret = vec![(cnt as usize).div_ceil(8) as u8];
+            for chunk in values.chunks(8) {
+                let byte = chunk.iter().enumerate().fold(0_u8, |acc, (k, x)| acc | ((*x as u8) << k));
+                ret.push(byte);
+            }
+            Ok(ret)
+        }
+        READ_HOLDINGS | READ_INPUTS => {
+            let (addr, cnt) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            check_count(cnt, MAX_READ_REGISTERS)?;
+            let table = if function_code == READ_HOLDINGS {
+                &map.holdings
+            } else {
+                &map.inputs
+            };
+            let mut ret = vec![2 * cnt as u8];
+            for x in &table[range(addr, cnt, table.len())?] {
+                ret.extend(&x.to_be_bytes());
+            }
+            Ok(ret)
+        }
+        WRITE_COIL => {
+            let (addr, value) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            let value = match value {
+                0xFF00 => true,
+                0x0000 => false,
+                _ => return Err(ModBusException::InvalidDataValue),
+            };
+            let len = map.coils.len();
+            map.coils[range(addr, 1, len)?][0] = value;
+            Ok(data[..4].to_vec())
+        }
+        WRITE_HOLDING => {
+            let (addr, value) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            let len = map.holdings.len();
+            map.holdings[range(addr, 1, len)?][0] = value;
+            Ok(data[..4].to_vec())
+        }
+        WRITE_MULTIPLE_COILS => {
+            let (addr, cnt) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            check_count(cnt, MAX_WRITE_COILS)?;
+            let bytes = payload(data, (cnt as usize).div_ceil(8))?;
+            let len = map.coils.len();
+            let coils = &mut map.coils[range(addr, cnt, len)?];
+            for (k, x) in coils.iter_mut().enumerate() {
+                *x = (bytes[k / 8] >> (k % 8)) & 1 == 1;
+            }
+            Ok(data[..4].to_vec())
+        }
+        WRITE_MULTIPLE_HOLDINGS => {
+            let (addr, cnt) = (u16_at(data, 0)?, u16_at(data, 2)?);
+            check_count(cnt, MAX_WRITE_REGISTERS)?;
+            let bytes = payload(data, 2 * cnt as usize)?;
+            let len = map.holdings.len();
+            let holdings = &mut map.holdings[range(addr, cnt, len)?];
+            for (x, value) in holdings.iter_mut().zip(bytes.chunks(2)) {
+                *x = u16::from_be_bytes([value[0], value[1]]);
+            }
+            Ok(data[..4].to_vec())
+        }
+        _ => Err(ModBusException::InvalidFunction),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ModBusException> {
+    data.get(offset..offset + 2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .ok_or(ModBusException::InvalidDataValue)
+}
+
+fn check_count(cnt: u16, max: usize) -> Result<(), ModBusException> {
+    if cnt == 0 || cnt as usize > max {
+        return Err(ModBusException::InvalidDataValue);
+    }
+    Ok(())
+}
+
+/// The data of a write request following the byte count, which must match `len`.
+fn payload(data: &[u8], len: usize) -> Result<&[u8], ModBusException> {
+    if data.get(4).map(|x| *x as usize) != Some(len) {
+        return Err(ModBusException::InvalidDataValue);
+    }
+    data.get(5..5 + len).ok_or(ModBusException::InvalidDataValue)
+}
+
+/// The range of `cnt` items starting at `addr`, which must be within a table of `len` items.
+fn range(addr: u16, cnt: u16, len: usize) -> Result<Range<usize>, ModBusException> {
+    let range = addr as usize..addr as usize + cnt as usize;
+    if range.end > len {
+        return Err(ModBusException::InvalidDataAddress);
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> ModBusRegisterMap {
+        ModBusRegisterMap {
+            coils: vec![false; 10],
+            discretes: vec![true, false, true],
+            inputs: vec![],
+            holdings: vec![0x1234, 0x5678],
+        }
+    }
+
+    #[test]
+    fn read_and_write() {
+        let mut map = map();
+        assert_eq!(handle_pdu(&mut map, &[3, 0, 1, 0, 1]), [3, 2, 0x56, 0x78]);
+        assert_eq!(handle_pdu(&mut map, &[2, 0, 0, 0, 3]), [2, 1, 0b101]);
+        assert_eq!(handle_pdu(&mut map, &[6, 0, 0, 0xAB, 0xCD]), [6, 0, 0, 0xAB, 0xCD]);
+        assert_eq!(map.holdings[0], 0xABCD);
+        assert_eq!(handle_pdu(&mut map, &[15, 0, 8, 0, 2, 1, 0b10]), [15, 0, 8, 0, 2]);
+        assert_eq!(map.coils[8..], [false, true]);
+        assert_eq!(handle_pdu(&mut map, &[1, 0, 8, 0, 2]), [1, 1, 0b10]);
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut map = map();
+        // out of range
+        assert_eq!(handle_pdu(&mut map, &[3, 0, 1, 0, 2]), [0x83, 2]);
+        assert_eq!(handle_pdu(&mut map, &[4, 0, 0, 0, 1]), [0x84, 2]);
+        // invalid count
+        assert_eq!(handle_pdu(&mut map, &[3, 0, 0, 0, 0]), [0x83, 3]);
+        // truncated request
+        assert_eq!(handle_pdu(&mut map, &[3, 0]), [0x83, 3]);
+        // invalid coil value
+        assert_eq!(handle_pdu(&mut map, &[5, 0, 0, 0x12, 0x34]), [0x85, 3]);
+        assert_eq!(handle_pdu(&mut map, &[0x44]), [0xC4, 1]);
+    }
+}
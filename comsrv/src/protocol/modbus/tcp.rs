@@ -1,5 +1,6 @@
 use crate::protocol::modbus::{FunctionCode, ModBusException, TransactionInfo};
 use anyhow::anyhow;
+use std::io::ErrorKind;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub struct TcpHandler<T: FunctionCode> {
@@ -31,6 +32,9 @@ impl<T: FunctionCode> TcpHandler<T> {
         request[5] = len_buf[1];
         stream.write_all(&request).await.map_err(crate::Error::transport)?;
         let reply = read_tcp_frame(transaction, self.function_code.function_code(), stream).await?;
+        if self.function_code.is_unframed() {
+            return self.function_code.parse_frame(&reply);
+        }
         let header_len = self.function_code.get_header_length();
         if reply.len() < header_len {
             return Err(crate::Error::argument(anyhow!("ModBus frame shorter than header")));
@@ -43,6 +47,104 @@ impl<T: FunctionCode> TcpHandler<T> {
     }
 }
 
+/// Upper limit of the MBAP length field: unit id, function code and at most 253 bytes of PDU data
+const MAX_MBAP_LENGTH: u16 = 255;
+
+/// Validate the MBAP header and function code of a reply. Returns the number of bytes following the header as
+/// announced by the MBAP length field.
+fn parse_header(transaction: &TransactionInfo, function_code: u8, header: &[u8; 8]) -> crate::Result<usize> {
+    let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+    let proto = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]);
+    let station_address = header[6];
+    let parsed_function_code = header[7];
+    let err = if proto != 0 {
+        anyhow!("Invalid protocol identifier {} in MBAP header, expected 0", proto)
+    } else if len < 2 {
+        anyhow!("MBAP length field {} too short to hold unit id and function code", len)
+    } else if len > MAX_MBAP_LENGTH {
+        anyhow!("MBAP length field {} exceeds maximum frame length", len)
+    } else if transaction_id != transaction.transaction_id {
+        anyhow!(
+            "Unexpected transaction id {:#06x} in MBAP header, expected {:#06x}",
+            transaction_id,
+            transaction.transaction_id
+        )
+    } else if station_address != transaction.station_address {
+        anyhow!(
+            "Unexpected unit id {} in MBAP header, expected {}",
+            station_address,
+            transaction.station_address
+        )
+    } else if parsed_function_code == (0x80 | function_code) {
+        if len != 3 {
+            anyhow!("MBAP length field {} invalid for an exception reply", len)
+        } else {
+            return Ok(1);
+        }
+    } else if parsed_function_code != function_code {
+        anyhow!(
+            "Unexpected function code {:#04x} in reply, expected {:#04x}",
+            parsed_function_code,
+            function_code
+        )
+    } else {
+        return Ok((len - 2) as usize);
+    };
+    log::debug!("Rejecting ModBus TCP header {:02x?}: {}", header, err);
+    Err(crate::Error::protocol(err))
+}
+
+/// MBAP header of a request received by a ModBus TCP server
+pub struct RequestHeader {
+    pub transaction_id: u16,
+    pub unit_id: u8,
+}
+
+/// Read a request frame and return its header and PDU. Returns `None` if the connection was closed before a
+/// request started.
+pub async fn read_request<T: AsyncRead + Unpin>(stream: &mut T) -> crate::Result<Option<(RequestHeader, Vec<u8>)>> {
+    let mut header = [0_u8; 7];
+    match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(crate::Error::transport(err)),
+    }
+    let proto = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]);
+    if proto != 0 {
+        return Err(crate::Error::protocol(anyhow!(
+            "Invalid protocol identifier {} in MBAP header, expected 0",
+            proto
+        )));
+    }
+    if !(2..=MAX_MBAP_LENGTH).contains(&len) {
+        return Err(crate::Error::protocol(anyhow!("Invalid MBAP length field {}", len)));
+    }
+    let mut pdu = vec![0_u8; len as usize - 1];
+    stream.read_exact(&mut pdu).await.map_err(crate::Error::transport)?;
+    let header = RequestHeader {
+        transaction_id: u16::from_be_bytes([header[0], header[1]]),
+        unit_id: header[6],
+    };
+    Ok(Some((header, pdu)))
+}
+
+/// Frame the reply `pdu` to the request with the given `header`.
+pub fn format_reply(header: &RequestHeader, pdu: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(pdu.len() + 7);
+    ret.extend(&header.transaction_id.to_be_bytes());
+    ret.extend(&[0_u8, 0]);
+    ret.extend(&(pdu.len() as u16 + 1).to_be_bytes());
+    ret.push(header.unit_id);
+    ret.extend(pdu);
+    ret
+}
+
+/// Read a reply frame and return the data following the function code.
+///
+/// Frames may be split across several TCP segments, hence the header and data are read with `read_exact()`, which
+/// keeps reading until the buffer is filled.
 async fn read_tcp_frame<T: AsyncRead + AsyncWrite + Unpin>(
     transaction: &TransactionInfo,
     function_code: u8,
@@ -50,25 +152,67 @@ async fn read_tcp_frame<T: AsyncRead + AsyncWrite + Unpin>(
 ) -> crate::Result<Vec<u8>> {
     let mut header = [0_u8; 8];
     stream.read_exact(&mut header).await.map_err(crate::Error::transport)?;
-    let transaction_id = u16::from_be_bytes([header[0], header[1]]);
-    let proto = u16::from_be_bytes([header[2], header[3]]);
-    let len = u16::from_be_bytes([header[4], header[5]]);
-    let station_address = header[6];
-    let parsed_function_code = header[7];
-    if station_address != transaction.station_address
-        || transaction_id != transaction.transaction_id
-        || proto != 0
-        || len < 2
-    {
-        return Err(crate::Error::protocol(anyhow!("Invalid answer received.")));
-    }
-    if parsed_function_code == (0x80 | function_code) {
-        let exception_code = stream.read_u8().await?;
-        return Err(crate::Error::protocol(anyhow!(ModBusException::from_code(exception_code))));
-    } else if parsed_function_code != function_code {
-        return Err(crate::Error::protocol(anyhow!("Invalid frame")));
+    let len = parse_header(transaction, function_code, &header)?;
+    let mut buf = vec![0_u8; len];
+    stream.read_exact(&mut buf).await.map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => crate::Error::protocol(anyhow!(
+            "Connection closed before the {} bytes announced in the MBAP header were received",
+            len
+        )),
+        _ => crate::Error::transport(err),
+    })?;
+    if header[7] == (0x80 | function_code) {
+        return Err(crate::Error::protocol(anyhow!(ModBusException::from_code(buf[0]))));
     }
-    let mut buf = vec![0_u8; (len - 2) as usize];
-    stream.read_exact(&mut buf).await.map_err(crate::Error::transport)?;
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::modbus::registers::RawPdu;
+    use tokio::io::duplex;
+
+    async fn query(reply: Vec<Vec<u8>>) -> crate::Result<Vec<u8>> {
+        let (mut a, mut b) = duplex(256);
+        let transaction = TransactionInfo {
+            transaction_id: 0x1234,
+            station_address: 0x11,
+            inter_frame_delay: Default::default(),
+        };
+        let responder = tokio::spawn(async move {
+            let mut request = [0_u8; 9];
+            b.read_exact(&mut request).await.unwrap();
+            for segment in reply {
+                b.write_all(&segment).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let handler = TcpHandler::new(RawPdu::new(0x65, vec![0xAA]).unwrap());
+        let ret = handler.handle(&transaction, &mut a).await;
+        responder.await.unwrap();
+        ret
+    }
+
+    #[tokio::test]
+    async fn segmented_header() {
+        let ret = query(vec![vec![0x12, 0x34, 0], vec![0, 0, 5, 0x11], vec![0x65, 0x02, 0x12, 0x34]]).await;
+        assert_eq!(ret.unwrap(), vec![0x12, 0x34]);
+    }
+
+    #[tokio::test]
+    async fn invalid_header() {
+        // wrong protocol id
+        let ret = query(vec![vec![0x12, 0x34, 0, 1, 0, 5, 0x11, 0x65, 0x02, 0x12, 0x34]]).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+        // length field does not cover the function code
+        let ret = query(vec![vec![0x12, 0x34, 0, 0, 0, 1, 0x11, 0x65]]).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+        // connection closed before the announced length was received
+        let ret = query(vec![vec![0x12, 0x34, 0, 0, 0, 5, 0x11, 0x65, 0x02]]).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+        // exception
+        let ret = query(vec![vec![0x12, 0x34, 0, 0, 0, 3, 0x11, 0xE5, 0x02]]).await;
+        assert!(matches!(ret, Err(crate::Error::Protocol(_))));
+    }
+}
@@ -14,13 +14,83 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time;
 
 const PROLOGIX_TIMEOUT: f32 = 1.0;
+const GPIB_ADDRESSES: std::ops::RangeInclusive<u8> = 1..=30;
 
-pub async fn init_prologix<T: AsyncRead + AsyncWrite + Unpin>(serial: &mut T) -> crate::Result<()> {
+/// Configuration of the adapter as set with `PrologixAdapterRequest::Configure`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrologixConfig {
+    pub auto: bool,
+    pub eoi: bool,
+    pub eos: u8,
+    pub read_timeout_ms: u32,
+}
+
+impl PrologixConfig {
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.eos > 3 {
+            return Err(Error::argument(anyhow!("Invalid ++eos mode {}, expected 0..3", self.eos)));
+        }
+        if !(1..=3000).contains(&self.read_timeout_ms) {
+            return Err(Error::argument(anyhow!(
+                "Invalid read timeout of {}ms, expected 1..3000ms",
+                self.read_timeout_ms
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub async fn init_prologix<T: AsyncRead + AsyncWrite + Unpin>(
+    serial: &mut T,
+    config: Option<&PrologixConfig>,
+) -> crate::Result<()> {
     log::debug!("Initalizing prologix.");
     write(serial, "++savecfg 0\n").await?;
-    write(serial, "++auto 0\n").await?;
-    // we manually append termination chars
-    write(serial, "++eos 3\n").await
+    match config {
+        Some(config) => configure_prologix(serial, config).await,
+        None => {
+            write(serial, "++auto 0\n").await?;
+            // we manually append termination chars
+            write(serial, "++eos 3\n").await
+        }
+    }
+}
+
+pub async fn configure_prologix<T: AsyncWrite + Unpin>(serial: &mut T, config: &PrologixConfig) -> crate::Result<()> {
+    log::debug!("Configuring prologix: {:?}", config);
+    config.validate()?;
+    write(serial, &format!("++auto {}\n", config.auto as u8)).await?;
+    write(serial, &format!("++eoi {}\n", config.eoi as u8)).await?;
+    write(serial, &format!("++eos {}\n", config.eos)).await?;
+    write(serial, &format!("++read_tmo_ms {}\n", config.read_timeout_ms)).await
+}
+
+/// Serial polls all GPIB addresses and returns the addresses which responded within `timeout`.
+pub async fn scan_bus<T: AsyncRead + AsyncWrite + Unpin>(serial: &mut T, timeout: Duration) -> crate::Result<Vec<u8>> {
+    let mut ret = Vec::new();
+    for addr in GPIB_ADDRESSES {
+        let _ = read_all(serial).await.map_err(crate::Error::transport)?;
+        write(serial, &format!("++addr {}\n", addr)).await?;
+        write(serial, "++spoll\n").await?;
+        match read_prologix(serial, timeout).await {
+            Ok(reply) if reply.trim().parse::<u8>().is_ok() => ret.push(addr),
+            Ok(_) | Err(Error::Protocol(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    log::debug!("Found GPIB instruments at {:?}", ret);
+    Ok(ret)
+}
+
+/// Reject requests which are not supported by Prologix adapters.
+pub fn validate_request(req: &ScpiRequest) -> crate::Result<()> {
+    match req {
+        ScpiRequest::QueryBinary(_) => Err(Error::argument(anyhow!(
+            "ScpiRequest::QueryBinary not implemented for Prologix."
+        ))),
+        ScpiRequest::ReadRaw => Err(Error::argument(anyhow!("ScpiRequest::ReadRaw not implemented for Prologix."))),
+        req => crate::protocol::scpi::validate_non_visa(req),
+    }
 }
 
 pub async fn handle_prologix_request<T: AsyncRead + AsyncWrite + Unpin>(
@@ -30,6 +100,7 @@ pub async fn handle_prologix_request<T: AsyncRead + AsyncWrite + Unpin>(
     timeout: Option<Duration>,
 ) -> crate::Result<ScpiResponse> {
     log::debug!("handling prologix request for address {}", addr);
+    validate_request(&req)?;
     let _ = read_all(serial).await.map_err(crate::Error::transport)?;
     let addr_set = format!("++addr {}\n", addr);
     serial.write(addr_set.as_bytes()).await.map_err(Error::transport)?;
@@ -45,16 +116,12 @@ pub async fn handle_prologix_request<T: AsyncRead + AsyncWrite + Unpin>(
             let reply = read_prologix(serial, timeout).await?;
             Ok(ScpiResponse::String(reply))
         }
-        ScpiRequest::QueryBinary(_) => {
-            log::error!("ScpiRequest::QueryBinary not implemented for Prologix.");
-            Err(Error::argument(anyhow!(
-                "ScpiRequest::QueryBinary not implemented for Prologix."
-            )))
-        }
-        ScpiRequest::ReadRaw => {
-            log::error!("ScpiRequest::ReadRaw not implemented for Prologix.");
-            Err(Error::argument(anyhow!("ScpiRequest::ReadRaw not implemented for Prologix.")))
-        }
+        ScpiRequest::QueryBinary(_)
+        | ScpiRequest::ReadRaw
+        | ScpiRequest::VisaLock { .. }
+        | ScpiRequest::VisaUnlock
+        | ScpiRequest::GetAttribute { .. }
+        | ScpiRequest::SetAttribute { .. } => unreachable!("rejected by validate_request"),
     }
 }
 
@@ -98,3 +165,68 @@ async fn read_prologix<T: AsyncRead + Unpin>(serial: &mut T, timeout: Duration)
     }
     String::from_utf8(ret).map_err(|_| crate::Error::protocol(anyhow!("Could not decode reply.")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncBufReadExt};
+
+    #[tokio::test]
+    async fn configure() {
+        let (mut a, mut b) = duplex(1024);
+        let config = PrologixConfig {
+            auto: true,
+            eoi: false,
+            eos: 2,
+            read_timeout_ms: 500,
+        };
+        init_prologix(&mut a, Some(&config)).await.unwrap();
+        let written = read_all(&mut b).await.unwrap();
+        assert_eq!(
+            written,
+            b"++savecfg 0\n++auto 1\n++eoi 0\n++eos 2\n++read_tmo_ms 500\n".to_vec()
+        );
+
+        init_prologix(&mut a, None).await.unwrap();
+        let written = read_all(&mut b).await.unwrap();
+        assert_eq!(written, b"++savecfg 0\n++auto 0\n++eos 3\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let (mut a, b) = duplex(1024);
+        tokio::spawn(async move {
+            let (rx, mut tx) = tokio::io::split(b);
+            let mut lines = tokio::io::BufReader::new(rx).lines();
+            let mut addr = 0;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if let Some(x) = line.strip_prefix("++addr ") {
+                    addr = x.parse::<u8>().unwrap();
+                } else if line == "++spoll" && (addr == 5 || addr == 22) {
+                    tx.write_all(b"16\n").await.unwrap();
+                }
+            }
+        });
+        let ret = scan_bus(&mut a, Duration::from_millis(20)).await.unwrap();
+        assert_eq!(ret, vec![5, 22]);
+    }
+
+    #[tokio::test]
+    async fn configure_invalid() {
+        let (mut a, mut b) = duplex(1024);
+        let config = PrologixConfig {
+            auto: false,
+            eoi: true,
+            eos: 4,
+            read_timeout_ms: 500,
+        };
+        assert!(configure_prologix(&mut a, &config).await.is_err());
+        let config = PrologixConfig {
+            eos: 3,
+            read_timeout_ms: 5000,
+            ..config
+        };
+        assert!(configure_prologix(&mut a, &config).await.is_err());
+        assert!(read_all(&mut b).await.unwrap().is_empty());
+    }
+}
@@ -1,39 +1,209 @@
 /// This module implements some base types and functions to interact with SCPI-based instruments
 use anyhow::anyhow;
+use comsrv_protocol::ScpiRequest;
+use thiserror::Error;
 
-fn invalid_binary_header() -> crate::Error {
-    crate::Error::protocol(anyhow!("Invalid binary header"))
-}
+/// Default limit for the length of binary data returned by an instrument.
+pub const DEFAULT_MAX_BINARY_LENGTH: usize = 64 * 1024 * 1024;
+
+const DEFAULT_LENGTH_BEFORE_BLOCK: usize = 25;
 
-/// Parse an SCPI binary header.
-pub fn parse_binary_header(rx: &[u8]) -> crate::Result<(usize, usize)> {
-    let begin = rx.iter().position(|x| *x == b'#').ok_or_else(invalid_binary_header)?;
+/// Upper bound for the number of bytes preceding the binary data, i.e. some leading bytes,
+/// `#`, the digit count and up to 9 length digits.
+pub const MAX_BINARY_HEADER_LENGTH: usize = DEFAULT_LENGTH_BEFORE_BLOCK + 2 + 9;
 
-    const DEFAULT_LENGTH_BEFORE_BLOCK: usize = 25;
+/// Errors occurring while parsing an IEEE 488.2 binary block.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BinaryBlockError {
+    #[error("Binary block header not found")]
+    MissingHeader,
+    #[error("Binary block header truncated")]
+    TruncatedHeader,
+    #[error("Invalid digit count `{0}` in binary block header")]
+    InvalidDigitCount(char),
+    #[error("Invalid length in binary block header")]
+    InvalidLength,
+    #[error("Binary block truncated: expected {expected} bytes but received {received}")]
+    TruncatedData { expected: usize, received: usize },
+    #[error("Binary response of {length} bytes exceeds the limit of {max_length} bytes")]
+    LengthExceeded { length: usize, max_length: usize },
+}
 
-    if begin > DEFAULT_LENGTH_BEFORE_BLOCK {
-        return Err(invalid_binary_header());
+impl From<BinaryBlockError> for crate::Error {
+    fn from(err: BinaryBlockError) -> Self {
+        crate::Error::protocol(anyhow::Error::from(err))
     }
-    let header_length = if rx.len() < begin + 2 {
-        0
-    } else {
-        let data = String::from_utf8(vec![rx[begin + 1]]).map_err(|_| invalid_binary_header())?;
-        data.parse::<usize>().map_err(|_| invalid_binary_header())?
+}
+
+/// Reject VISA locks and attributes, which are only supported by VISA instruments.
+pub fn validate_non_visa(req: &ScpiRequest) -> crate::Result<()> {
+    match req {
+        ScpiRequest::VisaLock { .. } | ScpiRequest::VisaUnlock => Err(crate::Error::argument(anyhow!(
+            "VISA locks are only supported by VISA instruments."
+        ))),
+        ScpiRequest::GetAttribute { .. } | ScpiRequest::SetAttribute { .. } => Err(crate::Error::argument(anyhow!(
+            "VISA attributes are only supported by VISA instruments."
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Parse an SCPI binary header and return the offset and the length of the binary data in `rx`.
+///
+/// Both definite (`#<n><length><data>`) and indefinite length blocks (`#0<data>\n`) are supported.
+/// Rejects blocks announcing more than `max_length` bytes of data.
+pub fn parse_binary_header(rx: &[u8], max_length: usize) -> crate::Result<(usize, usize)> {
+    Ok(parse_binary_block(rx, max_length)?)
+}
+
+fn parse_binary_block(rx: &[u8], max_length: usize) -> Result<(usize, usize), BinaryBlockError> {
+    let (offset, length) = parse_block_header(rx)?;
+    let (length, received) = match length {
+        Some(length) => (length, rx.len() - offset),
+        None => {
+            // indefinite length block, terminated by a newline (sent with EOI)
+            let data = &rx[offset..];
+            let length = data.strip_suffix(b"\n").unwrap_or(data).len();
+            (length, length)
+        }
     };
+    if length > max_length {
+        return Err(BinaryBlockError::LengthExceeded { length, max_length });
+    }
+    if length > received {
+        return Err(BinaryBlockError::TruncatedData {
+            expected: length,
+            received,
+        });
+    }
+    Ok((offset, length))
+}
+
+/// Parse the header of a binary block and return the offset of the data and its length, which is `None` for
+/// indefinite length blocks.
+fn parse_block_header(rx: &[u8]) -> Result<(usize, Option<usize>), BinaryBlockError> {
+    let begin = rx
+        .iter()
+        .take(DEFAULT_LENGTH_BEFORE_BLOCK + 1)
+        .position(|x| *x == b'#')
+        .ok_or(BinaryBlockError::MissingHeader)?;
+    let digits = *rx.get(begin + 1).ok_or(BinaryBlockError::TruncatedHeader)?;
+    let header_length = (digits as char)
+        .to_digit(10)
+        .ok_or(BinaryBlockError::InvalidDigitCount(digits as char))? as usize;
     let offset = begin + 2 + header_length;
-    if offset > rx.len() {
-        return Err(invalid_binary_header());
-    }
-    let data_length = if header_length > 0 {
-        let x: Vec<_> = rx[begin + 2..offset].to_vec();
-        let data = String::from_utf8(x).map_err(|_| invalid_binary_header())?;
-        data.parse::<usize>().map_err(|_| invalid_binary_header())?
-    } else {
-        0
+    if header_length == 0 {
+        return Ok((offset, None));
+    }
+    let length = rx.get(begin + 2..offset).ok_or(BinaryBlockError::TruncatedHeader)?;
+    let length = std::str::from_utf8(length)
+        .ok()
+        .filter(|x| x.bytes().all(|x| x.is_ascii_digit()))
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or(BinaryBlockError::InvalidLength)?;
+    Ok((offset, Some(length)))
+}
+
+/// Determine the progress of a binary block transfer given the data `rx` received so far. Returns the number of
+/// data bytes received and the length announced by the header, or `None` if no complete header was received.
+pub fn binary_progress(rx: &[u8]) -> Option<(u64, Option<u64>)> {
+    let (offset, length) = parse_block_header(rx).ok()?;
+    let received = rx.len().saturating_sub(offset);
+    let ret = match length {
+        Some(length) => (received.min(length) as u64, Some(length as u64)),
+        None => (received as u64, None),
     };
-    if offset + data_length > rx.len() {
-        Err(invalid_binary_header())
-    } else {
-        Ok((offset, data_length))
+    Some(ret)
+}
+
+/// Check whether `rx` holds a complete binary block including its termination. Also returns `true` once `rx` cannot
+/// become a valid block of at most `max_length` bytes, such that no further data is awaited.
+pub fn binary_block_complete(rx: &[u8], max_length: usize) -> bool {
+    match parse_block_header(rx) {
+        Ok((offset, Some(length))) => length > max_length || rx.len() > offset + length,
+        Ok((_, None)) => rx.ends_with(b"\n"),
+        Err(BinaryBlockError::MissingHeader | BinaryBlockError::TruncatedHeader) => {
+            rx.len() >= MAX_BINARY_HEADER_LENGTH || rx.ends_with(b"\n")
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rx: &[u8]) -> Result<(usize, usize), BinaryBlockError> {
+        parse_binary_block(rx, DEFAULT_MAX_BINARY_LENGTH)
+    }
+
+    #[test]
+    fn binary_header() {
+        assert_eq!(parse(b"#15abcde\n"), Ok((3, 5)));
+        assert_eq!(parse_binary_block(b"#15abcde\n", 5), Ok((3, 5)));
+
+        let mut rx = b"DATA #42048".to_vec();
+        rx.extend((0..2048_u32).map(|x| x as u8));
+        rx.push(b'\n');
+        assert_eq!(parse(&rx), Ok((11, 2048)));
+        assert!(parse_binary_header(&rx, DEFAULT_MAX_BINARY_LENGTH).is_ok());
+    }
+
+    #[test]
+    fn indefinite_length() {
+        assert_eq!(parse(b"#0abcde\n"), Ok((2, 5)));
+        assert_eq!(parse(b"#0abcde"), Ok((2, 5)));
+        assert_eq!(parse(b"#0"), Ok((2, 0)));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse(b"abc"), Err(BinaryBlockError::MissingHeader));
+        assert_eq!(parse(b""), Err(BinaryBlockError::MissingHeader));
+        assert_eq!(parse(&[b'a'; 30]), Err(BinaryBlockError::MissingHeader));
+        assert_eq!(parse(b"#"), Err(BinaryBlockError::TruncatedHeader));
+        assert_eq!(parse(b"#x5abcde"), Err(BinaryBlockError::InvalidDigitCount('x')));
+        assert_eq!(parse(b"#312"), Err(BinaryBlockError::TruncatedHeader));
+        assert_eq!(parse(b"#2x1abc"), Err(BinaryBlockError::InvalidLength));
+        assert_eq!(parse(b"#2+1abc"), Err(BinaryBlockError::InvalidLength));
+        assert_eq!(
+            parse(b"#15abc"),
+            Err(BinaryBlockError::TruncatedData {
+                expected: 5,
+                received: 3
+            })
+        );
+    }
+
+    #[test]
+    fn progress() {
+        assert_eq!(binary_progress(b"#"), None);
+        assert_eq!(binary_progress(b"#41"), None);
+        assert_eq!(binary_progress(b"#41000"), Some((0, Some(1000))));
+        assert_eq!(binary_progress(b"#41000abc"), Some((3, Some(1000))));
+        assert_eq!(binary_progress(b"#13abc\n"), Some((3, Some(3))));
+        assert_eq!(binary_progress(b"#0abc"), Some((3, None)));
+    }
+
+    #[test]
+    fn binary_header_exceeds_limit() {
+        assert_eq!(
+            parse_binary_block(b"#15abcde\n", 4),
+            Err(BinaryBlockError::LengthExceeded {
+                length: 5,
+                max_length: 4
+            })
+        );
+        assert_eq!(
+            parse_binary_block(b"#0abcde\n", 4),
+            Err(BinaryBlockError::LengthExceeded {
+                length: 5,
+                max_length: 4
+            })
+        );
+
+        // the length is checked before the data, a bogus header must not be accepted
+        let err = parse_binary_header(b"#9999999999abc", DEFAULT_MAX_BINARY_LENGTH).unwrap_err();
+        assert!(format!("{}", err).contains("exceeds the limit"));
     }
 }
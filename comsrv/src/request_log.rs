@@ -0,0 +1,171 @@
+//! Implements the request log, which is enabled with `--request-log`.
+//!
+//! All requests and their responses are appended to a file in JSON-lines format, one object per line carrying
+//! the time, the request id and either the `request` or the `response`. Arrays longer than
+//! [`MAX_LOGGED_ARRAY_LEN`] and strings longer than [`MAX_LOGGED_STRING_LEN`], such as binary payloads which are
+//! serialized as base64, are replaced by their length. Once the file exceeds its maximum size, it is renamed by
+//! appending `.1` to its name, replacing an older log, and a new file is started. The file is written on a
+//! dedicated thread, such that slow disks do not stall the request handlers.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+/// Arrays with more items are logged by their length
+pub const MAX_LOGGED_ARRAY_LEN: usize = 64;
+
+/// Strings with more characters are logged by their length
+pub const MAX_LOGGED_STRING_LEN: usize = 256;
+
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+pub struct RequestLog {
+    tx: Option<mpsc::UnboundedSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl RequestLog {
+    /// Append to the log at `path`, which is rotated once it exceeds `max_size` bytes.
+    pub fn open(path: PathBuf, max_size: u64) -> crate::Result<Self> {
+        if max_size == 0 {
+            return Err(crate::Error::argument(anyhow!("Request log size must be larger than 0.")));
+        }
+        let mut file = open_file(path, max_size)?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let writer = thread::spawn(move || {
+            while let Some(line) = rx.blocking_recv() {
+                if let Err(err) = file.write(line.as_bytes()) {
+                    log::warn!("Cannot write to request log `{}`: {}", file.path.display(), err);
+                }
+            }
+        });
+        Ok(Self {
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    /// Log an incoming request
+    pub fn request<T: Serialize>(&self, request_id: &str, request: &T) {
+        self.append(request_id, "request", request);
+    }
+
+    /// Log the response to the request with the given `request_id`
+    pub fn response<T: Serialize>(&self, request_id: &str, response: &T) {
+        self.append(request_id, "response", response);
+    }
+
+    fn append<T: Serialize>(&self, request_id: &str, key: &str, value: &T) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut entry = json!({ "time": time.as_secs_f64(), "request_id": request_id });
+        entry[key] = serde_json::to_value(value).map(elide).unwrap_or_default();
+        let mut line = entry.to_string();
+        line.push('\n');
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(line);
+        }
+    }
+}
+
+impl Drop for RequestLog {
+    /// Wait until the pending entries are written.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl LogFile {
+    fn write(&mut self, line: &[u8]) -> crate::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated).map_err(crate::Error::transport)?;
+            *self = open_file(self.path.clone(), self.max_size)?;
+        }
+        self.file.write_all(line).map_err(crate::Error::transport)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_file(path: PathBuf, max_size: u64) -> crate::Result<LogFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|err| crate::Error::argument(anyhow!("Cannot open request log `{}`: {}", path.display(), err)))?;
+    let size = file.metadata().map_err(crate::Error::transport)?.len();
+    Ok(LogFile {
+        path,
+        max_size,
+        file,
+        size,
+    })
+}
+
+/// Replace the arrays in `value` with more than [`MAX_LOGGED_ARRAY_LEN`] items and the strings with more than
+/// [`MAX_LOGGED_STRING_LEN`] characters by their length.
+fn elide(value: Value) -> Value {
+    match value {
+        Value::Array(x) if x.len() > MAX_LOGGED_ARRAY_LEN => json!({ "len": x.len() }),
+        Value::Array(x) => Value::Array(x.into_iter().map(elide).collect()),
+        Value::Object(x) => Value::Object(x.into_iter().map(|(k, v)| (k, elide(v))).collect()),
+        Value::String(x) if x.len() > MAX_LOGGED_STRING_LEN => json!({ "len": x.len() }),
+        x => x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(path: &Path) -> Vec<Value> {
+        let content = fs::read_to_string(path).unwrap();
+        content.lines().map(|x| serde_json::from_str(x).unwrap()).collect()
+    }
+
+    #[test]
+    fn log_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.log");
+        let log = RequestLog::open(path.clone(), 200).unwrap();
+        log.request("1", &comsrv_protocol::Request::Version);
+        log.response("1", &vec![0_u8; 1000]);
+        drop(log);
+        let entries = lines(&path);
+        assert_eq!(entries[0]["request"], json!("Version"));
+        assert_eq!(entries[0]["request_id"], json!("1"));
+        assert_eq!(entries[1]["response"], json!({ "len": 1000 }));
+
+        let log = RequestLog::open(path.clone(), 200).unwrap();
+        for k in 0..5 {
+            log.request(&k.to_string(), &comsrv_protocol::Request::ListAliases);
+        }
+        drop(log);
+        assert!(fs::metadata(&path).unwrap().len() <= 200);
+        assert!(dir.path().join("requests.log.1").exists());
+    }
+
+    #[test]
+    fn elide_binary_payloads() {
+        let response = comsrv_protocol::Response::Scpi(comsrv_protocol::ScpiResponse::Binary { data: vec![0; 1000] });
+        let value = elide(serde_json::to_value(&response).unwrap());
+        assert_eq!(value["Scpi"]["Binary"]["data"], json!({ "len": 1336 }));
+        assert_eq!(elide(json!("short")), json!("short"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Implements [`Request::SelfTest`](comsrv_protocol::Request::SelfTest), which exercises the core code paths of the
+//! `comsrv` without requiring any hardware.
+//!
+//! The tests run against a private [`Server`] instance, such that no notifications are broadcast to connected
+//! clients. However, CAN frames are transmitted on the process-wide loopback bus and are thus visible to other
+//! loopback instruments.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use comsrv_protocol::cobs_stream::{CobsStreamResponse, CrcKind};
+use comsrv_protocol::{
+    ByteStreamInstrument, ByteStreamRequest, CanInstrument, CanMessage, CanRequest, CanResponse, DataFrame, GctMessage,
+    Request, Response, TcpInstrument,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::app::Server;
+use crate::inventory::Instrument as _;
+use crate::protocol::bytestream::cobs::{cobs_decode, cobs_encode};
+use crate::protocol::can::crc::crc16;
+use crate::protocol::cobs_stream::{crc32, CobsStream};
+use crate::transport::can;
+
+/// Time granted to each test involving IO
+const TEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Id of the raw CAN frame transmitted on the loopback bus
+const LOOPBACK_FRAME_ID: u32 = 0x1E57_0001;
+
+/// Run all tests. Returns whether all tests passed and one line per test describing its result.
+pub async fn run() -> (bool, Vec<String>) {
+    let results = [
+        ("JSON encoding", json_encoding()),
+        ("COBS encoding", cobs_encoding()),
+        ("CRC", crc()),
+        ("COBS stream", cobs_stream().await),
+        ("CAN loopback", can_loopback().await),
+    ];
+    let passed = results.iter().all(|(_, x)| x.is_ok());
+    let details = results
+        .iter()
+        .map(|(name, result)| match result {
+            Ok(_) => format!("{}: ok", name),
+            Err(err) => format!("{}: failed: {}", name, err),
+        })
+        .collect();
+    (passed, details)
+}
+
+fn failed(msg: &str) -> crate::Error {
+    crate::Error::internal(anyhow!(msg.to_string()))
+}
+
+fn check(condition: bool, msg: &str) -> crate::Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(failed(msg))
+    }
+}
+
+fn json_encoding() -> crate::Result<()> {
+    let request = Request::Bytes {
+        instrument: ByteStreamInstrument::Tcp(TcpInstrument::new("127.0.0.1", 5000)),
+        request: ByteStreamRequest::Write(vec![0, 1, 0xFF]),
+        lock: None,
+        truncate: false,
+    };
+    let encoded = serde_json::to_string(&request).map_err(crate::Error::internal)?;
+    let decoded: Request = serde_json::from_str(&encoded).map_err(crate::Error::internal)?;
+    let reencoded = serde_json::to_string(&decoded).map_err(crate::Error::internal)?;
+    check(encoded == reencoded, "Request changed in JSON round trip")
+}
+
+fn cobs_encoding() -> crate::Result<()> {
+    // longer than a COBS block and containing zeros
+    let data: Vec<u8> = (0..600).map(|x| (x % 256) as u8).collect();
+    let encoded = cobs_encode(&data);
+    check(
+        encoded.iter().position(|x| *x == 0) == Some(encoded.len() - 1),
+        "Encoded frame must end with the only zero byte",
+    )?;
+    check(cobs_decode(&encoded) == data, "Frame changed in COBS round trip")
+}
+
+fn crc() -> crate::Result<()> {
+    check(crc16(b"123456789") == 0x29B1, "Invalid CRC-16 check value")?;
+    check(crc32(b"123456789") == 0xCBF4_3926, "Invalid CRC-32 check value")
+}
+
+/// Start a COBS stream on an in-memory bytestream, which echoes all bytes back to the stream.
+async fn cobs_stream() -> crate::Result<()> {
+    let (server, _rx) = Server::new();
+    let mut client = server.loopback().await;
+    let (local, remote) = tokio::io::duplex(1024);
+    let (read, write) = tokio::io::split(local);
+    let instr = ByteStreamInstrument::Tcp(TcpInstrument::new("127.0.0.1", 0));
+    let stream = CobsStream::start(read, write, server, instr, CrcKind::Crc32);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+    let echo = tokio::spawn(async move {
+        let mut buf = [0_u8; 256];
+        while let Ok(n) = remote_read.read(&mut buf).await {
+            if n == 0 || remote_write.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+    });
+    let data = vec![1, 0, 2, 0, 0, 3];
+    stream.send(data.clone())?;
+    let received = timeout(TEST_TIMEOUT, async {
+        while let Some(msg) = client.next().await {
+            if let broadcast_wsrpc::Response::Notify(Response::CobsStream(CobsStreamResponse::MessageReceived {
+                data,
+                ..
+            })) = msg
+            {
+                return Some(data);
+            }
+        }
+        None
+    })
+    .await;
+    stream.cancel();
+    echo.abort();
+    match received {
+        Ok(Some(received)) => check(received == data, "Frame changed in COBS stream round trip"),
+        Ok(None) => Err(failed("Server closed")),
+        Err(_) => Err(failed("No frame received")),
+    }
+}
+
+/// Transmit a raw frame and a GCT message spanning several frames on the loopback CAN bus.
+async fn can_loopback() -> crate::Result<()> {
+    let (server, _rx) = Server::new();
+    let mut client = server.loopback().await;
+    let mut instr = can::Instrument::new(&server);
+    let request = |inner| can::Request {
+        inner,
+        instrument: CanInstrument::Loopback,
+    };
+    let ret = async {
+        instr.request(request(CanRequest::ListenRaw(true))).await?;
+        instr.request(request(CanRequest::ListenGct(true))).await?;
+        let frame = DataFrame {
+            id: LOOPBACK_FRAME_ID,
+            ext_id: true,
+            data: vec![1, 2, 3],
+        };
+        instr.request(request(CanRequest::TxRaw(CanMessage::Data(frame)))).await?;
+        let gct = GctMessage::Ddp {
+            src: 0x3E,
+            dst: 0x3D,
+            data: (0..20).collect(),
+            version: 1,
+        };
+        let expected = gct.to_string();
+        instr.request(request(CanRequest::TxGct(gct))).await?;
+
+        let (mut raw_received, mut gct_received) = (false, false);
+        let received = timeout(TEST_TIMEOUT, async {
+            while !(raw_received && gct_received) {
+                let msg = match client.next().await {
+                    Some(broadcast_wsrpc::Response::Notify(Response::Can { response, .. })) => response,
+                    Some(_) => continue,
+                    None => break,
+                };
+                // other loopback instruments may transmit concurrently
+                match msg {
+                    CanResponse::Raw(CanMessage::Data(x)) if x.id == LOOPBACK_FRAME_ID => {
+                        raw_received = x.data == [1, 2, 3];
+                    }
+                    CanResponse::Gct(x) if x.to_string() == expected => gct_received = true,
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        check(received.is_ok() && raw_received, "Raw frame not received")?;
+        check(gct_received, "GCT message not received")
+    }
+    .await;
+    instr.disconnect();
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn self_test_passes() {
+        let (passed, details) = run().await;
+        assert!(passed, "{:?}", details);
+        assert_eq!(details.len(), 5);
+    }
+}
@@ -0,0 +1,292 @@
+//! Applies the notification filter registered with [`Request::Subscribe`](comsrv_protocol::Request::Subscribe)
+//! to a WebSocket connection.
+//!
+//! `broadcast_wsrpc` sends every broadcast to all connections and does not expose the connections to the
+//! application. Hence, the WebSocket connections are forwarded to the RPC server by [`crate::message_limit`],
+//! which passes the data sent by the RPC server through a [`Subscription`]. It reassembles the WebSocket messages
+//! and drops the notifications not selected by the filter of the connection. The reply to a `Subscribe` request,
+//! which is only sent on the connection the request was received on, installs the filter.
+
+use comsrv_protocol::{NotificationFilter, Response};
+use serde_json::Value;
+
+const OPCODE_TEXT: u8 = 0x1;
+
+enum State {
+    /// Reading the headers of the handshake response
+    Headers,
+    /// Reading the header of a WebSocket frame
+    FrameHeader,
+    /// Reading the payload of a frame
+    Payload { remaining: u64, control: bool },
+}
+
+/// Follows the data sent by the RPC server to a client and filters the notifications.
+pub struct Subscription {
+    filter: Option<NotificationFilter>,
+    state: State,
+    header: Vec<u8>,
+    /// Opcode of the message being received
+    opcode: u8,
+    /// Whether the current data frame is the last one of its message
+    fin: bool,
+    /// Frames of the message being received, as sent by the RPC server
+    frames: Vec<u8>,
+    /// Payload of the message being received
+    payload: Vec<u8>,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            state: State::Headers,
+            header: Vec::new(),
+            opcode: 0,
+            fin: false,
+            frames: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+}
+
+impl Subscription {
+    /// Process the next chunk of `data` sent by the RPC server. Returns the data to forward to the client.
+    pub fn process(&mut self, mut data: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(data.len());
+        while !data.is_empty() {
+            match self.state {
+                State::Headers => {
+                    self.header.push(data[0]);
+                    data = &data[1..];
+                    if self.header.ends_with(b"\r\n\r\n") {
+                        ret.append(&mut self.header);
+                        self.state = State::FrameHeader;
+                    }
+                }
+                State::FrameHeader => {
+                    self.header.push(data[0]);
+                    data = &data[1..];
+                    if let Some(state) = self.frame_header_complete(&mut ret) {
+                        self.state = state;
+                    }
+                }
+                State::Payload { remaining, control } => {
+                    let n = remaining.min(data.len() as u64) as usize;
+                    if control {
+                        ret.extend_from_slice(&data[..n]);
+                    } else {
+                        self.frames.extend_from_slice(&data[..n]);
+                        self.payload.extend_from_slice(&data[..n]);
+                    }
+                    data = &data[n..];
+                    let remaining = remaining - n as u64;
+                    self.state = State::Payload { remaining, control };
+                    if remaining == 0 {
+                        self.state = State::FrameHeader;
+                        if !control && self.fin {
+                            self.message_complete(&mut ret);
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Returns the next state once the frame header is complete. Control frames, which may interleave the frames
+    /// of a message, are forwarded immediately.
+    fn frame_header_complete(&mut self, ret: &mut Vec<u8>) -> Option<State> {
+        let header = &self.header;
+        if header.len() < 2 {
+            return None;
+        }
+        let masked = header[1] & 0x80 != 0;
+        let (ext_len, length) = match header[1] & 0x7F {
+            126 => (2, None),
+            127 => (8, None),
+            x => (0, Some(x as u64)),
+        };
+        if header.len() < 2 + ext_len + if masked { 4 } else { 0 } {
+            return None;
+        }
+        let length = length.unwrap_or_else(|| header[2..2 + ext_len].iter().fold(0, |acc, x| (acc << 8) | *x as u64));
+        let opcode = header[0] & 0x0F;
+        let fin = header[0] & 0x80 != 0;
+        let control = opcode >= 0x8;
+        if control {
+            ret.append(&mut self.header);
+        } else {
+            if opcode != 0 {
+                self.opcode = opcode;
+            }
+            // the RPC server does not mask its frames, masked messages are forwarded without inspection
+            if masked {
+                self.opcode = 0;
+            }
+            self.fin = fin;
+            self.frames.append(&mut self.header);
+        }
+        if length > 0 {
+            return Some(State::Payload {
+                remaining: length,
+                control,
+            });
+        }
+        if !control && fin {
+            self.message_complete(ret);
+        }
+        Some(State::FrameHeader)
+    }
+
+    fn message_complete(&mut self, ret: &mut Vec<u8>) {
+        let payload = std::mem::take(&mut self.payload);
+        if self.opcode != OPCODE_TEXT || self.select(&payload) {
+            ret.append(&mut self.frames);
+        }
+        self.frames.clear();
+    }
+
+    /// Returns false if the text message `payload` is a notification which is not selected by the filter.
+    /// Installs the filter of a reply to a subscription.
+    fn select(&mut self, payload: &[u8]) -> bool {
+        let subscribed = payload.windows(12).any(|x| x == b"\"Subscribed\"");
+        if self.filter.is_none() && !subscribed {
+            return true;
+        }
+        let value: Value = match serde_json::from_slice(payload) {
+            Ok(x) => x,
+            Err(_) => return true,
+        };
+        if let Some(notification) = value.get("Notify") {
+            return match (&self.filter, serde_json::from_value::<Response>(notification.clone())) {
+                (Some(filter), Ok(notification)) => filter.matches(&notification),
+                _ => true,
+            };
+        }
+        if subscribed {
+            if let Some(filter) = subscribed_filter(&value) {
+                log::debug!("Notification filter of connection: {}", serde_json::to_string(&filter).unwrap());
+                self.filter = Some(filter);
+            }
+        }
+        true
+    }
+}
+
+/// Find the filter of a [`Response::Subscribed`] within a reply. The reply may also be wrapped, e.g. in
+/// [`Response::Echo`].
+fn subscribed_filter(value: &Value) -> Option<NotificationFilter> {
+    match value {
+        Value::Object(x) => {
+            let filter = x.get("Subscribed").and_then(|x| x.as_object()).filter(|x| x.len() == 1);
+            if let Some(filter) = filter.and_then(|x| x.get("filter")) {
+                return serde_json::from_value(filter.clone()).ok();
+            }
+            x.values().find_map(subscribed_filter)
+        }
+        Value::Array(x) => x.iter().find_map(subscribed_filter),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::cobs_stream::CobsStreamResponse;
+    use comsrv_protocol::{CanAddress, CanResponse, NotificationKind};
+    use serde_json::json;
+
+    const HANDSHAKE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+
+    /// Build an unmasked frame as sent by the server.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut ret = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            ret.push(payload.len() as u8);
+        } else {
+            ret.push(126);
+            ret.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        ret.extend_from_slice(payload);
+        ret
+    }
+
+    fn text(value: Value) -> Vec<u8> {
+        frame(true, OPCODE_TEXT, value.to_string().as_bytes())
+    }
+
+    fn notify(notification: Response) -> Vec<u8> {
+        text(json!({ "Notify": notification }))
+    }
+
+    fn can() -> Response {
+        Response::Can {
+            source: CanAddress::Loopback,
+            response: CanResponse::Started,
+        }
+    }
+
+    fn cobs() -> Response {
+        Response::CobsStream(CobsStreamResponse::Done)
+    }
+
+    fn subscribed(kinds: Vec<NotificationKind>) -> Vec<u8> {
+        let filter = NotificationFilter {
+            kinds,
+            ..Default::default()
+        };
+        let reply = Response::Subscribed { filter };
+        // the envelope of the reply is defined by the RPC server
+        text(json!({ "Reply": { "message": reply } }))
+    }
+
+    #[test]
+    fn filter_notifications() {
+        let mut subscription = Subscription::default();
+        let mut data = HANDSHAKE.to_vec();
+        data.extend(notify(cobs()));
+        assert_eq!(subscription.process(&data), data);
+
+        let mut data = subscribed(vec![NotificationKind::Can]);
+        data.extend(notify(cobs()));
+        data.extend(frame(true, 0x9, b"ping"));
+        data.extend(notify(can()));
+        let mut expected = subscribed(vec![NotificationKind::Can]);
+        expected.extend(frame(true, 0x9, b"ping"));
+        expected.extend(notify(can()));
+        // feed the data in small chunks, splitting headers and payloads
+        let forwarded: Vec<u8> = data.chunks(3).flat_map(|x| subscription.process(x)).collect();
+        assert_eq!(forwarded, expected);
+
+        // the default filter selects all notifications
+        let mut data = subscribed(vec![]);
+        data.extend(notify(cobs()));
+        assert_eq!(subscription.process(&data), data);
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let mut subscription = Subscription::default();
+        subscription.process(HANDSHAKE);
+        subscription.process(&subscribed(vec![NotificationKind::Can]));
+
+        let fragments = |notification: Response| {
+            let payload = json!({ "Notify": notification }).to_string().into_bytes();
+            let (first, second) = payload.split_at(10);
+            let mut ret = frame(false, OPCODE_TEXT, first);
+            ret.extend(frame(true, 0xA, b""));
+            ret.extend(frame(true, 0, second));
+            ret
+        };
+        // the control frame interleaving the fragments is forwarded in any case
+        assert_eq!(subscription.process(&fragments(cobs())), frame(true, 0xA, b""));
+        let data = fragments(can());
+        let forwarded = subscription.process(&data);
+        assert_eq!(forwarded.len(), data.len());
+        assert!(forwarded.starts_with(&frame(true, 0xA, b"")));
+
+        let binary = frame(true, 0x2, &[0; 200]);
+        assert_eq!(subscription.process(&binary), binary);
+    }
+}
@@ -16,9 +16,19 @@ use anyhow::anyhow;
 use async_can::CanFrameError;
 use async_can::Error as CanError;
 use comsrv_protocol::{
-    CanAddress, CanDeviceInfo, CanDriverType, CanMessage, CanRequest, CanResponse, DataFrame, RemoteFrame, Response,
+    CanAddress, CanDeviceInfo, CanDriverType, CanMessage, CanRequest, CanResponse, DataFrame, MessageId, RemoteFrame,
+    Response,
 };
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
 
 pub fn map_error(err: CanError) -> crate::Error {
     match err {
@@ -87,9 +97,31 @@ pub async fn list_can_devices() -> crate::Result<Vec<CanDeviceInfo>> {
     }
 }
 
+/// Check whether the CAN device at `addr` is present. For USR-CANET devices, a connection is opened and closed.
+pub async fn probe(addr: &CanAddress) -> crate::Result<()> {
+    let name = match addr {
+        CanAddress::Loopback => return Ok(()),
+        CanAddress::UsrCanet { host, port } => {
+            tokio::net::TcpStream::connect((host.as_str(), *port))
+                .await
+                .map_err(crate::Error::transport)?;
+            return Ok(());
+        }
+        CanAddress::PCan { address } => address,
+        CanAddress::SocketCan { interface } => interface,
+    };
+    if list_can_devices().await?.iter().any(|x| &x.interface_name == name) {
+        Ok(())
+    } else {
+        Err(crate::Error::transport(anyhow!("CAN device `{}` not found.", name)))
+    }
+}
+
 #[derive(Clone)]
 pub struct Instrument {
     io: IoTask<Handler>,
+    /// Set while raw or GCT frames are listened to or recorded
+    listening: Arc<AtomicBool>,
 }
 
 pub struct Request {
@@ -98,16 +130,35 @@ pub struct Request {
 }
 
 impl Instrument {
+    /// Create an instrument with recording and replay disabled.
     pub fn new(server: &Server) -> Self {
+        Self::create(server, None)
+    }
+
+    /// Create an instrument which stores recordings in `recording_dir`.
+    pub fn with_recording_dir(server: &Server, recording_dir: PathBuf) -> Self {
+        Self::create(server, Some(recording_dir))
+    }
+
+    fn create(server: &Server, recording_dir: Option<PathBuf>) -> Self {
+        let listening = Arc::new(AtomicBool::new(false));
         let handler = Handler {
             server: server.clone(),
             sender: None,
             listener: None,
             loopback: false,
             last_instrument: None,
+            recording_dir,
+            replay: None,
+            backpressure: Arc::new(AtomicBool::new(true)),
+            listen_raw: false,
+            listen_gct: false,
+            recording: false,
+            listening: listening.clone(),
         };
         Self {
             io: IoTask::new(handler),
+            listening,
         }
     }
 
@@ -120,13 +171,25 @@ impl Instrument {
 impl crate::inventory::Instrument for Instrument {
     type Address = CanAddress;
 
-    fn connect(server: &Server, _addr: &Self::Address) -> crate::Result<Self> {
-        Ok(Instrument::new(server))
+    fn connect(
+        server: &Server,
+        _addr: &Self::Address,
+        defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
+        Ok(Instrument::create(server, defaults.recording_dir.clone()))
     }
 
     async fn wait_for_closed(&self) {
         self.io.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.io.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.io.is_busy() || self.listening.load(Ordering::Relaxed)
+    }
 }
 
 struct Handler {
@@ -135,9 +198,21 @@ struct Handler {
     sender: Option<CanSender>,
     listener: Option<UnboundedSender<ListenerMsg>>,
     loopback: bool,
+    recording_dir: Option<PathBuf>,
+    replay: Option<JoinHandle<()>>,
+    backpressure: Arc<AtomicBool>,
+    listen_raw: bool,
+    listen_gct: bool,
+    recording: bool,
+    listening: Arc<AtomicBool>,
 }
 
 impl Handler {
+    fn update_listening(&self) {
+        let listening = self.listen_raw || self.listen_gct || self.recording;
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
     async fn check_listener(&mut self) {
         if let Some(tx) = self.listener.as_ref() {
             if tx.is_closed() {
@@ -179,7 +254,7 @@ impl Handler {
         self.last_instrument = Some(instr.clone());
     }
 
-    async fn handle_request(&mut self, req: &Request) -> crate::Result<CanResponse> {
+    async fn handle_request(&mut self, ctx: &IoContext<Self>, req: &Request) -> crate::Result<CanResponse> {
         // save because we just created it
         let device = self.sender.as_mut().unwrap();
         let listener = self.listener.as_ref().unwrap();
@@ -187,11 +262,19 @@ impl Handler {
         match &req.inner {
             CanRequest::ListenRaw(en) => {
                 let _ = listener.send(ListenerMsg::EnableRaw(*en));
+                self.listen_raw = *en;
+                self.update_listening();
                 Ok(CanResponse::Started)
             }
             CanRequest::StopAll => {
+                if let Some(replay) = self.replay.take() {
+                    replay.abort();
+                }
                 let _ = listener.send(ListenerMsg::EnableRaw(false));
                 let _ = listener.send(ListenerMsg::EnableGct(false));
+                self.listen_raw = false;
+                self.listen_gct = false;
+                self.update_listening();
                 Ok(CanResponse::Stopped)
             }
             CanRequest::TxRaw(msg) => {
@@ -203,6 +286,8 @@ impl Handler {
             }
             CanRequest::ListenGct(en) => {
                 let _ = listener.send(ListenerMsg::EnableGct(*en));
+                self.listen_gct = *en;
+                self.update_listening();
                 Ok(CanResponse::Started)
             }
             CanRequest::TxGct(msg) => {
@@ -219,6 +304,56 @@ impl Handler {
                 self.loopback = *en;
                 Ok(CanResponse::Ok)
             }
+            CanRequest::StartRecording { path } => {
+                let path = resolve_recording_path(self.recording_dir.as_deref(), path)?;
+                let recorder = Recorder::create(&path, interface_name(&req.instrument))?;
+                let _ = listener.send(ListenerMsg::StartRecording(recorder));
+                self.recording = true;
+                self.update_listening();
+                Ok(CanResponse::Started)
+            }
+            CanRequest::StopRecording => {
+                let (tx, rx) = oneshot::channel();
+                if listener.send(ListenerMsg::StopRecording(tx)).is_ok() {
+                    let _ = rx.await;
+                }
+                self.recording = false;
+                self.update_listening();
+                Ok(CanResponse::Stopped)
+            }
+            CanRequest::ReplayFile { path, repeat, speed } => {
+                let path = resolve_recording_path(self.recording_dir.as_deref(), path)?;
+                let data = task::spawn_blocking(move || {
+                    fs::read_to_string(&path)
+                        .map_err(|x| crate::Error::argument(anyhow!("Cannot read log `{}`: {}", path.display(), x)))
+                })
+                .await
+                .map_err(crate::Error::internal)??;
+                let frames = replay_schedule(parse_log(&data)?, *speed, *repeat)?;
+                if let Some(replay) = self.replay.take() {
+                    replay.abort();
+                }
+                let fut = replay_task(ctx.clone(), req.instrument.clone(), frames, *repeat);
+                self.replay = Some(task::spawn(fut));
+                Ok(CanResponse::Started)
+            }
+            CanRequest::StopReplay => {
+                if let Some(replay) = self.replay.take() {
+                    replay.abort();
+                }
+                Ok(CanResponse::Stopped)
+            }
+            CanRequest::ClearRx => {
+                let (tx, rx) = oneshot::channel();
+                if listener.send(ListenerMsg::ClearRx(tx)).is_ok() {
+                    let _ = rx.await;
+                }
+                Ok(CanResponse::Ok)
+            }
+            CanRequest::SetBackpressure(en) => {
+                self.backpressure.store(*en, Ordering::Relaxed);
+                Ok(CanResponse::Ok)
+            }
         }
     }
 
@@ -273,7 +408,7 @@ impl IoHandler for Handler {
     type Request = Request;
     type Response = CanResponse;
 
-    async fn handle(&mut self, _ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+    async fn handle(&mut self, ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
         self.check_listener().await;
         self.update_bitrate(&req.instrument).await;
 
@@ -284,7 +419,13 @@ impl IoHandler for Handler {
 
             let (sender, receiver) = Self::make_sender_and_receiver(&req.instrument).await?;
             let (tx, rx) = mpsc::unbounded_channel();
-            let fut = listener_task(rx, receiver, self.server.clone(), req.instrument.clone());
+            let fut = listener_task(
+                rx,
+                receiver,
+                self.backpressure.clone(),
+                self.server.clone(),
+                req.instrument.clone(),
+            );
             task::spawn(fut);
             self.sender.replace(sender);
             self.listener.replace(tx);
@@ -293,7 +434,7 @@ impl IoHandler for Handler {
         if self.listener.is_none() {}
         let mut retries = 0;
         let ret = loop {
-            let ret = self.handle_request(&req).await;
+            let ret = self.handle_request(ctx, &req).await;
             if let Err(err) = ret {
                 retries += 1;
                 if retries > 3 {
@@ -311,22 +452,44 @@ impl IoHandler for Handler {
         };
         Err(ret)
     }
+
+    async fn disconnect(&mut self) {
+        if let Some(replay) = self.replay.take() {
+            replay.abort();
+        }
+    }
 }
 
 enum ListenerMsg {
     EnableGct(bool),
     EnableRaw(bool),
     Loopback(CanMessage),
+    StartRecording(Recorder),
+    StopRecording(oneshot::Sender<()>),
+    ClearRx(oneshot::Sender<()>),
     Close(oneshot::Sender<()>),
 }
 
+/// Number of received frames buffered until they are processed by the listener
+const RX_QUEUE_DEPTH: usize = 1000;
+
+type RxQueue = mpsc::Receiver<crate::Result<CanMessage>>;
+
+/// Handle to the [`reader_task`] receiving frames from the device
+struct Reader {
+    task: JoinHandle<()>,
+    clear: UnboundedSender<oneshot::Sender<usize>>,
+}
+
 struct Listener {
     listen_gct: bool,
     listen_raw: bool,
     decoder: Decoder,
     server: Server,
-    device: Option<CanReceiver>,
+    queue: Option<RxQueue>,
+    reader: Option<Reader>,
     instr: CanInstrument,
+    recorder: Option<Recorder>,
 }
 
 impl Listener {
@@ -347,9 +510,35 @@ impl Listener {
                 self.rx(msg);
                 true
             }
+            ListenerMsg::StartRecording(recorder) => {
+                if let Some(old) = self.recorder.replace(recorder) {
+                    old.finish().await;
+                }
+                true
+            }
+            ListenerMsg::StopRecording(fut) => {
+                if let Some(recorder) = self.recorder.take() {
+                    recorder.finish().await;
+                }
+                let _ = fut.send(());
+                true
+            }
+            ListenerMsg::ClearRx(fut) => {
+                let dropped = self.clear_queue().await;
+                log::debug!("{:?} - Discarded {} pending frames", self.instr, dropped);
+                self.decoder.reset();
+                let _ = fut.send(());
+                true
+            }
             ListenerMsg::Close(fut) => {
-                if let Some(device) = self.device.take() {
-                    drop(device);
+                if let Some(recorder) = self.recorder.take() {
+                    recorder.finish().await;
+                }
+                if let Some(reader) = self.reader.take() {
+                    // awaiting the reader ensures the device is dropped
+                    reader.task.abort();
+                    let _ = reader.task.await;
+                    self.queue.take();
                     let _ = fut.send(());
                 }
                 false
@@ -358,7 +547,14 @@ impl Listener {
     }
 
     fn rx(&mut self, msg: CanMessage) {
-        log::debug!("CAN received - ID = {:x}", msg.id());
+        if msg.ext_id() {
+            log::debug!("CAN received - ID = {:x} ({})", msg.id(), MessageId(msg.id()));
+        } else {
+            log::debug!("CAN received - ID = {:x}", msg.id());
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&msg);
+        }
         if self.listen_raw {
             let tx = Response::Can {
                 source: self.instr.clone().into(),
@@ -369,7 +565,7 @@ impl Listener {
         }
         if self.listen_gct {
             if let Some(msg) = self.decoder.decode(msg) {
-                log::debug!("Broadcast GCT CAN message: {}", serde_json::to_string(&msg).unwrap());
+                log::debug!("Broadcast GCT CAN message: {}", msg);
                 let msg = Response::Can {
                     source: self.instr.clone().into(),
                     response: CanResponse::Gct(msg),
@@ -379,8 +575,37 @@ impl Listener {
         }
     }
 
+    /// Discard the frames pending in the device and in the queue. Returns the number of discarded frames.
+    async fn clear_queue(&mut self) -> usize {
+        let (queue, reader) = match (&mut self.queue, &self.reader) {
+            (Some(queue), Some(reader)) => (queue, reader),
+            _ => return 0,
+        };
+        let mut dropped = 0;
+        let (tx, mut rx) = oneshot::channel();
+        if reader.clear.send(tx).is_ok() {
+            // keep draining the queue, such that the reader does not block while applying backpressure
+            loop {
+                tokio::select! {
+                    ret = &mut rx => {
+                        dropped += ret.unwrap_or(0);
+                        break;
+                    }
+                    msg = queue.recv() => match msg {
+                        Some(_) => dropped += 1,
+                        None => break,
+                    }
+                }
+            }
+        }
+        while queue.try_recv().is_ok() {
+            dropped += 1;
+        }
+        dropped
+    }
+
     async fn err(&mut self, err: crate::Error) -> bool {
-        if self.device.is_some() {
+        if self.queue.is_some() {
             self.server.broadcast(err.clone().into());
             // depending on error, continue listening or quit...
             match err {
@@ -400,9 +625,58 @@ impl Listener {
     }
 
     async fn recv(&mut self) -> Option<crate::Result<CanMessage>> {
-        match &mut self.device {
+        match &mut self.queue {
             None => None,
-            Some(device) => Some(device.recv().await),
+            Some(queue) => queue.recv().await,
+        }
+    }
+}
+
+/// Receive frames from `device` into `queue`. If the queue is full, reception pauses until the listener catches up.
+/// If `backpressure` is cleared, frames are dropped instead. A request on `clear` discards the frames pending in the
+/// device.
+async fn reader_task(
+    mut device: CanReceiver,
+    queue: mpsc::Sender<crate::Result<CanMessage>>,
+    mut clear: UnboundedReceiver<oneshot::Sender<usize>>,
+    backpressure: Arc<AtomicBool>,
+    instr: CanInstrument,
+) {
+    let mut overruns = 0_u64;
+    loop {
+        let msg = tokio::select! {
+            biased;
+            fut = clear.recv() => match fut {
+                Some(fut) => {
+                    let dropped = match &mut device {
+                        CanReceiver::Loopback(device) => device.clear(),
+                        CanReceiver::Bus { .. } => 0,
+                    };
+                    let _ = fut.send(dropped);
+                    continue;
+                }
+                None => break,
+            },
+            msg = device.recv() => msg,
+        };
+        let stop = matches!(msg, Err(crate::Error::Transport(_)));
+        if backpressure.load(Ordering::Relaxed) || msg.is_err() {
+            if queue.send(msg).await.is_err() {
+                break;
+            }
+        } else {
+            match queue.try_send(msg) {
+                Ok(()) if overruns > 0 => {
+                    log::warn!("{:?} - Receive queue overrun, dropped {} frames", instr, overruns);
+                    overruns = 0;
+                }
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => overruns += 1,
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+        if stop {
+            break;
         }
     }
 }
@@ -410,16 +684,22 @@ impl Listener {
 async fn listener_task(
     mut rx: UnboundedReceiver<ListenerMsg>,
     device: CanReceiver,
+    backpressure: Arc<AtomicBool>,
     server: Server,
     instr: CanInstrument,
 ) {
+    let (queue_tx, queue) = mpsc::channel(RX_QUEUE_DEPTH);
+    let (clear, clear_rx) = mpsc::unbounded_channel();
+    let task = task::spawn(reader_task(device, queue_tx, clear_rx, backpressure, instr.clone()));
     let mut listener = Listener {
         listen_gct: true,
         listen_raw: true,
         decoder: Decoder::new(),
         server,
-        device: Some(device),
+        queue: Some(queue),
+        reader: Some(Reader { task, clear }),
         instr,
+        recorder: None,
     };
     loop {
         tokio::select! {
@@ -438,7 +718,253 @@ async fn listener_task(
             }
         }
     }
+    if let Some(reader) = listener.reader.take() {
+        reader.task.abort();
+    }
+}
+
+/// Resolve the file name `path` of a recording within `recording_dir`. Rejects names which contain path
+/// separators or `..`, such that clients cannot access files outside of `recording_dir`.
+fn resolve_recording_path(recording_dir: Option<&Path>, path: &str) -> crate::Result<PathBuf> {
+    let recording_dir = recording_dir.ok_or_else(|| {
+        crate::Error::argument(anyhow!(
+            "CAN recording is disabled. Start the comsrv with `--recording-dir` to enable it."
+        ))
+    })?;
+    let is_valid = !path.is_empty() && path != "." && !path.contains(['/', '\\']) && !path.contains("..");
+    if !is_valid {
+        return Err(crate::Error::argument(anyhow!(
+            "Invalid recording file name `{}`: must not contain path separators or `..`",
+            path
+        )));
+    }
+    Ok(recording_dir.join(path))
+}
+
+/// Fill in `default` for the bitrate of PCAN instruments which do not specify one.
+pub fn apply_default_bitrate(instr: &mut CanInstrument, default: Option<u32>) -> crate::Result<()> {
+    if let CanInstrument::PCan { bitrate, .. } = instr {
+        if *bitrate == 0 {
+            *bitrate = default.ok_or_else(|| {
+                crate::Error::argument(anyhow!("No bitrate given for PCAN instrument and no default configured."))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the interface written to the log, which must not contain whitespace.
+fn interface_name(instr: &CanInstrument) -> String {
+    let name = match instr {
+        CanInstrument::PCan { address, .. } => address.clone(),
+        CanInstrument::SocketCan { interface } => interface.clone(),
+        CanInstrument::UsrCanet { host, port } => format!("{}:{}", host, port),
+        CanInstrument::Loopback => "loopback".to_string(),
+    };
+    name.replace(char::is_whitespace, "_")
+}
+
+/// Format a frame in the candump log format, e.g. `(1436509052.249713) can0 123#DEADBEEF`.
+fn format_candump(timestamp: std::time::Duration, interface: &str, msg: &CanMessage) -> String {
+    let id = |id: u32, ext_id: bool| {
+        if ext_id {
+            format!("{:08X}", id)
+        } else {
+            format!("{:03X}", id)
+        }
+    };
+    let frame = match msg {
+        CanMessage::Data(x) => {
+            let data: String = x.data.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{}#{}", id(x.id, x.ext_id), data)
+        }
+        CanMessage::Remote(x) => format!("{}#R{}", id(x.id, x.ext_id), x.dlc),
+    };
+    format!(
+        "({}.{:06}) {} {}\n",
+        timestamp.as_secs(),
+        timestamp.subsec_micros(),
+        interface,
+        frame
+    )
+}
+
+/// Parse a log file in the candump or ASC format into timestamped frames. Lines of an ASC file which
+/// do not describe a CAN frame, such as headers or error frames, are skipped.
+fn parse_log(data: &str) -> crate::Result<Vec<(f64, CanMessage)>> {
+    let mut frames = Vec::new();
+    let mut hex = true;
+    for (idx, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('(') {
+            let frame = parse_candump_line(line)
+                .ok_or_else(|| crate::Error::argument(anyhow!("Invalid frame in line {}: `{}`", idx + 1, line)))?;
+            frames.push(frame);
+        } else if line.starts_with("base ") {
+            hex = line.split_whitespace().nth(1) != Some("dec");
+        } else if let Some(frame) = parse_asc_line(line, hex) {
+            frames.push(frame);
+        }
+    }
+    if frames.is_empty() {
+        return Err(crate::Error::argument(anyhow!("Log file does not contain any frames")));
+    }
+    Ok(frames)
+}
+
+fn parse_hex_bytes<'a>(data: impl Iterator<Item = &'a str>) -> Option<Vec<u8>> {
+    data.map(|x| u8::from_str_radix(x, 16).ok()).collect()
+}
+
+/// Parse a line such as `(1436509052.249713) can0 123#DEADBEEF`.
+fn parse_candump_line(line: &str) -> Option<(f64, CanMessage)> {
+    let mut parts = line.split_whitespace();
+    let timestamp = parts.next()?.strip_prefix('(')?.strip_suffix(')')?.parse().ok()?;
+    let _interface = parts.next()?;
+    let (id, data) = parts.next()?.split_once('#')?;
+    let ext_id = id.len() > 3;
+    let id = u32::from_str_radix(id, 16).ok()?;
+    let msg = if let Some(dlc) = data.strip_prefix('R') {
+        let dlc = if dlc.is_empty() { 0 } else { dlc.parse().ok()? };
+        CanMessage::Remote(RemoteFrame { id, ext_id, dlc })
+    } else {
+        if data.len() % 2 != 0 || data.len() > 16 {
+            return None;
+        }
+        let data = parse_hex_bytes((0..data.len()).step_by(2).map(|x| &data[x..x + 2]))?;
+        CanMessage::Data(DataFrame { id, ext_id, data })
+    };
+    Some((timestamp, msg))
+}
+
+/// Parse a line such as `0.010000 1  123x  Rx   d 2 DE AD`. Extended ids are suffixed with `x`.
+fn parse_asc_line(line: &str, hex: bool) -> Option<(f64, CanMessage)> {
+    let parts: Vec<_> = line.split_whitespace().collect();
+    if parts.len() < 5 || !matches!(parts[3], "Rx" | "Tx") {
+        return None;
+    }
+    let timestamp = parts[0].parse().ok()?;
+    let _channel: u32 = parts[1].parse().ok()?;
+    let (id, ext_id) = match parts[2].strip_suffix('x') {
+        Some(id) => (id, true),
+        None => (parts[2], false),
+    };
+    let id = u32::from_str_radix(id, if hex { 16 } else { 10 }).ok()?;
+    let msg = match parts[4] {
+        "d" => {
+            let dlc: usize = parts.get(5)?.parse().ok()?;
+            if dlc > 8 {
+                return None;
+            }
+            let data = parse_hex_bytes(parts.get(6..6 + dlc)?.iter().copied())?;
+            CanMessage::Data(DataFrame { id, ext_id, data })
+        }
+        "r" => {
+            let dlc = match parts.get(5) {
+                Some(dlc) => dlc.parse().ok()?,
+                None => 0,
+            };
+            CanMessage::Remote(RemoteFrame { id, ext_id, dlc })
+        }
+        _ => return None,
+    };
+    Some((timestamp, msg))
+}
+
+/// Compute the time at which each frame is sent relative to the start of the replay.
+fn replay_schedule(
+    frames: Vec<(f64, CanMessage)>,
+    speed: f32,
+    repeat: bool,
+) -> crate::Result<Vec<(Duration, CanMessage)>> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err(crate::Error::argument(anyhow!("Invalid replay speed: {}", speed)));
+    }
+    let start = frames[0].0;
+    let frames = frames
+        .into_iter()
+        .map(|(timestamp, msg)| {
+            let offset = ((timestamp - start) / speed as f64).max(0.0);
+            Duration::try_from_secs_f64(offset)
+                .map(|x| (x, msg))
+                .map_err(|_| crate::Error::argument(anyhow!("Invalid timestamp in log: {}", timestamp)))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    if repeat && frames.last().unwrap().0.is_zero() {
+        return Err(crate::Error::argument(anyhow!("Cannot loop a log which spans no time")));
+    }
+    Ok(frames)
+}
+
+/// Send the frames through the handler, such that they are subject to the same processing as `TxRaw`.
+async fn replay_task(
+    mut ctx: IoContext<Handler>,
+    instrument: CanInstrument,
+    frames: Vec<(Duration, CanMessage)>,
+    repeat: bool,
+) {
+    loop {
+        let start = Instant::now();
+        for (offset, msg) in &frames {
+            match start.checked_add(*offset) {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => return,
+            }
+            ctx.send(Request {
+                inner: CanRequest::TxRaw(msg.clone()),
+                instrument: instrument.clone(),
+            });
+        }
+        if !repeat {
+            break;
+        }
+    }
+}
+
+/// Writes received frames to a file. The file is written on a blocking thread, such that slow
+/// disks do not stall the listener.
+struct Recorder {
+    interface: String,
+    tx: std_mpsc::Sender<String>,
+    writer: JoinHandle<()>,
+}
+
+impl Recorder {
+    fn create(path: &Path, interface: String) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|x| crate::Error::argument(anyhow!("Cannot create recording `{}`: {}", path.display(), x)))?;
+        let (tx, rx) = std_mpsc::channel::<String>();
+        let path = path.to_path_buf();
+        let writer = task::spawn_blocking(move || {
+            let mut file = BufWriter::new(file);
+            for line in rx {
+                if let Err(x) = file.write_all(line.as_bytes()) {
+                    log::error!("Cannot write recording `{}`: {}", path.display(), x);
+                    return;
+                }
+            }
+            if let Err(x) = file.flush() {
+                log::error!("Cannot write recording `{}`: {}", path.display(), x);
+            }
+        });
+        Ok(Self { interface, tx, writer })
+    }
+
+    fn record(&self, msg: &CanMessage) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let _ = self.tx.send(format_candump(timestamp, &self.interface, msg));
+    }
+
+    /// Stop recording and wait until all frames are written.
+    async fn finish(self) {
+        drop(self.tx);
+        let _ = self.writer.await;
+    }
 }
+
 pub enum CanSender {
     Loopback(LoopbackDevice),
     Bus { device: Box<dyn Sender + Send> },
@@ -515,11 +1041,132 @@ impl LoopbackDevice {
     pub fn send(&self, msg: CanMessage) {
         LOOPBACK_ADAPTER.send(msg)
     }
+
+    /// Discard all frames which were sent but not yet received. Returns the number of discarded frames.
+    pub fn clear(&mut self) -> usize {
+        let mut dropped = 0;
+        loop {
+            match self.rx.try_recv() {
+                Ok(_) => dropped += 1,
+                Err(broadcast::error::TryRecvError::Lagged(n)) => dropped += n as usize,
+                Err(_) => break dropped,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use comsrv_protocol::GctMessage;
+
+    #[test]
+    fn default_bitrate() {
+        let pcan = |bitrate| CanInstrument::PCan {
+            address: "usb1".to_string(),
+            bitrate,
+        };
+        let mut instr = pcan(500000);
+        apply_default_bitrate(&mut instr, Some(1000000)).unwrap();
+        assert_eq!(instr.bitrate(), Some(500000));
+        let mut instr = pcan(0);
+        apply_default_bitrate(&mut instr, Some(1000000)).unwrap();
+        assert_eq!(instr.bitrate(), Some(1000000));
+        let mut instr = pcan(0);
+        assert!(matches!(
+            apply_default_bitrate(&mut instr, None),
+            Err(crate::Error::Argument(_))
+        ));
+        let mut instr = CanInstrument::Loopback;
+        apply_default_bitrate(&mut instr, None).unwrap();
+    }
+
+    /// Yields `count` frames with increasing ids, then signals `done` and pends forever.
+    struct Frames {
+        next: u32,
+        count: u32,
+        done: Option<oneshot::Sender<()>>,
+    }
+
+    #[async_trait]
+    impl Receiver for Frames {
+        async fn recv(&mut self) -> async_can::Result<async_can::Message> {
+            if self.next == self.count {
+                if let Some(done) = self.done.take() {
+                    let _ = done.send(());
+                }
+                std::future::pending::<()>().await;
+            }
+            self.next += 1;
+            Ok(async_can::Message::new_data(self.next - 1, false, &[]).unwrap())
+        }
+    }
+
+    fn spawn_reader(count: u32, backpressure: bool) -> (RxQueue, oneshot::Receiver<()>, JoinHandle<()>) {
+        let (done, done_rx) = oneshot::channel();
+        let device = CanReceiver::Bus {
+            device: Box::new(Frames {
+                next: 0,
+                count,
+                done: Some(done),
+            }),
+        };
+        let (queue_tx, queue) = mpsc::channel(4);
+        let (_clear, clear_rx) = mpsc::unbounded_channel();
+        let reader = task::spawn(reader_task(
+            device,
+            queue_tx,
+            clear_rx,
+            Arc::new(AtomicBool::new(backpressure)),
+            CanInstrument::Loopback,
+        ));
+        (queue, done_rx, reader)
+    }
+
+    fn frame_id(msg: Option<crate::Result<CanMessage>>) -> u32 {
+        match msg {
+            Some(Ok(CanMessage::Data(frame))) => frame.id,
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        // by default, reception pauses while the queue is full and no frame is lost
+        let (mut queue, mut done, reader) = spawn_reader(10, true);
+        for id in 0..10 {
+            // at most 4 frames are queued ahead of the ones processed
+            if id < 6 {
+                assert!(done.try_recv().is_err());
+            }
+            assert_eq!(frame_id(queue.recv().await), id);
+        }
+        done.await.unwrap();
+        reader.abort();
+
+        // without backpressure, frames received while the queue is full are dropped
+        let (mut queue, done, reader) = spawn_reader(10, false);
+        done.await.unwrap();
+        for id in 0..4 {
+            assert_eq!(frame_id(queue.recv().await), id);
+        }
+        assert!(queue.try_recv().is_err());
+        reader.abort();
+    }
+
+    #[test]
+    fn error_mapping() {
+        let err = map_error(CanError::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed")));
+        assert!(matches!(err, crate::Error::Transport(_)));
+
+        let msg = CanMessage::Data(DataFrame {
+            id: 0x123,
+            ext_id: false,
+            data: vec![0; 9],
+        });
+        let err = into_async_can_message(msg).map_err(map_frame_error).err().unwrap();
+        assert!(matches!(err, crate::Error::Argument(_)));
+    }
 
     #[tokio::test]
     async fn loopback() {
@@ -570,4 +1217,232 @@ mod tests {
         assert_eq!(&msg.data, &[1, 2, 3, 4]);
         assert!(msg.ext_id);
     }
+
+    #[test]
+    fn recording_path() {
+        let dir = Path::new("/var/comsrv");
+        assert_eq!(
+            resolve_recording_path(Some(dir), "bus.log").unwrap(),
+            PathBuf::from("/var/comsrv/bus.log")
+        );
+        assert!(matches!(
+            resolve_recording_path(None, "bus.log"),
+            Err(crate::Error::Argument(_))
+        ));
+        for path in [
+            "../bus.log",
+            "logs/bus.log",
+            "logs\\bus.log",
+            "..",
+            "/etc/passwd",
+            "",
+            ".",
+        ] {
+            assert!(
+                matches!(resolve_recording_path(Some(dir), path), Err(crate::Error::Argument(_))),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn listening_is_busy() {
+        use crate::inventory::Instrument as _;
+
+        let (srv, _) = Server::new();
+        let mut instr = Instrument::new(&srv);
+        let request = |inner| Request {
+            inner,
+            instrument: CanInstrument::Loopback,
+        };
+        assert!(!instr.is_busy());
+        instr.request(request(CanRequest::ListenGct(true))).await.unwrap();
+        assert!(instr.is_busy());
+        instr.request(request(CanRequest::StopAll)).await.unwrap();
+        assert!(!instr.is_busy());
+    }
+
+    #[tokio::test]
+    async fn record_loopback() {
+        let (srv, _) = Server::new();
+        let mut client = srv.loopback().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut instr = Instrument::with_recording_dir(&srv, dir.path().to_path_buf());
+        let request = |inner| Request {
+            inner,
+            instrument: CanInstrument::Loopback,
+        };
+
+        let ret = instr
+            .request(request(CanRequest::StartRecording {
+                path: "bus.log".to_string(),
+            }))
+            .await;
+        assert!(matches!(ret, Ok(CanResponse::Started)));
+        let msgs = vec![
+            CanMessage::Data(DataFrame {
+                id: 0x5A1,
+                ext_id: false,
+                data: vec![0xDE, 0xAD],
+            }),
+            CanMessage::Data(DataFrame {
+                id: 0x15A1_0000,
+                ext_id: true,
+                data: vec![],
+            }),
+            CanMessage::Remote(RemoteFrame {
+                id: 0x5A2,
+                ext_id: false,
+                dlc: 4,
+            }),
+        ];
+        for msg in &msgs {
+            instr.request(request(CanRequest::TxRaw(msg.clone()))).await.unwrap();
+        }
+        // frames are recorded before they are broadcast
+        let mut received = 0;
+        while received < msgs.len() {
+            if let Some(broadcast_wsrpc::Response::Notify(Response::Can {
+                response: CanResponse::Raw(msg),
+                ..
+            })) = client.next().await
+            {
+                if matches!(msg.id(), 0x5A1 | 0x15A1_0000 | 0x5A2) {
+                    received += 1;
+                }
+            }
+        }
+        let ret = instr.request(request(CanRequest::StopRecording)).await;
+        assert!(matches!(ret, Ok(CanResponse::Stopped)));
+
+        // other tests may send frames on the loopback bus concurrently, only consider frames sent here
+        let log = std::fs::read_to_string(dir.path().join("bus.log")).unwrap();
+        let frames: Vec<_> = log
+            .lines()
+            .map(|line| {
+                let mut parts = line.split(' ');
+                let timestamp = parts.next().unwrap();
+                assert!(timestamp.starts_with('(') && timestamp.ends_with(')'));
+                timestamp[1..timestamp.len() - 1].parse::<f64>().unwrap();
+                assert_eq!(parts.next().unwrap(), "loopback");
+                parts.next().unwrap().to_string()
+            })
+            .filter(|frame| frame.starts_with("5A") || frame.starts_with("15A1"))
+            .collect();
+        assert_eq!(frames, vec!["5A1#DEAD", "15A10000#", "5A2#R4"]);
+    }
+
+    #[tokio::test]
+    async fn clear_rx_loopback() {
+        let (srv, _) = Server::new();
+        let mut client = srv.loopback().await;
+        let mut instr = Instrument::new(&srv);
+        let request = |inner| Request {
+            inner,
+            instrument: CanInstrument::Loopback,
+        };
+        let ddp = |data: Vec<u8>| GctMessage::Ddp {
+            src: 0x2B,
+            dst: 0x2C,
+            data,
+            version: 1,
+        };
+
+        // only the first frame of the stale message is received before clearing
+        let stale = crate::protocol::can::gct::encode(ddp((0..20).collect())).unwrap();
+        assert_eq!(stale.len(), 3);
+        instr.request(request(CanRequest::TxRaw(stale[0].clone()))).await.unwrap();
+        let ret = instr.request(request(CanRequest::ClearRx)).await;
+        assert!(matches!(ret, Ok(CanResponse::Ok)));
+        for msg in &stale[1..] {
+            instr.request(request(CanRequest::TxRaw(msg.clone()))).await.unwrap();
+        }
+        instr.request(request(CanRequest::TxGct(ddp(vec![0xAA; 4])))).await.unwrap();
+
+        // other tests may send frames on the loopback bus concurrently, only consider messages sent here
+        let data = loop {
+            if let Some(broadcast_wsrpc::Response::Notify(Response::Can {
+                response: CanResponse::Gct(GctMessage::Ddp { dst: 0x2C, data, .. }),
+                ..
+            })) = client.next().await
+            {
+                break data;
+            }
+        };
+        assert_eq!(data, vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn parse_log_formats() {
+        let candump = "(1436509052.249713) can0 123#DEADBEEF\n(1436509052.449713) can0 12345678#R2\n";
+        let frames = parse_log(candump).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(
+            matches!(&frames[0].1, CanMessage::Data(x) if x.id == 0x123 && !x.ext_id && x.data == [0xDE, 0xAD, 0xBE, 0xEF])
+        );
+        assert!(matches!(&frames[1].1, CanMessage::Remote(x) if x.id == 0x12345678 && x.ext_id && x.dlc == 2));
+
+        let asc = "date Mon Jan 1 00:00:00 2024\n\
+                   base hex  timestamps absolute\n\
+                   Begin Triggerblock\n\
+                   0.000000 Start of measurement\n\
+                   0.010000 1  1A0             Rx   d 2 01 FF\n\
+                   0.020000 1  1ABCDEx         Tx   r 4\n\
+                   0.030000 1  ErrorFrame\n\
+                   End TriggerBlock\n";
+        let frames = parse_log(asc).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, 0.01);
+        assert!(matches!(&frames[0].1, CanMessage::Data(x) if x.id == 0x1A0 && !x.ext_id && x.data == [0x01, 0xFF]));
+        assert!(matches!(&frames[1].1, CanMessage::Remote(x) if x.id == 0x1ABCDE && x.ext_id && x.dlc == 4));
+
+        assert!(matches!(parse_log("(1.0) can0 123#ABC\n"), Err(crate::Error::Argument(_))));
+        assert!(matches!(parse_log("date Mon Jan 1\n"), Err(crate::Error::Argument(_))));
+        assert!(replay_schedule(parse_log(candump).unwrap(), 0.0, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_loopback() {
+        let (srv, _) = Server::new();
+        let mut client = srv.loopback().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("bus.log"),
+            "(100.000000) can0 6B1#0102\n(100.100000) can0 6B2#R1\n(100.200000) can0 16B30000#\n",
+        )
+        .unwrap();
+        let mut instr = Instrument::with_recording_dir(&srv, dir.path().to_path_buf());
+
+        let start = Instant::now();
+        let ret = instr
+            .request(Request {
+                inner: CanRequest::ReplayFile {
+                    path: "bus.log".to_string(),
+                    repeat: false,
+                    speed: 2.0,
+                },
+                instrument: CanInstrument::Loopback,
+            })
+            .await;
+        assert!(matches!(ret, Ok(CanResponse::Started)));
+
+        // other tests may send frames on the loopback bus concurrently, only consider frames sent here
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            if let Some(broadcast_wsrpc::Response::Notify(Response::Can {
+                response: CanResponse::Raw(msg),
+                ..
+            })) = client.next().await
+            {
+                if matches!(msg.id(), 0x6B1 | 0x6B2 | 0x16B3_0000) {
+                    received.push(msg);
+                }
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(&received[0], CanMessage::Data(x) if x.id == 0x6B1 && x.data == [1, 2]));
+        assert!(matches!(&received[1], CanMessage::Remote(x) if x.id == 0x6B2 && x.dlc == 1));
+        assert!(matches!(&received[2], CanMessage::Data(x) if x.id == 0x16B3_0000 && x.ext_id && x.data.is_empty()));
+    }
 }
@@ -0,0 +1,151 @@
+//! This module allows downstream crates to add bytestream-like instruments to the `comsrv` without
+//! extending the protocol. A [`Transport`] is registered for a `kind` with `App::register_transport()` and
+//! serves all instruments addressed with a `CustomAddress` of that kind.
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use comsrv_protocol::{ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, CustomAddress};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::app::Server;
+use crate::iotask::{IoContext, IoHandler, IoTask};
+use crate::protocol::bytestream;
+
+/// A full-duplex byte stream opened by a [`Transport`]
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A backend for instruments which are not built into the `comsrv`.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Open a connection to the instrument at `address`. Transport errors cause the connection to be dropped
+    /// and opened again on the next request.
+    async fn connect(&self, address: &str) -> crate::Result<Box<dyn Stream>>;
+}
+
+/// The transports registered with the `comsrv`, indexed by their kind.
+#[derive(Default)]
+pub struct Transports(RwLock<HashMap<String, Arc<dyn Transport>>>);
+
+impl Transports {
+    /// Register `transport` for addresses of the given `kind`. Fails if the kind is already registered.
+    pub fn register(&self, kind: &str, transport: Arc<dyn Transport>) -> crate::Result<()> {
+        if kind.is_empty() {
+            return Err(crate::Error::argument(anyhow!("Transport kind must not be empty.")));
+        }
+        let mut transports = self.0.write().unwrap();
+        if transports.contains_key(kind) {
+            return Err(crate::Error::argument(anyhow!("Transport `{}` is already registered.", kind)));
+        }
+        transports.insert(kind.to_string(), transport);
+        Ok(())
+    }
+
+    /// Return the transport serving addresses of the given `kind`.
+    pub fn get(&self, kind: &str) -> crate::Result<Arc<dyn Transport>> {
+        self.0
+            .read()
+            .unwrap()
+            .get(kind)
+            .cloned()
+            .ok_or_else(|| crate::Error::argument(anyhow!("No transport registered for `{}`.", kind)))
+    }
+}
+
+pub struct Request {
+    pub transport: Arc<dyn Transport>,
+    pub request: ByteStreamRequest,
+}
+
+#[derive(Clone)]
+pub struct Instrument {
+    inner: IoTask<Handler>,
+}
+
+impl Instrument {
+    pub fn new(server: &Server, addr: &CustomAddress) -> Self {
+        let handler = Handler {
+            server: server.clone(),
+            addr: addr.clone(),
+            stream: None,
+        };
+        Self {
+            inner: IoTask::new(handler),
+        }
+    }
+
+    pub async fn request(&mut self, req: Request) -> crate::Result<ByteStreamResponse> {
+        self.inner.request(req).await
+    }
+}
+
+#[async_trait]
+impl crate::inventory::Instrument for Instrument {
+    type Address = CustomAddress;
+
+    fn connect(
+        server: &Server,
+        addr: &Self::Address,
+        _defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
+        Ok(Instrument::new(server, addr))
+    }
+
+    async fn wait_for_closed(&self) {
+        self.inner.wait_for_closed().await
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
+}
+
+struct Handler {
+    server: Server,
+    addr: CustomAddress,
+    stream: Option<Box<dyn Stream>>,
+}
+
+#[async_trait]
+impl IoHandler for Handler {
+    type Request = Request;
+    type Response = ByteStreamResponse;
+
+    async fn handle(&mut self, _ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        if let ByteStreamRequest::Disconnect = req.request {
+            self.stream.take();
+            return Ok(ByteStreamResponse::Done);
+        }
+        if let ByteStreamRequest::Close { flush } = req.request {
+            let ret = match self.stream.take() {
+                Some(mut stream) if flush => AsyncWriteExt::flush(&mut stream).await.map_err(crate::Error::transport),
+                _ => Ok(()),
+            };
+            return ret.map(|_| ByteStreamResponse::Done);
+        }
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => req.transport.connect(&self.addr.address).await?,
+        };
+        let sender = ByteStreamInstrument::Custom(self.addr.clone());
+        let ret = bytestream::handle_broadcast(&mut stream, req.request, &self.server, sender).await;
+        match &ret {
+            Ok(_) | Err(crate::Error::Protocol(_)) | Err(crate::Error::Argument(_)) => {
+                self.stream.replace(stream);
+            }
+            Err(_) => {}
+        }
+        ret
+    }
+
+    async fn disconnect(&mut self) {
+        self.stream.take();
+    }
+}
@@ -74,13 +74,25 @@ impl From<SerialParams> for async_ftdi::SerialParams {
 impl crate::inventory::Instrument for Instrument {
     type Address = FtdiAddress;
 
-    fn connect(_server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
+    fn connect(
+        _server: &crate::app::Server,
+        addr: &Self::Address,
+        _defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
         Ok(Self::new(&addr.port))
     }
 
     async fn wait_for_closed(&self) {
         self.inner.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
 }
 
 struct Handler {
@@ -4,10 +4,25 @@ use crate::iotask::{IoContext, IoHandler, IoTask};
 use async_trait::async_trait;
 use comsrv_protocol::{HidDeviceInfo, HidIdentifier, HidRequest, HidResponse, TransportError};
 use hidapi::HidDevice as HidApiDevice;
-use hidapi::{HidApi, HidError, HidResult};
+use hidapi::{DeviceInfo, HidApi, HidError, HidResult};
 use lazy_static::lazy_static;
 
+use anyhow::anyhow;
+use std::convert::TryInto;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::time::Duration;
 use tokio::task;
+use tokio::time::timeout;
+
+/// sysfs directory listing the hidraw devices on Linux
+#[cfg(target_os = "linux")]
+const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+/// Upper bound for the timeout of a `HidRequest::Read`
+const MAX_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time granted to hidapi in excess of the requested timeout before the request is aborted
+const BLOCKING_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 lazy_static! {
     static ref HID_API: HidResult<HidApi> = HidApi::new();
@@ -34,7 +49,29 @@ struct Handler {
 }
 
 fn open_device(idn: &HidIdentifier) -> crate::Result<HidApiDevice> {
-    get_hidapi()?.open(idn.vid, idn.pid).map_err(to_error)
+    let api = get_hidapi()?;
+    if idn.interface_number.is_none() {
+        return api.open(idn.vid, idn.pid).map_err(to_error);
+    }
+    api.open_path(find_device(api, idn)?.path()).map_err(to_error)
+}
+
+/// Find the first interface addressed by `idn` in the device list of `api`.
+fn find_device<'a>(api: &'a HidApi, idn: &HidIdentifier) -> crate::Result<&'a DeviceInfo> {
+    api.device_list()
+        .find(|x| idn.matches(&identifier(x)))
+        .ok_or_else(|| crate::Error::transport(io::Error::new(io::ErrorKind::NotFound, "No such HID interface")))
+}
+
+/// Identify the interface described by `device`.
+fn identifier(device: &DeviceInfo) -> HidIdentifier {
+    match device.interface_number().try_into() {
+        Ok(interface_number) => {
+            HidIdentifier::with_interface(device.vendor_id(), device.product_id(), interface_number)
+        }
+        // hidapi reports -1 if the interface number is unknown
+        Err(_) => HidIdentifier::new(device.vendor_id(), device.product_id()),
+    }
 }
 
 #[async_trait]
@@ -43,9 +80,14 @@ impl IoHandler for Handler {
     type Response = HidResponse;
 
     async fn handle(&mut self, _ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        let bound = match &req {
+            HidRequest::Read { timeout } => read_timeout(timeout.clone().into())? + BLOCKING_GRACE_PERIOD,
+            _ => MAX_READ_TIMEOUT,
+        };
         let device = self.device.take();
         let idn = self.idn.clone();
-        let (device, result) = task::spawn_blocking(move || handle_blocking(device, &idn, req)).await.unwrap();
+        // in case hidapi hangs, the device remains owned by the blocking task and is dropped once it returns
+        let (device, result) = run_blocking(bound, move || handle_blocking(device, &idn, req)).await?;
         let device_ok = !matches!(result, Err(crate::Error::Transport(_))) || result.is_ok();
         if device_ok {
             self.device = device;
@@ -59,14 +101,34 @@ fn handle_blocking(
     idn: &HidIdentifier,
     req: HidRequest,
 ) -> (Option<HidApiDevice>, crate::Result<HidResponse>) {
-    let mut device = match device {
-        Some(dev) => dev,
-        None => match open_device(idn) {
-            Ok(device) => device,
+    with_reopen(device, || open_device(idn), |device| handle_request(device, idn, req.clone()))
+}
+
+/// Run `handle` on the cached `device` or a newly opened one.
+///
+/// A cached handle becomes stale if the device was unplugged and plugged back in. Hence, if the request fails
+/// with a transport error on a cached handle, the device is reopened and the request is retried once.
+fn with_reopen<D, R>(
+    device: Option<D>,
+    mut open: impl FnMut() -> crate::Result<D>,
+    mut handle: impl FnMut(&mut D) -> crate::Result<R>,
+) -> (Option<D>, crate::Result<R>) {
+    let (mut device, cached) = match device {
+        Some(dev) => (dev, true),
+        None => match open() {
+            Ok(device) => (device, false),
             Err(e) => return (None, Err(e)),
         },
     };
-    let ret = handle_request(&mut device, idn, req);
+    let mut ret = handle(&mut device);
+    if cached && matches!(ret, Err(crate::Error::Transport(_))) {
+        drop(device);
+        device = match open() {
+            Ok(device) => device,
+            Err(e) => return (None, Err(e)),
+        };
+        ret = handle(&mut device);
+    }
     if ret.is_ok() {
         (Some(device), ret)
     } else {
@@ -79,7 +141,7 @@ fn handle_request(device: &mut HidApiDevice, idn: &HidIdentifier, req: HidReques
         HidRequest::Write { data } => device.write(&data).map(|_| HidResponse::Ok).map_err(to_error),
         HidRequest::Read { timeout } => {
             let mut buf = [0u8; 64];
-            let timeout: std::time::Duration = timeout.into();
+            let timeout = read_timeout(timeout.into())?;
             device
                 .read_timeout(&mut buf, timeout.as_millis() as i32)
                 .map_err(to_error)
@@ -90,6 +152,7 @@ fn handle_request(device: &mut HidApiDevice, idn: &HidIdentifier, req: HidReques
                     Ok(HidResponse::Data(buf[0..x].to_vec()))
                 })
         }
+        HidRequest::GetReportDescriptor => report_descriptor(idn).map(HidResponse::ReportDescriptor),
         HidRequest::GetInfo => {
             let mfr = device.get_manufacturer_string().map_err(to_error)?;
             let product = device.get_product_string().map_err(to_error)?;
@@ -104,6 +167,60 @@ fn handle_request(device: &mut HidApiDevice, idn: &HidIdentifier, req: HidReques
     }
 }
 
+/// Read the report descriptor of the device from sysfs, since hidapi does not provide it.
+#[cfg(target_os = "linux")]
+fn report_descriptor(idn: &HidIdentifier) -> crate::Result<Vec<u8>> {
+    let api = get_hidapi()?;
+    let path = find_device(api, idn)?.path().to_string_lossy().to_string();
+    read_report_descriptor(Path::new(SYSFS_HIDRAW), &path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn report_descriptor(_idn: &HidIdentifier) -> crate::Result<Vec<u8>> {
+    Err(crate::Error::argument(anyhow!(
+        "Reading HID report descriptors is only supported on Linux."
+    )))
+}
+
+/// Read the report descriptor of the hidraw device at `device_path`, e.g. `/dev/hidraw0`, from the sysfs
+/// class directory `sysfs`.
+#[cfg(target_os = "linux")]
+fn read_report_descriptor(sysfs: &Path, device_path: &str) -> crate::Result<Vec<u8>> {
+    let name = Path::new(device_path)
+        .file_name()
+        .filter(|x| x.to_string_lossy().starts_with("hidraw"))
+        .ok_or_else(|| {
+            crate::Error::argument(anyhow!(
+                "Cannot read the report descriptor of `{}`, which is not a hidraw device.",
+                device_path
+            ))
+        })?;
+    std::fs::read(sysfs.join(name).join("device").join("report_descriptor")).map_err(crate::Error::transport)
+}
+
+/// Validate the timeout of a read request, such that it fits into the signed milliseconds accepted by hidapi.
+pub fn read_timeout(timeout: Duration) -> crate::Result<Duration> {
+    if timeout > MAX_READ_TIMEOUT {
+        return Err(crate::Error::argument(anyhow!(
+            "HID read timeout of {:?} exceeds maximum of {:?}",
+            timeout,
+            MAX_READ_TIMEOUT
+        )));
+    }
+    Ok(timeout)
+}
+
+/// Run `f` on the blocking thread pool and fail with a transport error if it does not complete within `bound`.
+async fn run_blocking<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(bound: Duration, f: F) -> crate::Result<T> {
+    match timeout(bound, task::spawn_blocking(f)).await {
+        Ok(ret) => Ok(ret.unwrap()),
+        Err(_) => Err(crate::Error::transport(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "HID device did not respond",
+        ))),
+    }
+}
+
 #[derive(Clone)]
 pub struct Instrument {
     inner: IoTask<Handler>,
@@ -129,27 +246,36 @@ impl Instrument {
 impl crate::inventory::Instrument for Instrument {
     type Address = HidIdentifier;
 
-    fn connect(_server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
+    fn connect(
+        _server: &crate::app::Server,
+        addr: &Self::Address,
+        _defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
         Ok(Instrument::new(addr))
     }
 
     async fn wait_for_closed(&self) {
         self.inner.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
 }
 
 fn list_devices_blocking() -> crate::Result<Vec<HidDeviceInfo>> {
     let api = get_hidapi()?;
     let ret = api
         .device_list()
-        .map(|device| {
-            let idn = HidIdentifier::new(device.vendor_id(), device.product_id());
-            HidDeviceInfo {
-                idn,
-                manufacturer: device.manufacturer_string().map(|x| x.to_string()),
-                product: device.product_string().map(|x| x.to_string()),
-                serial_number: device.serial_number().map(|x| x.to_string()),
-            }
+        .map(|device| HidDeviceInfo {
+            idn: identifier(device),
+            manufacturer: device.manufacturer_string().map(|x| x.to_string()),
+            product: device.product_string().map(|x| x.to_string()),
+            serial_number: device.serial_number().map(|x| x.to_string()),
         })
         .collect();
     Ok(ret)
@@ -158,3 +284,96 @@ fn list_devices_blocking() -> crate::Result<Vec<HidDeviceInfo>> {
 pub async fn list_devices() -> crate::Result<Vec<HidDeviceInfo>> {
     task::spawn_blocking(list_devices_blocking).await.unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_timeout_bounded() {
+        assert_eq!(read_timeout(Duration::from_millis(100)).unwrap(), Duration::from_millis(100));
+        assert!(matches!(
+            read_timeout(Duration::from_secs(u32::MAX as u64)),
+            Err(crate::Error::Argument(_))
+        ));
+    }
+
+    struct MockDevice {
+        plugged: bool,
+    }
+
+    #[test]
+    fn reopen_after_replug() {
+        let mut opened = 0;
+        let open = || {
+            opened += 1;
+            Ok(MockDevice { plugged: true })
+        };
+        let handle = |device: &mut MockDevice| {
+            if device.plugged {
+                Ok(42)
+            } else {
+                Err(crate::Error::transport(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "No such device",
+                )))
+            }
+        };
+        let (device, ret) = with_reopen(Some(MockDevice { plugged: false }), open, handle);
+        assert_eq!(ret.unwrap(), 42);
+        assert!(device.unwrap().plugged);
+        assert_eq!(opened, 1);
+
+        // a newly opened device is not retried
+        let (device, ret) = with_reopen(None, || Ok(MockDevice { plugged: false }), handle);
+        assert!(matches!(ret, Err(crate::Error::Transport(_))));
+        assert!(device.is_none());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn report_descriptor_from_sysfs() {
+        // empty application collection of a generic desktop mouse
+        const DESCRIPTOR: [u8; 7] = [0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0xC0];
+        let sysfs = tempfile::tempdir().unwrap();
+        let device = sysfs.path().join("hidraw3").join("device");
+        std::fs::create_dir_all(&device).unwrap();
+        std::fs::write(device.join("report_descriptor"), DESCRIPTOR).unwrap();
+
+        let ret = read_report_descriptor(sysfs.path(), "/dev/hidraw3").unwrap();
+        assert_eq!(ret, DESCRIPTOR);
+        assert!(matches!(
+            read_report_descriptor(sysfs.path(), "/dev/hidraw4"),
+            Err(crate::Error::Transport(_))
+        ));
+        assert!(matches!(
+            read_report_descriptor(sysfs.path(), "IOService:/AppleACPIPlatformExpert"),
+            Err(crate::Error::Argument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn distinct_interfaces() {
+        let (server, _rx) = crate::app::Server::new();
+        let inventory = crate::inventory::Inventory::<Instrument>::new();
+        inventory
+            .connect(&server, &HidIdentifier::with_interface(0x1209, 0x0001, 0))
+            .unwrap();
+        inventory
+            .connect(&server, &HidIdentifier::with_interface(0x1209, 0x0001, 1))
+            .unwrap();
+        inventory
+            .connect(&server, &HidIdentifier::with_interface(0x1209, 0x0001, 1))
+            .unwrap();
+        inventory.connect(&server, &HidIdentifier::new(0x1209, 0x0001)).unwrap();
+        assert_eq!(inventory.list().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn blocking_read_times_out() {
+        let ret = run_blocking(Duration::from_millis(50), || std::thread::sleep(Duration::from_millis(500))).await;
+        assert!(matches!(ret, Err(crate::Error::Transport(_))));
+        let ret = run_blocking(Duration::from_millis(500), || 42).await;
+        assert_eq!(ret.unwrap(), 42);
+    }
+}
@@ -0,0 +1,195 @@
+//! Implements an in-memory bytestream instrument for testing. Each loopback instrument is connected to a
+//! simulated device, which echoes all data written to it. Clients may script the device with
+//! [`LoopbackRequest::Script`](comsrv_protocol::LoopbackRequest::Script) and tests with [`script_loopback()`] to
+//! reply with canned responses instead.
+//!
+//! Scripts are process-wide, such that they are shared by all `App` instances.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use comsrv_protocol::{ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, LoopbackAddress};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::app::Server;
+use crate::iotask::{IoContext, IoHandler, IoTask};
+use crate::protocol::bytestream;
+
+/// Size of the in-memory buffer between the instrument and the simulated device
+const BUFFER_SIZE: usize = 4096;
+
+/// A request expected by the simulated device and its reply
+type Exchange = (Vec<u8>, Vec<u8>);
+
+lazy_static! {
+    static ref SCRIPTS: Scripts = Scripts::default();
+}
+
+/// Script the loopback device `name` to reply with `response` once it received `request`, for each
+/// `(request, response)` pair in order. The pairs are appended to the pairs not yet consumed.
+/// Input not matching the next request is discarded. Once all pairs are consumed, the device echoes again.
+pub fn script_loopback(name: &str, exchanges: Vec<Exchange>) {
+    SCRIPTS.append(name, exchanges)
+}
+
+#[derive(Default)]
+struct Scripts(Mutex<HashMap<String, VecDeque<Exchange>>>);
+
+impl Scripts {
+    fn append(&self, name: &str, exchanges: Vec<Exchange>) {
+        let mut scripts = self.0.lock().unwrap();
+        scripts.entry(name.to_string()).or_default().extend(exchanges);
+    }
+
+    /// Consume the complete requests at the start of `input` and return the reply of the device.
+    fn respond(&self, name: &str, input: &mut Vec<u8>) -> Vec<u8> {
+        let mut scripts = self.0.lock().unwrap();
+        let exchanges = match scripts.get_mut(name) {
+            Some(x) => x,
+            None => return std::mem::take(input),
+        };
+        let mut reply = Vec::new();
+        while let Some((request, response)) = exchanges.front() {
+            if input.starts_with(request) {
+                input.drain(..request.len());
+                reply.extend_from_slice(response);
+                exchanges.pop_front();
+            } else if request.starts_with(input) {
+                // wait for the rest of the request
+                return reply;
+            } else {
+                input.remove(0);
+            }
+        }
+        scripts.remove(name);
+        reply.append(input);
+        reply
+    }
+}
+
+async fn run_device(name: String, mut device: DuplexStream) {
+    let mut input = Vec::new();
+    let mut buf = [0_u8; 256];
+    loop {
+        let n = match device.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        input.extend_from_slice(&buf[..n]);
+        let reply = SCRIPTS.respond(&name, &mut input);
+        if device.write_all(&reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Instrument {
+    inner: IoTask<Handler>,
+}
+
+impl Instrument {
+    pub fn new(server: &Server, addr: &LoopbackAddress) -> Self {
+        let handler = Handler {
+            server: server.clone(),
+            addr: addr.clone(),
+            stream: None,
+        };
+        Self {
+            inner: IoTask::new(handler),
+        }
+    }
+
+    pub async fn request(&mut self, req: ByteStreamRequest) -> crate::Result<ByteStreamResponse> {
+        self.inner.request(req).await
+    }
+}
+
+#[async_trait]
+impl crate::inventory::Instrument for Instrument {
+    type Address = LoopbackAddress;
+
+    fn connect(
+        server: &Server,
+        addr: &Self::Address,
+        _defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
+        Ok(Instrument::new(server, addr))
+    }
+
+    async fn wait_for_closed(&self) {
+        self.inner.wait_for_closed().await
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
+}
+
+struct Handler {
+    server: Server,
+    addr: LoopbackAddress,
+    stream: Option<DuplexStream>,
+}
+
+#[async_trait]
+impl IoHandler for Handler {
+    type Request = ByteStreamRequest;
+    type Response = ByteStreamResponse;
+
+    async fn handle(&mut self, _ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        if let ByteStreamRequest::Disconnect = req {
+            self.stream.take();
+            return Ok(ByteStreamResponse::Done);
+        }
+        if let ByteStreamRequest::Close { flush } = req {
+            let ret = match self.stream.take() {
+                Some(mut stream) if flush => AsyncWriteExt::flush(&mut stream).await.map_err(crate::Error::transport),
+                _ => Ok(()),
+            };
+            return ret.map(|_| ByteStreamResponse::Done);
+        }
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => {
+                let (stream, device) = tokio::io::duplex(BUFFER_SIZE);
+                tokio::spawn(run_device(self.addr.name.clone(), device));
+                stream
+            }
+        };
+        let sender = ByteStreamInstrument::Loopback(self.addr.clone());
+        let ret = bytestream::handle_broadcast(&mut stream, req, &self.server, sender).await;
+        self.stream.replace(stream);
+        ret
+    }
+
+    async fn disconnect(&mut self) {
+        self.stream.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_responses() {
+        let scripts = Scripts::default();
+        let mut input = b"abc".to_vec();
+        assert_eq!(scripts.respond("dev", &mut input), b"abc");
+        assert!(input.is_empty());
+
+        scripts.append("dev", vec![(b"*IDN?\n".to_vec(), b"Mock\n".to_vec())]);
+        scripts.append("dev", vec![(b"OUT?\n".to_vec(), b"1\n".to_vec())]);
+        let mut input = b"x*IDN".to_vec();
+        assert!(scripts.respond("dev", &mut input).is_empty());
+        assert_eq!(input, b"*IDN");
+        input.extend_from_slice(b"?\nOUT?\nxy");
+        assert_eq!(scripts.respond("dev", &mut input), b"Mock\n1\nxy");
+        assert!(input.is_empty());
+    }
+}
@@ -1,6 +1,8 @@
 pub mod can;
+pub mod custom;
 pub mod ftdi;
 pub mod hid;
+pub mod loopback;
 pub mod serial;
 pub mod sigrok;
 pub mod tcp;
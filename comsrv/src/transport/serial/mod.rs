@@ -4,7 +4,7 @@ use crate::app::Server;
 use crate::protocol::cobs_stream::CobsStream;
 use crate::rpc::FlowControl;
 use async_trait::async_trait;
-use comsrv_protocol::cobs_stream::{CobsStreamRequest, CobsStreamResponse};
+use comsrv_protocol::cobs_stream::{CobsStreamRequest, CobsStreamResponse, CrcKind};
 use serde::{Deserialize, Serialize};
 use tokio::io;
 use tokio::task::{self, JoinHandle};
@@ -15,16 +15,18 @@ use anyhow::anyhow;
 pub use params::SerialParams;
 
 use crate::inventory;
-use crate::iotask::{IoContext, IoHandler, IoTask};
+use crate::iotask::{IoContext, IoHandler, IoTask, RateLimit};
 use crate::protocol::bytestream;
-use crate::protocol::prologix::{handle_prologix_request, init_prologix};
+use crate::protocol::prologix::{configure_prologix, handle_prologix_request, init_prologix, scan_bus, PrologixConfig};
 use crate::transport::serial::params::{DataBits, Parity, StopBits};
 use comsrv_protocol::{
-    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, ScpiRequest, ScpiResponse, SerialAddress,
-    SerialInstrument, SerialRequest, SerialResponse,
+    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, InstrumentMode, PrologixAdapterRequest,
+    PrologixAdapterResponse, ScpiRequest, ScpiResponse, SerialAddress, SerialInstrument, SerialOptions,
+    SerialPortConfig, SerialPortInfo, SerialRequest, SerialResponse, UsbPortInfo,
 };
 
 pub mod params;
+pub mod usb;
 
 #[cfg(target_os = "linux")]
 mod linux_low_latency;
@@ -37,25 +39,30 @@ pub enum Request {
         req: ScpiRequest,
         timeout: Option<Duration>,
     },
+    PrologixAdapter(PrologixAdapterRequest),
     Bytes {
         params: SerialParams,
         req: ByteStreamRequest,
+        options: Option<SerialOptions>,
     },
     Serial {
         params: SerialParams,
         req: SerialRequest,
+        options: Option<SerialOptions>,
     },
     Cobs {
         params: SerialParams,
         req: CobsStreamRequest,
+        options: Option<SerialOptions>,
     },
+    GetOptions,
     DropCheck,
 }
 
 impl Request {
     pub fn params(&self) -> Option<SerialParams> {
         match self {
-            Request::Prologix { .. } => Some(SerialParams {
+            Request::Prologix { .. } | Request::PrologixAdapter(_) => Some(SerialParams {
                 baud: 9600,
                 data_bits: DataBits::Eight,
                 stop_bits: StopBits::One,
@@ -65,7 +72,16 @@ impl Request {
             Request::Bytes { params, .. } => Some(params.clone()),
             Request::Serial { params, .. } => Some(params.clone()),
             Request::Cobs { params, .. } => Some(params.clone()),
-            Request::DropCheck => None,
+            Request::GetOptions | Request::DropCheck => None,
+        }
+    }
+
+    fn options(&self) -> Option<&SerialOptions> {
+        match self {
+            Request::Bytes { options, .. } | Request::Serial { options, .. } | Request::Cobs { options, .. } => {
+                options.as_ref()
+            }
+            _ => None,
         }
     }
 }
@@ -74,8 +90,10 @@ impl Request {
 pub enum Response {
     Bytes(ByteStreamResponse),
     Scpi(ScpiResponse),
+    PrologixAdapter(PrologixAdapterResponse),
     Serial(SerialResponse),
     Cobs(CobsStreamResponse),
+    Options(serde_json::Value),
     Done,
 }
 
@@ -83,12 +101,16 @@ pub struct Handler {
     serial: Option<(SerialStream, SerialParams)>,
     cobs_stream: Option<(CobsStream, SerialParams)>,
     prologix_initialized: bool,
+    prologix_config: Option<PrologixConfig>,
     path: String,
     drop_delay: Duration,
+    mode: InstrumentMode,
+    default_term: Option<u8>,
     last_request: Instant,
+    rate_limit: RateLimit,
     drop_delay_task: Option<JoinHandle<()>>,
     server: Server,
-    cobs_stream_use_crc: bool,
+    cobs_stream_crc: CrcKind,
 }
 
 impl Handler {
@@ -98,11 +120,25 @@ impl Handler {
             FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
             FlowControl::Software => tokio_serial::FlowControl::Software,
         };
+        let parity = match params.parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Odd => tokio_serial::Parity::Odd,
+            Parity::Even => tokio_serial::Parity::Even,
+        };
+        let stop_bits = match params.stop_bits {
+            StopBits::One => tokio_serial::StopBits::One,
+            StopBits::Two => tokio_serial::StopBits::Two,
+        };
+        let data_bits = match params.data_bits {
+            DataBits::Seven => tokio_serial::DataBits::Seven,
+            DataBits::Eight => tokio_serial::DataBits::Eight,
+        };
 
-        let serial_stream = tokio_serial::new(path, params.baud)
-            .parity(params.parity.into())
-            .stop_bits(params.stop_bits.into())
-            .data_bits(params.data_bits.into())
+        let path = usb::resolve_port(path).await?;
+        let serial_stream = tokio_serial::new(&path, params.baud)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .data_bits(data_bits)
             .flow_control(flow_control)
             .open_native_async()
             .map_err(|x| crate::Error::transport(anyhow!(x)))?;
@@ -117,6 +153,38 @@ impl Handler {
         Ok(serial_stream)
     }
 
+    fn set_options(&mut self, opts: &SerialOptions) {
+        if let Some(drop_delay) = &opts.auto_drop {
+            self.drop_delay = drop_delay.clone().into();
+        }
+        if let Some(mode) = opts.mode {
+            self.mode = mode;
+        }
+        if let Some(term) = opts.default_term {
+            self.default_term = Some(term);
+        }
+        if let Some(min_interval) = &opts.min_interval {
+            self.rate_limit.set_min_interval(min_interval.clone().into());
+        }
+    }
+
+    /// The options currently in effect and the configuration of the open port, if any
+    fn options(&self) -> serde_json::Value {
+        let options = SerialOptions {
+            auto_drop: Some(self.drop_delay.into()),
+            mode: Some(self.mode),
+            default_term: self.default_term,
+            min_interval: Some(self.rate_limit.min_interval().into()),
+        };
+        let mut ret = serde_json::to_value(options).unwrap();
+        let params = self.serial.as_ref().map(|x| &x.1);
+        let params = params.or_else(|| self.cobs_stream.as_ref().map(|x| &x.1));
+        if let Some(params) = params {
+            ret["port_config"] = serde_json::to_value(SerialPortConfig::from(params.clone())).unwrap();
+        }
+        ret
+    }
+
     fn drop_check(&mut self, req: &Request) -> Option<crate::Result<Response>> {
         match req {
             Request::Cobs {
@@ -179,18 +247,52 @@ impl Handler {
                 timeout,
             } => {
                 if !self.prologix_initialized {
-                    init_prologix(serial).await?;
+                    init_prologix(serial, self.prologix_config.as_ref()).await?;
                     self.prologix_initialized = true;
                 }
                 handle_prologix_request(serial, gpib_addr, req, timeout)
                     .await
                     .map(Response::Scpi)
             }
-            Request::Bytes { params: _, req } => {
+            Request::PrologixAdapter(PrologixAdapterRequest::Configure {
+                auto,
+                eoi,
+                eos,
+                read_timeout_ms,
+            }) => {
+                let config = PrologixConfig {
+                    auto,
+                    eoi,
+                    eos,
+                    read_timeout_ms,
+                };
+                config.validate()?;
+                if !self.prologix_initialized {
+                    init_prologix(serial, None).await?;
+                    self.prologix_initialized = true;
+                }
+                configure_prologix(serial, &config).await?;
+                self.prologix_config = Some(config);
+                Ok(Response::PrologixAdapter(PrologixAdapterResponse::Done))
+            }
+            Request::PrologixAdapter(PrologixAdapterRequest::ScanBus { timeout }) => {
+                if !self.prologix_initialized {
+                    init_prologix(serial, self.prologix_config.as_ref()).await?;
+                    self.prologix_initialized = true;
+                }
+                scan_bus(serial, timeout.into())
+                    .await
+                    .map(|x| Response::PrologixAdapter(PrologixAdapterResponse::Addresses(x)))
+            }
+            Request::Bytes { params, mut req, .. } => {
+                bytestream::apply_default_term(&mut req, self.default_term);
                 self.prologix_initialized = false;
-                bytestream::handle(serial, req).await.map(Response::Bytes)
+                let sender = self.get_instrument(&params);
+                bytestream::handle_broadcast(serial, req, &self.server, sender)
+                    .await
+                    .map(Response::Bytes)
             }
-            Request::Serial { params: _, req } => match req {
+            Request::Serial { req, .. } => match req {
                 SerialRequest::WriteDataTerminalReady(x) => {
                     serial.write_data_terminal_ready(x).map_err(map_tokio_serial_error)?;
                     Ok(Response::Serial(SerialResponse::Done))
@@ -211,24 +313,40 @@ impl Handler {
                 SerialRequest::ReadClearToSend => Ok(Response::Serial(SerialResponse::PinLevel(
                     serial.read_clear_to_send().map_err(map_tokio_serial_error)?,
                 ))),
+                SerialRequest::GetActualConfig => {
+                    let params = actual_params(serial)?;
+                    Ok(Response::Serial(SerialResponse::Config(params.into())))
+                }
             },
-            Request::DropCheck => unreachable!(),
+            Request::GetOptions | Request::DropCheck => unreachable!(),
             Request::Cobs { .. } => unreachable!(),
         }
     }
 
     async fn handle_cobs_request(&mut self, params: SerialParams, req: CobsStreamRequest) -> crate::Result<Response> {
+        if let CobsStreamRequest::Status = req {
+            let alive = self.cobs_stream.as_ref().is_some_and(|(x, _)| x.is_alive());
+            return Ok(Response::Cobs(CobsStreamResponse::Status {
+                alive,
+                use_crc: self.cobs_stream_crc != CrcKind::None,
+                crc: Some(self.cobs_stream_crc),
+            }));
+        }
+        if let CobsStreamRequest::Flush = req {
+            if let Some((cobs_stream, _)) = self.cobs_stream.as_ref().filter(|(x, _)| x.is_alive()) {
+                cobs_stream.flush().await?;
+            }
+            return Ok(Response::Cobs(CobsStreamResponse::Done));
+        }
         drop(self.serial.take());
 
-        if let CobsStreamRequest::Start { use_crc } = req {
-            self.cobs_stream_use_crc = use_crc;
+        if let CobsStreamRequest::Start { use_crc, crc } = req {
+            self.cobs_stream_crc = crc.unwrap_or_else(|| use_crc.into());
         }
 
         let cobs_stream = match self.cobs_stream.take() {
             Some((cobs_stream, old_params))
-                if old_params == params
-                    && cobs_stream.is_alive()
-                    && cobs_stream.use_crc() == self.cobs_stream_use_crc =>
+                if old_params == params && cobs_stream.is_alive() && cobs_stream.crc() == self.cobs_stream_crc =>
             {
                 cobs_stream
             }
@@ -240,7 +358,7 @@ impl Handler {
                     write,
                     self.server.clone(),
                     self.get_instrument(&params),
-                    self.cobs_stream_use_crc,
+                    self.cobs_stream_crc,
                 )
             }
         };
@@ -271,16 +389,66 @@ fn map_tokio_serial_error(err: tokio_serial::Error) -> crate::Error {
     crate::Error::transport(anyhow!(err))
 }
 
+/// Read back the parameters the driver applied to `port`, which may differ from the requested ones.
+fn actual_params<P: SerialPort + ?Sized>(port: &P) -> crate::Result<SerialParams> {
+    let data_bits = match port.data_bits().map_err(map_tokio_serial_error)? {
+        tokio_serial::DataBits::Seven => DataBits::Seven,
+        tokio_serial::DataBits::Eight => DataBits::Eight,
+        x => return Err(crate::Error::protocol(anyhow!("Port reports unsupported data bits: {}", x))),
+    };
+    let parity = match port.parity().map_err(map_tokio_serial_error)? {
+        tokio_serial::Parity::None => Parity::None,
+        tokio_serial::Parity::Odd => Parity::Odd,
+        tokio_serial::Parity::Even => Parity::Even,
+    };
+    let stop_bits = match port.stop_bits().map_err(map_tokio_serial_error)? {
+        tokio_serial::StopBits::One => StopBits::One,
+        tokio_serial::StopBits::Two => StopBits::Two,
+    };
+    let hardware_flow_control = match port.flow_control().map_err(map_tokio_serial_error)? {
+        tokio_serial::FlowControl::None => FlowControl::NoFlowControl,
+        tokio_serial::FlowControl::Hardware => FlowControl::Hardware,
+        tokio_serial::FlowControl::Software => FlowControl::Software,
+    };
+    Ok(SerialParams {
+        baud: port.baud_rate().map_err(map_tokio_serial_error)?,
+        data_bits,
+        stop_bits,
+        parity,
+        hardware_flow_control,
+    })
+}
+
 #[async_trait]
 impl IoHandler for Handler {
     type Request = Request;
     type Response = Response;
 
     async fn handle(&mut self, ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        if let Request::GetOptions = req {
+            return Ok(Response::Options(self.options()));
+        }
+        if let Request::Bytes {
+            req: ByteStreamRequest::Close { flush },
+            ..
+        } = req
+        {
+            let ret = match self.serial.take() {
+                Some((mut serial, _)) if flush => {
+                    io::AsyncWriteExt::flush(&mut serial).await.map_err(crate::Error::transport)
+                }
+                _ => Ok(()),
+            };
+            return ret.map(|_| Response::Bytes(ByteStreamResponse::Done));
+        }
         if let Some(reply) = self.drop_check(&req) {
             return reply;
         }
-        if let Request::Cobs { params, req } = req {
+        if let Some(opts) = req.options() {
+            self.set_options(opts);
+        }
+        self.rate_limit.wait().await;
+        if let Request::Cobs { params, req, .. } = req {
             return self.handle_cobs_request(params, req).await;
         }
         drop(self.cobs_stream.take());
@@ -289,8 +457,9 @@ impl IoHandler for Handler {
         let mut serial = self.open_serial(&new_params).await?;
 
         let ret = self.handle_request(req, &mut serial).await;
+        self.last_request = Instant::now();
         match &ret {
-            Err(crate::Error::Protocol(_)) | Ok(_) => {
+            Err(crate::Error::Protocol(_)) | Ok(_) if self.mode == InstrumentMode::Persistent => {
                 self.serial.replace((serial, new_params));
 
                 let mut ctx = ctx.clone();
@@ -300,7 +469,7 @@ impl IoHandler for Handler {
                     let _ = ctx.send(Request::DropCheck);
                 }));
             }
-            Err(_) => {}
+            _ => {}
         }
         ret
     }
@@ -312,17 +481,21 @@ pub struct Instrument {
 }
 
 impl Instrument {
-    pub fn new(path: String, server: Server) -> Self {
+    pub fn new(path: String, server: Server, defaults: &inventory::InstrumentDefaults) -> Self {
         let handler = Handler {
             serial: None,
             path,
             prologix_initialized: false,
-            drop_delay: DEFAULT_DROP_DELAY,
+            prologix_config: None,
+            drop_delay: defaults.drop_delay.unwrap_or(DEFAULT_DROP_DELAY),
+            mode: InstrumentMode::Persistent,
+            default_term: None,
             last_request: Instant::now(),
+            rate_limit: RateLimit::default(),
             drop_delay_task: None,
             cobs_stream: None,
             server,
-            cobs_stream_use_crc: true,
+            cobs_stream_crc: CrcKind::Crc16,
         };
         Self {
             inner: IoTask::new(handler),
@@ -338,13 +511,25 @@ impl Instrument {
 impl inventory::Instrument for Instrument {
     type Address = SerialAddress;
 
-    fn connect(server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
-        Ok(Instrument::new(addr.port.clone(), server.clone()))
+    fn connect(
+        server: &crate::app::Server,
+        addr: &Self::Address,
+        defaults: &inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
+        Ok(Instrument::new(addr.port.clone(), server.clone(), defaults))
     }
 
     async fn wait_for_closed(&self) {
         self.inner.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
 }
 
 pub async fn list_devices() -> crate::Result<Vec<String>> {
@@ -358,3 +543,107 @@ pub async fn list_devices() -> crate::Result<Vec<String>> {
     .await
     .unwrap()
 }
+
+/// Check whether the serial port at `path` is present without opening it. USB selectors are resolved first.
+pub async fn probe(path: &str) -> crate::Result<()> {
+    let port = usb::resolve_port(path).await?;
+    if list_devices().await?.contains(&port) {
+        Ok(())
+    } else {
+        Err(crate::Error::transport(anyhow!("Serial port `{}` not found.", port)))
+    }
+}
+
+/// Lists the serial ports including the USB metadata of USB serial adapters.
+pub async fn list_devices_detailed() -> crate::Result<Vec<SerialPortInfo>> {
+    task::spawn_blocking(move || match tokio_serial::available_ports() {
+        Ok(x) => Ok(x.into_iter().map(port_info).collect()),
+        Err(err) => Err(crate::Error::transport(anyhow!(err.description))),
+    })
+    .await
+    .unwrap()
+}
+
+fn port_info(info: tokio_serial::SerialPortInfo) -> SerialPortInfo {
+    let usb = match info.port_type {
+        tokio_serial::SerialPortType::UsbPort(x) => Some(UsbPortInfo {
+            vendor_id: x.vid,
+            product_id: x.pid,
+            serial_number: x.serial_number,
+            manufacturer: x.manufacturer,
+            product: x.product,
+        }),
+        _ => None,
+    };
+    SerialPortInfo {
+        port_name: info.port_name,
+        usb,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn actual_config() {
+        let (mut port, _peer) = SerialStream::pair().unwrap();
+        port.set_baud_rate(19200).unwrap();
+        port.set_parity(tokio_serial::Parity::Even).unwrap();
+        port.set_stop_bits(tokio_serial::StopBits::Two).unwrap();
+        // a pseudo terminal may not retain all settings, hence compare to what the port reports
+        let params = actual_params(&port).unwrap();
+        assert_eq!(params.baud, port.baud_rate().unwrap());
+        assert_eq!(
+            params.parity == Parity::Even,
+            port.parity().unwrap() == tokio_serial::Parity::Even
+        );
+        assert_eq!(
+            params.stop_bits == StopBits::Two,
+            port.stop_bits().unwrap() == tokio_serial::StopBits::Two
+        );
+        let config: SerialPortConfig = params.into();
+        assert_eq!(config.baudrate, port.baud_rate().unwrap());
+    }
+
+    #[test]
+    fn detailed_port_info() {
+        let ports = vec![
+            tokio_serial::SerialPortInfo {
+                port_name: "COM3".to_string(),
+                port_type: tokio_serial::SerialPortType::UsbPort(tokio_serial::UsbPortInfo {
+                    vid: 0x0957,
+                    pid: 0x1755,
+                    serial_number: Some("MY12345678".to_string()),
+                    manufacturer: Some("Keysight".to_string()),
+                    product: None,
+                }),
+            },
+            tokio_serial::SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: tokio_serial::SerialPortType::Unknown,
+            },
+        ];
+        let ports: Vec<_> = ports.into_iter().map(port_info).collect();
+        assert_eq!(
+            ports,
+            vec![
+                SerialPortInfo {
+                    port_name: "COM3".to_string(),
+                    usb: Some(UsbPortInfo {
+                        vendor_id: 0x0957,
+                        product_id: 0x1755,
+                        serial_number: Some("MY12345678".to_string()),
+                        manufacturer: Some("Keysight".to_string()),
+                        product: None,
+                    }),
+                },
+                SerialPortInfo {
+                    port_name: "/dev/ttyS0".to_string(),
+                    usb: None,
+                },
+            ]
+        );
+    }
+}
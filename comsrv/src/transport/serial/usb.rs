@@ -0,0 +1,223 @@
+//! Selection of serial ports by USB identity instead of the port name.
+//!
+//! Port names of USB serial adapters (e.g. `COM3` or `/dev/ttyUSB0`) depend on the order the devices
+//! were attached. Instead, a port may be addressed as `usb:<vid>:<pid>` or `usb:<vid>:<pid>:<serial-number>`
+//! with `vid` and `pid` given in hex. The selector is resolved against the available ports before a request
+//! is dispatched, such that an adapter is registered in the inventory under its port name, however it is addressed.
+
+use anyhow::anyhow;
+use comsrv_protocol::{Address, ByteStreamInstrument, Request};
+use tokio::task;
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+const USB_PREFIX: &str = "usb:";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UsbPortSelector {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+}
+
+impl UsbPortSelector {
+    /// Parses a `usb:<vid>:<pid>[:<serial-number>]` port path. Returns `None` if `path` does not
+    /// select a port by USB identity.
+    pub fn parse(path: &str) -> Option<crate::Result<Self>> {
+        let selector = path.strip_prefix(USB_PREFIX)?;
+        let invalid = || crate::Error::argument(anyhow!("Invalid USB serial port selector: `{}`", path));
+        let mut parts = selector.splitn(3, ':');
+        let vid = parts.next().and_then(|x| u16::from_str_radix(x, 16).ok());
+        let pid = parts.next().and_then(|x| u16::from_str_radix(x, 16).ok());
+        let serial_number = parts.next().filter(|x| !x.is_empty()).map(|x| x.to_string());
+        let ret = match (vid, pid) {
+            (Some(vid), Some(pid)) => Ok(UsbPortSelector {
+                vid,
+                pid,
+                serial_number,
+            }),
+            _ => Err(invalid()),
+        };
+        Some(ret)
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                info.vid == self.vid
+                    && info.pid == self.pid
+                    && (self.serial_number.is_none() || self.serial_number == info.serial_number)
+            }
+            _ => false,
+        }
+    }
+
+    /// Find the port name of the single port matching this selector.
+    pub fn resolve(&self, ports: &[SerialPortInfo]) -> crate::Result<String> {
+        let matching: Vec<_> = ports.iter().filter(|x| self.matches(x)).map(|x| x.port_name.clone()).collect();
+        match matching.len() {
+            0 => Err(crate::Error::transport(anyhow!(
+                "No serial port found for USB device {:04x}:{:04x}",
+                self.vid,
+                self.pid
+            ))),
+            1 => Ok(matching.into_iter().next().unwrap()),
+            _ => Err(crate::Error::argument(anyhow!(
+                "Multiple serial ports found for USB device {:04x}:{:04x}: {}",
+                self.vid,
+                self.pid,
+                matching.join(", ")
+            ))),
+        }
+    }
+}
+
+/// Resolve `path` to a port name if it is a USB selector, otherwise `path` is returned unchanged.
+pub async fn resolve_port(path: &str) -> crate::Result<String> {
+    let selector = match UsbPortSelector::parse(path) {
+        Some(selector) => selector?,
+        None => return Ok(path.to_string()),
+    };
+    let ports = task::spawn_blocking(tokio_serial::available_ports)
+        .await
+        .unwrap()
+        .map_err(|err| crate::Error::transport(anyhow!(err.description)))?;
+    selector.resolve(&ports)
+}
+
+/// Returns the serial port path referred to by `req`, if any.
+fn serial_port(req: &mut Request) -> Option<&mut String> {
+    match req {
+        Request::Bytes {
+            instrument: ByteStreamInstrument::Serial(x),
+            ..
+        }
+        | Request::CobsStream {
+            instrument: ByteStreamInstrument::Serial(x),
+            ..
+        } => Some(&mut x.address.port),
+        Request::Serial { instrument, .. } => Some(&mut instrument.address.port),
+        Request::Prologix { instrument, .. } | Request::PrologixAdapter { instrument, .. } => {
+            Some(&mut instrument.address.port)
+        }
+        Request::Lock { addr, .. }
+        | Request::Unlock { addr, .. }
+        | Request::Drop { addr, .. }
+        | Request::SetInstrumentOptions { addr, .. }
+        | Request::GetInstrumentOptions { addr }
+        | Request::Ping { addr, .. } => match addr {
+            Address::Serial(x) => Some(&mut x.port),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replace a USB selector used as serial port in `req` with the name of the selected port.
+pub async fn resolve_request(req: &mut Request) -> crate::Result<()> {
+    if let Some(port) = serial_port(req) {
+        let resolved = resolve_port(port).await?;
+        *port = resolved;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::SerialAddress;
+    use std::time::Duration;
+    use tokio_serial::UsbPortInfo;
+
+    fn usb_port(name: &str, vid: u16, pid: u16, serial_number: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial_number.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn ports() -> Vec<SerialPortInfo> {
+        vec![
+            SerialPortInfo {
+                port_name: "COM1".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            usb_port("COM3", 0x0403, 0x6001, "A10K1234"),
+            usb_port("COM4", 0x0403, 0x6001, "A10K5678"),
+            usb_port("COM5", 0x0957, 0x1755, "MY1234"),
+        ]
+    }
+
+    #[test]
+    fn parse() {
+        assert!(UsbPortSelector::parse("/dev/ttyUSB0").is_none());
+        assert_eq!(
+            UsbPortSelector::parse("usb:0403:6001").unwrap().unwrap(),
+            UsbPortSelector {
+                vid: 0x0403,
+                pid: 0x6001,
+                serial_number: None
+            }
+        );
+        assert_eq!(
+            UsbPortSelector::parse("usb:0957:1755:MY1234").unwrap().unwrap(),
+            UsbPortSelector {
+                vid: 0x0957,
+                pid: 0x1755,
+                serial_number: Some("MY1234".to_string())
+            }
+        );
+        assert!(UsbPortSelector::parse("usb:0403").unwrap().is_err());
+        assert!(UsbPortSelector::parse("usb:0403:xyz").unwrap().is_err());
+        assert!(UsbPortSelector::parse("usb:10403:6001").unwrap().is_err());
+    }
+
+    #[test]
+    fn resolve_single() {
+        let selector = UsbPortSelector::parse("usb:0957:1755").unwrap().unwrap();
+        assert_eq!(selector.resolve(&ports()).unwrap(), "COM5");
+        let selector = UsbPortSelector::parse("usb:0403:6001:A10K5678").unwrap().unwrap();
+        assert_eq!(selector.resolve(&ports()).unwrap(), "COM4");
+    }
+
+    #[test]
+    fn resolve_none() {
+        let selector = UsbPortSelector::parse("usb:1234:5678").unwrap().unwrap();
+        assert!(matches!(selector.resolve(&ports()), Err(crate::Error::Transport(_))));
+    }
+
+    #[test]
+    fn resolve_ambiguous() {
+        let selector = UsbPortSelector::parse("usb:0403:6001").unwrap().unwrap();
+        let err = selector.resolve(&ports()).unwrap_err();
+        assert!(matches!(err, crate::Error::Argument(_)));
+        let msg = format!("{}", err);
+        assert!(msg.contains("COM3") && msg.contains("COM4"));
+    }
+
+    fn lock(port: &str) -> Request {
+        Request::Lock {
+            addr: Address::Serial(SerialAddress { port: port.to_string() }),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_request_port() {
+        let mut req = lock("usb:0403:6001");
+        assert_eq!(serial_port(&mut req).unwrap(), "usb:0403:6001");
+        assert!(serial_port(&mut Request::ListConnectedInstruments).is_none());
+
+        let mut req = lock("/dev/ttyUSB0");
+        resolve_request(&mut req).await.unwrap();
+        assert_eq!(serial_port(&mut req).unwrap(), "/dev/ttyUSB0");
+
+        let mut req = lock("usb:0403:xyz");
+        assert!(matches!(resolve_request(&mut req).await, Err(crate::Error::Argument(_))));
+    }
+}
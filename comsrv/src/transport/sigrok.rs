@@ -4,15 +4,22 @@ use std::process::{Command, Stdio};
 use anyhow::anyhow;
 use bitvec::order::Lsb0;
 use bitvec::vec::BitVec;
-use comsrv_protocol::{SigrokAcquire, SigrokData, SigrokDevice, SigrokRequest, SigrokResponse};
+use comsrv_protocol::{
+    SigrokAcquire, SigrokData, SigrokDevice, SigrokDeviceInfo, SigrokRequest, SigrokResponse, SigrokSampleRateRange,
+};
 use tokio::task;
 
 pub async fn read(device: &str, req: SigrokRequest) -> crate::Result<SigrokResponse> {
     let device = device.to_string();
-    let ret = task::spawn_blocking(|| do_read(device, req))
-        .await
-        .map_err(|x| crate::Error::internal(anyhow!(x)))?;
-    ret.map(SigrokResponse::Data)
+    task::spawn_blocking(|| {
+        if req.analog {
+            do_read_analog(device, req)
+        } else {
+            do_read(device, req).map(SigrokResponse::Data)
+        }
+    })
+    .await
+    .map_err(|x| crate::Error::internal(anyhow!(x)))?
 }
 
 fn run_command(args: &[&str]) -> crate::Result<String> {
@@ -64,8 +71,103 @@ fn do_list() -> crate::Result<SigrokResponse> {
     Ok(SigrokResponse::Devices(ret))
 }
 
+/// Query the channels and sample rates supported by a device.
+///
+/// `addr` is either the driver specification passed to `sigrok-cli -d` or an address as returned by [`list()`].
+pub async fn device_info(addr: &str) -> crate::Result<SigrokResponse> {
+    let device = addr.strip_prefix("sigrok::").unwrap_or(addr).to_string();
+    task::spawn_blocking(move || do_device_info(&device))
+        .await
+        .map_err(|x| crate::Error::internal(anyhow!(x)))?
+        .map(SigrokResponse::DeviceInfo)
+}
+
+fn do_device_info(device: &str) -> crate::Result<SigrokDeviceInfo> {
+    let stdout = run_command(&["-d", device, "--show"])?;
+    parse_device_info(&stdout)
+}
+
+/// Parse the output of `sigrok-cli --show`.
+pub fn parse_device_info(data: &str) -> crate::Result<SigrokDeviceInfo> {
+    let invalid = || crate::Error::transport(anyhow!("Invalid Output"));
+    let channels = data
+        .lines()
+        .filter_map(|line| line.split_once(" - "))
+        .filter_map(|(_, desc)| desc.split_once(" with "))
+        .filter_map(|(_, channels)| channels.split_once(':'))
+        .map(|(_, channels)| channels.split_whitespace().map(|x| x.to_string()).collect::<Vec<_>>())
+        .next()
+        .ok_or_else(invalid)?;
+
+    let mut sample_rates = Vec::new();
+    let mut sample_rate_range = None;
+    let mut lines = data.lines().map(|x| x.trim()).skip_while(|x| !x.starts_with("samplerate"));
+    if let Some(line) = lines.next() {
+        if let Some(range) = line.strip_prefix("samplerate (") {
+            // e.g. `samplerate (1 Hz - 1 GHz in steps of 1 Hz)`
+            let range = range.strip_suffix(')').ok_or_else(invalid)?;
+            let (min, rest) = range.split_once(" - ").ok_or_else(invalid)?;
+            let (max, step) = rest.split_once(" in steps of ").ok_or_else(invalid)?;
+            sample_rate_range = Some(SigrokSampleRateRange {
+                min: parse_sample_rate(min).ok_or_else(invalid)?,
+                max: parse_sample_rate(max).ok_or_else(invalid)?,
+                step: parse_sample_rate(step).ok_or_else(invalid)?,
+            });
+        } else if line.starts_with("samplerate - supported samplerates") {
+            // followed by one sample rate per line, e.g. `24 MHz (current)`
+            for line in lines {
+                match parse_sample_rate(line.trim_end_matches("(current)")) {
+                    Some(rate) => sample_rates.push(rate),
+                    None => break,
+                }
+            }
+        } else if let Some(rate) = line.strip_prefix("samplerate:") {
+            let rate = parse_sample_rate(rate.trim().trim_end_matches("(current)")).ok_or_else(invalid)?;
+            sample_rates.push(rate);
+        }
+    }
+    Ok(SigrokDeviceInfo {
+        channels,
+        sample_rates,
+        sample_rate_range,
+    })
+}
+
+fn parse_sample_rate(rate: &str) -> Option<u64> {
+    let (value, unit) = rate.trim().split_once(' ')?;
+    let multiplier = match unit.trim() {
+        "Hz" => 1.0,
+        "kHz" => 1e3,
+        "MHz" => 1e6,
+        "GHz" => 1e9,
+        _ => return None,
+    };
+    let value: f64 = value.parse().ok()?;
+    Some((value * multiplier).round() as u64)
+}
+
 fn do_read(device: String, req: SigrokRequest) -> crate::Result<SigrokData> {
-    let mut args = vec!["-d", &device];
+    let csv = acquire_csv(&device, &req)?;
+    let (channels, length) = parse_csv(csv)?;
+    Ok(SigrokData {
+        tsample: 1.0 / (req.sample_rate as f64),
+        length,
+        channels,
+    })
+}
+
+fn do_read_analog(device: String, req: SigrokRequest) -> crate::Result<SigrokResponse> {
+    let csv = acquire_csv(&device, &req)?;
+    let (channels, length) = parse_analog_csv(&csv)?;
+    Ok(SigrokResponse::AnalogData {
+        tsample: 1.0 / (req.sample_rate as f64),
+        length,
+        channels,
+    })
+}
+
+fn acquire_csv(device: &str, req: &SigrokRequest) -> crate::Result<String> {
+    let mut args = vec!["-d", device];
     let channels = req.channels.join(",");
     if !req.channels.is_empty() {
         args.push("--channels");
@@ -87,14 +189,7 @@ fn do_read(device: String, req: SigrokRequest) -> crate::Result<SigrokData> {
     args.push(&acq);
     args.push("--output-format");
     args.push("csv:label=channel:header=false");
-    let csv = run_command(&args)?;
-
-    let (channels, length) = parse_csv(csv)?;
-    Ok(SigrokData {
-        tsample: 1.0 / (req.sample_rate as f64),
-        length,
-        channels,
-    })
+    run_command(&args)
 }
 
 pub fn parse_csv(data: String) -> crate::Result<(HashMap<String, Vec<u8>>, usize)> {
@@ -137,3 +232,130 @@ pub fn parse_csv(data: String) -> crate::Result<(HashMap<String, Vec<u8>>, usize
     }
     Ok((ret, len))
 }
+
+/// Parse the CSV output of an acquisition of analog channels. Logic channels are converted to `0.0` and `1.0`.
+pub fn parse_analog_csv(data: &str) -> crate::Result<(HashMap<String, Vec<f32>>, usize)> {
+    let mut line_iter = data.split('\n');
+    let head = line_iter
+        .next()
+        .ok_or_else(|| crate::Error::transport(anyhow!("Invalid Output")))?;
+    let channels: Vec<_> = head.trim().split(',').map(|x| x.to_string()).collect();
+    let mut cols: Vec<Vec<f32>> = vec![Vec::new(); channels.len()];
+
+    let mut len = 0;
+    for line in line_iter {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        for (k, v) in line.split(',').enumerate() {
+            if k >= channels.len() {
+                return Err(crate::Error::transport(anyhow!("Invalid Output")));
+            }
+            let v = v
+                .trim()
+                .parse()
+                .map_err(|_| crate::Error::transport(anyhow!("Invalid Output")))?;
+            cols[k].push(v)
+        }
+        len += 1;
+    }
+    Ok((channels.into_iter().zip(cols).collect(), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEMO: &str = "Driver functions:
+    Demo device
+    Logic analyzer
+    Oscilloscope
+demo - Demo device with 13 channels: D0 D1 D2 D3 D4 D5 D6 D7 A0 A1 A2 A3 A4
+Channel groups:
+    Logic: channels D0 D1 D2 D3 D4 D5 D6 D7
+    Analog0: channel A0
+Supported configuration options across all channel groups:
+    continuous: on, off
+    limit_samples: 0 (current)
+    samplerate (1 Hz - 1 GHz in steps of 1 Hz)
+    averaging: on, off (current)
+";
+
+    const FX2LAFW: &str = "Driver functions:
+    Logic analyzer
+fx2lafw:conn=1.5 - Saleae Logic with 8 channels: D0 D1 D2 D3 D4 D5 D6 D7
+Supported configuration options:
+    continuous: on, off (current)
+    samplerate - supported samplerates:
+      20 kHz
+      2.5 MHz
+      24 MHz (current)
+    captureratio: 0 (current)
+";
+
+    #[test]
+    fn device_info_range() {
+        let info = parse_device_info(DEMO).unwrap();
+        assert_eq!(info.channels.len(), 13);
+        assert_eq!(info.channels[0], "D0");
+        assert_eq!(info.channels[12], "A4");
+        assert!(info.sample_rates.is_empty());
+        assert_eq!(
+            info.sample_rate_range,
+            Some(SigrokSampleRateRange {
+                min: 1,
+                max: 1_000_000_000,
+                step: 1
+            })
+        );
+    }
+
+    #[test]
+    fn device_info_list() {
+        let info = parse_device_info(FX2LAFW).unwrap();
+        assert_eq!(info.channels, vec!["D0", "D1", "D2", "D3", "D4", "D5", "D6", "D7"]);
+        assert_eq!(info.sample_rates, vec![20_000, 2_500_000, 24_000_000]);
+        assert!(info.sample_rate_range.is_none());
+    }
+
+    #[test]
+    fn analog_csv() {
+        let (channels, length) = parse_analog_csv("A0,A1,D0\n1.5,-0.25,1\n2.0,0.5,0\n").unwrap();
+        assert_eq!(length, 2);
+        assert_eq!(channels["A0"], vec![1.5, 2.0]);
+        assert_eq!(channels["A1"], vec![-0.25, 0.5]);
+        assert_eq!(channels["D0"], vec![1.0, 0.0]);
+
+        assert!(parse_analog_csv("A0\nabc\n").is_err());
+        assert!(parse_analog_csv("A0\n1.0,2.0\n").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires sigrok-cli"]
+    async fn demo_analog() {
+        let req = SigrokRequest {
+            channels: vec!["A0".to_string(), "A1".to_string()],
+            acquire: SigrokAcquire::Samples(100),
+            sample_rate: 100_000,
+            analog: true,
+        };
+        match read("demo", req).await.unwrap() {
+            SigrokResponse::AnalogData { length, channels, .. } => {
+                assert_eq!(length, 100);
+                assert_eq!(channels["A0"].len(), 100);
+                assert_eq!(channels["A1"].len(), 100);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires sigrok-cli"]
+    async fn demo_device() {
+        match device_info("sigrok::demo").await.unwrap() {
+            SigrokResponse::DeviceInfo(info) => assert!(!info.channels.is_empty()),
+            _ => panic!(),
+        }
+    }
+}
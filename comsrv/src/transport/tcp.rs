@@ -1,11 +1,13 @@
 use crate::app::Server;
-use crate::iotask::{IoContext, IoHandler, IoTask};
+use crate::iotask::{IoContext, IoHandler, IoTask, RateLimit};
+use crate::protocol::bytestream::{self, WriteBuffer};
 use crate::protocol::cobs_stream::CobsStream;
 use crate::{inventory, Error};
 use async_trait::async_trait;
-use comsrv_protocol::cobs_stream::{CobsStreamRequest, CobsStreamResponse};
+use comsrv_protocol::cobs_stream::{CobsStreamRequest, CobsStreamResponse, CrcKind};
 use comsrv_protocol::{
-    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, TcpAddress, TcpInstrument, TcpOptions,
+    ByteStreamInstrument, ByteStreamRequest, ByteStreamResponse, InstrumentMode, ProtocolError, TcpAddress,
+    TcpInstrument, TcpOptions,
 };
 use std::io;
 use std::net::ToSocketAddrs;
@@ -13,12 +15,15 @@ use std::net::ToSocketAddrs;
 use tokio::task::{self, JoinHandle};
 
 use anyhow::anyhow;
+use socket2::{SockRef, TcpKeepalive};
 use std::net::SocketAddr;
 use tokio::net::TcpStream;
-use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
 
 const DEFAULT_DROP_DELAY: Duration = Duration::from_secs(100);
 const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// Small requests and responses are typical for instrument control, hence Nagle's algorithm is disabled by default.
+const DEFAULT_NODELAY: bool = true;
 
 #[derive(Clone)]
 pub struct Instrument {
@@ -29,17 +34,25 @@ struct Handler {
     addr: SocketAddr,
     stream: Option<TcpStream>,
     last_request: Instant,
+    rate_limit: RateLimit,
     drop_delay: Duration,
     connection_timeout: Duration,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    mode: InstrumentMode,
+    default_term: Option<u8>,
+    detect_term: bool,
+    line_term: Option<LineTerm>,
     drop_delay_task: Option<JoinHandle<()>>,
+    write_buffer: WriteBuffer,
+    flush_task: Option<JoinHandle<()>>,
     cobs_stream: Option<CobsStream>,
-    cobs_stream_use_crc: bool,
+    cobs_stream_crc: CrcKind,
     server: Server,
 }
 
 #[derive(Clone)]
 pub enum TcpRequest {
-    #[allow(dead_code)]
     SetOptions(TcpOptions),
     Bytes {
         request: ByteStreamRequest,
@@ -49,7 +62,9 @@ pub enum TcpRequest {
         request: CobsStreamRequest,
         options: Option<TcpOptions>,
     },
+    GetOptions,
     DropCheck,
+    FlushCheck,
 }
 
 impl TcpRequest {
@@ -66,9 +81,48 @@ impl TcpRequest {
 pub enum TcpResponse {
     Bytes(ByteStreamResponse),
     Cobs(CobsStreamResponse),
+    Options(TcpOptions),
     Nope,
 }
 
+/// Termination detected for line requests with a `term` of 0, see [`TcpOptions::detect_term`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LineTerm {
+    Lf,
+    CrLf,
+}
+
+impl LineTerm {
+    /// Rewrite a line request with a `term` of 0 to use this termination.
+    fn apply(self, req: &mut ByteStreamRequest) {
+        match req {
+            ByteStreamRequest::WriteLine { line, term } | ByteStreamRequest::QueryLine { line, term, .. }
+                if *term == 0 =>
+            {
+                if self == LineTerm::CrLf {
+                    line.push('\r');
+                }
+                *term = b'\n';
+            }
+            ByteStreamRequest::ReadLine { term, .. } if *term == 0 => *term = b'\n',
+            _ => {}
+        }
+    }
+
+    /// Remove the carriage return preceding the line feed from a line read.
+    fn strip(self, response: ByteStreamResponse) -> ByteStreamResponse {
+        match response {
+            ByteStreamResponse::String(mut x) if self == LineTerm::CrLf => {
+                if x.ends_with('\r') {
+                    x.pop();
+                }
+                ByteStreamResponse::String(x)
+            }
+            x => x,
+        }
+    }
+}
+
 impl Handler {
     fn set_options(&mut self, opts: &TcpOptions) {
         if let Some(drop_delay) = &opts.auto_drop {
@@ -77,6 +131,97 @@ impl Handler {
         if let Some(connection_timeout) = &opts.connection_timeout {
             self.connection_timeout = connection_timeout.clone().into();
         }
+        if let Some(nodelay) = opts.nodelay {
+            self.nodelay = nodelay;
+        }
+        if let Some(mode) = opts.mode {
+            self.mode = mode;
+        }
+        if let Some(term) = opts.default_term {
+            self.default_term = Some(term);
+        }
+        if let Some(detect_term) = opts.detect_term {
+            if detect_term != self.detect_term {
+                self.line_term = None;
+            }
+            self.detect_term = detect_term;
+        }
+        if let Some(keepalive) = &opts.keepalive {
+            self.keepalive = Some(keepalive.clone().into());
+        }
+        if let Some(write_buffering) = &opts.write_buffering {
+            self.write_buffer.configure(write_buffering);
+        }
+        if let Some(min_interval) = &opts.min_interval {
+            self.rate_limit.set_min_interval(min_interval.clone().into());
+        }
+        if let Some(stream) = &self.stream {
+            let _ = configure_stream(stream, self.nodelay, self.keepalive);
+        }
+    }
+
+    /// The options currently in effect
+    fn options(&self) -> TcpOptions {
+        TcpOptions {
+            auto_drop: Some(self.drop_delay.into()),
+            connection_timeout: Some(self.connection_timeout.into()),
+            nodelay: Some(self.nodelay),
+            keepalive: self.keepalive.map(Into::into),
+            mode: Some(self.mode),
+            default_term: self.default_term,
+            write_buffering: Some(self.write_buffer.config()),
+            min_interval: Some(self.rate_limit.min_interval().into()),
+            detect_term: Some(self.detect_term),
+        }
+    }
+
+    /// Schedule flushing the write buffer once its deadline passed.
+    fn schedule_flush(&mut self, ctx: &IoContext<Self>) {
+        if let Some(x) = self.flush_task.take() {
+            x.abort();
+        }
+        if let Some(deadline) = self.write_buffer.deadline() {
+            let mut ctx = ctx.clone();
+            self.flush_task = Some(task::spawn(async move {
+                sleep_until(deadline).await;
+                ctx.send(TcpRequest::FlushCheck);
+            }));
+        }
+    }
+
+    /// Write the buffered data to the connection. The connection is dropped if this fails, while the data remains
+    /// buffered for the next request.
+    async fn flush_write_buffer(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(err) = self.write_buffer.flush(stream).await {
+                log::warn!("Flushing write buffer of {} failed: {}", self.addr, err);
+                self.stream.take();
+            }
+        }
+    }
+
+    /// Schedule a check whether the connection should be dropped `drop_delay` after the last request.
+    fn schedule_drop_check(&mut self, ctx: &IoContext<Self>) {
+        if let Some(x) = self.drop_delay_task.take() {
+            x.abort();
+        }
+        let mut ctx = ctx.clone();
+        let deadline = self.last_request + self.drop_delay + Duration::from_millis(100);
+        self.drop_delay_task = Some(task::spawn(async move {
+            sleep_until(deadline).await;
+            ctx.send(TcpRequest::DropCheck);
+        }));
+    }
+
+    /// Close the connection. The buffered data is written first if `flush` is set, otherwise it is discarded.
+    async fn close(&mut self, flush: bool) -> crate::Result<()> {
+        let ret = match self.stream.as_mut() {
+            Some(stream) if flush => self.write_buffer.flush(stream).await,
+            _ => Ok(()),
+        };
+        self.write_buffer.clear();
+        self.close_stream();
+        ret
     }
 
     fn close_stream(&mut self) {
@@ -86,7 +231,7 @@ impl Handler {
         }
     }
 
-    fn check_close(&mut self, req: &TcpRequest) -> Option<crate::Result<TcpResponse>> {
+    async fn check_close(&mut self, req: &TcpRequest) -> Option<crate::Result<TcpResponse>> {
         if matches!(
             req,
             TcpRequest::Bytes {
@@ -111,6 +256,8 @@ impl Handler {
         if let TcpRequest::DropCheck = &req {
             let now = Instant::now();
             if now - self.last_request > self.drop_delay {
+                // writes were already acknowledged, hence the buffered data is written before closing
+                self.flush_write_buffer().await;
                 self.stream.take();
             }
             return Some(Ok(TcpResponse::Nope));
@@ -132,23 +279,36 @@ impl Handler {
     }
 
     async fn handle_cobs_request(&mut self, req: CobsStreamRequest) -> crate::Result<TcpResponse> {
-        if let CobsStreamRequest::Start { use_crc } = req {
-            self.cobs_stream_use_crc = use_crc;
+        if let CobsStreamRequest::Status = req {
+            let alive = self.cobs_stream.as_ref().is_some_and(|x| x.is_alive());
+            return Ok(TcpResponse::Cobs(CobsStreamResponse::Status {
+                alive,
+                use_crc: self.cobs_stream_crc != CrcKind::None,
+                crc: Some(self.cobs_stream_crc),
+            }));
+        }
+        if let CobsStreamRequest::Flush = req {
+            if let Some(cobs_stream) = self.cobs_stream.as_ref().filter(|x| x.is_alive()) {
+                cobs_stream.flush().await?;
+            }
+            return Ok(TcpResponse::Cobs(CobsStreamResponse::Done));
+        }
+        if let CobsStreamRequest::Start { use_crc, crc } = req {
+            self.cobs_stream_crc = crc.unwrap_or_else(|| use_crc.into());
         }
 
         let cobs_stream = match self.cobs_stream.take() {
-            Some(cobs_stream) if cobs_stream.is_alive() && cobs_stream.use_crc() == self.cobs_stream_use_crc => {
-                cobs_stream
-            }
+            Some(cobs_stream) if cobs_stream.is_alive() && cobs_stream.crc() == self.cobs_stream_crc => cobs_stream,
             _ => {
-                let stream = connect_tcp_stream(self.addr, self.connection_timeout).await?;
+                let stream =
+                    connect_tcp_stream(self.addr, self.connection_timeout, self.nodelay, self.keepalive).await?;
                 let (read, write) = tokio::io::split(stream);
                 CobsStream::start(
                     read,
                     write,
                     self.server.clone(),
                     self.create_byte_stream_instrument(),
-                    self.cobs_stream_use_crc,
+                    self.cobs_stream_crc,
                 )
             }
         };
@@ -166,9 +326,53 @@ impl Handler {
 
     async fn handle_bytestream_request(
         &mut self,
-        req: ByteStreamRequest,
+        mut req: ByteStreamRequest,
         ctx: &mut IoContext<Self>,
     ) -> crate::Result<TcpResponse> {
+        if self.detect_term && self.default_term.is_none() {
+            return self.handle_with_detected_term(req, ctx).await.map(TcpResponse::Bytes);
+        }
+        bytestream::apply_default_term(&mut req, self.default_term);
+        self.transfer(req, ctx).await.map(TcpResponse::Bytes)
+    }
+
+    /// Handle `req` with the detected line termination. If it is not known yet, it is detected with `req` if that
+    /// is a `QueryLine` with a `term` of 0. Other line requests use LF until the termination is detected.
+    async fn handle_with_detected_term(
+        &mut self,
+        mut req: ByteStreamRequest,
+        ctx: &mut IoContext<Self>,
+    ) -> crate::Result<ByteStreamResponse> {
+        if let Some(line_term) = self.line_term {
+            line_term.apply(&mut req);
+            return self.transfer(req, ctx).await.map(|x| line_term.strip(x));
+        }
+        if !matches!(req, ByteStreamRequest::QueryLine { term: 0, .. }) {
+            LineTerm::Lf.apply(&mut req);
+            return self.transfer(req, ctx).await;
+        }
+        let mut query = req.clone();
+        LineTerm::Lf.apply(&mut query);
+        let (line_term, ret) = match self.transfer(query, ctx).await {
+            Ok(ByteStreamResponse::String(x)) if x.ends_with('\r') => (LineTerm::CrLf, ByteStreamResponse::String(x)),
+            Ok(x) => (LineTerm::Lf, x),
+            Err(Error::Protocol(ProtocolError::Timeout)) => {
+                LineTerm::CrLf.apply(&mut req);
+                (LineTerm::CrLf, self.transfer(req, ctx).await?)
+            }
+            Err(x) => return Err(x),
+        };
+        log::debug!("Detected line termination {:?} of {}", line_term, self.addr);
+        self.line_term = Some(line_term);
+        Ok(line_term.strip(ret))
+    }
+
+    /// Run `req` on the connection, which is opened if required.
+    async fn transfer(
+        &mut self,
+        req: ByteStreamRequest,
+        ctx: &mut IoContext<Self>,
+    ) -> crate::Result<ByteStreamResponse> {
         self.last_request = Instant::now();
         let mut tries = 0;
         let err = loop {
@@ -177,7 +381,7 @@ impl Handler {
                 stream
             } else {
                 let addr = self.addr;
-                match connect_tcp_stream(addr, self.connection_timeout).await {
+                match connect_tcp_stream(addr, self.connection_timeout, self.nodelay, self.keepalive).await {
                     Ok(stream) => stream,
                     Err(x) => {
                         if !x.should_retry() || tries > 3 {
@@ -188,18 +392,24 @@ impl Handler {
                 }
             };
 
-            let ret = crate::protocol::bytestream::handle(&mut stream, req.clone())
-                .await
-                .map(TcpResponse::Bytes);
+            let sender = self.create_byte_stream_instrument();
+            let mut ret = self
+                .write_buffer
+                .handle_broadcast(&mut stream, req.clone(), &self.server, sender)
+                .await;
+            if self.mode == InstrumentMode::Transactional && ret.is_ok() {
+                // the connection is closed after this request
+                if let Err(err) = self.write_buffer.flush(&mut stream).await {
+                    ret = Err(err);
+                }
+            }
             match ret {
                 Ok(ret) => {
-                    self.stream.replace(stream);
-                    let mut ctx = ctx.clone();
-                    let drop_delay = self.drop_delay;
-                    self.drop_delay_task = Some(task::spawn(async move {
-                        sleep(drop_delay + Duration::from_millis(100)).await;
-                        ctx.send(TcpRequest::DropCheck);
-                    }));
+                    if self.mode == InstrumentMode::Persistent {
+                        self.stream.replace(stream);
+                        self.schedule_drop_check(ctx);
+                        self.schedule_flush(ctx);
+                    }
                     return Ok(ret);
                 }
                 Err(x) => {
@@ -213,10 +423,26 @@ impl Handler {
     }
 }
 
-async fn connect_tcp_stream(addr: SocketAddr, connection_timeout: Duration) -> crate::Result<TcpStream> {
+/// Check whether `addr` accepts connections. The connection is closed immediately.
+pub async fn probe(addr: &TcpAddress) -> crate::Result<()> {
+    TcpStream::connect((addr.host.as_str(), addr.port))
+        .await
+        .map_err(Error::transport)?;
+    Ok(())
+}
+
+async fn connect_tcp_stream(
+    addr: SocketAddr,
+    connection_timeout: Duration,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> crate::Result<TcpStream> {
     let fut = async move { TcpStream::connect(&addr).await.map_err(Error::transport) };
     match timeout(connection_timeout, fut).await {
-        Ok(Ok(x)) => Ok(x),
+        Ok(Ok(x)) => {
+            configure_stream(&x, nodelay, keepalive).map_err(Error::transport)?;
+            Ok(x)
+        }
         Ok(Err(x)) => Err(x),
         Err(_) => Err(crate::Error::transport(io::Error::new(
             io::ErrorKind::TimedOut,
@@ -225,18 +451,63 @@ async fn connect_tcp_stream(addr: SocketAddr, connection_timeout: Duration) -> c
     }
 }
 
+/// Apply socket options to `stream`. If `keepalive` is `None`, the system default is kept.
+fn configure_stream(stream: &TcpStream, nodelay: bool, keepalive: Option<Duration>) -> io::Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(time) = keepalive {
+        let socket = SockRef::from(stream);
+        if time.is_zero() {
+            socket.set_keepalive(false)?;
+        } else {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+    }
+    Ok(())
+}
+
 #[async_trait]
 impl IoHandler for Handler {
     type Request = TcpRequest;
     type Response = TcpResponse;
 
     async fn handle(&mut self, ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        if let TcpRequest::FlushCheck = req {
+            self.flush_write_buffer().await;
+            return Ok(TcpResponse::Nope);
+        }
+        if let TcpRequest::Bytes {
+            request: ByteStreamRequest::Disconnect,
+            ..
+        } = req
+        {
+            if let Err(err) = self.close(true).await {
+                log::warn!("Flushing write buffer of {} failed: {}", self.addr, err);
+            }
+        }
+        if let TcpRequest::Bytes {
+            request: ByteStreamRequest::Close { flush },
+            ..
+        } = req
+        {
+            self.close(flush).await?;
+            return Ok(TcpResponse::Bytes(ByteStreamResponse::Done));
+        }
+        if let TcpRequest::GetOptions = req {
+            return Ok(TcpResponse::Options(self.options()));
+        }
         if let Some(opts) = req.options() {
             self.set_options(opts);
         }
-        if let Some(ret) = self.check_close(&req) {
+        if let TcpRequest::SetOptions(_) = req {
+            if self.stream.is_some() {
+                self.schedule_drop_check(ctx);
+            }
+            return Ok(TcpResponse::Nope);
+        }
+        if let Some(ret) = self.check_close(&req).await {
             return ret;
         }
+        self.rate_limit.wait().await;
         if let TcpRequest::Cobs {
             request: cobs_request, ..
         } = req
@@ -251,20 +522,35 @@ impl IoHandler for Handler {
             _ => Err(crate::Error::internal(anyhow!("Unreachable code."))),
         }
     }
+
+    async fn disconnect(&mut self) {
+        if let Err(err) = self.close(true).await {
+            log::warn!("Flushing write buffer of {} failed: {}", self.addr, err);
+        }
+    }
 }
 
 impl Instrument {
-    pub fn new(addr: SocketAddr, server: Server) -> Self {
+    pub fn new(addr: SocketAddr, server: Server, defaults: &inventory::InstrumentDefaults) -> Self {
         let handler = Handler {
             stream: None,
             addr,
             last_request: Instant::now(),
-            drop_delay: DEFAULT_DROP_DELAY,
+            rate_limit: RateLimit::default(),
+            drop_delay: defaults.drop_delay.unwrap_or(DEFAULT_DROP_DELAY),
             connection_timeout: DEFAULT_CONNECTION_TIMEOUT,
+            nodelay: DEFAULT_NODELAY,
+            keepalive: None,
+            mode: InstrumentMode::Persistent,
+            default_term: None,
+            detect_term: false,
+            line_term: None,
             drop_delay_task: None,
+            write_buffer: WriteBuffer::default(),
+            flush_task: None,
             cobs_stream: None,
             server,
-            cobs_stream_use_crc: false,
+            cobs_stream_crc: CrcKind::None,
         };
         Self {
             inner: IoTask::new(handler),
@@ -280,11 +566,15 @@ impl Instrument {
 impl inventory::Instrument for Instrument {
     type Address = TcpAddress;
 
-    fn connect(server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
+    fn connect(
+        server: &crate::app::Server,
+        addr: &Self::Address,
+        defaults: &inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
         let format_addr = (&addr.host as &str, addr.port).to_socket_addrs();
         let mut iter = format_addr.map_err(crate::Error::argument)?;
         if let Some(x) = iter.next() {
-            Ok(Instrument::new(x, server.clone()))
+            Ok(Instrument::new(x, server.clone(), defaults))
         } else {
             Err(crate::Error::argument(anyhow!("Invalid tcp socket address: {:?}", addr)))
         }
@@ -293,4 +583,185 @@ impl inventory::Instrument for Instrument {
     async fn wait_for_closed(&self) {
         self.inner.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_with_nodelay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for nodelay in [true, false] {
+            let stream = connect_tcp_stream(addr, DEFAULT_CONNECTION_TIMEOUT, nodelay, None)
+                .await
+                .unwrap();
+            assert_eq!(stream.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_with_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = connect_tcp_stream(addr, DEFAULT_CONNECTION_TIMEOUT, true, Some(Duration::from_secs(30)))
+            .await
+            .unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        configure_stream(&stream, true, Some(Duration::ZERO)).unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (server, _rx) = Server::new();
+        let mut instr = Instrument::new(listener.local_addr().unwrap(), server, &Default::default());
+        let min_interval = Duration::from_millis(200);
+        let options = TcpOptions {
+            auto_drop: None,
+            connection_timeout: None,
+            nodelay: None,
+            keepalive: None,
+            mode: None,
+            default_term: None,
+            write_buffering: None,
+            min_interval: Some(min_interval.into()),
+            detect_term: None,
+        };
+        instr.request(TcpRequest::SetOptions(options)).await.unwrap();
+
+        let start = Instant::now();
+        for _ in 0..2 {
+            let req = TcpRequest::Bytes {
+                request: ByteStreamRequest::Write(b"poll".to_vec()),
+                options: None,
+            };
+            instr.request(req).await.unwrap();
+        }
+        assert!(start.elapsed() >= min_interval);
+    }
+
+    #[tokio::test]
+    async fn flush_buffered_writes_on_drop() {
+        use comsrv_protocol::WriteBuffering;
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (server, _rx) = Server::new();
+        // dropped by the inventory and by the drop check
+        for drop_delay in [None, Some(Duration::from_millis(50))] {
+            let mut instr = Instrument::new(listener.local_addr().unwrap(), server.clone(), &Default::default());
+            let options = TcpOptions {
+                auto_drop: drop_delay.map(Into::into),
+                connection_timeout: None,
+                nodelay: None,
+                keepalive: None,
+                mode: None,
+                default_term: None,
+                write_buffering: Some(WriteBuffering {
+                    max_size: 1024,
+                    max_delay: Duration::from_secs(10).into(),
+                }),
+                min_interval: None,
+                detect_term: None,
+            };
+            let req = TcpRequest::Bytes {
+                request: ByteStreamRequest::Write(b"hello".to_vec()),
+                options: Some(options),
+            };
+            instr.request(req).await.unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            if drop_delay.is_none() {
+                inventory::Instrument::disconnect(&mut instr);
+            }
+            let mut buf = Vec::new();
+            timeout(Duration::from_secs(2), socket.read_to_end(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_line_term() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // replies only to lines terminated with CRLF and records all lines received
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = tokio::io::split(stream);
+                    let mut lines = BufReader::new(read).split(b'\n');
+                    while let Ok(Some(line)) = lines.next_segment().await {
+                        if line.ends_with(b"\r") {
+                            write.write_all(b"ok\r\n").await.unwrap();
+                        }
+                        tx.send(line).unwrap();
+                    }
+                });
+            }
+        });
+
+        let (server, _rx) = Server::new();
+        let mut instr = Instrument::new(addr, server, &Default::default());
+        let options = TcpOptions {
+            auto_drop: None,
+            connection_timeout: None,
+            nodelay: None,
+            keepalive: None,
+            mode: None,
+            default_term: None,
+            write_buffering: None,
+            min_interval: None,
+            detect_term: Some(true),
+        };
+        instr.request(TcpRequest::SetOptions(options)).await.unwrap();
+        let query = || TcpRequest::Bytes {
+            request: ByteStreamRequest::QueryLine {
+                line: "*IDN?".to_string(),
+                timeout: Duration::from_millis(100).into(),
+                term: 0,
+                flush_input: true,
+            },
+            options: None,
+        };
+        let write = || TcpRequest::Bytes {
+            request: ByteStreamRequest::WriteLine {
+                line: "*RST".to_string(),
+                term: 0,
+            },
+            options: None,
+        };
+        let ret = instr.request(write()).await.unwrap();
+        assert!(matches!(ret, TcpResponse::Bytes(ByteStreamResponse::Done)));
+        for _ in 0..2 {
+            let ret = instr.request(query()).await.unwrap();
+            assert!(matches!(ret, TcpResponse::Bytes(ByteStreamResponse::String(x)) if x == "ok"));
+        }
+        let ret = instr.request(write()).await.unwrap();
+        assert!(matches!(ret, TcpResponse::Bytes(ByteStreamResponse::Done)));
+        // lines preceding the detection and the first query are sent with LF
+        assert_eq!(received.recv().await.unwrap(), b"*RST");
+        assert_eq!(received.recv().await.unwrap(), b"*IDN?");
+        assert_eq!(received.recv().await.unwrap(), b"*IDN?\r");
+        assert_eq!(received.recv().await.unwrap(), b"*IDN?\r");
+        assert_eq!(received.recv().await.unwrap(), b"*RST\r");
+        assert!(received.try_recv().is_err());
+    }
 }
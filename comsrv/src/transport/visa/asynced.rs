@@ -5,14 +5,18 @@ use std::time::Duration;
 use async_trait::async_trait;
 use tokio::sync::oneshot;
 
+use crate::app::Server;
+use crate::iotask::RequestCounter;
 use crate::Error;
 use crate::{inventory, transport::visa::blocking::Instrument as BlockingInstrument};
 use anyhow::anyhow;
-use comsrv_protocol::{ScpiRequest, ScpiResponse};
+use comsrv_protocol::{Address, Response, ScpiRequest, ScpiResponse, VisaOptions};
 
 #[derive(Clone)]
 pub struct Instrument {
     tx: Arc<Mutex<mpsc::Sender<Msg>>>,
+    options: Arc<Mutex<VisaOptions>>,
+    requests: RequestCounter,
 }
 
 enum Msg {
@@ -21,13 +25,19 @@ enum Msg {
         reply: oneshot::Sender<crate::Result<ScpiResponse>>,
         timeout: Option<Duration>,
     },
+    Probe {
+        reply: oneshot::Sender<crate::Result<()>>,
+    },
     Drop,
 }
 
 impl Instrument {
-    pub fn new<T: Into<String>>(addr: T) -> Self {
+    pub fn new<T: Into<String>>(server: &Server, addr: T) -> Self {
         let (tx, rx) = mpsc::channel();
         let addr = addr.into();
+        let server = server.clone();
+        let options = Arc::new(Mutex::new(VisaOptions::default()));
+        let thread_options = options.clone();
         thread::spawn(move || {
             let mut oinstr = None;
             while let Ok(msg) = rx.recv() {
@@ -37,7 +47,22 @@ impl Instrument {
                         reply,
                         timeout,
                     } => {
-                        Self::run_request(&mut oinstr, &addr, request, reply, timeout);
+                        let options = thread_options.lock().unwrap().clone();
+                        Self::run_request(&mut oinstr, &server, &addr, request, reply, timeout, options);
+                    }
+                    Msg::Probe { reply } => {
+                        let ret = if oinstr.is_some() {
+                            Ok(())
+                        } else {
+                            match BlockingInstrument::open(&addr) {
+                                Ok(instr) => {
+                                    oinstr = Some(instr);
+                                    Ok(())
+                                }
+                                Err(err) => Err(err.at(&addr)),
+                            }
+                        };
+                        let _ = reply.send(ret);
                     }
                     Msg::Drop => {
                         break;
@@ -48,15 +73,19 @@ impl Instrument {
 
         Instrument {
             tx: Arc::new(Mutex::new(tx)),
+            options,
+            requests: RequestCounter::default(),
         }
     }
 
     fn run_request(
         oinstr: &mut Option<BlockingInstrument>,
+        server: &Server,
         addr: &str,
         request: ScpiRequest,
         reply: oneshot::Sender<crate::Result<ScpiResponse>>,
         timeout: Option<Duration>,
+        options: VisaOptions,
     ) {
         let instr = if let Some(instr) = oinstr.take() {
             Ok(instr)
@@ -64,17 +93,26 @@ impl Instrument {
             BlockingInstrument::open(&addr)
         };
         match instr {
-            Ok(instr) => {
-                let _ = reply.send(instr.handle_scpi(request, timeout));
+            Ok(mut instr) => {
+                instr.set_options(options);
+                let mut progress = |bytes, total| {
+                    server.broadcast(Response::TransferProgress {
+                        source: Address::Visa(addr.to_string()),
+                        bytes,
+                        total,
+                    })
+                };
+                let _ = reply.send(instr.handle_scpi(request, timeout, &mut progress));
                 oinstr.replace(instr);
             }
             Err(err) => {
-                let _ = reply.send(Err(err.into()));
+                let _ = reply.send(Err(err.at(addr)));
             }
         }
     }
 
     pub async fn request(self, req: ScpiRequest, timeout: Option<Duration>) -> crate::Result<ScpiResponse> {
+        let _request = self.requests.start();
         let (tx, rx) = oneshot::channel();
         let thmsg = Msg::Scpi {
             request: req,
@@ -89,6 +127,27 @@ impl Instrument {
         rx.await.map_err(|_| Error::internal(anyhow!("Disconnected")))?
     }
 
+    /// Check that the instrument is reachable using the session of this instrument, which is opened if required.
+    pub async fn probe(&self) -> crate::Result<()> {
+        let _request = self.requests.start();
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .lock()
+            .unwrap()
+            .send(Msg::Probe { reply: tx })
+            .map_err(|_| Error::internal(anyhow!("Disconnected")))?;
+        rx.await.map_err(|_| Error::internal(anyhow!("Disconnected")))?
+    }
+
+    /// Options applied to all subsequent requests
+    pub fn options(&self) -> VisaOptions {
+        self.options.lock().unwrap().clone()
+    }
+
+    pub fn set_options(&self, options: VisaOptions) {
+        *self.options.lock().unwrap() = options;
+    }
+
     pub fn disconnect(self) {
         let _ = self.tx.lock().unwrap().send(Msg::Drop);
     }
@@ -98,9 +157,21 @@ impl Instrument {
 impl inventory::Instrument for Instrument {
     type Address = String;
 
-    fn connect(_server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
-        Ok(Instrument::new(addr))
+    fn connect(
+        server: &Server,
+        addr: &Self::Address,
+        _defaults: &inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
+        Ok(Instrument::new(server, addr))
     }
 
     async fn wait_for_closed(&self) {}
+
+    fn disconnect(&mut self) {
+        Instrument::disconnect(self.clone())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.requests.is_busy()
+    }
 }
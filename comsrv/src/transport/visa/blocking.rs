@@ -1,14 +1,12 @@
 use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
-
 use super::visa_sys::Instrument as VisaInstrument;
 #[allow(unused_imports)]
 pub use super::visa_sys::{VisaError, VisaResult};
 use anyhow::anyhow;
 
 use crate::{protocol::scpi, Error};
-use comsrv_protocol::{ScpiRequest, ScpiResponse};
+use comsrv_protocol::{visa_attributes, ScpiRequest, ScpiResponse, VisaOptions};
 
 use super::consts;
 
@@ -17,20 +15,34 @@ const DEFAULT_CHUNK_SIZE: usize = 20 * 1024;
 // from pyvisa
 const DEFAULT_TERMINATION: &str = "\n";
 
-#[derive(Default, Clone, Serialize, Deserialize)]
-pub struct VisaOptions {}
+/// Default limit for the size of a response
+const DEFAULT_MAX_READ_LENGTH: usize = 128 * 1024 * 1024;
+/// Default limit for the number of chunks read for a response, allows for `DEFAULT_MAX_READ_LENGTH` in full chunks
+const DEFAULT_MAX_READ_CHUNKS: usize = 8192;
 
 pub struct Instrument {
     instr: VisaInstrument,
+    options: VisaOptions,
 }
 
 impl Instrument {
     pub fn open(addr: &str) -> VisaResult<Self> {
         Ok(Self {
             instr: VisaInstrument::open(addr.to_string(), Some(DEFAULT_TIMEOUT))?,
+            options: VisaOptions::default(),
         })
     }
 
+    pub fn set_options(&mut self, options: VisaOptions) {
+        self.options = options;
+    }
+
+    fn max_binary_length(&self) -> usize {
+        self.options
+            .max_binary_length
+            .map_or(scpi::DEFAULT_MAX_BINARY_LENGTH, |x| x as usize)
+    }
+
     pub fn write<T: AsRef<str>>(&self, msg: T) -> VisaResult<()> {
         let mut msg = msg.as_ref().to_string();
         if !msg.ends_with(DEFAULT_TERMINATION) {
@@ -39,22 +51,32 @@ impl Instrument {
         self.instr.write(msg.as_bytes())
     }
 
-    fn read(&self) -> VisaResult<Vec<u8>> {
-        let mut ret = Vec::new();
-        loop {
-            let (data, status) = self.instr.read(DEFAULT_CHUNK_SIZE)?;
-            ret.extend(data);
-            if status != (consts::VI_SUCCESS_MAX_CNT as i32) {
-                break;
-            }
-        }
-        Ok(ret)
+    fn read(&self) -> crate::Result<Vec<u8>> {
+        self.read_limited(usize::MAX, &mut |_, _| {})
+    }
+
+    /// Read a response of at most `max_length` bytes and report the progress of binary transfers to `progress`.
+    /// The read is further bounded by the options of the instrument.
+    fn read_limited(&self, max_length: usize, progress: &mut dyn FnMut(u64, Option<u64>)) -> crate::Result<Vec<u8>> {
+        let read_chunk = || {
+            let (data, status) = self.instr.read(DEFAULT_CHUNK_SIZE).map_err(|x| x.at(self.instr.addr()))?;
+            Ok((data, status == (consts::VI_SUCCESS_MAX_CNT as i32)))
+        };
+        let limits = ReadLimits {
+            max_length: self
+                .options
+                .max_read_length
+                .map_or(DEFAULT_MAX_READ_LENGTH, |x| x as usize)
+                .min(max_length),
+            max_chunks: self.options.max_read_chunks.map_or(DEFAULT_MAX_READ_CHUNKS, |x| x as usize),
+        };
+        read_chunks(read_chunk, &limits, progress)
     }
 
     pub fn query_string<T: AsRef<str>>(&self, msg: T) -> crate::Result<String> {
         log::debug!("Query[{}]: `{}`", self.instr.addr(), msg.as_ref());
-        self.write(msg).map_err(|x| crate::Error::transport(anyhow!(x)))?;
-        let rx = self.read().map_err(|x| crate::Error::transport(anyhow!(x)))?;
+        self.write(msg).map_err(|x| x.at(self.instr.addr()))?;
+        let rx = self.read()?;
         let ret =
             String::from_utf8(rx).map_err(|x| crate::Error::protocol(anyhow!("Invalid UTF-8 received. {}", x)))?;
         log::debug!("Reply[{}]: `{}`", self.instr.addr(), ret);
@@ -72,11 +94,17 @@ impl Instrument {
         todo!()
     }
 
-    pub fn query_binary<T: AsRef<str>>(&self, msg: T) -> crate::Result<Vec<u8>> {
+    pub fn query_binary<T: AsRef<str>>(
+        &self,
+        msg: T,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> crate::Result<Vec<u8>> {
         log::debug!("QueryBinary[{}]: `{}`", self.instr.addr(), msg.as_ref());
-        self.write(msg).map_err(|x| crate::Error::transport(anyhow!(x)))?;
-        let rx = self.read().map_err(|x| crate::Error::transport(anyhow!(x)))?;
-        let (offset, length) = scpi::parse_binary_header(&rx)?;
+        self.write(msg).map_err(|x| x.at(self.instr.addr()))?;
+        // allow for the header and the termination
+        let max_length = self.max_binary_length() + scpi::MAX_BINARY_HEADER_LENGTH + DEFAULT_TERMINATION.len();
+        let rx = self.read_limited(max_length, progress)?;
+        let (offset, length) = scpi::parse_binary_header(&rx, self.max_binary_length())?;
         Ok(rx[offset..offset + length].to_vec())
     }
 
@@ -84,19 +112,246 @@ impl Instrument {
         self.instr.addr()
     }
 
-    pub fn handle_scpi(&self, req: ScpiRequest, _: Option<Duration>) -> crate::Result<ScpiResponse> {
+    /// Handle `req` and report the progress of binary transfers to `progress`.
+    pub fn handle_scpi(
+        &self,
+        req: ScpiRequest,
+        _: Option<Duration>,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> crate::Result<ScpiResponse> {
         // TODO: handle timeout
         match req {
-            ScpiRequest::Write(x) => self
-                .write(x)
-                .map_err(|x| crate::Error::transport(anyhow!(x)))
-                .map(|_| ScpiResponse::Done),
+            ScpiRequest::Write(x) => self.write(x).map_err(|x| x.at(self.instr.addr())).map(|_| ScpiResponse::Done),
             ScpiRequest::QueryString(x) => self.query_string(x).map(ScpiResponse::String),
-            ScpiRequest::QueryBinary(x) => self.query_binary(x).map(|data| ScpiResponse::Binary { data }),
+            ScpiRequest::QueryBinary(x) => self.query_binary(x, progress).map(|data| ScpiResponse::Binary { data }),
             ScpiRequest::ReadRaw => self
-                .read()
-                .map_err(|x| crate::Error::transport(anyhow!(x)))
+                .read_limited(usize::MAX, progress)
                 .map(|data| ScpiResponse::Binary { data }),
+            ScpiRequest::VisaLock { exclusive, timeout_ms } => lock(&self.instr, exclusive, timeout_ms),
+            ScpiRequest::VisaUnlock => unlock(&self.instr),
+            ScpiRequest::GetAttribute { code } => get_attribute(&self.instr, code).map(ScpiResponse::Attribute),
+            ScpiRequest::SetAttribute { code, value } => {
+                set_attribute(&self.instr, code, value).map(|_| ScpiResponse::Done)
+            }
+        }
+    }
+}
+
+/// Locking in the VISA driver, which is independent of the locks of the inventory.
+trait DriverLock {
+    fn acquire(&self, exclusive: bool, timeout_ms: u32) -> crate::Result<Option<String>>;
+    fn release(&self) -> crate::Result<()>;
+}
+
+impl DriverLock for VisaInstrument {
+    fn acquire(&self, exclusive: bool, timeout_ms: u32) -> crate::Result<Option<String>> {
+        self.lock(exclusive, timeout_ms).map_err(|x| x.at(self.addr()))
+    }
+
+    fn release(&self) -> crate::Result<()> {
+        self.unlock().map_err(|x| x.at(self.addr()))
+    }
+}
+
+/// Acquire a driver lock and reply with the access key for shared locks.
+fn lock<L: DriverLock>(session: &L, exclusive: bool, timeout_ms: u32) -> crate::Result<ScpiResponse> {
+    log::debug!("Acquiring {} VISA lock", if exclusive { "exclusive" } else { "shared" });
+    match session.acquire(exclusive, timeout_ms)? {
+        Some(key) => Ok(ScpiResponse::AccessKey(key)),
+        None => Ok(ScpiResponse::Done),
+    }
+}
+
+fn unlock<L: DriverLock>(session: &L) -> crate::Result<ScpiResponse> {
+    log::debug!("Releasing VISA lock");
+    session.release().map(|_| ScpiResponse::Done)
+}
+
+/// Access to the attributes of a VISA session
+trait Attributes {
+    fn get(&self, code: u32) -> crate::Result<u64>;
+    fn set(&self, code: u32, value: u64) -> crate::Result<()>;
+}
+
+impl Attributes for VisaInstrument {
+    fn get(&self, code: u32) -> crate::Result<u64> {
+        self.attribute(code).get().map_err(|x| x.at(self.addr()))
+    }
+
+    fn set(&self, code: u32, value: u64) -> crate::Result<()> {
+        self.attribute(code).set(value).map_err(|x| x.at(self.addr()))
+    }
+}
+
+/// Only the attributes in [`visa_attributes::ALL`] are accessible by clients, others may break the session.
+pub fn check_attribute(code: u32) -> crate::Result<()> {
+    if visa_attributes::ALL.contains(&code) {
+        Ok(())
+    } else {
+        Err(Error::argument(anyhow!(
+            "Access to VISA attribute 0x{:08X} is not allowed.",
+            code
+        )))
+    }
+}
+
+fn get_attribute<A: Attributes>(session: &A, code: u32) -> crate::Result<u64> {
+    check_attribute(code)?;
+    session.get(code)
+}
+
+fn set_attribute<A: Attributes>(session: &A, code: u32, value: u64) -> crate::Result<()> {
+    check_attribute(code)?;
+    log::debug!("Setting VISA attribute 0x{:08X} to {}", code, value);
+    session.set(code, value)
+}
+
+/// Bounds the size of a response and the number of chunks read for it.
+struct ReadLimits {
+    max_length: usize,
+    max_chunks: usize,
+}
+
+/// Read chunks with `read_chunk`, which returns the data and whether more data is pending, until the message is
+/// complete. Fails once the response exceeds `limits`. Once a binary block header was received, `progress` is
+/// invoked after each chunk.
+fn read_chunks<F>(
+    mut read_chunk: F,
+    limits: &ReadLimits,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> crate::Result<Vec<u8>>
+where
+    F: FnMut() -> crate::Result<(Vec<u8>, bool)>,
+{
+    let mut ret = Vec::new();
+    for _ in 0..limits.max_chunks {
+        let (data, pending) = read_chunk()?;
+        ret.extend(data);
+        if ret.len() > limits.max_length {
+            return Err(scpi::BinaryBlockError::LengthExceeded {
+                length: ret.len(),
+                max_length: limits.max_length,
+            }
+            .into());
+        }
+        if let Some((bytes, total)) = scpi::binary_progress(&ret) {
+            progress(bytes, total);
         }
+        if !pending {
+            return Ok(ret);
+        }
+    }
+    Err(crate::Error::protocol(anyhow!(
+        "Response incomplete after {} reads of {} bytes in total.",
+        limits.max_chunks,
+        ret.len()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_per_chunk() {
+        let mut chunks = vec![b"#210".to_vec(), b"0123".to_vec(), b"456789\n".to_vec()].into_iter();
+        let read_chunk = || {
+            let chunk = chunks.next().unwrap();
+            Ok((chunk, chunks.len() > 0))
+        };
+        let mut events = Vec::new();
+        let limits = ReadLimits {
+            max_length: 100,
+            max_chunks: 10,
+        };
+        let ret = read_chunks(read_chunk, &limits, &mut |bytes, total| events.push((bytes, total))).unwrap();
+        assert_eq!(ret, b"#2100123456789\n");
+        assert_eq!(events, vec![(0, Some(10)), (4, Some(10)), (10, Some(10))]);
+    }
+
+    #[test]
+    fn read_exceeds_limit() {
+        let limits = ReadLimits {
+            max_length: 20,
+            max_chunks: 10,
+        };
+        let read_chunk = || Ok((vec![0; 8], true));
+        let err = read_chunks(read_chunk, &limits, &mut |_, _| {}).unwrap_err();
+        assert!(matches!(err, crate::Error::Protocol(_)));
+    }
+
+    #[test]
+    fn read_exceeds_chunks() {
+        // an instrument which keeps reporting VI_SUCCESS_MAX_CNT without sending data
+        let mut reads = 0;
+        let read_chunk = || {
+            reads += 1;
+            Ok((vec![], true))
+        };
+        let limits = ReadLimits {
+            max_length: 20,
+            max_chunks: 10,
+        };
+        let err = read_chunks(read_chunk, &limits, &mut |_, _| {}).unwrap_err();
+        assert!(format!("{}", err).contains("after 10 reads"));
+        assert_eq!(reads, 10);
+    }
+
+    #[derive(Default)]
+    struct MockSession {
+        calls: std::cell::RefCell<Vec<String>>,
+        attributes: std::cell::RefCell<std::collections::HashMap<u32, u64>>,
+    }
+
+    impl Attributes for MockSession {
+        fn get(&self, code: u32) -> crate::Result<u64> {
+            Ok(self.attributes.borrow().get(&code).copied().unwrap_or(2000))
+        }
+
+        fn set(&self, code: u32, value: u64) -> crate::Result<()> {
+            self.attributes.borrow_mut().insert(code, value);
+            Ok(())
+        }
+    }
+
+    impl DriverLock for MockSession {
+        fn acquire(&self, exclusive: bool, timeout_ms: u32) -> crate::Result<Option<String>> {
+            self.calls.borrow_mut().push(format!("viLock({}, {})", exclusive, timeout_ms));
+            Ok(if exclusive { None } else { Some("key".to_string()) })
+        }
+
+        fn release(&self) -> crate::Result<()> {
+            self.calls.borrow_mut().push("viUnlock".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let session = MockSession::default();
+        assert!(matches!(lock(&session, true, 100).unwrap(), ScpiResponse::Done));
+        assert!(matches!(unlock(&session).unwrap(), ScpiResponse::Done));
+        let key = lock(&session, false, 200).unwrap();
+        assert!(matches!(key, ScpiResponse::AccessKey(x) if x == "key"));
+        assert!(matches!(unlock(&session).unwrap(), ScpiResponse::Done));
+        assert_eq!(
+            *session.calls.borrow(),
+            vec!["viLock(true, 100)", "viUnlock", "viLock(false, 200)", "viUnlock"]
+        );
+    }
+
+    #[test]
+    fn timeout_attribute() {
+        let session = MockSession::default();
+        let code = visa_attributes::VI_ATTR_TMO_VALUE;
+        assert_eq!(get_attribute(&session, code).unwrap(), 2000);
+        set_attribute(&session, code, 5000).unwrap();
+        assert_eq!(get_attribute(&session, code).unwrap(), 5000);
+
+        // VI_ATTR_RM_SESSION
+        let err = get_attribute(&session, 0x3FFF00C4).unwrap_err();
+        assert!(matches!(err, crate::Error::Argument(_)));
+        assert!(set_attribute(&session, 0x3FFF00C4, 0).is_err());
+        assert_eq!(session.attributes.borrow().len(), 1);
     }
 }
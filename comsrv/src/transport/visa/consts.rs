@@ -108,3 +108,7 @@ pub const VI_ERROR_CONN_LOST: u32 = 0xBFFF00A6;
 // The following two are a non-standard NI extensions
 pub const VI_ERROR_MACHINE_NAVAIL: u32 = 0xBFFF00A7;
 pub const VI_ERROR_NPERMISSION: u32 = 0xBFFF00A8;
+
+// Lock types of viLock
+pub const VI_EXCLUSIVE_LOCK: u32 = 1;
+pub const VI_SHARED_LOCK: u32 = 2;
@@ -7,3 +7,12 @@ mod blocking;
 mod visa_sys;
 
 pub use asynced::Instrument;
+pub use blocking::check_attribute;
+
+/// Open and close a session to the instrument at `addr`.
+pub async fn probe(addr: String) -> crate::Result<()> {
+    tokio::task::spawn_blocking(move || blocking::Instrument::open(&addr).map(|_| ()))
+        .await
+        .map_err(crate::Error::internal)??;
+    Ok(())
+}
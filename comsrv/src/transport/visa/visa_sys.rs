@@ -8,11 +8,14 @@ use std::mem::MaybeUninit;
 use std::os::raw::c_char;
 
 use anyhow::anyhow;
+use comsrv_protocol::visa_attributes;
 use dlopen::wrapper::{Container, WrapperApi};
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
+use super::consts::{VI_EXCLUSIVE_LOCK, VI_SHARED_LOCK};
+
 #[derive(Error, Clone, Debug, Serialize, Deserialize)]
 pub struct VisaError {
     desc: String,
@@ -32,6 +35,22 @@ impl VisaError {
         let desc = describe_status(code);
         Self { desc, code }
     }
+
+    /// The VISA status code
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// The description of the status code as reported by VISA
+    pub fn description(&self) -> &str {
+        &self.desc
+    }
+
+    /// Convert into a [`crate::Error`] naming the resource `addr` on which the error occurred.
+    pub fn at(self, addr: &str) -> crate::Error {
+        log::error!("VISA error on `{}`: {}", addr, self);
+        crate::Error::transport(anyhow!("VISA error {} on `{}`: {}", self.code, addr, self.desc))
+    }
 }
 
 impl From<VisaError> for crate::Error {
@@ -72,6 +91,14 @@ struct Api {
     viRead: unsafe extern "C" fn(vi: ViSession, buf: *mut u8, cnt: u32, cnt_ret: *mut u32) -> ViStatus,
     viWrite: unsafe extern "C" fn(vi: ViSession, buf: *const u8, cnt: u32, cnt_ret: *mut u32) -> ViStatus,
     viClear: unsafe extern "C" fn(vi: ViSession) -> ViStatus,
+    viLock: unsafe extern "C" fn(
+        vi: ViSession,
+        lock_type: ViAccessMode,
+        timeout: u32,
+        requested_key: *const c_char,
+        access_key: *mut c_char,
+    ) -> ViStatus,
+    viUnlock: extern "C" fn(vi: ViSession) -> ViStatus,
 }
 
 pub struct Visa {
@@ -205,8 +232,46 @@ impl Instrument {
         Ok(())
     }
 
+    /// Lock the resource with `viLock`, waiting at most `timeout` milliseconds for the lock. Returns the access key
+    /// granted by VISA for shared locks.
+    pub fn lock(&self, exclusive: bool, timeout: u32) -> Result<Option<String>, VisaError> {
+        let lock_type = if exclusive { VI_EXCLUSIVE_LOCK } else { VI_SHARED_LOCK };
+        let mut key: MaybeUninit<[c_char; ACCESS_KEY_LENGTH]> = MaybeUninit::uninit();
+        unsafe {
+            let status = VISA.api.viLock(
+                self.instr,
+                lock_type,
+                timeout,
+                std::ptr::null(),
+                key.as_mut_ptr() as *mut c_char,
+            );
+            if status < 0 {
+                return Err(VisaError::new(status));
+            }
+            if exclusive {
+                return Ok(None);
+            }
+            let key = CStr::from_ptr(key.as_ptr() as *const c_char);
+            Ok(Some(key.to_string_lossy().to_string()))
+        }
+    }
+
+    /// Release a lock acquired with [`Instrument::lock()`].
+    pub fn unlock(&self) -> Result<(), VisaError> {
+        let status = VISA.api.viUnlock(self.instr);
+        if status < 0 {
+            Err(VisaError::new(status))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn timeout(&self) -> Attr {
-        Attr::new(self.instr, 0x3FFF001A)
+        self.attribute(visa_attributes::VI_ATTR_TMO_VALUE)
+    }
+
+    pub fn attribute(&self, code: u32) -> Attr {
+        Attr::new(self.instr, code)
     }
 
     pub fn addr(&self) -> &str {
@@ -224,6 +289,8 @@ impl Drop for Instrument {
 }
 
 const VI_TMO_INFINITE: u32 = 0xFFFFFFFF;
+/// Size of the buffer receiving the access key of a shared lock, `VI_FIND_BUFLEN` in `visa.h`
+const ACCESS_KEY_LENGTH: usize = 256;
 const VI_TMO_IMMEDIATE: u32 = 0;
 
 struct Timeout {
@@ -249,3 +316,23 @@ impl Timeout {
         Ok((ret as f32) * 1000.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_preserves_status() {
+        let err = VisaError {
+            desc: "Timeout expired before operation completed.".to_string(),
+            code: -1073807339,
+        };
+        assert_eq!(err.code(), -1073807339);
+        let err = err.at("USB0::0x0957::0x1796::MY1234::INSTR");
+        assert!(err.is_transport_error());
+        let msg = format!("{}", err);
+        assert!(msg.contains("-1073807339"));
+        assert!(msg.contains("Timeout expired before operation completed."));
+        assert!(msg.contains("USB0::0x0957::0x1796::MY1234::INSTR"));
+    }
+}
@@ -8,11 +8,17 @@ use tokio::time::{sleep, Duration};
 use crate::iotask::{IoContext, IoHandler, IoTask};
 use crate::{protocol::scpi, Error};
 use anyhow::anyhow;
-use comsrv_protocol::{ScpiRequest, ScpiResponse};
+use comsrv_protocol::{ScpiRequest, ScpiResponse, VxiOptions};
+use serde_json::json;
 
 const DEFAULT_TERMINATION: &str = "\n";
+/// Port of the portmapper, which each VXI-11 instrument runs
+const PORTMAPPER_PORT: u16 = 111;
 
 const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default limit for the length of string responses
+const DEFAULT_MAX_READ_LENGTH: usize = 1024 * 1024;
 const DEFAULT_DROP_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
@@ -26,23 +32,29 @@ enum Request {
         scpi: ScpiRequest,
         timeout: Option<Duration>,
     },
+    SetOptions(VxiOptions),
+    GetOptions,
     DropCheck,
 }
 
 enum Response {
     Scpi(ScpiResponse),
+    Options(serde_json::Value),
     Done,
 }
 
 impl Instrument {
-    pub fn new(addr: IpAddr) -> Self {
+    pub fn new(addr: IpAddr, defaults: &crate::inventory::InstrumentDefaults) -> Self {
         Self {
             inner: IoTask::new(Handler {
                 addr,
                 client: None,
-                drop_delay: DEFAULT_DROP_DELAY,
+                drop_delay: defaults.drop_delay.unwrap_or(DEFAULT_DROP_DELAY),
                 last_request: Instant::now(),
                 drop_delay_task: None,
+                timeout: DEFAULT_TIMEOUT,
+                max_read_length: DEFAULT_MAX_READ_LENGTH,
+                max_binary_length: scpi::DEFAULT_MAX_BINARY_LENGTH,
             }),
         }
     }
@@ -51,7 +63,20 @@ impl Instrument {
         let req = Request::Scpi { scpi: req, timeout };
         match self.inner.request(req).await? {
             Response::Scpi(x) => Ok(x),
-            Response::Done => Err(crate::Error::internal(anyhow!("Invalid response for request."))),
+            _ => Err(crate::Error::internal(anyhow!("Invalid response for request."))),
+        }
+    }
+
+    /// Apply `options` to this instrument. Options which are not set keep their current value.
+    pub async fn set_options(&mut self, options: VxiOptions) -> crate::Result<()> {
+        self.inner.request(Request::SetOptions(options)).await.map(|_| ())
+    }
+
+    /// Query the options in effect for this instrument.
+    pub async fn options(&mut self) -> crate::Result<serde_json::Value> {
+        match self.inner.request(Request::GetOptions).await? {
+            Response::Options(x) => Ok(x),
+            _ => Err(crate::Error::internal(anyhow!("Invalid response for request."))),
         }
     }
 }
@@ -60,11 +85,15 @@ impl Instrument {
 impl crate::inventory::Instrument for Instrument {
     type Address = String;
 
-    fn connect(_server: &crate::app::Server, addr: &Self::Address) -> crate::Result<Self> {
+    fn connect(
+        _server: &crate::app::Server,
+        addr: &Self::Address,
+        defaults: &crate::inventory::InstrumentDefaults,
+    ) -> crate::Result<Self> {
         let format_addr = format!("{}:443", addr).to_socket_addrs();
         let mut iter = format_addr.map_err(crate::Error::argument)?;
         if let Some(x) = iter.next() {
-            Ok(Instrument::new(x.ip()))
+            Ok(Instrument::new(x.ip(), defaults))
         } else {
             Err(crate::Error::argument(anyhow!("Invalid tcp socket address: {:?}", addr)))
         }
@@ -73,6 +102,14 @@ impl crate::inventory::Instrument for Instrument {
     async fn wait_for_closed(&self) {
         self.inner.wait_for_closed().await
     }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.inner.is_busy()
+    }
 }
 
 struct Handler {
@@ -81,6 +118,16 @@ struct Handler {
     drop_delay: Duration,
     last_request: Instant,
     drop_delay_task: Option<JoinHandle<()>>,
+    timeout: Duration,
+    max_read_length: usize,
+    max_binary_length: usize,
+}
+
+/// Limits for the length of responses
+#[derive(Clone, Copy)]
+struct ReadLimits {
+    max_read_length: usize,
+    max_binary_length: usize,
 }
 
 impl Handler {
@@ -111,14 +158,19 @@ impl Handler {
         client: &mut CoreClient,
         req: ScpiRequest,
         timeout: Duration,
+        limits: ReadLimits,
     ) -> crate::Result<ScpiResponse> {
-        let fut = Self::handle_request(client, req);
+        let fut = Self::handle_request(client, req, limits);
         tokio::time::timeout(timeout, fut)
             .await
             .map_err(|_| crate::Error::protocol_timeout())?
     }
 
-    async fn handle_request(client: &mut CoreClient, req: ScpiRequest) -> crate::Result<ScpiResponse> {
+    async fn handle_request(
+        client: &mut CoreClient,
+        req: ScpiRequest,
+        limits: ReadLimits,
+    ) -> crate::Result<ScpiResponse> {
         match req {
             ScpiRequest::Write(mut msg) => {
                 if !msg.ends_with(DEFAULT_TERMINATION) {
@@ -132,26 +184,22 @@ impl Handler {
             }
             ScpiRequest::QueryString(data) => {
                 client.device_write(data.as_bytes().to_vec()).await.map_err(map_error)?;
-                let data = client.device_read().await.map_err(map_error)?;
-                let ret =
-                    String::from_utf8(data).map_err(|_| crate::Error::protocol(anyhow!("Data not terminated.")))?;
-                if !ret.ends_with(DEFAULT_TERMINATION) {
-                    return Err(Error::protocol(anyhow!("Data not terminated.")));
-                }
-                let ret = ret[..ret.len() - DEFAULT_TERMINATION.len()].to_string();
+                let ret = read_string(client, limits.max_read_length).await?;
                 Ok(ScpiResponse::String(ret))
             }
             ScpiRequest::QueryBinary(data) => {
                 client.device_write(data.as_bytes().to_vec()).await.map_err(map_error)?;
-                let rx = client.device_read().await.map_err(map_error)?;
-                let (offset, length) = scpi::parse_binary_header(&rx)?;
-                let ret = rx[offset..offset + length].to_vec();
-                Ok(ScpiResponse::Binary { data: ret })
+                let data = read_binary(client, limits.max_binary_length).await?;
+                Ok(ScpiResponse::Binary { data })
             }
             ScpiRequest::ReadRaw => {
                 let data = client.device_read().await.map_err(map_error)?;
                 Ok(ScpiResponse::Binary { data })
             }
+            ScpiRequest::VisaLock { .. }
+            | ScpiRequest::VisaUnlock
+            | ScpiRequest::GetAttribute { .. }
+            | ScpiRequest::SetAttribute { .. } => unreachable!("rejected in `handle`"),
         }
     }
 
@@ -171,9 +219,38 @@ impl IoHandler for Handler {
     type Response = Response;
 
     async fn handle(&mut self, ctx: &mut IoContext<Self>, req: Self::Request) -> crate::Result<Self::Response> {
+        if let Request::GetOptions = req {
+            return Ok(Response::Options(json!({
+                "auto_drop": comsrv_protocol::Duration::from(self.drop_delay),
+                "timeout": comsrv_protocol::Duration::from(self.timeout),
+                "max_read_length": self.max_read_length,
+                "max_binary_length": self.max_binary_length,
+            })));
+        }
+        if let Request::SetOptions(options) = req {
+            if options.max_read_length == Some(0) {
+                return Err(Error::argument(anyhow!("Maximum read length must be larger than 0.")));
+            }
+            if options.max_binary_length == Some(0) {
+                return Err(Error::argument(anyhow!("Maximum binary length must be larger than 0.")));
+            }
+            if let Some(timeout) = options.timeout {
+                self.timeout = timeout.into();
+            }
+            if let Some(max_read_length) = options.max_read_length {
+                self.max_read_length = max_read_length as usize;
+            }
+            if let Some(max_binary_length) = options.max_binary_length {
+                self.max_binary_length = max_binary_length as usize;
+            }
+            return Ok(Response::Done);
+        }
         if let Some(x) = self.drop_check(&req) {
             return x;
         }
+        if let Request::Scpi { scpi: req, .. } = &req {
+            scpi::validate_non_visa(req)?;
+        }
         let mut client = if let Some(client) = self.client.take() {
             client
         } else {
@@ -181,8 +258,12 @@ impl IoHandler for Handler {
         };
         match req {
             Request::Scpi { scpi: req, timeout } => {
-                let timeout = timeout.unwrap_or(DEFAULT_CONNECTION_TIMEOUT);
-                let ret = Self::handle_request_timeout(&mut client, req.clone(), timeout).await;
+                let timeout = timeout.unwrap_or(self.timeout);
+                let limits = ReadLimits {
+                    max_read_length: self.max_read_length,
+                    max_binary_length: self.max_binary_length,
+                };
+                let ret = Self::handle_request_timeout(&mut client, req.clone(), timeout, limits).await;
                 match ret {
                     Ok(ret) => {
                         self.client.replace(client);
@@ -194,7 +275,7 @@ impl IoHandler for Handler {
                         if err.should_retry() {
                             sleep(Duration::from_millis(100)).await;
                             let mut client = self.connect().await?;
-                            let ret = Self::handle_request_timeout(&mut client, req, timeout).await;
+                            let ret = Self::handle_request_timeout(&mut client, req, timeout, limits).await;
                             if ret.is_ok() {
                                 self.client.replace(client);
                                 self.spawn_drop_check(ctx);
@@ -206,14 +287,135 @@ impl IoHandler for Handler {
                     }
                 }
             }
-            Request::DropCheck => Ok(Response::Done),
+            Request::SetOptions(_) | Request::GetOptions | Request::DropCheck => Ok(Response::Done),
         }
     }
 }
 
+/// Source of the chunks of a response, each returned by a `device_read` call.
+#[async_trait]
+trait DeviceRead: Send {
+    async fn read_chunk(&mut self) -> crate::Result<Vec<u8>>;
+}
+
+#[async_trait]
+impl DeviceRead for CoreClient {
+    async fn read_chunk(&mut self) -> crate::Result<Vec<u8>> {
+        self.device_read().await.map_err(map_error)
+    }
+}
+
+/// Accumulate chunks until `complete` accepts the data received so far. Fails once more than `max_length` bytes
+/// are received.
+async fn read_until<R: DeviceRead, F: Fn(&[u8]) -> bool + Send>(
+    reader: &mut R,
+    max_length: usize,
+    complete: F,
+) -> crate::Result<Vec<u8>> {
+    let mut rx = Vec::new();
+    loop {
+        rx.extend(reader.read_chunk().await?);
+        if complete(&rx) {
+            return Ok(rx);
+        }
+        if rx.len() > max_length {
+            return Err(Error::protocol(anyhow!("Response exceeds the limit of {} bytes.", max_length)));
+        }
+    }
+}
+
+/// Read a string response of at most `max_length` bytes and strip its termination.
+async fn read_string<R: DeviceRead>(reader: &mut R, max_length: usize) -> crate::Result<String> {
+    let term = DEFAULT_TERMINATION.as_bytes();
+    let mut rx = read_until(reader, max_length + term.len(), |rx| rx.ends_with(term)).await?;
+    rx.truncate(rx.len() - term.len());
+    String::from_utf8(rx).map_err(|_| Error::protocol(anyhow!("Response is not valid UTF-8.")))
+}
+
+/// Read a binary block of at most `max_binary_length` bytes of data and return the data.
+async fn read_binary<R: DeviceRead>(reader: &mut R, max_binary_length: usize) -> crate::Result<Vec<u8>> {
+    // allow for the header and the termination
+    let max_length = max_binary_length + scpi::MAX_BINARY_HEADER_LENGTH + DEFAULT_TERMINATION.len();
+    let rx = read_until(reader, max_length, |rx| scpi::binary_block_complete(rx, max_binary_length)).await?;
+    let (offset, length) = scpi::parse_binary_header(&rx, max_binary_length)?;
+    Ok(rx[offset..offset + length].to_vec())
+}
+
 fn map_error(err: async_vxi11::Error) -> crate::Error {
     match err {
         async_vxi11::Error::Io(io) => crate::Error::transport(io),
         err => crate::Error::transport(anyhow!(err)),
     }
 }
+
+/// Check whether the portmapper of the instrument at `host` accepts connections.
+pub async fn probe(host: &str) -> crate::Result<()> {
+    tokio::net::TcpStream::connect((host, PORTMAPPER_PORT))
+        .await
+        .map_err(Error::transport)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    #[async_trait]
+    impl DeviceRead for Chunks {
+        async fn read_chunk(&mut self) -> crate::Result<Vec<u8>> {
+            self.0.pop_front().map(|x| x.to_vec()).ok_or_else(Error::protocol_timeout)
+        }
+    }
+
+    fn chunks(chunks: &[&'static [u8]]) -> Chunks {
+        Chunks(chunks.iter().copied().collect())
+    }
+
+    #[tokio::test]
+    async fn read_in_two_chunks() {
+        let mut reader = chunks(&[b"1.0,2.0,", b"3.0\n", b"stale\n"]);
+        assert_eq!(read_string(&mut reader, 100).await.unwrap(), "1.0,2.0,3.0");
+        assert_eq!(reader.0.len(), 1);
+
+        let mut reader = chunks(&[b"#15ab", b"cde\n"]);
+        assert_eq!(read_binary(&mut reader, 100).await.unwrap(), b"abcde");
+        assert!(reader.0.is_empty());
+
+        let mut reader = chunks(&[b"#", b"0abc", b"\n"]);
+        assert_eq!(read_binary(&mut reader, 100).await.unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn set_options() {
+        let mut instr = Instrument::new(IpAddr::from([127, 0, 0, 1]), &Default::default());
+        let options = VxiOptions {
+            timeout: None,
+            max_read_length: None,
+            max_binary_length: Some(1024),
+        };
+        instr.set_options(options).await.unwrap();
+        let options = instr.options().await.unwrap();
+        assert_eq!(options["max_binary_length"], 1024);
+        assert_eq!(options["max_read_length"], DEFAULT_MAX_READ_LENGTH);
+
+        let options = VxiOptions {
+            max_binary_length: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(instr.set_options(options).await, Err(Error::Argument(_))));
+    }
+
+    #[tokio::test]
+    async fn read_limit() {
+        let mut reader = chunks(&[b"0123456789", b"0123456789"]);
+        let err = read_string(&mut reader, 15).await.unwrap_err();
+        assert!(matches!(err, Error::Protocol(_)));
+
+        let mut reader = chunks(&[b"#3200", b"abc"]);
+        assert!(read_binary(&mut reader, 100).await.is_err());
+        assert_eq!(reader.0.len(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Validates requests without performing any IO, as requested with
+//! [`Request::Validate`](comsrv_protocol::Request::Validate).
+//!
+//! The checks are the ones the request handlers run before any IO, such that a request passing validation only
+//! fails on the device or the connection to it. A `term` of 0 in line requests is checked against the default
+//! termination in the options of the request, as options applied earlier are only known to the instrument.
+
+use std::convert::TryInto;
+
+use comsrv_protocol::{
+    ByteStreamInstrument, CanRequest, HidRequest, Request, ScpiInstrument, ScpiRequest, SerialPortConfig,
+};
+
+use crate::protocol::{bytestream, prologix, scpi};
+use crate::transport::serial::usb::UsbPortSelector;
+use crate::transport::serial::SerialParams;
+use crate::transport::{hid, visa};
+
+/// Check the arguments and address of `req`.
+pub fn validate(req: &Request) -> crate::Result<()> {
+    match req {
+        Request::Bytes {
+            instrument, request, ..
+        } => {
+            validate_instrument(instrument)?;
+            let mut request = request.clone();
+            bytestream::apply_default_term(&mut request, default_term(instrument));
+            bytestream::validate(&request)
+        }
+        Request::CobsStream { instrument, .. } => validate_instrument(instrument),
+        Request::Serial { instrument, .. } => {
+            validate_port(&instrument.address.port)?;
+            validate_port_config(&instrument.port_config)
+        }
+        Request::Prologix {
+            instrument, request, ..
+        } => {
+            validate_port(&instrument.address.port)?;
+            prologix::validate_request(&request.scpi)
+        }
+        Request::PrologixAdapter { instrument, .. } => validate_port(&instrument.address.port),
+        Request::Can {
+            request: CanRequest::TxGct(msg),
+            ..
+        }
+        | Request::GctEncode(msg) => msg.validate().map_err(crate::Error::argument),
+        Request::Hid {
+            request: HidRequest::Read { timeout },
+            ..
+        } => hid::read_timeout(timeout.clone().into()).map(|_| ()),
+        Request::Scpi {
+            instrument: ScpiInstrument::Visa(_),
+            request: ScpiRequest::GetAttribute { code } | ScpiRequest::SetAttribute { code, .. },
+            ..
+        } => visa::check_attribute(*code),
+        Request::Scpi {
+            instrument: ScpiInstrument::Vxi(_),
+            request,
+            ..
+        } => scpi::validate_non_visa(request),
+        Request::Validate(inner) => validate(inner),
+        _ => Ok(()),
+    }
+}
+
+fn validate_instrument(instrument: &ByteStreamInstrument) -> crate::Result<()> {
+    match instrument {
+        ByteStreamInstrument::Serial(x) => {
+            validate_port(&x.address.port)?;
+            validate_port_config(&x.port_config)
+        }
+        ByteStreamInstrument::Ftdi(x) => validate_port_config(&x.port_config),
+        _ => Ok(()),
+    }
+}
+
+fn default_term(instrument: &ByteStreamInstrument) -> Option<u8> {
+    match instrument {
+        ByteStreamInstrument::Serial(x) => x.options.as_ref().and_then(|x| x.default_term),
+        ByteStreamInstrument::Ftdi(x) => x.options.as_ref().and_then(|x| x.default_term),
+        ByteStreamInstrument::Tcp(x) => x.options.as_ref().and_then(|x| x.default_term),
+        _ => None,
+    }
+}
+
+fn validate_port(port: &str) -> crate::Result<()> {
+    match UsbPortSelector::parse(port) {
+        Some(selector) => selector.map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+fn validate_port_config(config: &SerialPortConfig) -> crate::Result<()> {
+    let _: SerialParams = config.clone().try_into()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comsrv_protocol::{
+        ByteStreamRequest, ModBusProtocol, ModBusRequest, PrologixInstrument, PrologixRequest, SerialAddress,
+        SerialInstrument, VxiInstrument,
+    };
+    use std::time::Duration;
+
+    fn write_registers(values: Vec<u16>) -> Request {
+        Request::Bytes {
+            instrument: ByteStreamInstrument::Serial(SerialInstrument {
+                address: SerialAddress {
+                    port: "/dev/ttyUSB0".to_string(),
+                },
+                port_config: SerialPortConfig::new(9600, "8N1").unwrap(),
+                options: None,
+            }),
+            request: ByteStreamRequest::ModBus {
+                timeout: Duration::from_millis(100).into(),
+                station_address: 1,
+                protocol: ModBusProtocol::Rtu,
+                inter_frame_delay_ms: 0,
+                request: ModBusRequest::WriteRegisters { addr: 0, values },
+            },
+            lock: None,
+            truncate: false,
+        }
+    }
+
+    #[test]
+    fn valid_request() {
+        assert!(validate(&write_registers(vec![1; 100])).is_ok());
+        assert!(validate(&Request::Validate(Box::new(write_registers(vec![1; 100])))).is_ok());
+    }
+
+    #[test]
+    fn modbus_broadcast() {
+        let mut req = write_registers(vec![1]);
+        assert!(validate(&req).is_ok());
+        if let Request::Bytes {
+            request:
+                ByteStreamRequest::ModBus {
+                    station_address,
+                    request,
+                    ..
+                },
+            ..
+        } = &mut req
+        {
+            *station_address = 0;
+            *request = ModBusRequest::ReadHolding { addr: 0, cnt: 1 };
+        }
+        assert!(matches!(validate(&req), Err(crate::Error::Argument(_))));
+    }
+
+    #[test]
+    fn visa_only_requests() {
+        let req = Request::Scpi {
+            instrument: ScpiInstrument::Vxi(VxiInstrument {
+                host: "127.0.0.1".to_string(),
+            }),
+            request: ScpiRequest::VisaUnlock,
+            lock: None,
+            timeout: None,
+            truncate: false,
+        };
+        assert!(matches!(validate(&req), Err(crate::Error::Argument(_))));
+
+        let req = Request::Prologix {
+            instrument: PrologixInstrument {
+                address: SerialAddress {
+                    port: "/dev/ttyUSB0".to_string(),
+                },
+            },
+            request: PrologixRequest {
+                addr: 1,
+                scpi: ScpiRequest::GetAttribute { code: 0 },
+            },
+            lock: None,
+            timeout: None,
+        };
+        assert!(matches!(validate(&req), Err(crate::Error::Argument(_))));
+    }
+
+    #[test]
+    fn invalid_request() {
+        let err = validate(&write_registers(vec![1; 200])).unwrap_err();
+        assert!(matches!(err, crate::Error::Argument(_)));
+
+        let mut req = write_registers(vec![1]);
+        if let Request::Bytes {
+            instrument: ByteStreamInstrument::Serial(instr),
+            ..
+        } = &mut req
+        {
+            instr.address.port = "usb:xyz".to_string();
+        }
+        assert!(matches!(validate(&req), Err(crate::Error::Argument(_))));
+    }
+}
@@ -0,0 +1,68 @@
+//! Runs the `comsrv` binary as server and queries it using the client subcommands of the same binary.
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const COMSRV: &str = env!("CARGO_BIN_EXE_comsrv");
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn spawn_server() -> (Server, String) {
+    let port = free_port();
+    let http_port = free_port();
+    let child = Command::new(COMSRV)
+        .args([
+            "--bind",
+            "127.0.0.1",
+            "--port",
+            &port.to_string(),
+            "--http-port",
+            &http_port.to_string(),
+        ])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server(child);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "comsrv did not start listening");
+        sleep(Duration::from_millis(50));
+    }
+    (server, format!("ws://127.0.0.1:{}", port))
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(COMSRV).args(args).output().unwrap()
+}
+
+#[test]
+fn query_subcommand() {
+    let (_server, url) = spawn_server();
+
+    let output = run(&["query", &url, "version"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let response: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(response["Version"]["major"].is_u64());
+
+    let output = run(&["query", &url, "\"ListAliases\""]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let response: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(response.get("Aliases").is_some());
+
+    let output = run(&["query", &url, "no-such-request"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
@@ -1,10 +1,30 @@
 use crate::{Address, Duration};
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// Defines the lifetime of the connection to an instrument
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+pub enum InstrumentMode {
+    /// Keep the connection open across requests until it is dropped explicitly or `auto_drop` expires
+    #[default]
+    Persistent,
+    /// Open the connection for each request and close it as soon as the request completes
+    Transactional,
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SerialOptions {
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub auto_drop: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mode: Option<InstrumentMode>,
+    /// Termination used by line requests specifying a `term` of 0
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub default_term: Option<u8>,
+    /// Minimum interval between the start of consecutive requests. Requests arriving earlier are delayed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_interval: Option<Duration>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
@@ -30,6 +50,14 @@ impl Default for FlowControl {
     }
 }
 
+fn flush_input_default() -> bool {
+    true
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
 impl FlowControl {
     fn has_no_flow_control(&self) -> bool {
         *self == FlowControl::NoFlowControl
@@ -44,6 +72,109 @@ pub struct SerialPortConfig {
     pub hardware_flow_control: FlowControl,
 }
 
+impl SerialPortConfig {
+    /// Create a port configuration without flow control. `config` defines data bits, parity and
+    /// stop bits, e.g. `8N1`.
+    pub fn new(baudrate: u32, config: &str) -> Result<Self, crate::Error> {
+        if baudrate == 0 {
+            return Err(crate::Error::argument(anyhow!("Invalid baudrate: 0")));
+        }
+        let (data_bits, parity, stop_bits) = parse_serial_settings(config)?;
+        Ok(Self {
+            config: format_serial_settings(data_bits, parity, stop_bits),
+            baudrate,
+            hardware_flow_control: FlowControl::NoFlowControl,
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub enum DataBits {
+    Seven,
+    Eight,
+}
+
+/// Parse serial port settings such as `8N1` into data bits, parity and stop bits. Case insensitive.
+pub fn parse_serial_settings(settings: &str) -> Result<(DataBits, Parity, StopBits), crate::Error> {
+    let invalid = || {
+        crate::Error::argument(anyhow!(
+            "Invalid serial settings `{}`, expected e.g. `8N1`",
+            settings
+        ))
+    };
+    let lower = settings.to_lowercase();
+    let chars = lower.as_bytes();
+    if chars.len() != 3 {
+        return Err(invalid());
+    }
+    let data_bits = match chars[0] as char {
+        '8' => DataBits::Eight,
+        '7' => DataBits::Seven,
+        _ => return Err(invalid()),
+    };
+    let parity = match chars[1] as char {
+        'n' => Parity::None,
+        'o' => Parity::Odd,
+        'e' => Parity::Even,
+        _ => return Err(invalid()),
+    };
+    let stop_bits = match chars[2] as char {
+        '1' => StopBits::One,
+        '2' => StopBits::Two,
+        _ => return Err(invalid()),
+    };
+    Ok((data_bits, parity, stop_bits))
+}
+
+/// Format serial port settings as accepted by [`parse_serial_settings`], e.g. `8N1`.
+pub fn format_serial_settings(data_bits: DataBits, parity: Parity, stop_bits: StopBits) -> String {
+    format!("{}{}{}", data_bits, parity, stop_bits)
+}
+
+impl Display for DataBits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            DataBits::Seven => "7",
+            DataBits::Eight => "8",
+        };
+        f.write_str(x)
+    }
+}
+
+impl Display for Parity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            Parity::None => "N",
+            Parity::Odd => "O",
+            Parity::Even => "E",
+        };
+        f.write_str(x)
+    }
+}
+
+impl Display for StopBits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            StopBits::One => "1",
+            StopBits::Two => "2",
+        };
+        f.write_str(x)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
 pub struct TcpAddress {
     pub host: String,
@@ -58,6 +189,21 @@ pub struct SerialInstrument {
     pub options: Option<SerialOptions>,
 }
 
+impl SerialInstrument {
+    /// Create a serial instrument at `port`, e.g. `SerialInstrument::new("/dev/ttyUSB0", 115200, "8N1")`.
+    pub fn new<T: Into<String>>(
+        port: T,
+        baudrate: u32,
+        config: &str,
+    ) -> Result<Self, crate::Error> {
+        Ok(Self {
+            address: SerialAddress { port: port.into() },
+            port_config: SerialPortConfig::new(baudrate, config)?,
+            options: None,
+        })
+    }
+}
+
 impl From<SerialInstrument> for SerialAddress {
     fn from(val: SerialInstrument) -> Self {
         val.address
@@ -84,6 +230,40 @@ pub struct TcpOptions {
     pub auto_drop: Option<Duration>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub connection_timeout: Option<Duration>,
+    /// Disable Nagle's algorithm on the connection. Enabled by default.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nodelay: Option<bool>,
+    /// Idle time before TCP keepalive probes are sent. A zero duration disables keepalive.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub keepalive: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mode: Option<InstrumentMode>,
+    /// Termination used by line requests specifying a `term` of 0
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub default_term: Option<u8>,
+    /// Coalesce `Write` requests into fewer writes to the socket. Ignored in transactional mode.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub write_buffering: Option<WriteBuffering>,
+    /// Minimum interval between the start of consecutive requests. Requests arriving earlier are delayed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_interval: Option<Duration>,
+    /// Detect the termination of line requests specifying a `term` of 0 if no `default_term` is set. The first
+    /// such `QueryLine` is tried with `\n` and then with `\r\n`, the termination replied to is kept for the
+    /// connection. Line requests preceding the detection use `\n`. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub detect_term: Option<bool>,
+}
+
+/// Configures buffering of [`ByteStreamRequest::Write`] requests.
+///
+/// Buffered data is written once it reaches `max_size` bytes, `max_delay` after the first write into the empty
+/// buffer, on [`ByteStreamRequest::Flush`] and before any other request is handled. Hence, the instrument receives
+/// all data in the order it was requested and reads never wait for a reply to a request still in the buffer.
+/// A `max_size` of 0 disables buffering.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WriteBuffering {
+    pub max_size: u32,
+    pub max_delay: Duration,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -93,17 +273,61 @@ pub struct TcpInstrument {
     pub options: Option<TcpOptions>,
 }
 
+impl TcpInstrument {
+    pub fn new<T: Into<String>>(host: T, port: u16) -> Self {
+        Self {
+            address: TcpAddress {
+                host: host.into(),
+                port,
+            },
+            options: None,
+        }
+    }
+}
+
 impl From<TcpInstrument> for TcpAddress {
     fn from(val: TcpInstrument) -> Self {
         val.address
     }
 }
 
+/// Address of an instrument served by a transport registered with the `comsrv` at runtime. `kind` selects
+/// the transport and `address` is interpreted by the transport.
+#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct CustomAddress {
+    pub kind: String,
+    pub address: String,
+}
+
+/// Address of an in-memory instrument for testing. It echoes all data written to it, unless
+/// responses were scripted for it on the `comsrv`.
+#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct LoopbackAddress {
+    pub name: String,
+}
+
+/// A request expected by a loopback instrument and the response it replies with
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LoopbackExchange {
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum LoopbackRequest {
+    /// Reply with the `response` of each exchange once its `request` was received, in order. The
+    /// exchanges are appended to the ones not yet consumed. Input not matching the next request is
+    /// discarded. Once all exchanges are consumed, the instrument echoes again.
+    Script(Vec<LoopbackExchange>),
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ByteStreamInstrument {
     Serial(SerialInstrument),
     Ftdi(FtdiInstrument),
     Tcp(TcpInstrument),
+    Custom(CustomAddress),
+    Loopback(LoopbackAddress),
 }
 
 impl From<ByteStreamInstrument> for Address {
@@ -112,6 +336,8 @@ impl From<ByteStreamInstrument> for Address {
             ByteStreamInstrument::Serial(x) => Address::Serial(x.into()),
             ByteStreamInstrument::Ftdi(x) => Address::Ftdi(x.into()),
             ByteStreamInstrument::Tcp(x) => Address::Tcp(x.into()),
+            ByteStreamInstrument::Custom(x) => Address::Custom(x),
+            ByteStreamInstrument::Loopback(x) => Address::Loopback(x),
         }
     }
 }
@@ -122,6 +348,8 @@ impl ByteStreamInstrument {
             ByteStreamInstrument::Serial(x) => Address::Serial(x.address.clone()),
             ByteStreamInstrument::Ftdi(x) => Address::Ftdi(x.address.clone()),
             ByteStreamInstrument::Tcp(x) => Address::Tcp(x.address.clone()),
+            ByteStreamInstrument::Custom(x) => Address::Custom(x.clone()),
+            ByteStreamInstrument::Loopback(x) => Address::Loopback(x.clone()),
         }
     }
 }
@@ -130,13 +358,32 @@ impl ByteStreamInstrument {
 pub enum ModBusProtocol {
     Tcp,
     Rtu,
+    /// ModBus ASCII framing with `:` and CRLF, protected by an LRC
+    Ascii,
+}
+
+/// Byte order of an integer transmitted over the wire
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ByteStreamRequest {
     Connect,
+    /// Close the connection to the instrument. Data buffered by the `comsrv` is written first. The instrument
+    /// remains in the inventory and reconnects on the next request, while [`Request::Drop`](crate::Request::Drop)
+    /// also removes it from the inventory.
     Disconnect,
+    /// Same as `Disconnect`, but data buffered by the `comsrv` is only written if `flush` is set and discarded
+    /// otherwise. Fails if writing the data fails, in which case the connection is closed anyways.
+    Close {
+        flush: bool,
+    },
     Write(Vec<u8>),
+    /// Write all buffered data to the instrument. Replied with `Done`.
+    Flush,
     ReadToTerm {
         term: u8,
         timeout: Duration,
@@ -145,12 +392,48 @@ pub enum ByteStreamRequest {
         count: u32,
         timeout: Duration,
     },
+    /// Read up to `count` bytes. Waits up to `timeout` for data to arrive and returns as soon as
+    /// any data is available. A zero `timeout` only returns data which is already buffered.
+    ReadUpTo {
+        count: u32,
+        timeout: Duration,
+    },
     ReadAll,
+    /// Same as `ReadAll` but returns at most `max_bytes`. Remaining data is kept buffered.
+    ReadAllMax {
+        max_bytes: u32,
+    },
+    /// Read data as it arrives and broadcast each chunk as [`ByteStreamResponse::Chunk`] until no data
+    /// arrived for `idle` or `timeout` elapsed. Replied with `Done` once the stream idles.
+    ReadAllStream {
+        idle: Duration,
+        timeout: Duration,
+    },
+    /// Read a length prefix of `prefix_len` bytes (1, 2 or 4) followed by a payload of the given length.
+    /// Returns the payload. Fails if the length exceeds `max_len`.
+    ReadLengthPrefixed {
+        prefix_len: u8,
+        endian: Endian,
+        max_len: u32,
+        timeout: Duration,
+    },
     CobsWrite(Vec<u8>),
     CobsRead(Duration),
+    /// Same as `CobsRead` but fails if the encoded frame exceeds `max_frame_size` bytes instead of the default
+    /// limit of the `comsrv`.
+    CobsReadMax {
+        timeout: Duration,
+        max_frame_size: u32,
+    },
     CobsQuery {
         data: Vec<u8>,
         timeout: Duration,
+        /// Discard buffered input before writing the query.
+        #[serde(default = "flush_input_default")]
+        flush_input: bool,
+        /// Maximum size of the encoded reply frame, defaults to the limit of the `comsrv`
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        max_frame_size: Option<u32>,
     },
     WriteLine {
         line: String,
@@ -164,11 +447,18 @@ pub enum ByteStreamRequest {
         line: String,
         timeout: Duration,
         term: u8,
+        /// Discard buffered input before writing the query.
+        #[serde(default = "flush_input_default")]
+        flush_input: bool,
     },
     ModBus {
         timeout: Duration,
         station_address: u8,
         protocol: ModBusProtocol,
+        /// Guard time in milliseconds between writing the request and reading the reply. Only applies to
+        /// ModBus RTU, where slow devices would otherwise truncate their replies.
+        #[serde(skip_serializing_if = "is_zero", default)]
+        inter_frame_delay_ms: u32,
         request: ModBusRequest,
     },
 }
@@ -181,6 +471,13 @@ pub enum ModBusRequest {
         response: bool,
         data: Vec<u8>,
     },
+    /// Send a PDU with an arbitrary function code. Replied with the response PDU as is, i.e. the
+    /// function code followed by the unparsed response data. The end of ModBus RTU replies is
+    /// detected by their CRC.
+    RawPdu {
+        function_code: u8,
+        data: Vec<u8>,
+    },
     ReadCoil {
         addr: u16,
         cnt: u16,
@@ -213,6 +510,11 @@ pub enum ByteStreamResponse {
     Data(Vec<u8>),
     String(String),
     ModBus(ModBusResponse),
+    /// Notification carrying data read by [`ByteStreamRequest::ReadAllStream`]
+    Chunk {
+        sender: ByteStreamInstrument,
+        data: Vec<u8>,
+    },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -223,6 +525,41 @@ pub enum ModBusResponse {
     Data(Vec<u8>),
 }
 
+/// The data served by a ModBus server started with [`crate::Request::StartModbusServer`]. Each table starts at
+/// address 0 and ModBus masters may write the coils and holding registers.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ModBusRegisterMap {
+    #[serde(default)]
+    pub coils: Vec<bool>,
+    #[serde(default)]
+    pub discretes: Vec<bool>,
+    #[serde(default)]
+    pub inputs: Vec<u16>,
+    #[serde(default)]
+    pub holdings: Vec<u16>,
+}
+
+/// A serial port found on the system running the `comsrv`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerialPortInfo {
+    pub port_name: String,
+    /// Only available for USB serial adapters.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub usb: Option<UsbPortInfo>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct UsbPortInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub product: Option<String>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct FtdiDeviceInfo {
     pub port_open: bool,
@@ -231,3 +568,65 @@ pub struct FtdiDeviceInfo {
     pub serial_number: String,
     pub description: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_settings() {
+        assert_eq!(
+            parse_serial_settings("8N1").unwrap(),
+            (DataBits::Eight, Parity::None, StopBits::One)
+        );
+        assert_eq!(
+            parse_serial_settings("7e2").unwrap(),
+            (DataBits::Seven, Parity::Even, StopBits::Two)
+        );
+        for settings in ["", "8N", "8N11", "9N1", "8X1", "8N3", "N81"] {
+            assert!(
+                matches!(
+                    parse_serial_settings(settings),
+                    Err(crate::Error::Argument(_))
+                ),
+                "{}",
+                settings
+            );
+        }
+    }
+
+    #[test]
+    fn serial_settings_round_trip() {
+        for data_bits in [DataBits::Seven, DataBits::Eight] {
+            for parity in [Parity::None, Parity::Odd, Parity::Even] {
+                for stop_bits in [StopBits::One, StopBits::Two] {
+                    let settings = format_serial_settings(data_bits, parity, stop_bits);
+                    assert_eq!(
+                        parse_serial_settings(&settings).unwrap(),
+                        (data_bits, parity, stop_bits)
+                    );
+                }
+            }
+        }
+        assert_eq!(
+            format_serial_settings(DataBits::Eight, Parity::None, StopBits::One),
+            "8N1"
+        );
+    }
+
+    #[test]
+    fn construct_instruments() {
+        let instr = SerialInstrument::new("/dev/ttyUSB0", 115200, "8o1").unwrap();
+        assert_eq!(instr.address.port, "/dev/ttyUSB0");
+        assert_eq!(instr.port_config.config, "8O1");
+        assert_eq!(instr.port_config.baudrate, 115200);
+        assert!(SerialInstrument::new("/dev/ttyUSB0", 115200, "8N").is_err());
+        assert!(SerialInstrument::new("/dev/ttyUSB0", 0, "8N1").is_err());
+
+        let instr = TcpInstrument::new("127.0.0.1", 502);
+        assert_eq!(
+            Address::from(ByteStreamInstrument::Tcp(instr)),
+            Address::tcp("127.0.0.1", 502)
+        );
+    }
+}
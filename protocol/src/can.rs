@@ -1,5 +1,6 @@
 use byteorder::{ByteOrder, LittleEndian};
 use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
 use std::iter::repeat;
 use thiserror::Error;
 
@@ -13,7 +14,12 @@ pub enum CanAddress {
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum CanInstrument {
-    PCan { address: String, bitrate: u32 },
+    PCan {
+        address: String,
+        /// Bitrate in bit/s. If omitted or `0`, the default bitrate of the `comsrv` is used.
+        #[serde(default)]
+        bitrate: u32,
+    },
     SocketCan { interface: String },
     UsrCanet { host: String, port: u16 },
     Loopback,
@@ -95,6 +101,30 @@ pub enum CanRequest {
     EnableLoopback(bool),
     TxRaw(CanMessage),
     TxGct(GctMessage),
+    /// Record all received frames to `path` in the candump log format. `path` is a file name within the
+    /// directory given with `--recording-dir` and must not exist yet. Recording is rejected if the
+    /// `comsrv` was started without `--recording-dir`.
+    StartRecording {
+        path: String,
+    },
+    StopRecording,
+    /// Transmit the frames of a candump or ASC log file at `path`, preserving the time between frames
+    /// scaled by `speed`. `path` is a file name within the recording directory, like the path of a recording.
+    ReplayFile {
+        path: String,
+        #[serde(rename = "loop")]
+        repeat: bool,
+        speed: f32,
+    },
+    StopReplay,
+    /// Discard frames which were received but not yet processed as well as partially received
+    /// GCT messages, such that only frames received afterwards are reported.
+    ClearRx,
+    /// Received frames are buffered in a queue of 1000 frames until they are processed. By default,
+    /// reception pauses while the queue is full, such that frames queue up in the driver or the
+    /// adapter. Note that this risks receive overruns of the hardware if the backlog persists.
+    /// With backpressure disabled, frames received while the queue is full are dropped instead.
+    SetBackpressure(bool),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -168,13 +198,57 @@ pub const MAX_DDP_DATA_LEN_V2: usize = 8 * 256 - 3; // 256 message * 8bytes - cr
 
 #[derive(Error, Debug)]
 pub enum ValidationError {
+    #[deprecated(note = "validation reports the specific field which is invalid")]
     #[error("Invalid Param")]
     InvalidParam,
+    #[error("Invalid source address: {0}")]
+    InvalidSourceAddress(u8),
+    #[error("Invalid destination address: {0}")]
+    InvalidDestinationAddress(u8),
+    #[error("Command out of range: {0}. Must be < 1024")]
+    CommandOutOfRange(u16),
+    #[error("Data too long: {len} bytes. Maximum {max} bytes")]
+    DataTooLong { len: usize, max: usize },
+    #[error("Group index out of range: {0}. Must be < 32")]
+    GroupIndexOutOfRange(u8),
+    #[error("Reading index out of range: {0}. Must be < 64")]
+    ReadingIndexOutOfRange(u8),
+    #[error("Unsupported DDP version: {0}")]
+    UnsupportedDdpVersion(u32),
+    #[error("Invalid product id: {0:#06x}")]
+    InvalidProductId(u16),
+}
+
+fn validate_src(src: u8) -> Result<(), ValidationError> {
+    if src < BROADCAST_ADDR {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidSourceAddress(src))
+    }
+}
+
+fn validate_dst(dst: u8) -> Result<(), ValidationError> {
+    if dst <= BROADCAST_ADDR {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidDestinationAddress(dst))
+    }
+}
+
+fn validate_data_len(data: &[u8], max: usize) -> Result<(), ValidationError> {
+    if data.len() <= max {
+        Ok(())
+    } else {
+        Err(ValidationError::DataTooLong {
+            len: data.len(),
+            max,
+        })
+    }
 }
 
 impl GctMessage {
     pub fn validate(&self) -> Result<(), ValidationError> {
-        let ok = match self {
+        match self {
             GctMessage::SysCtrl {
                 src,
                 dst,
@@ -182,47 +256,62 @@ impl GctMessage {
                 cmd,
                 ..
             } => {
-                let cmd_ok = *cmd < 1024;
-                let addr_ok = *src < BROADCAST_ADDR && *dst <= BROADCAST_ADDR;
-                addr_ok && data.len() <= 8 && cmd_ok
+                validate_src(*src)?;
+                validate_dst(*dst)?;
+                if *cmd >= 1024 {
+                    return Err(ValidationError::CommandOutOfRange(*cmd));
+                }
+                validate_data_len(data, 8)
             }
             GctMessage::MonitoringData {
                 src,
                 group_idx,
                 reading_idx,
                 data,
-            } => *src < BROADCAST_ADDR && data.len() <= 8 && *group_idx < 32 && *reading_idx < 64,
+            } => {
+                validate_src(*src)?;
+                if *group_idx >= 32 {
+                    return Err(ValidationError::GroupIndexOutOfRange(*group_idx));
+                }
+                if *reading_idx >= 64 {
+                    return Err(ValidationError::ReadingIndexOutOfRange(*reading_idx));
+                }
+                validate_data_len(data, 8)
+            }
             GctMessage::MonitoringRequest {
                 src,
                 dst,
                 group_idx,
                 ..
-            } => *src < BROADCAST_ADDR && *dst <= BROADCAST_ADDR && *group_idx < 32,
+            } => {
+                validate_src(*src)?;
+                validate_dst(*dst)?;
+                if *group_idx >= 32 {
+                    return Err(ValidationError::GroupIndexOutOfRange(*group_idx));
+                }
+                Ok(())
+            }
             GctMessage::Ddp {
                 src,
                 dst,
                 data,
                 version,
             } => {
-                let addr_ok = *src < BROADCAST_ADDR && *dst <= BROADCAST_ADDR;
-                if *version == 0 || *version == 1 {
-                    addr_ok && data.len() <= MAX_DDP_DATA_LEN_V1
-                } else if *version == 2 {
-                    addr_ok && data.len() <= MAX_DDP_DATA_LEN_V2
-                } else {
-                    false
+                validate_src(*src)?;
+                validate_dst(*dst)?;
+                match *version {
+                    0 | 1 => validate_data_len(data, MAX_DDP_DATA_LEN_V1),
+                    2 => validate_data_len(data, MAX_DDP_DATA_LEN_V2),
+                    version => Err(ValidationError::UnsupportedDdpVersion(version)),
                 }
             }
             GctMessage::Heartbeat { src, product_id } => {
-                let addr_ok = *src < BROADCAST_ADDR;
-                let prod_id_ok = *product_id != 0 && *product_id != 0xFFFF;
-                addr_ok && prod_id_ok
+                validate_src(*src)?;
+                if *product_id == 0 || *product_id == 0xFFFF {
+                    return Err(ValidationError::InvalidProductId(*product_id));
+                }
+                Ok(())
             }
-        };
-        if ok {
-            Ok(())
-        } else {
-            Err(ValidationError::InvalidParam)
         }
     }
 
@@ -301,6 +390,94 @@ impl GctMessage {
     }
 }
 
+impl Display for SysCtrlType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            SysCtrlType::Value => "Value",
+            SysCtrlType::Query => "Query",
+            SysCtrlType::None => "None",
+        };
+        f.write_str(x)
+    }
+}
+
+struct HexData<'a>(&'a [u8]);
+
+impl Display for HexData<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (idx, x) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02X}", x)?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Renders the message symbolically, e.g. `SysCtrl src=12 dst=34 cmd=452 Value data=[01 02]`.
+impl Display for GctMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GctMessage::SysCtrl {
+                src,
+                dst,
+                cmd,
+                tp,
+                data,
+            } => write!(
+                f,
+                "SysCtrl src={} dst={} cmd={} {} data={}",
+                src,
+                dst,
+                cmd,
+                tp,
+                HexData(data)
+            ),
+            GctMessage::MonitoringData {
+                src,
+                group_idx,
+                reading_idx,
+                data,
+            } => write!(
+                f,
+                "MonitoringData src={} group={} reading={} data={}",
+                src,
+                group_idx,
+                reading_idx,
+                HexData(data)
+            ),
+            GctMessage::MonitoringRequest {
+                src,
+                dst,
+                group_idx,
+                readings,
+            } => write!(
+                f,
+                "MonitoringRequest src={} dst={} group={} readings={:#018x}",
+                src, dst, group_idx, readings
+            ),
+            GctMessage::Ddp {
+                src,
+                dst,
+                data,
+                version,
+            } => write!(
+                f,
+                "Ddp src={} dst={} version={} data={}",
+                src,
+                dst,
+                version,
+                HexData(data)
+            ),
+            GctMessage::Heartbeat { src, product_id } => {
+                write!(f, "Heartbeat src={} product_id={:#06x}", src, product_id)
+            }
+        }
+    }
+}
+
 pub struct MessageId(pub u32);
 
 impl MessageId {
@@ -328,3 +505,96 @@ impl MessageId {
         (self.0 & 0x7FF) as u16
     }
 }
+
+/// Renders the fields of the id, e.g. `SysCtrl src=12 dst=34 type_data=0x712`.
+impl Display for MessageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.msg_type() {
+            MSGTYPE_SYSCTRL => f.write_str("SysCtrl")?,
+            MSGTYPE_MONITORING_DATA => f.write_str("MonitoringData")?,
+            MSGTYPE_MONITORING_REQUEST => f.write_str("MonitoringRequest")?,
+            MSGTYPE_DDP => f.write_str("Ddp")?,
+            MSGTYPE_HEARTBEAT => f.write_str("Heartbeat")?,
+            x => write!(f, "Type{}", x)?,
+        }
+        write!(
+            f,
+            " src={} dst={} type_data={:#05x}",
+            self.src(),
+            self.dst(),
+            self.type_data()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcan_bitrate() {
+        let instr: CanInstrument = serde_json::from_str(r#"{"PCan": {"address": "usb1"}}"#).unwrap();
+        assert_eq!(instr.bitrate(), Some(0));
+        let instr = CanInstrument::PCan {
+            address: "usb1".to_string(),
+            bitrate: 500000,
+        };
+        let json = serde_json::to_value(&instr).unwrap();
+        assert_eq!(json["PCan"]["bitrate"], 500000);
+    }
+
+    #[test]
+    fn format_message_id() {
+        let id = MessageId::new(MSGTYPE_SYSCTRL, 12, 34, 0x712);
+        assert_eq!(id.to_string(), "SysCtrl src=12 dst=34 type_data=0x712");
+        let id = MessageId::new(3, 1, BROADCAST_ADDR, 5);
+        assert_eq!(id.to_string(), "Type3 src=1 dst=127 type_data=0x005");
+    }
+
+    #[test]
+    fn format_gct_message() {
+        let msg = GctMessage::SysCtrl {
+            src: 12,
+            dst: 34,
+            cmd: 452,
+            tp: SysCtrlType::Value,
+            data: vec![1, 0xAB],
+        };
+        assert_eq!(
+            msg.to_string(),
+            "SysCtrl src=12 dst=34 cmd=452 Value data=[01 AB]"
+        );
+        let msg = GctMessage::MonitoringData {
+            src: 1,
+            group_idx: 2,
+            reading_idx: 3,
+            data: vec![],
+        };
+        assert_eq!(
+            msg.to_string(),
+            "MonitoringData src=1 group=2 reading=3 data=[]"
+        );
+        let msg = GctMessage::MonitoringRequest {
+            src: 1,
+            dst: 2,
+            group_idx: 3,
+            readings: 0x81,
+        };
+        assert_eq!(
+            msg.to_string(),
+            "MonitoringRequest src=1 dst=2 group=3 readings=0x0000000000000081"
+        );
+        let msg = GctMessage::Ddp {
+            src: 5,
+            dst: 6,
+            data: vec![0xFF],
+            version: 2,
+        };
+        assert_eq!(msg.to_string(), "Ddp src=5 dst=6 version=2 data=[FF]");
+        let msg = GctMessage::Heartbeat {
+            src: 7,
+            product_id: 0x1234,
+        };
+        assert_eq!(msg.to_string(), "Heartbeat src=7 product_id=0x1234");
+    }
+}
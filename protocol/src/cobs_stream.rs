@@ -2,11 +2,52 @@ use serde::{Deserialize, Serialize};
 
 use crate::ByteStreamInstrument;
 
+/// CRC appended to each frame before it is COBS encoded
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum CrcKind {
+    None,
+    /// CRC-16/CCITT-FALSE, appended big endian
+    Crc16,
+    /// CRC-32 as used by Ethernet and zlib, appended little endian
+    Crc32,
+}
+
+impl From<bool> for CrcKind {
+    /// Maps the `use_crc` flag of older clients
+    fn from(use_crc: bool) -> Self {
+        if use_crc {
+            CrcKind::Crc16
+        } else {
+            CrcKind::None
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum CobsStreamRequest {
-    Start { use_crc: bool },
+    Start {
+        use_crc: bool,
+        /// Takes precedence over `use_crc` if given
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        crc: Option<CrcKind>,
+    },
     Stop,
     SendFrame { data: Vec<u8> },
+    /// Wait until all frames sent before are written to the underlying stream
+    Flush,
+    /// Query whether the stream is alive, replied with [`CobsStreamResponse::Status`]
+    Status,
+}
+
+impl CobsStreamRequest {
+    /// Start the stream using the given CRC. `use_crc` is set as well, such that the request remains
+    /// valid for older `comsrv` instances.
+    pub fn start(crc: CrcKind) -> Self {
+        CobsStreamRequest::Start {
+            use_crc: crc != CrcKind::None,
+            crc: Some(crc),
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -19,4 +60,10 @@ pub enum CobsStreamResponse {
     InstrumentDropped {
         error: Option<crate::Error>,
     },
+    Status {
+        alive: bool,
+        use_crc: bool,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        crc: Option<CrcKind>,
+    },
 }
@@ -43,6 +43,18 @@ pub enum Error {
     ),
 }
 
+/// Determines whether an IO error is due to a transient condition, such as a connection that was reset.
+pub fn is_retryable_io_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
 impl Error {
     pub fn transport<T: Into<TransportError>>(err: T) -> Self {
         Self::Transport(err.into())
@@ -57,16 +69,15 @@ impl Error {
         Error::Protocol(ProtocolError::Timeout)
     }
 
+    /// Helper function to create `Error::Protocol(ProtocolError::InvalidUtf8 { .. })`
+    pub fn invalid_utf8(data: Vec<u8>) -> Self {
+        Error::Protocol(ProtocolError::InvalidUtf8 { data })
+    }
+
     /// Deteremines based on the error type, whether it makes sense (and is allowed) to retry an operation.
     pub fn should_retry(&self) -> bool {
         match self {
-            Error::Transport(TransportError::Io(err)) => {
-                err.kind() == io::ErrorKind::ConnectionReset
-                    || err.kind() == io::ErrorKind::ConnectionAborted
-                    || err.kind() == io::ErrorKind::BrokenPipe
-                    || err.kind() == io::ErrorKind::TimedOut
-                    || err.kind() == io::ErrorKind::UnexpectedEof
-            }
+            Error::Transport(TransportError::Io(err)) => is_retryable_io_error(err),
             Error::Transport(TransportError::Other(_)) => false,
             Error::Protocol(_) => false,
             Error::Argument(_) => false,
@@ -189,6 +200,15 @@ pub enum ProtocolError {
     Timeout,
     #[error("Unexpected Response: {0}")]
     UnexpectedResponse(String),
+    /// A reply could not be decoded as UTF-8. Contains the raw bytes received, such that they may be decoded otherwise.
+    #[error("Cannot decode {} bytes as UTF-8", data.len())]
+    InvalidUtf8 {
+        #[serde(
+            serialize_with = "crate::util::to_base64",
+            deserialize_with = "crate::util::from_base64"
+        )]
+        data: Vec<u8>,
+    },
     #[error("Other Error: {0}")]
     Other(
         #[serde(
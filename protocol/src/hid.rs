@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
 use crate::Duration;
@@ -12,6 +13,8 @@ pub enum HidRequest {
     Write { data: Vec<u8> },
     Read { timeout: Duration },
     GetInfo,
+    /// Read the raw report descriptor of the device, replied with [`HidResponse::ReportDescriptor`]
+    GetReportDescriptor,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -20,6 +23,7 @@ pub enum HidResponse {
     Data(Vec<u8>),
     Info(HidDeviceInfo),
     List(Vec<HidDeviceInfo>),
+    ReportDescriptor(Vec<u8>),
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -30,15 +34,62 @@ pub struct HidDeviceInfo {
     pub serial_number: Option<String>,
 }
 
+/// Identifies a HID device by its vendor and product id.
+///
+/// Composite devices expose several interfaces with the same ids. These are told apart by `interface_number`.
+/// If it is not given, the first interface found is opened.
 #[derive(Hash, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct HidIdentifier {
     pub pid: u16,
     pub vid: u16,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub interface_number: Option<u8>,
 }
 
 impl HidIdentifier {
     pub fn new(vid: u16, pid: u16) -> Self {
-        HidIdentifier { pid, vid }
+        HidIdentifier {
+            pid,
+            vid,
+            interface_number: None,
+        }
+    }
+
+    pub fn with_interface(vid: u16, pid: u16, interface_number: u8) -> Self {
+        HidIdentifier {
+            pid,
+            vid,
+            interface_number: Some(interface_number),
+        }
+    }
+
+    /// Parse an address of the form `hid::<vid>::<pid>` or `hid::<vid>::<pid>::<interface>`.
+    /// The vendor and product ids are given in hex, the interface number in decimal.
+    pub fn parse(address: &str) -> Result<Self, crate::Error> {
+        let invalid = || {
+            crate::Error::argument(anyhow!(
+                "Invalid HID address `{}`, expected `hid::<vid>::<pid>` or `hid::<vid>::<pid>::<interface>`",
+                address
+            ))
+        };
+        let parts: Vec<_> = address.strip_prefix("hid::").ok_or_else(invalid)?.split("::").collect();
+        let id = |x: &str| u16::from_str_radix(x.trim_start_matches("0x"), 16).map_err(|_| invalid());
+        match parts.as_slice() {
+            [vid, pid] => Ok(Self::new(id(vid)?, id(pid)?)),
+            [vid, pid, interface] => {
+                let interface = interface.parse().map_err(|_| invalid())?;
+                Ok(Self::with_interface(id(vid)?, id(pid)?, interface))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Returns true if the device identified by `other` is addressed by `self`. Devices match on vendor and
+    /// product id, and on the interface number if `self` specifies one.
+    pub fn matches(&self, other: &HidIdentifier) -> bool {
+        self.vid == other.vid
+            && self.pid == other.pid
+            && (self.interface_number.is_none() || self.interface_number == other.interface_number)
     }
 
     pub fn pid(&self) -> u16 {
@@ -49,3 +100,30 @@ impl HidIdentifier {
         self.vid
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(HidIdentifier::parse("hid::1209::0001").unwrap(), HidIdentifier::new(0x1209, 0x0001));
+        assert_eq!(
+            HidIdentifier::parse("hid::0x1209::abcd::2").unwrap(),
+            HidIdentifier::with_interface(0x1209, 0xABCD, 2)
+        );
+        assert!(HidIdentifier::parse("hid::1209").is_err());
+        assert!(HidIdentifier::parse("hid::1209::0001::x").is_err());
+        assert!(HidIdentifier::parse("hid::1209::0001::1::2").is_err());
+        assert!(HidIdentifier::parse("serial::1209::0001").is_err());
+    }
+
+    #[test]
+    fn match_interface() {
+        let device = HidIdentifier::with_interface(0x1209, 0x0001, 1);
+        assert!(HidIdentifier::new(0x1209, 0x0001).matches(&device));
+        assert!(HidIdentifier::with_interface(0x1209, 0x0001, 1).matches(&device));
+        assert!(!HidIdentifier::with_interface(0x1209, 0x0001, 0).matches(&device));
+        assert!(!HidIdentifier::new(0x1209, 0x0002).matches(&device));
+    }
+}
@@ -1,5 +1,6 @@
 use cobs_stream::{CobsStreamRequest, CobsStreamResponse};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Debug;
 use uuid::Uuid;
 
@@ -10,6 +11,7 @@ pub use can::*;
 pub use error::*;
 
 pub use hid::*;
+pub use notification::*;
 pub use scpi::*;
 pub use sigrok::*;
 
@@ -18,12 +20,17 @@ pub mod can;
 pub mod cobs_stream;
 pub mod error;
 pub mod hid;
+pub mod notification;
 pub mod scpi;
 pub mod sigrok;
 mod util;
 
 pub use crate::error::{Error, ProtocolError, TransportError};
 
+/// Version of the request/response API, reported with [`Response::Capabilities`]. Increased on changes which are
+/// not backward compatible.
+pub const API_VERSION: u32 = 1;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Duration {
     pub micros: u32,
@@ -45,7 +52,7 @@ impl From<std::time::Duration> for Duration {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
 pub enum Address {
     Tcp(TcpAddress),
     Ftdi(FtdiAddress),
@@ -54,6 +61,16 @@ pub enum Address {
     Vxi(String),
     Visa(String),
     Can(CanAddress),
+    Custom(CustomAddress),
+    Loopback(LoopbackAddress),
+}
+
+/// Options applied to an instrument with [`Request::SetInstrumentOptions`]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum InstrumentOptions {
+    Tcp(TcpOptions),
+    Visa(VisaOptions),
+    Vxi(VxiOptions),
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -63,6 +80,10 @@ pub enum Request {
         request: ByteStreamRequest,
         #[serde(skip_serializing_if = "Option::is_none", default)]
         lock: Option<Uuid>,
+        /// Truncate responses exceeding the size limit of the `comsrv` instead of failing. The response is then
+        /// wrapped in [`Response::Limited`].
+        #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+        truncate: bool,
     },
     CobsStream {
         instrument: ByteStreamInstrument,
@@ -83,6 +104,10 @@ pub enum Request {
         lock: Option<Uuid>,
         #[serde(default = "Option::default")]
         timeout: Option<Duration>,
+        /// Truncate responses exceeding the size limit of the `comsrv` instead of failing. The response is then
+        /// wrapped in [`Response::Limited`].
+        #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+        truncate: bool,
     },
     Prologix {
         instrument: PrologixInstrument,
@@ -92,9 +117,19 @@ pub enum Request {
         #[serde(default = "Option::default")]
         timeout: Option<Duration>,
     },
+    PrologixAdapter {
+        instrument: PrologixInstrument,
+        request: PrologixAdapterRequest,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        lock: Option<Uuid>,
+    },
     Sigrok {
         instrument: SigrokInstrument,
         request: SigrokRequest,
+        /// Truncate responses exceeding the size limit of the `comsrv` instead of failing. The response is then
+        /// wrapped in [`Response::Limited`].
+        #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+        truncate: bool,
     },
     Hid {
         instrument: HidInstrument,
@@ -108,8 +143,17 @@ pub enum Request {
         #[serde(skip_serializing_if = "Option::is_none", default)]
         lock: Option<Uuid>,
     },
+    /// Control the simulated device of a loopback instrument, replied with [`Response::Done`]
+    Loopback {
+        instrument: LoopbackAddress,
+        request: LoopbackRequest,
+    },
     ListSigrokDevices,
+    SigrokDeviceInfo {
+        addr: String,
+    },
     ListSerialPorts,
+    ListSerialPortsDetailed,
     ListHidDevices,
     ListFtdiDevices,
     ListCanDevices,
@@ -122,11 +166,86 @@ pub enum Request {
         addr: Address,
         id: Uuid,
     },
+    /// Close all connections to the instrument at `addr` and remove it from the inventory, which also discards
+    /// its options. If the instrument is locked, waits for the lock unless `id` matches it.
     Drop {
         addr: Address,
         id: Option<Uuid>,
     },
     DropAll,
+    /// Update the options of an instrument without performing any IO
+    SetInstrumentOptions {
+        addr: Address,
+        options: InstrumentOptions,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        lock: Option<Uuid>,
+    },
+    /// Return the options in effect for the instrument connected at `addr`
+    GetInstrumentOptions {
+        addr: Address,
+    },
+    /// Register `alias` for `addr`. Afterwards, `alias::<alias>` may be used in place of the address string.
+    SetAlias {
+        alias: String,
+        addr: Address,
+    },
+    ListAliases,
+    /// Describe the kind of instrument at `addr` and the requests it supports
+    DescribeInstrument {
+        addr: Address,
+    },
+    /// Return up to `count` of the most recent broadcasts, oldest first. Requires the `comsrv` to be
+    /// started with a replay buffer.
+    ReplayRecent {
+        count: usize,
+    },
+    /// Only send the notifications selected by `filter` to the WebSocket connection this request is sent on,
+    /// replacing the filter of a previous subscription. The default filter selects all notifications. Replied
+    /// with [`Response::Subscribed`], after which the filter is in effect. Has no effect on HTTP connections.
+    Subscribe {
+        filter: NotificationFilter,
+    },
+    /// Reload the configuration file the `comsrv` was started with
+    ReloadConfig,
+    /// Write the aliases and default options currently in effect to the configuration file the `comsrv` was
+    /// started with
+    SaveConfig,
+    /// Exercise the core code paths of the `comsrv` without hardware, replied with [`Response::SelfTest`]
+    SelfTest,
+    /// Run the argument and address validation of the inner request without performing any IO. Replied with
+    /// [`Response::Done`] if the request is valid, otherwise with the error the request would fail with.
+    Validate(Box<Request>),
+    /// Handle `request` under the correlation id `id`, which the `comsrv` logs in place of the id it assigns
+    /// itself. The response is wrapped in [`Response::Echo`] carrying `id`, regardless of `--echo-requests`.
+    WithId {
+        id: Uuid,
+        request: Box<Request>,
+    },
+    /// Check whether the instrument at `addr` is reachable with the cheapest check its transport supports,
+    /// replied with [`Response::Pong`]. No connection is left open by the check.
+    Ping {
+        addr: Address,
+        timeout: Duration,
+    },
+    /// Serve `register_map` to ModBus TCP masters connecting to `bind`, e.g. `0.0.0.0:502`. Replied with
+    /// [`Response::ModbusServer`] carrying the address the server listens on.
+    StartModbusServer {
+        bind: String,
+        register_map: ModBusRegisterMap,
+    },
+    /// Stop the ModBus server listening on `addr` and close its connections
+    StopModbusServer {
+        addr: String,
+    },
+    /// Encode a GCT message to the CAN frames it is sent as, replied with [`Response::CanMessages`]. No bus is
+    /// involved.
+    GctEncode(GctMessage),
+    /// Decode the GCT messages contained in the given CAN frames, replied with [`Response::GctMessages`]. Frames
+    /// which are not part of a GCT message and incomplete DDP messages are ignored. No bus is involved.
+    GctDecode(Vec<CanMessage>),
+    /// List the request types and optional features supported by the `comsrv`, replied with
+    /// [`Response::Capabilities`]
+    Capabilities,
     Version,
     Shutdown,
 }
@@ -136,6 +255,7 @@ pub enum Response {
     Error(Error),
     Instruments(Vec<Address>),
     Scpi(ScpiResponse),
+    PrologixAdapter(PrologixAdapterResponse),
     Bytes(ByteStreamResponse),
     CobsStream(CobsStreamResponse),
     Can {
@@ -154,11 +274,99 @@ pub enum Response {
     },
     Serial(SerialResponse),
     SerialPorts(Vec<String>),
+    SerialPortsDetailed(Vec<SerialPortInfo>),
     FtdiDevices(Vec<FtdiDeviceInfo>),
     CanDevices(Vec<CanDeviceInfo>),
+    Aliases(HashMap<String, Address>),
+    /// Names of the variants of [`Request`] which may be sent to an instrument of the given `kind`
+    InstrumentCapabilities {
+        kind: String,
+        supported_requests: Vec<String>,
+    },
+    RecentBroadcasts(Vec<Response>),
+    /// CAN frames encoding the message of a [`Request::GctEncode`]
+    CanMessages(Vec<CanMessage>),
+    /// GCT messages decoded by a [`Request::GctDecode`]
+    GctMessages(Vec<GctMessage>),
+    /// Result of a [`Request::Capabilities`]. `supported_requests` lists the names of the variants of [`Request`]
+    /// and `features` the optional features enabled in the build or configuration of the `comsrv`.
+    Capabilities {
+        api_version: u32,
+        supported_requests: Vec<String>,
+        features: Vec<String>,
+    },
+    /// Options in effect for an instrument, encoded as JSON object. The fields depend on the kind of instrument.
+    InstrumentOptions(serde_json::Value),
+    /// Result of a [`Request::SelfTest`] with one line of `details` per test
+    SelfTest {
+        passed: bool,
+        details: Vec<String>,
+    },
+    /// Result of a [`Request::Ping`]. `latency_ms` is the duration of the check if the instrument is reachable.
+    Pong {
+        reachable: bool,
+        latency_ms: Option<u64>,
+    },
+    /// Notification broadcast while a binary SCPI response is received from `source`. `bytes` counts the data
+    /// received so far and `total` is the length announced by the block header, if any.
+    TransferProgress {
+        source: Address,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// Wraps the `response` to a request with `truncate` set. `truncated` is set if the response was cut to the
+    /// size limit of the `comsrv`.
+    Limited {
+        truncated: bool,
+        response: Box<Response>,
+    },
+    /// Wraps a `response` with the id and a summary of the originating request. Only sent if the
+    /// `comsrv` is started with `--echo-requests` or in reply to a [`Request::WithId`].
+    Echo {
+        request_id: String,
+        summary: String,
+        response: Box<Response>,
+    },
+    /// The `filter` of a [`Request::Subscribe`] is in effect for the connection
+    Subscribed {
+        filter: NotificationFilter,
+    },
+    /// A ModBus server started with [`Request::StartModbusServer`] is listening on `addr`
+    ModbusServer {
+        addr: String,
+    },
     Done,
 }
 
+impl Address {
+    pub fn serial<T: Into<String>>(port: T) -> Self {
+        Address::Serial(SerialAddress { port: port.into() })
+    }
+
+    pub fn tcp<T: Into<String>>(host: T, port: u16) -> Self {
+        Address::Tcp(TcpAddress {
+            host: host.into(),
+            port,
+        })
+    }
+
+    pub fn can_socket<T: Into<String>>(interface: T) -> Self {
+        Address::Can(CanAddress::SocketCan {
+            interface: interface.into(),
+        })
+    }
+}
+
+impl Response {
+    /// Unwrap the response if it is wrapped in [`Response::Echo`].
+    pub fn without_echo(self) -> Response {
+        match self {
+            Response::Echo { response, .. } => *response,
+            x => x,
+        }
+    }
+}
+
 impl From<std::result::Result<Response, Error>> for Response {
     fn from(x: std::result::Result<Response, Error>) -> Self {
         match x {
@@ -176,10 +384,14 @@ pub enum SerialRequest {
     ReadRingIndicator,
     ReadCarrierDetect,
     ReadClearToSend,
+    /// Return the configuration the driver applied to the open port, replied with [`SerialResponse::Config`].
+    /// Some adapters snap the requested baud rate to the nearest supported one.
+    GetActualConfig,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum SerialResponse {
     PinLevel(bool),
+    Config(SerialPortConfig),
     Done,
 }
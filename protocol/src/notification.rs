@@ -0,0 +1,87 @@
+use crate::{CanAddress, CanResponse, Response};
+use serde::{Deserialize, Serialize};
+
+/// Kinds of notifications broadcast by the `comsrv`
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum NotificationKind {
+    Can,
+    CobsStream,
+    Error,
+}
+
+/// Selects notifications by their kind and CAN notifications additionally by source and message id.
+/// Criteria which are left empty match all notifications.
+///
+/// Sent with [`crate::Request::Subscribe`], the `comsrv` applies the filter to the notifications sent on a
+/// connection. Clients may also apply it to the notifications they received.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct NotificationFilter {
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub kinds: Vec<NotificationKind>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub can_source: Option<CanAddress>,
+    /// If not empty, only raw CAN frames with one of the given ids are selected. Other CAN notifications,
+    /// such as GCT messages, are not affected.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub can_ids: Vec<u32>,
+}
+
+impl NotificationFilter {
+    pub fn matches(&self, notification: &Response) -> bool {
+        let kind = match notification {
+            Response::Can { .. } => NotificationKind::Can,
+            Response::CobsStream(_) => NotificationKind::CobsStream,
+            Response::Error(_) => NotificationKind::Error,
+            _ => return false,
+        };
+        if !self.kinds.is_empty() && !self.kinds.contains(&kind) {
+            return false;
+        }
+        match notification {
+            Response::Can { source, response } => {
+                if self.can_source.as_ref().is_some_and(|x| x != source) {
+                    return false;
+                }
+                match response {
+                    CanResponse::Raw(msg) if !self.can_ids.is_empty() => {
+                        self.can_ids.contains(&msg.id())
+                    }
+                    _ => true,
+                }
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CanMessage, DataFrame, GctMessage};
+
+    #[test]
+    fn can_ids_select_raw_frames() {
+        let filter = NotificationFilter {
+            can_ids: vec![0x10],
+            ..Default::default()
+        };
+        let raw = |id| Response::Can {
+            source: CanAddress::Loopback,
+            response: CanResponse::Raw(CanMessage::Data(DataFrame {
+                id,
+                ext_id: false,
+                data: vec![],
+            })),
+        };
+        assert!(filter.matches(&raw(0x10)));
+        assert!(!filter.matches(&raw(0x11)));
+        let gct = Response::Can {
+            source: CanAddress::Loopback,
+            response: CanResponse::Gct(GctMessage::Heartbeat {
+                src: 5,
+                product_id: 1,
+            }),
+        };
+        assert!(filter.matches(&gct));
+    }
+}
@@ -1,17 +1,47 @@
 use serde::{Deserialize, Serialize};
 
 use crate::bytestream::SerialAddress;
+use crate::{Address, Duration};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct VxiInstrument {
     pub host: String,
 }
 
+/// Options of a VXI-11 instrument, see [`crate::Request::SetInstrumentOptions`]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct VxiOptions {
+    /// Timeout of requests which do not specify their own timeout
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub timeout: Option<Duration>,
+    /// Maximum number of bytes read for a single string response
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_read_length: Option<u32>,
+    /// Maximum number of data bytes of a binary block, i.e. of the response to a `QueryBinary`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_binary_length: Option<u32>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct VisaInstrument {
     pub address: String,
 }
 
+/// Options of a VISA instrument, see [`crate::Request::SetInstrumentOptions`]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct VisaOptions {
+    /// Maximum number of bytes read for a single response
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_read_length: Option<u32>,
+    /// Maximum number of chunks read for a single response. Bounds reads from instruments which keep announcing
+    /// more data without sending it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_read_chunks: Option<u32>,
+    /// Maximum number of data bytes of a binary block, i.e. of the response to a `QueryBinary`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_binary_length: Option<u32>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PrologixInstrument {
     pub address: SerialAddress,
@@ -23,6 +53,27 @@ pub struct PrologixRequest {
     pub scpi: ScpiRequest,
 }
 
+/// Requests addressed to the Prologix adapter itself rather than to an instrument on the GPIB bus.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum PrologixAdapterRequest {
+    /// Configure `++auto`, `++eoi`, `++eos` and `++read_tmo_ms`. The configuration is kept and
+    /// re-applied whenever the adapter is re-initialized.
+    Configure {
+        auto: bool,
+        eoi: bool,
+        eos: u8,
+        read_timeout_ms: u32,
+    },
+    /// Serial poll the GPIB addresses 1 to 30 and list the ones responding within `timeout`.
+    ScanBus { timeout: Duration },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum PrologixAdapterResponse {
+    Done,
+    Addresses(Vec<u8>),
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ScpiInstrument {
     Vxi(VxiInstrument),
@@ -35,6 +86,16 @@ pub enum ScpiRequest {
     QueryString(String),
     QueryBinary(String),
     ReadRaw,
+    /// Lock the instrument in the VISA driver with `viLock`. This lock is shared with other VISA applications and
+    /// is independent of the locks of the comsrv inventory. Shared locks reply with the access key granted by
+    /// VISA, exclusive locks with [`ScpiResponse::Done`]. Only supported by VISA instruments.
+    VisaLock { exclusive: bool, timeout_ms: u32 },
+    /// Release a lock acquired with [`ScpiRequest::VisaLock`].
+    VisaUnlock,
+    /// Read a VISA attribute, see [`visa_attributes`] for the supported attributes
+    GetAttribute { code: u32 },
+    /// Write a VISA attribute, see [`visa_attributes`] for the supported attributes
+    SetAttribute { code: u32, value: u64 },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -48,4 +109,45 @@ pub enum ScpiResponse {
         )]
         data: Vec<u8>,
     },
+    /// The access key of a shared VISA lock
+    AccessKey(String),
+    /// The value of a VISA attribute
+    Attribute(u64),
+}
+
+/// The VISA attributes which may be accessed with [`ScpiRequest::GetAttribute`] and [`ScpiRequest::SetAttribute`]
+pub mod visa_attributes {
+    /// Timeout of I/O operations in milliseconds
+    pub const VI_ATTR_TMO_VALUE: u32 = 0x3FFF001A;
+    /// Termination character of reads
+    pub const VI_ATTR_TERMCHAR: u32 = 0x3FFF0018;
+    /// Whether reads terminate on the termination character
+    pub const VI_ATTR_TERMCHAR_EN: u32 = 0x3FFF0038;
+    /// Whether END is asserted with the last byte of writes
+    pub const VI_ATTR_SEND_END_EN: u32 = 0x3FFF0016;
+    /// Whether reads ignore END, such that they only terminate on the termination character
+    pub const VI_ATTR_SUPPRESS_END_EN: u32 = 0x3FFF0036;
+
+    pub const ALL: &[u32] = &[
+        VI_ATTR_TMO_VALUE,
+        VI_ATTR_TERMCHAR,
+        VI_ATTR_TERMCHAR_EN,
+        VI_ATTR_SEND_END_EN,
+        VI_ATTR_SUPPRESS_END_EN,
+    ];
+}
+
+impl ScpiInstrument {
+    pub fn address(&self) -> Address {
+        match self {
+            ScpiInstrument::Vxi(x) => Address::Vxi(x.host.clone()),
+            ScpiInstrument::Visa(x) => Address::Visa(x.address.clone()),
+        }
+    }
+}
+
+impl From<ScpiInstrument> for Address {
+    fn from(val: ScpiInstrument) -> Self {
+        val.address()
+    }
 }
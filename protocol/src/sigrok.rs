@@ -13,6 +13,9 @@ pub struct SigrokRequest {
     pub channels: Vec<String>,
     pub acquire: SigrokAcquire,
     pub sample_rate: u64,
+    /// Acquire analog samples instead of logic levels, replied with [`SigrokResponse::AnalogData`]
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub analog: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -25,6 +28,12 @@ pub enum SigrokAcquire {
 pub enum SigrokResponse {
     Data(SigrokData),
     Devices(Vec<SigrokDevice>),
+    DeviceInfo(SigrokDeviceInfo),
+    AnalogData {
+        tsample: f64,
+        length: usize,
+        channels: HashMap<String, Vec<f32>>,
+    },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -33,6 +42,25 @@ pub struct SigrokDevice {
     pub desc: String,
 }
 
+/// Channels and sample rates supported by a sigrok device.
+///
+/// Devices either report a list of discrete sample rates in `sample_rates` or a continuous
+/// range in `sample_rate_range`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SigrokDeviceInfo {
+    pub channels: Vec<String>,
+    pub sample_rates: Vec<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub sample_rate_range: Option<SigrokSampleRateRange>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SigrokSampleRateRange {
+    pub min: u64,
+    pub max: u64,
+    pub step: u64,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SigrokData {
     pub tsample: f64,
@@ -3,6 +3,7 @@ This modules implements the API of the `comsrv` utility to connect
 to instruments.
 """
 
+import base64
 from dataclasses import dataclass
 import json
 from enum import Enum
@@ -70,6 +71,10 @@ class ProtocolError(ComSrvError):
         assert isinstance(data, dict)
         if "Timeout" in data:
             return ProtocolTimeoutError()
+        if "InvalidUtf8" in data:
+            invalid = data["InvalidUtf8"]
+            assert isinstance(invalid, dict)
+            return InvalidUtf8Error(base64.b64decode(invalid["data"]))  # type: ignore
         if "Other" in data:
             other = data["Other"]
             assert isinstance(other, dict)
@@ -80,6 +85,16 @@ class ProtocolError(ComSrvError):
         return ProtocolError(data)
 
 
+class InvalidUtf8Error(ProtocolError):
+    """
+    Occurs if a reply cannot be decoded as UTF-8. The raw bytes are available as `data`.
+    """
+
+    def __init__(self, data: bytes) -> None:
+        super().__init__("Cannot decode {} bytes as UTF-8".format(len(data)))
+        self.data = data
+
+
 class ProtocolTimeoutError(ProtocolError):
     """
     Occurs if an operation times out on protocol level.
@@ -180,6 +195,16 @@ class Rpc(object):
         return HttpRpc()
 
 
+def _without_echo(response: JsonObject) -> JsonObject:
+    """
+    Unwrap a response which the comsrv wrapped with its request if started with `--echo-requests`.
+    """
+    echo = response.get("Echo")
+    if isinstance(echo, dict):
+        return echo["response"]  # type: ignore
+    return response
+
+
 class HttpRpc(Rpc):
     """
     RPC service implementation using HTTP as transport
@@ -202,7 +227,7 @@ class HttpRpc(Rpc):
                 json_data = json.loads(await resp.text())
                 if resp.status != 200:
                     raise ComSrvError(data)
-                return json_data
+                return _without_echo(json_data)
 
     @classmethod
     def make_default(cls) -> "Rpc":
@@ -231,7 +256,7 @@ class WsRpc(Rpc):
         ret = await self._client.request(request, timeout)
         if not isinstance(ret, dict):
             raise ComSrvError(f"Got wrong JSON type. Expected dict, got {type(ret)}")
-        return ret
+        return _without_echo(ret)
 
     async def connect(self, url: str | None = None) -> "WsRpc":
         """
@@ -454,6 +479,7 @@ class HidDeviceInfo:
     manufacturer: Optional[str] = None
     product: Optional[str] = None
     serial_number: Optional[str] = None
+    interface_number: Optional[int] = None
 
 
 class CanDriverType(Enum):
@@ -530,6 +556,123 @@ class ComSrv(object):
         assert isinstance(instruments, list)
         return instruments  # type: ignore
 
+    async def set_alias(self, alias: str, addr: Address) -> None:
+        """
+        Register `alias` for `addr`. Afterwards, `alias::<alias>` may be used in place of the address string.
+        """
+        result = await self.get({"SetAlias": {"alias": alias, "addr": addr.to_json_enum()}})
+        ComSrvError.check_raise(result)
+
+    async def list_aliases(self) -> JsonObject:
+        result = await self.get({"ListAliases": None})
+        ComSrvError.check_raise(result)
+        return result["Aliases"]  # type: ignore
+
+    async def describe_instrument(self, addr: Address) -> JsonObject:
+        """
+        Query the kind of instrument at `addr` and the names of the requests it supports.
+        Returns a dict with the keys `kind` and `supported_requests`.
+        """
+        result = await self.get({"DescribeInstrument": {"addr": addr.to_json_enum()}})
+        ComSrvError.check_raise(result)
+        return result["InstrumentCapabilities"]  # type: ignore
+
+    async def get_instrument_options(self, addr: Address) -> JsonObject:
+        """
+        Query the options in effect for the instrument connected at `addr`.
+        The keys of the returned dict depend on the kind of instrument.
+        """
+        result = await self.get({"GetInstrumentOptions": {"addr": addr.to_json_enum()}})
+        ComSrvError.check_raise(result)
+        return result["InstrumentOptions"]  # type: ignore
+
+    async def replay_recent(self, count: int) -> List[JsonObject]:
+        """
+        Query up to `count` of the most recent broadcasts, oldest first.
+        Requires the comsrv to be started with `--replay-buffer`.
+        """
+        result = await self.get({"ReplayRecent": {"count": count}})
+        ComSrvError.check_raise(result)
+        return result["RecentBroadcasts"]  # type: ignore
+
+    async def self_test(self) -> Tuple[bool, List[str]]:
+        """
+        Run the self-test of the comsrv, which exercises its core code paths without any hardware.
+        Returns whether all tests passed and one line per test describing its result.
+        """
+        result = await self.get({"SelfTest": None})
+        ComSrvError.check_raise(result)
+        self_test = result["SelfTest"]  # type: ignore
+        return self_test["passed"], self_test["details"]  # type: ignore
+
+    async def start_modbus_server(
+        self,
+        bind: str,
+        coils: Optional[List[bool]] = None,
+        discretes: Optional[List[bool]] = None,
+        inputs: Optional[List[int]] = None,
+        holdings: Optional[List[int]] = None,
+    ) -> str:
+        """
+        Serve a register map to ModBus TCP masters connecting to `bind`, e.g. "0.0.0.0:502".
+        Each table starts at address 0. Returns the address the server listens on.
+        """
+        register_map = {
+            "coils": coils or [],
+            "discretes": discretes or [],
+            "inputs": inputs or [],
+            "holdings": holdings or [],
+        }
+        result = await self.get(
+            {"StartModbusServer": {"bind": bind, "register_map": register_map}}
+        )
+        ComSrvError.check_raise(result)
+        return result["ModbusServer"]["addr"]  # type: ignore
+
+    async def stop_modbus_server(self, addr: str) -> None:
+        result = await self.get({"StopModbusServer": {"addr": addr}})
+        ComSrvError.check_raise(result)
+
+    async def ping(self, addr: Address, timeout: float = 1.0) -> Optional[float]:
+        """
+        Check whether the instrument at `addr` is reachable within `timeout` seconds without leaving a
+        connection open. Returns the duration of the check in seconds if it is reachable, `None` otherwise.
+        """
+        result = await self.get(
+            {"Ping": {"addr": addr.to_json_enum(), "timeout": duration_to_json(timeout)}},
+            timeout + self._timeout,
+        )
+        ComSrvError.check_raise(result)
+        pong = result["Pong"]  # type: ignore
+        if not pong["reachable"] or pong["latency_ms"] is None:  # type: ignore
+            return None
+        return pong["latency_ms"] / 1000  # type: ignore
+
+    async def reload_config(self) -> None:
+        result = await self.get({"ReloadConfig": None})
+        ComSrvError.check_raise(result)
+
+    async def save_config(self) -> None:
+        result = await self.get({"SaveConfig": None})
+        ComSrvError.check_raise(result)
+
+    async def validate(self, request: JsonType) -> None:
+        """
+        Check the arguments and address of `request` without performing any IO.
+        Raises the error the request would fail with, if it is invalid.
+        """
+        result = await self.get({"Validate": request})
+        ComSrvError.check_raise(result)
+
+    async def capabilities(self) -> JsonObject:
+        """
+        Query the API version, request types and optional features supported by the comsrv.
+        Returns a dict with the keys `api_version`, `supported_requests` and `features`.
+        """
+        result = await self.get({"Capabilities": None})
+        ComSrvError.check_raise(result)
+        return result["Capabilities"]  # type: ignore
+
     async def list_hid_devices(self) -> List[HidDeviceInfo]:
         from .hid import enumerate_hid_devices
 
@@ -577,6 +720,8 @@ from .bytestream import (  # noqa: E402
     ByteStreamPipe,
     FtdiAddress,
     FtdiInstrument,
+    LoopbackAddress,
+    LoopbackInstrument,
     SerialAddress,
     SerialInstrument,
     SerialPortConfig,
@@ -584,7 +729,11 @@ from .bytestream import (  # noqa: E402
     TcpInstrument,
 )
 from .can import CanBus  # noqa: E402
-from .hid import HidDevice, enumerate_hid_devices  # noqa: E402
+from .hid import (  # noqa: E402
+    HidDevice,
+    enumerate_hid_devices,
+    summarize_report_descriptor,
+)
 from .modbus import ModBusDevice  # noqa: E402
 from .scpi import ScpiPipe, SerialScpiPipe  # noqa: E402
 from .sigrok import SigrokDevice  # noqa: E402
@@ -595,6 +744,8 @@ __all__ = [
     "ByteStreamPipe",
     "FtdiAddress",
     "FtdiInstrument",
+    "LoopbackAddress",
+    "LoopbackInstrument",
     "SerialAddress",
     "SerialInstrument",
     "SerialPortConfig",
@@ -603,6 +754,7 @@ __all__ = [
     "CanBus",
     "HidDevice",
     "enumerate_hid_devices",
+    "summarize_report_descriptor",
     "ModBusDevice",
     "ScpiPipe",
     "SerialScpiPipe",
@@ -1,5 +1,5 @@
 import asyncio
-from typing import Any, Optional, Union
+from typing import Any, Optional, Sequence, Tuple, Union
 from broadcast_wsrpc import Client, JsonType, JsonObject
 from . import (
     Address,
@@ -42,8 +42,11 @@ class ByteStreamInstrument(Instrument):
         Supported strings are of the form:
 
          - `serial::<path-to-serial>::<baud-rate>::<settings>` - For serial ports
+         - `serial::usb:<vid>:<pid>[:<serial-number>]::<baud-rate>::<settings>` - For USB serial adapters,
+            independent of the assigned port name
          - `ftdi::<serial-number>::<baud-rate>::<settings>` - For FTDI devices
          - `tcp::<host_or_ip>:<port>` - Connect to TCP socket
+         - `loopback::<name>` - In-memory instrument for testing
         """
         address_string = address_string.strip()
         if address_string.startswith("serial::"):
@@ -75,8 +78,11 @@ class ByteStreamInstrument(Instrument):
             if port < 1 or port > 65535:
                 raise ValueError("Port needs to be in range (1, 65535)")
             return TcpInstrument(TcpAddress(host_or_ip, port))
+        elif address_string.startswith("loopback::"):
+            name = address_string.replace("loopback::", "", 1)
+            return LoopbackInstrument(LoopbackAddress(name))
         raise ValueError(
-            "Invalid address. Must start with `serial::` or `ftdi::` or `tcp::`"
+            "Invalid address. Must start with `serial::` or `ftdi::` or `tcp::` or `loopback::`"
         )
 
     @classmethod
@@ -137,6 +143,36 @@ class TcpInstrument(ByteStreamInstrument):
         return {"Tcp": {"address": self.address.to_json()}}
 
 
+class LoopbackAddress(ByteStreamAddress):
+    def __init__(self, name: str) -> None:
+        self.name = name
+
+    def to_json(self) -> JsonObject:
+        return {"name": self.name}
+
+    @property
+    def enum_name(self) -> str:
+        return "Loopback"
+
+
+class LoopbackInstrument(ByteStreamInstrument):
+    """
+    In-memory instrument which echoes all data written to it, unless responses were scripted for it
+    on the comsrv. Intended for testing.
+    """
+
+    def __init__(self, address: LoopbackAddress) -> None:
+        super().__init__()
+        self._address = address
+
+    @property
+    def address(self) -> Address:
+        return self._address
+
+    def to_json(self) -> JsonObject:
+        return {"Loopback": self.address.to_json()}
+
+
 class SerialPortConfig(object):
     def __init__(self, config: str, baudrate: int) -> None:
         self.config = config
@@ -191,8 +227,11 @@ class ByteStreamPipe(BasePipe):
     The constructor accepts resource strings to describe the instrument and its configuration:
 
     - `serial::<path-to-serial>::<baud-rate>::<settings>` - For serial ports
+    - `serial::usb:<vid>:<pid>[:<serial-number>]::<baud-rate>::<settings>` - For USB serial adapters,
+        independent of the assigned port name
     - `ftdi::<serial-number>::<baud-rate>::<settings>` - For FTDI devices
     - `tcp::<host_or_ip>:<port>` - Connect to TCP socket
+    - `loopback::<name>` - In-memory instrument for testing
 
     :param instrumnt: Either a `ByteStreamInstrument` or a resource string describing an appropriate
         instrument.
@@ -243,6 +282,12 @@ class ByteStreamPipe(BasePipe):
         """
         await self.request({"Write": list(data)})
 
+    async def flush(self) -> None:
+        """
+        Write all data buffered by the comsrv to the instrument.
+        """
+        await self.request("Flush")
+
     async def read_all(self) -> bytes:
         """
         Read all data in the buffer of the stream and returns without
@@ -252,6 +297,31 @@ class ByteStreamPipe(BasePipe):
         data = bytes(result["Data"])  # type: ignore
         return data
 
+    async def read_all_max(self, max_bytes: int) -> bytes:
+        """
+        Same as `read_all` but returns at most `max_bytes`. Remaining data
+        stays in the buffer of the stream.
+        """
+        result = await self.request({"ReadAllMax": {"max_bytes": max_bytes}})
+        data = bytes(result["Data"])  # type: ignore
+        return data
+
+    async def read_all_stream(self, idle: float, timeout: float) -> None:
+        """
+        Read data as it arrives until no data arrived for `idle` seconds or `timeout` elapsed.
+        The data is not returned but broadcast in `{"Bytes": {"Chunk": {"sender": ..., "data": ...}}}`
+        notifications.
+        """
+        await self.request(
+            {
+                "ReadAllStream": {
+                    "idle": duration_to_json(idle),
+                    "timeout": duration_to_json(timeout),
+                }
+            },
+            timeout=timeout + idle + 1.0,
+        )
+
     async def read_to_term(self, term: int, timeout: float) -> bytes:
         """
         Read from the stream until the termination character is found.
@@ -274,29 +344,81 @@ class ByteStreamPipe(BasePipe):
         data = bytes(result["Data"])  # type: ignore
         return data
 
+    async def read_up_to(self, count: int, timeout: float = 0.0) -> bytes:
+        """
+        Read up to `count` bytes from the stream. Returns as soon as any data is
+        available or an empty buffer if no data arrived within `timeout`.
+        With a zero `timeout`, only data already buffered is returned.
+        """
+        result = await self.request(
+            {"ReadUpTo": {"count": count, "timeout": duration_to_json(timeout)}},
+            timeout=timeout + self._timeout,
+        )
+        data = bytes(result["Data"])  # type: ignore
+        return data
+
+    async def read_length_prefixed(
+        self, prefix_len: int, max_len: int, timeout: float, big_endian: bool = True
+    ) -> bytes:
+        """
+        Read a message preceded by a length prefix of `prefix_len` bytes (1, 2 or 4)
+        and return the payload. Fails if the length exceeds `max_len`.
+        """
+        result = await self.request(
+            {
+                "ReadLengthPrefixed": {
+                    "prefix_len": prefix_len,
+                    "endian": "Big" if big_endian else "Little",
+                    "max_len": max_len,
+                    "timeout": duration_to_json(timeout),
+                }
+            },
+            timeout=timeout + self._timeout,
+        )
+        return bytes(result["Data"])  # type: ignore
+
     async def cobs_write(self, data: bytes) -> None:
         """
         Apply the COBS framing to the provided `data` and write it to the stream.
         """
         await self.request({"CobsWrite": list(data)})
 
-    async def cobs_read(self, timeout: float) -> bytes:
+    async def cobs_read(
+        self, timeout: float, max_frame_size: Optional[int] = None
+    ) -> bytes:
         """
         Read a COBS encoded frame from stream.
+        If `max_frame_size` is given, the read fails if the encoded frame exceeds
+        `max_frame_size` bytes instead of the default limit of the comsrv.
         """
-        result = await self.request(
-            {"CobsRead": duration_to_json(timeout)},
-            timeout=timeout + self._timeout,
-        )
+        if max_frame_size is None:
+            request: JsonType = {"CobsRead": duration_to_json(timeout)}
+        else:
+            request = {
+                "CobsReadMax": {
+                    "timeout": duration_to_json(timeout),
+                    "max_frame_size": max_frame_size,
+                }
+            }
+        result = await self.request(request, timeout=timeout + self._timeout)
         data = bytes(result["Data"])  # type: ignore
         return data
 
-    async def cobs_query(self, data: bytes, timeout: float) -> bytes:
+    async def cobs_query(
+        self, data: bytes, timeout: float, flush_input: bool = True
+    ) -> bytes:
         """
         This is a combination of `cobs_write` followed by a `cobs_read` call.
+        If `flush_input` is set, buffered input is discarded before writing.
         """
         result = await self.request(
-            {"CobsQuery": {"data": list(data), "timeout": duration_to_json(timeout)}},
+            {
+                "CobsQuery": {
+                    "data": list(data),
+                    "timeout": duration_to_json(timeout),
+                    "flush_input": flush_input,
+                }
+            },
             timeout=timeout + self._timeout,
         )
         data = bytes(result["Data"])  # type: ignore
@@ -329,9 +451,11 @@ class ByteStreamPipe(BasePipe):
         line: str,
         timeout: float,
         term: Union[int, str] = "\n",
+        flush_input: bool = True,
     ) -> str:
         """
         This is a combination of `write_line` followed by a `read_line` call.
+        If `flush_input` is set, buffered input is discarded before writing.
         """
         if isinstance(term, str):
             assert len(term) == 1
@@ -342,6 +466,7 @@ class ByteStreamPipe(BasePipe):
                     "line": line,
                     "term": term,
                     "timeout": duration_to_json(timeout),
+                    "flush_input": flush_input,
                 }
             },
             timeout=timeout + self._timeout,
@@ -350,16 +475,46 @@ class ByteStreamPipe(BasePipe):
 
     async def disconnect(self) -> None:
         """
-        Disconnect the underlying handle.
+        Disconnect the underlying handle. Data buffered by the comsrv is written first.
+        The instrument is not dropped from the comsrv and reconnects on the next request.
         """
         await self.request("Disconnect")
 
+    async def close(self, flush: bool = True) -> None:
+        """
+        Same as `disconnect()`, but data buffered by the comsrv is only written if `flush` is set
+        and discarded otherwise.
+        """
+        await self.request({"Close": {"flush": flush}})
+
     async def connect(self) -> None:
         """
         Connect the underlying handle
         """
         await self.request("Connect")
 
+    async def script_loopback(self, exchanges: Sequence[Tuple[bytes, bytes]]) -> None:
+        """
+        Script the simulated device of a loopback instrument to reply with `response` once it
+        received `request`, for each `(request, response)` pair in order. The pairs are appended
+        to the pairs not yet consumed. Once all pairs are consumed, the device echoes again.
+        """
+        if not isinstance(self._instrument, LoopbackInstrument):
+            raise ValueError("Only loopback instruments may be scripted")
+        script = [
+            {"request": list(request), "response": list(response)}
+            for (request, response) in exchanges
+        ]
+        result = await self.get(
+            {
+                "Loopback": {
+                    "instrument": self._instrument.address.to_json(),
+                    "request": {"Script": script},
+                }
+            }
+        )
+        ComSrvError.check_raise(result)
+
 
 class CobsStream:
     def __init__(
@@ -368,11 +523,19 @@ class CobsStream:
         use_crc: bool,
         maxsize: int = 0,
         client: Optional[Client] = None,
+        crc: Optional[str] = None,
     ) -> None:
+        """
+        `crc` selects the CRC appended to each frame, one of `"None"`, `"Crc16"` or `"Crc32"`.
+        If given, it takes precedence over `use_crc`, which selects a CRC-16.
+        """
+        if crc is not None and crc not in ("None", "Crc16", "Crc32"):
+            raise ValueError(f"Invalid CRC kind: {crc}")
         if not isinstance(instrument, ByteStreamInstrument):
             instrument = ByteStreamInstrument.parse(instrument)
         self._instrument = instrument
         self._use_crc = use_crc
+        self._crc = crc
         self._receiver_task: asyncio.Task[None] | None = None
         if client is None:
             client = Client()
@@ -390,7 +553,10 @@ class CobsStream:
     async def start(self) -> None:
         await self.connect()
         self._receiver_task = asyncio.create_task(self._receive_loop())
-        await self.rpc({"Start": {"use_crc": self._use_crc}})
+        start: JsonObject = {"use_crc": self._use_crc}
+        if self._crc is not None:
+            start = {"use_crc": self._crc != "None", "crc": self._crc}
+        await self.rpc({"Start": start})
 
     async def rpc(self, request: JsonType) -> JsonObject:
         await self.connect()
@@ -437,9 +603,23 @@ class CobsStream:
     async def send(self, data: bytes) -> None:
         await self.rpc({"SendFrame": {"data": list(data)}})
 
+    async def flush(self) -> None:
+        """
+        Wait until all frames sent before are written to the underlying stream.
+        """
+        await self.rpc({"Flush": None})
+
     async def stop(self) -> None:
         await self.rpc({"Stop": None})
 
+    async def status(self) -> Tuple[bool, bool]:
+        """
+        Returns whether the stream is alive and whether it uses a CRC.
+        """
+        result = await self.rpc({"Status": None})
+        status = result["Status"]  # type: ignore
+        return status["alive"], status["use_crc"]  # type: ignore
+
     async def close(self, stop: bool = True) -> None:
         if stop:
             await self.stop()
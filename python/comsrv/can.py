@@ -10,7 +10,7 @@ import re
 from enum import Enum
 
 
-PCAN_RE = re.compile(r"(?P<addr>.*?)::(?P<bitrate>.*?)$")
+PCAN_RE = re.compile(r"(?P<addr>[^:]+)(::(?P<bitrate>\d+))?$")
 
 
 class CanAddress(Address):
@@ -54,18 +54,26 @@ class LoopbackAddress(CanAddress):
 
 class CanInstrument(Instrument):
     def __init__(self, address: CanAddress, bitrate: int = 0) -> None:
+        """
+        :param bitrate: The bitrate of PCAN instruments in bit/s. If 0, the default bitrate
+            of the comsrv is used.
+        """
         self._address = address
-        self._baudrate = bitrate
+        self._bitrate = bitrate
         super().__init__()
 
     @property
     def address(self) -> Address:
         return self._address
 
+    @property
+    def bitrate(self) -> int:
+        return self._bitrate
+
     def to_json(self) -> JsonType:
         if isinstance(self._address, PCanAddress):
             return {
-                "PCan": {"address": self._address.address, "bitrate": self._baudrate}
+                "PCan": {"address": self._address.address, "bitrate": self._bitrate}
             }
         elif isinstance(self._address, SocketCanAddress):
             return self._address.to_json()
@@ -87,7 +95,7 @@ class CanInstrument(Instrument):
             if match is None:
                 raise ValueError("PCan address is invalid.")
             addr = match.group("addr")
-            bitrate = int(match.group("bitrate"))
+            bitrate = int(match.group("bitrate") or 0)
             return CanInstrument(PCanAddress(addr), bitrate=bitrate)
         elif addr.startswith("can::socket::"):
             addr = addr.replace("can::socket::", "", 1)
@@ -208,6 +216,51 @@ class CanBus(object):
     async def enable_loopback(self, loopback: bool = True) -> None:
         await self.rpc({"EnableLoopback": loopback})
 
+    async def start_recording(self, path: str) -> None:
+        """
+        Record all frames received on the bus to `path` in the candump log format.
+        `path` is the name of a new file in the recording directory of the comsrv,
+        which must be started with `--recording-dir`.
+        """
+        await self.rpc({"StartRecording": {"path": path}})
+
+    async def stop_recording(self) -> None:
+        """
+        Stop recording and wait until all frames are written.
+        """
+        await self.rpc("StopRecording")
+
+    async def replay_file(self, path: str, loop: bool = False, speed: float = 1.0) -> None:
+        """
+        Transmit the frames of a candump or ASC log file at `path` with their original timing.
+
+        :param path: Name of the log file in the recording directory of the comsrv
+        :param loop: True to replay the log until `stop_replay()` is called
+        :param speed: Factor by which the replay is sped up
+        """
+        await self.rpc({"ReplayFile": {"path": path, "loop": loop, "speed": speed}})
+
+    async def stop_replay(self) -> None:
+        """
+        Stop an ongoing replay.
+        """
+        await self.rpc("StopReplay")
+
+    async def clear_rx(self) -> None:
+        """
+        Discard frames which were received by the comsrv but not yet processed, as well as
+        partially received GCT messages.
+        """
+        await self.rpc("ClearRx")
+
+    async def set_backpressure(self, enable: bool = True) -> None:
+        """
+        Pause reception while the receive queue of the comsrv is full, which is the default.
+        Persistent backlogs then cause receive overruns in the driver or the adapter. If disabled,
+        frames received while the queue is full are dropped instead.
+        """
+        await self.rpc({"SetBackpressure": enable})
+
     async def rpc(self, task: JsonType, rx_reply: bool = True) -> JsonObject | None:
         """
         Perform an RPC to the CAN endpoint.
@@ -1,17 +1,41 @@
-from typing import List, Optional
+from typing import Dict, List, Optional
 
 from . import Address, BasePipe, ComSrvError, Rpc, duration_to_json, HidDeviceInfo
 from broadcast_wsrpc import JsonObject, JsonType
 
 
 class HidAddress(Address):
-    def __init__(self, pid: int, vid: int) -> None:
+    def __init__(
+        self, pid: int, vid: int, interface_number: Optional[int] = None
+    ) -> None:
         super().__init__()
         self.pid = pid
         self.vid = vid
+        self.interface_number = interface_number
+
+    @classmethod
+    def parse(cls, address_string: str) -> "HidAddress":
+        """
+        Parse an address of the form `hid::<vid>::<pid>` or `hid::<vid>::<pid>::<interface>`.
+        The vendor and product ids are given in hex, the interface number in decimal.
+        """
+        if not address_string.startswith("hid::"):
+            raise ValueError("Invalid address. Must start with `hid::`")
+        parts = address_string.replace("hid::", "", 1).split("::")
+        if len(parts) not in (2, 3):
+            raise ValueError(
+                "Invalid address. Expected `hid::<vid>::<pid>` or `hid::<vid>::<pid>::<interface>`"
+            )
+        vid = int(parts[0], 16)
+        pid = int(parts[1], 16)
+        interface_number = int(parts[2]) if len(parts) == 3 else None
+        return cls(pid, vid, interface_number)
 
     def to_json(self) -> JsonObject:
-        return {"pid": self.pid, "vid": self.vid}
+        ret: JsonObject = {"pid": self.pid, "vid": self.vid}
+        if self.interface_number is not None:
+            ret["interface_number"] = self.interface_number
+        return ret
 
     @property
     def enum_name(self) -> str:
@@ -23,7 +47,7 @@ class HidInstrument(object):
         self._address = address
 
     def to_json(self) -> JsonObject:
-        return {"address": {"pid": self._address.pid, "vid": self._address.vid}}
+        return {"address": self._address.to_json()}
 
     @property
     def address(self) -> HidAddress:
@@ -31,8 +55,14 @@ class HidInstrument(object):
 
 
 class HidDevice(BasePipe):
-    def __init__(self, vid: int, pid: int, rpc: Optional[Rpc] = None):
-        self._instrument = HidInstrument(HidAddress(vid, pid))
+    def __init__(
+        self,
+        vid: int,
+        pid: int,
+        rpc: Optional[Rpc] = None,
+        interface_number: Optional[int] = None,
+    ):
+        self._instrument = HidInstrument(HidAddress(pid, vid, interface_number))
         super().__init__(address=self._instrument.address, rpc=rpc)
 
     async def request(self, request: JsonType) -> JsonObject:
@@ -58,11 +88,21 @@ class HidDevice(BasePipe):
         return HidDeviceInfo(
             vid=dev["idn"]["vid"],  # type: ignore
             pid=dev["idn"]["pid"],  # type: ignore
+            interface_number=dev["idn"].get("interface_number"),  # type: ignore
             manufacturer=dev.get("manufacturer"),  # type: ignore
             product=dev.get("product"),  # type: ignore
             serial_number=dev.get("serial_number"),  # type: ignore
         )
 
+    async def get_report_descriptor(self) -> bytes:
+        """
+        Read the raw report descriptor of the device. Use `summarize_report_descriptor()` to decode it.
+
+        Only supported if the `comsrv` runs on Linux.
+        """
+        result = await self.request("GetReportDescriptor")
+        return bytes(result["ReportDescriptor"])  # type: ignore
+
     async def write(self, data: bytes) -> None:
         result = await self.request({"Write": {"data": list(data)}})
         ComSrvError.check_raise(result)
@@ -88,9 +128,97 @@ async def enumerate_hid_devices(
         x = HidDeviceInfo(
             vid=dev["idn"]["vid"],  # type: ignore
             pid=dev["idn"]["pid"],  # type: ignore
+            interface_number=dev["idn"].get("interface_number"),  # type: ignore
             manufacturer=dev.get("manufacturer"),  # type: ignore
             product=dev.get("product"),  # type: ignore
             serial_number=dev.get("serial_number"),  # type: ignore
         )
         ret.append(x)
     return ret
+
+
+_COLLECTION_TYPES = [
+    "Physical",
+    "Application",
+    "Logical",
+    "Report",
+    "Named Array",
+    "Usage Switch",
+    "Usage Modifier",
+]
+_REPORT_ITEMS = {0x8: "Input", 0x9: "Output", 0xB: "Feature"}
+_USAGE_PAGE = 0x0
+_REPORT_SIZE = 0x7
+_REPORT_ID = 0x8
+_REPORT_COUNT = 0x9
+
+
+def summarize_report_descriptor(descriptor: bytes) -> List[str]:
+    """
+    Summarize the structure of a HID report descriptor. Returns one line per collection and per
+    input, output or feature item, indented by the nesting of collections. Report items list
+    their report id, the number and size of their fields and the usages they apply to.
+    Raises `ValueError` if the descriptor is truncated.
+    """
+    lines: List[str] = []
+    depth = 0
+    global_items: Dict[int, int] = {}
+    usages: List[str] = []
+    usage_minimum = 0
+    pos = 0
+    while pos < len(descriptor):
+        prefix = descriptor[pos]
+        if prefix == 0xFE:
+            # long items are reserved and carry no structure
+            if pos + 1 >= len(descriptor):
+                raise ValueError("Truncated report descriptor")
+            pos += 3 + descriptor[pos + 1]
+            continue
+        size = [0, 1, 2, 4][prefix & 0x3]
+        if pos + 1 + size > len(descriptor):
+            raise ValueError("Truncated report descriptor")
+        data = int.from_bytes(descriptor[pos + 1 : pos + 1 + size], "little")
+        pos += 1 + size
+        item_type = (prefix >> 2) & 0x3
+        tag = prefix >> 4
+        indent = "  " * depth
+        if item_type == 0:
+            page = global_items.get(_USAGE_PAGE, 0)
+            usage_list = ", ".join(usages)
+            if tag == 0xA:
+                kind = (
+                    _COLLECTION_TYPES[data]
+                    if data < len(_COLLECTION_TYPES)
+                    else "0x{:02X}".format(data)
+                )
+                lines.append(
+                    "{}Collection {} (usage page 0x{:02X}, usages [{}])".format(
+                        indent, kind, page, usage_list
+                    )
+                )
+                depth += 1
+            elif tag == 0xC:
+                depth = max(depth - 1, 0)
+            elif tag in _REPORT_ITEMS:
+                lines.append(
+                    "{}{} report {}: {} x {} bits{} (usage page 0x{:02X}, usages [{}])".format(
+                        indent,
+                        _REPORT_ITEMS[tag],
+                        global_items.get(_REPORT_ID, 0),
+                        global_items.get(_REPORT_COUNT, 0),
+                        global_items.get(_REPORT_SIZE, 0),
+                        ", constant" if data & 0x1 else "",
+                        page,
+                        usage_list,
+                    )
+                )
+            usages = []
+        elif item_type == 1:
+            global_items[tag] = data
+        elif item_type == 2 and tag == 0x0:
+            usages.append("0x{:02X}".format(data))
+        elif item_type == 2 and tag == 0x1:
+            usage_minimum = data
+        elif item_type == 2 and tag == 0x2:
+            usages.append("0x{:02X}-0x{:02X}".format(usage_minimum, data))
+    return lines
@@ -19,14 +19,15 @@ from broadcast_wsrpc import JsonType
 class ModBusProtocol(enum.Enum):
     RTU = "Rtu"
     TCP = "Tcp"
+    ASCII = "Ascii"
 
 
 MODBUS_SERIAL_RE = re.compile(
-    r"modbus::(?P<protocol>(rtu)|(tcp))::(?P<port>.*?)::(?P<baudrate>\d+)::(?P<config>[78][ENO][12])(::(?P<station_address>\d+))?"
+    r"modbus::(?P<protocol>(rtu)|(tcp)|(ascii))::(?P<port>.*?)::(?P<baudrate>\d+)::(?P<config>[78][ENO][12])(::(?P<station_address>\d+)(::(?P<inter_frame_delay_ms>\d+)ms)?)?$"
 )
 
 MODBUS_TCP_RE = re.compile(
-    r"modbus::(?P<protocol>(rtu)|(tcp))::(?P<host>.*?):(?P<port>\d+)(::(?P<station_address>\d+))?"
+    r"modbus::(?P<protocol>(rtu)|(tcp)|(ascii))::(?P<host>.*?):(?P<port>\d+)(::(?P<station_address>\d+))?$"
 )
 
 
@@ -38,6 +39,8 @@ def parse_serial_modbus(
         ret_protocol = ModBusProtocol.RTU
     elif protocol == "tcp":
         ret_protocol = ModBusProtocol.TCP
+    elif protocol == "ascii":
+        ret_protocol = ModBusProtocol.ASCII
     else:
         raise AssertionError
     baudrate = int(match.group("baudrate"))
@@ -63,6 +66,8 @@ def parse_tcp_modbus(
         ret_protocol = ModBusProtocol.RTU
     elif protocol == "tcp":
         ret_protocol = ModBusProtocol.TCP
+    elif protocol == "ascii":
+        ret_protocol = ModBusProtocol.ASCII
     else:
         raise AssertionError
     host = match.group("host")
@@ -83,12 +88,35 @@ def parse_tcp_modbus(
 def parse_modbus_address(
     address: str, rpc: Rpc | None = None
 ) -> Tuple[ByteStreamPipe, int, ModBusProtocol]:
+    """
+    Parse a ModBus address string into the pipe, station address and protocol.
+
+    Use `parse_modbus_address_with_delay()` to also obtain the inter-frame delay.
+    """
+    bs_pipe, station_address, protocol, _ = parse_modbus_address_with_delay(
+        address, rpc
+    )
+    return bs_pipe, station_address, protocol
+
+
+def parse_modbus_address_with_delay(
+    address: str, rpc: Rpc | None = None
+) -> Tuple[ByteStreamPipe, int, ModBusProtocol, int]:
+    """
+    Parse a ModBus address string into the pipe, station address, protocol and inter-frame delay in ms.
+
+    Serial addresses may end with `::<delay>ms` following the station address to wait between writing a
+    request and reading the reply, e.g. `modbus::rtu::/dev/ttyUSB0::9600::8N1::1::20ms`.
+    """
     m = MODBUS_SERIAL_RE.match(address)
     if m is not None:
-        return parse_serial_modbus(m, rpc)
+        bs_pipe, station_address, protocol = parse_serial_modbus(m, rpc)
+        inter_frame_delay_ms = int(m.group("inter_frame_delay_ms") or 0)
+        return bs_pipe, station_address, protocol, inter_frame_delay_ms
     m = MODBUS_TCP_RE.match(address)
     if m is not None:
-        return parse_tcp_modbus(m, rpc)
+        bs_pipe, station_address, protocol = parse_tcp_modbus(m, rpc)
+        return bs_pipe, station_address, protocol, 0
     raise ValueError("Could not parse address: `{}`".format(address))
 
 
@@ -101,6 +129,9 @@ class ModBusDevice(object):
     Instead of a `ByteStreamPipe` a resource descriptor string may be provided.
     In this case, the optional `rpc = None` parameter is used to construct the
     `ByteStreamPipe`.
+
+    `inter_frame_delay_ms` is a guard time between writing a request and reading
+    the reply, which slow ModBus RTU devices require.
     """
 
     def __init__(
@@ -110,6 +141,7 @@ class ModBusDevice(object):
         protocol: Optional[ModBusProtocol] = None,
         station_address: int = 1,
         timeout: float = 1.0,
+        inter_frame_delay_ms: int = 0,
     ) -> None:
         if isinstance(bs_pipe, str):
             if (
@@ -119,15 +151,19 @@ class ModBusDevice(object):
             ):
                 bs_pipe = ByteStreamPipe(bs_pipe, rpc=rpc)
             else:
-                bs_pipe, station_address, protocol = parse_modbus_address(
-                    bs_pipe, rpc=rpc
-                )
+                (
+                    bs_pipe,
+                    station_address,
+                    protocol,
+                    inter_frame_delay_ms,
+                ) = parse_modbus_address_with_delay(bs_pipe, rpc=rpc)
         if protocol is None:
             protocol = ModBusProtocol.RTU
         self._station_address = station_address
         self._bs_pipe = bs_pipe
         self._timeout = timeout
         self._protocol = protocol
+        self._inter_frame_delay_ms = inter_frame_delay_ms
 
     @property
     def bytestream_pipe(self) -> ByteStreamPipe:
@@ -161,17 +197,24 @@ class ModBusDevice(object):
     def station_address(self, value: int) -> None:
         self._station_address = value
 
+    @property
+    def inter_frame_delay_ms(self) -> int:
+        return self._inter_frame_delay_ms
+
+    @inter_frame_delay_ms.setter
+    def inter_frame_delay_ms(self, value: int) -> None:
+        self._inter_frame_delay_ms = value
+
     async def request(self, request: JsonType) -> JsonType:
-        result = await self._bs_pipe.request(
-            {
-                "ModBus": {
-                    "timeout": duration_to_json(self._timeout),
-                    "station_address": self.station_address,
-                    "protocol": self.protocol.value,
-                    "request": request,
-                }
-            }
-        )
+        modbus_request = {
+            "timeout": duration_to_json(self._timeout),
+            "station_address": self.station_address,
+            "protocol": self.protocol.value,
+            "request": request,
+        }
+        if self._inter_frame_delay_ms > 0:
+            modbus_request["inter_frame_delay_ms"] = self._inter_frame_delay_ms
+        result = await self._bs_pipe.request({"ModBus": modbus_request})
         ComSrvError.check_raise(result)
         return result["ModBus"]
 
@@ -255,6 +298,17 @@ class ModBusDevice(object):
         assert isinstance(ret, list)
         return ret  # type: ignore
 
+    async def raw_pdu(self, function_code: int, data: bytes) -> bytes:
+        """
+        Send a PDU with an arbitrary function code. Returns the response PDU as is, i.e. the
+        function code followed by the unparsed response data.
+        """
+        result = await self.request(
+            {"RawPdu": {"function_code": function_code, "data": list(data)}}
+        )
+        assert isinstance(result, dict)
+        return bytes(result["Data"])  # type: ignore
+
     async def ddp(
         self,
         sub_cmd: int,
@@ -5,6 +5,21 @@ from . import Address, ComSrvError, BasePipe, Instrument, Rpc, duration_to_json
 from .bytestream import ByteStreamInstrument, ByteStreamPipe, SerialAddress
 from broadcast_wsrpc import JsonType, JsonObject
 
+# VISA attributes accessible with `ScpiPipe.get_attribute()` and `ScpiPipe.set_attribute()`
+VI_ATTR_TMO_VALUE = 0x3FFF001A
+VI_ATTR_TERMCHAR = 0x3FFF0018
+VI_ATTR_TERMCHAR_EN = 0x3FFF0038
+VI_ATTR_SEND_END_EN = 0x3FFF0016
+VI_ATTR_SUPPRESS_END_EN = 0x3FFF0036
+
+VISA_ATTRIBUTES = [
+    VI_ATTR_TMO_VALUE,
+    VI_ATTR_TERMCHAR,
+    VI_ATTR_TERMCHAR_EN,
+    VI_ATTR_SEND_END_EN,
+    VI_ATTR_SUPPRESS_END_EN,
+]
+
 
 class ScpiAddress(Address):
     pass
@@ -225,6 +240,48 @@ class ScpiPipe(BasePipe, ScpiPipeBase):
         data = result["Binary"]["data"]  # type: ignore
         return base64.b64decode(data)  # type: ignore
 
+    async def visa_lock(
+        self, exclusive: bool = True, timeout: float = 1.0
+    ) -> str | None:
+        """
+        Lock the instrument in the VISA driver. Returns the access key of shared locks.
+        This is independent of the locks of the comsrv.
+        """
+        result = await self.request(
+            {"VisaLock": {"exclusive": exclusive, "timeout_ms": int(timeout * 1000)}}
+        )
+        if result == "Done":
+            return None
+        if not isinstance(result, dict) or "AccessKey" not in result:
+            raise ComSrvError("Unexpected response")
+        return result["AccessKey"]  # type: ignore
+
+    async def visa_unlock(self) -> None:
+        await self.request({"VisaUnlock": None})
+
+    async def get_attribute(self, code: int) -> int:
+        """
+        Read a VISA attribute, the supported attributes are listed in `VISA_ATTRIBUTES`.
+        """
+        result = await self.request({"GetAttribute": {"code": code}})
+        if not isinstance(result, dict) or "Attribute" not in result:
+            raise ComSrvError("Unexpected response")
+        return result["Attribute"]  # type: ignore
+
+    async def set_attribute(self, code: int, value: int) -> None:
+        await self.request({"SetAttribute": {"code": code, "value": value}})
+
+    async def get_visa_timeout(self) -> float:
+        return await self.get_attribute(VI_ATTR_TMO_VALUE) / 1000.0
+
+    async def set_visa_timeout(self, timeout: float) -> None:
+        await self.set_attribute(VI_ATTR_TMO_VALUE, int(timeout * 1000))
+
+    async def set_termchar(self, termchar: int | None) -> None:
+        if termchar is not None:
+            await self.set_attribute(VI_ATTR_TERMCHAR, termchar)
+        await self.set_attribute(VI_ATTR_TERMCHAR_EN, int(termchar is not None))
+
 
 class SerialScpiPipe(ScpiPipeBase):
     def __init__(
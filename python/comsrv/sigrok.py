@@ -31,6 +31,14 @@ class SigrokDevice(object):
     def address(self) -> str:
         return self._addr
 
+    async def device_info(self) -> JsonObject:
+        """
+        Query the channels and sample rates supported by the device.
+        """
+        resp = await self._rpc.get({"SigrokDeviceInfo": {"addr": self._addr}}, TIMEOUT)
+        ComSrvError.check_raise(resp)
+        return resp["Sigrok"]["DeviceInfo"]  # type: ignore
+
     async def read(
         self,
         channels: Optional[List[str]] = None,
@@ -72,6 +80,47 @@ class SigrokDevice(object):
         return t, ret  # type: ignore
 
 
+    async def read_analog(
+        self,
+        channels: Optional[List[str]] = None,
+        samplerate: float | int = 1e6,
+        num_samples: int | None = None,
+        time: float | None = None,
+    ) -> tuple[npt.NDArray[np.float64], dict[str, npt.NDArray[np.float32]]]:
+        if time is not None and num_samples is not None:
+            raise ValueError("Specifiy only one of time or num_samples")
+        if time is not None:
+            acquire = {"Time": float(time)}
+        elif num_samples is not None:
+            acquire = {"Samples": int(num_samples)}
+        else:
+            raise ValueError("Neither time nor num_samples is given")
+        if channels is None:
+            channels = []
+        request = {
+            "Sigrok": {
+                "instrument": {"address": self._addr},
+                "request": {
+                    "channels": channels,
+                    "sample_rate": int(samplerate),
+                    "acquire": acquire,
+                    "analog": True,
+                },
+            }
+        }
+        data = await self._rpc.get(request, TIMEOUT)
+        ComSrvError.check_raise(data)
+        data: JsonObject = data["Sigrok"]["AnalogData"]  # type: ignore
+        tsample = float(data["tsample"])  # type: ignore
+        length = data["length"]
+        assert isinstance(length, int)
+        t = np.arange(0, length) * tsample
+        ret = {}
+        for k, v in data["channels"].items():  # type: ignore
+            ret[k] = np.array(v, dtype=np.float32)
+        return t, ret  # type: ignore
+
+
 async def list_devices(rpc: Optional[Rpc] = None) -> List[SigrokDevice]:
     if rpc is None:
         rpc = Rpc.make_default()
@@ -0,0 +1,44 @@
+import asyncio
+
+from broadcast_wsrpc import JsonObject, JsonType
+
+from comsrv import Rpc
+from comsrv.bytestream import ByteStreamPipe
+
+
+class RecordingRpc(Rpc):
+    def __init__(self, reply: JsonType = "Done") -> None:
+        self.requests: list[JsonType] = []
+        self.reply = reply
+
+    async def get(self, request: JsonType, timeout: float) -> JsonObject:
+        self.requests.append(request)
+        return self.reply  # type: ignore
+
+
+def test_script_loopback() -> None:
+    rpc = RecordingRpc()
+    pipe = ByteStreamPipe("loopback::dev", rpc=rpc)
+    asyncio.run(pipe.script_loopback([(b"*IDN?\n", b"Mock\n")]))
+    assert rpc.requests == [
+        {
+            "Loopback": {
+                "instrument": {"name": "dev"},
+                "request": {
+                    "Script": [
+                        {"request": list(b"*IDN?\n"), "response": list(b"Mock\n")}
+                    ]
+                },
+            }
+        }
+    ]
+
+
+def test_cobs_read_max_frame_size() -> None:
+    rpc = RecordingRpc({"Bytes": {"Data": [1, 2]}})
+    pipe = ByteStreamPipe("loopback::dev", rpc=rpc)
+    assert asyncio.run(pipe.cobs_read(1.0)) == b"\x01\x02"
+    assert asyncio.run(pipe.cobs_read(1.0, max_frame_size=16)) == b"\x01\x02"
+    requests = [x["Bytes"]["request"] for x in rpc.requests]  # type: ignore
+    assert "CobsRead" in requests[0]
+    assert requests[1]["CobsReadMax"]["max_frame_size"] == 16
@@ -0,0 +1,9 @@
+from comsrv.can import CanInstrument
+
+
+def test_parse_pcan() -> None:
+    instr = CanInstrument.parse("can::pcan::usb1::1000000")
+    assert instr.to_json() == {"PCan": {"address": "usb1", "bitrate": 1000000}}
+    instr = CanInstrument.parse("can::pcan::usb1")
+    assert instr.bitrate == 0
+    assert instr.to_json() == {"PCan": {"address": "usb1", "bitrate": 0}}
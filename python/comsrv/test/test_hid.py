@@ -0,0 +1,32 @@
+from comsrv.hid import summarize_report_descriptor
+
+# boot protocol mouse with three buttons and relative X/Y axes
+MOUSE = bytes(
+    [
+        0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01,
+        0xA1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03,
+        0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01,
+        0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+        0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81,
+        0x25, 0x7F, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+        0xC0, 0xC0,
+    ]
+)  # fmt: skip
+
+
+def test_summarize_mouse() -> None:
+    assert summarize_report_descriptor(MOUSE) == [
+        "Collection Application (usage page 0x01, usages [0x02])",
+        "  Collection Physical (usage page 0x01, usages [0x01])",
+        "    Input report 0: 3 x 1 bits (usage page 0x09, usages [0x01-0x03])",
+        "    Input report 0: 1 x 5 bits, constant (usage page 0x09, usages [])",
+        "    Input report 0: 2 x 8 bits (usage page 0x01, usages [0x30, 0x31])",
+    ]
+
+
+def test_truncated() -> None:
+    try:
+        summarize_report_descriptor(bytes([0x05, 0x01, 0x26, 0xFF]))
+    except ValueError:
+        return
+    assert False
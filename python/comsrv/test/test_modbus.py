@@ -0,0 +1,52 @@
+from comsrv import Rpc
+from comsrv.modbus import (
+    ModBusProtocol,
+    parse_modbus_address,
+    parse_modbus_address_with_delay,
+)
+
+
+def test_parse_serial() -> None:
+    rpc = Rpc()
+    _, station, protocol, delay = parse_modbus_address_with_delay(
+        "modbus::rtu::/dev/ttyUSB0::9600::8N1::3::20ms", rpc
+    )
+    assert (station, protocol, delay) == (3, ModBusProtocol.RTU, 20)
+    _, station, _, delay = parse_modbus_address_with_delay(
+        "modbus::rtu::/dev/ttyUSB0::9600::8N1::3", rpc
+    )
+    assert (station, delay) == (3, 0)
+    _, station, protocol = parse_modbus_address(
+        "modbus::rtu::/dev/ttyUSB0::9600::8N1::3::20ms", rpc
+    )
+    assert (station, protocol) == (3, ModBusProtocol.RTU)
+
+
+def test_delay_requires_station_address() -> None:
+    try:
+        parse_modbus_address("modbus::rtu::/dev/ttyUSB0::9600::8N1::20ms", Rpc())
+    except ValueError:
+        return
+    assert False
+
+
+def test_parse_tcp() -> None:
+    _, station, protocol = parse_modbus_address(
+        "modbus::tcp::192.168.1.2:502::7", Rpc()
+    )
+    assert (station, protocol) == (7, ModBusProtocol.TCP)
+    _, _, _, delay = parse_modbus_address_with_delay(
+        "modbus::tcp::192.168.1.2:502::7", Rpc()
+    )
+    assert delay == 0
+
+
+def test_parse_ascii() -> None:
+    _, station, protocol = parse_modbus_address(
+        "modbus::ascii::/dev/ttyUSB0::9600::7E1::4", Rpc()
+    )
+    assert (station, protocol) == (4, ModBusProtocol.ASCII)
+    _, station, protocol = parse_modbus_address(
+        "modbus::ascii::192.168.1.2:502::7", Rpc()
+    )
+    assert (station, protocol) == (7, ModBusProtocol.ASCII)